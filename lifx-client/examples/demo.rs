@@ -4,8 +4,7 @@ use std::{net::UdpSocket, time::Duration};
 
 fn main() -> io::Result<()> {
     let socket = UdpSocket::bind("0.0.0.0:0")?;
-    socket.set_read_timeout(Option::Some(Duration::from_millis(500)))?;
-    let mut client = lifx_client::client::Client::new(socket);
+    let client = lifx_client::client::Client::new(socket);
     let devices = client.discover()?;
     for device in devices {
         // Set to warm white at 50% brightness.
@@ -1,17 +1,12 @@
-use lifx_client::{self, client::Client};
-use std::{
-    collections::HashSet,
-    fs::File,
-    io::{self, Write},
-    net::UdpSocket,
-    path::PathBuf,
-    sync::{Arc, Mutex},
-    thread,
-    time::Duration,
+use lifx_client::{
+    self,
+    client::Client,
+    discovery::{DeviceEvent, DiscoveryService},
+    registry::DeviceRegistry,
 };
-use thread::JoinHandle;
+use std::{io, net::UdpSocket, path::PathBuf, thread, time::Duration};
 
-fn main() {
+fn main() -> io::Result<()> {
     let mut args = std::env::args();
     let filename = args.nth(1);
     if filename.is_none() {
@@ -21,49 +16,43 @@ fn main() {
     let filename = filename.unwrap();
     let path = PathBuf::from(&filename);
 
-    if path.exists() {
-        eprintln!("Output file {} already exists.", filename);
-        std::process::exit(1)
-    }
+    // Add to the devices found previously, if any.
+    let mut registry = if path.exists() {
+        DeviceRegistry::load(&path)?
+    } else {
+        DeviceRegistry::new()
+    };
 
     println!("Searching for devices. Press [Enter] when all devices have been found.");
 
-    let stop_searching = Arc::new(Mutex::new(false));
-
-    let stop = stop_searching.clone();
-    let handle: JoinHandle<io::Result<()>> = thread::spawn(move || {
-        let socket = UdpSocket::bind("0.0.0.0:0")?;
-        socket.set_read_timeout(Option::Some(Duration::from_millis(500)))?;
-        let mut client = Client::new(socket);
-        let mut devices = HashSet::new();
-        loop {
-            let new_devices = client.discover()?;
-            for device in new_devices {
-                if !devices.contains(&device) {
-                    println!("Found {} @ {}. ", device.label(), device.address());
-                    devices.insert(device);
-                }
-            }
-            if *stop.lock().unwrap() {
-                println!("Saving device addresses to file.");
-                let mut file = File::create(path)?;
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    let client = Client::new(socket);
+    let (mut service, events) = DiscoveryService::start(client.clone(), Duration::from_secs(1));
 
-                for device in devices {
-                    let mut address = device.address().to_string();
-                    address.push('\n');
-                    file.write_all(address.as_bytes())?;
-                }
-                return io::Result::Ok(());
+    // Print devices as they are found. The thread finishes when the service is stopped.
+    let printer = thread::spawn(move || {
+        for event in events {
+            if let DeviceEvent::Appeared(device) = event {
+                println!("Found {} @ {}. ", device.label(), device.address());
             }
         }
     });
 
     // Wait for user to press enter.
-    io::stdin().read_line(&mut String::new()).unwrap();
-    {
-        let mut stop = stop_searching.lock().unwrap();
-        *stop = true;
+    io::stdin().read_line(&mut String::new())?;
+    service.stop();
+    printer.join().unwrap();
+
+    println!("Saving devices to file.");
+    let devices = client.get_devices();
+    registry.merge(&devices);
+    for device in devices.iter() {
+        match client.get_product(device) {
+            Ok(product) => {
+                registry.set_product(device.address().mac_address(), product);
+            }
+            Err(e) => eprintln!("Could not get product of {}. {}", device.label(), e),
+        }
     }
-
-    handle.join().unwrap().unwrap();
+    registry.save(path)
 }
@@ -1,12 +1,5 @@
-use lifx_client::{client::Client, device::DeviceAddress};
-use std::{
-    collections::HashSet,
-    fs::File,
-    io::{self, BufRead, BufReader},
-    net::UdpSocket,
-    path::PathBuf,
-    time::Duration,
-};
+use lifx_client::{client::Client, registry::DeviceRegistry};
+use std::{io, net::UdpSocket, path::PathBuf};
 
 fn main() -> io::Result<()> {
     let mut args = std::env::args();
@@ -23,26 +16,19 @@ fn main() -> io::Result<()> {
         std::process::exit(1)
     }
 
-    let file = File::open(path);
-    if file.is_err() {
-        eprintln!("Could not open input file {}.", filename);
+    let registry = DeviceRegistry::load(path);
+    if let Err(e) = &registry {
+        eprintln!("Could not read input file {}. {}", filename, e);
         std::process::exit(1)
     }
-    let file = file?;
-    let reader = BufReader::new(file);
-    let mut devices = HashSet::new();
+    let registry = registry?;
 
+    // Use the saved devices instead of discovering them.
     let socket = UdpSocket::bind("0.0.0.0:0")?;
-    socket.set_read_timeout(Option::Some(Duration::from_millis(500)))?;
-    let mut client = Client::new(socket);
+    let client = Client::new(socket);
+    client.load_registry(&registry);
 
-    for line in reader.lines() {
-        let device_address: DeviceAddress = line?.parse().unwrap();
-        let device = client.find_device(device_address)?;
-        devices.insert(device);
-    }
-
-    for device in devices {
+    for device in client.get_devices() {
         client.toggle_power(&device)?;
     }
 
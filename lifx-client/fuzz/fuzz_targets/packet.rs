@@ -0,0 +1,7 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+// Decoding a packet must never panic, regardless of input.
+fuzz_target!(|data: &[u8]| {
+    lifx_client::fuzz_packet(data);
+});
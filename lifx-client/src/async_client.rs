@@ -0,0 +1,773 @@
+use super::{
+    client::{to_millis, ZERO_DURATION},
+    color::Color,
+    device::{self, Device, DeviceAddress},
+    pending::{PendingReplies, Ticket},
+    protocol::{
+        header::{DeviceMessageType, LightMessageType, MacAddress},
+        message::{Hsbk, Message, Power, SetColorPayload, SetPowerPayload, StatePayload},
+        packet::{Packet, PacketBuilder},
+    },
+    state::LightStatus,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    time::Duration,
+};
+use tokio::{
+    net::{ToSocketAddrs, UdpSocket},
+    sync::{mpsc, oneshot, Notify},
+    task::JoinSet,
+};
+
+type Reply = (Packet, SocketAddr);
+type Replies = PendingReplies<mpsc::UnboundedSender<Reply>>;
+
+// Requests waiting for replies.
+struct Pending {
+    replies: Mutex<Replies>,
+    // Notified when a request stops waiting, freeing its sequence.
+    freed: Notify,
+}
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// An asynchronous client for controlling LIFX devices, built on tokio. Requires the `tokio`
+/// feature.
+///
+/// Has the same methods as [`Client`](crate::client::Client), but returns futures instead of
+/// blocking. Clients are cheap to clone, and any number of requests can be in flight at once. If
+/// every sequence is in use, a request waits for one to be freed. Dropping a request's future
+/// before it completes cancels the request.
+pub struct AsyncClient {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    socket: Arc<UdpSocket>,
+    source: u32,
+    sequence: AtomicU8,
+    timeout: RwLock<Duration>,
+    pending: Arc<Pending>,
+    // Devices which have been found, by MAC address.
+    devices: RwLock<HashMap<MacAddress, Device>>,
+    // Stops the receive task when the last clone of the client is dropped.
+    _shutdown: oneshot::Sender<()>,
+}
+
+impl Clone for AsyncClient {
+    fn clone(&self) -> Self {
+        AsyncClient {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl AsyncClient {
+    /// Create a client which communicates over a socket bound to `address`.
+    pub async fn bind<A: ToSocketAddrs>(address: A) -> io::Result<AsyncClient> {
+        Result::Ok(AsyncClient::new(UdpSocket::bind(address).await?))
+    }
+
+    /// Create a client which communicates over `socket`. Responses are received by a task on the
+    /// current tokio runtime, so this must be called from within a runtime.
+    ///
+    /// Broadcasting is enabled on the socket, so that discovery can broadcast without changing
+    /// the socket while other requests are using it.
+    pub fn new(socket: UdpSocket) -> AsyncClient {
+        // Discovery fails if broadcasting cannot be enabled, but other requests still work.
+        let _ = socket.set_broadcast(true);
+        let socket = Arc::new(socket);
+        let pending = Arc::new(Pending {
+            replies: Mutex::new(PendingReplies::new()),
+            freed: Notify::new(),
+        });
+        let (shutdown, stopped) = oneshot::channel();
+        tokio::spawn(receive_loop(socket.clone(), pending.clone(), stopped));
+
+        AsyncClient {
+            inner: Arc::new(Inner {
+                socket,
+                source: rand::random::<u32>(),
+                sequence: AtomicU8::new(0),
+                timeout: RwLock::new(DEFAULT_TIMEOUT),
+                pending,
+                devices: RwLock::new(HashMap::new()),
+                _shutdown: shutdown,
+            }),
+        }
+    }
+
+    /// Set the time to wait for each response from a device. Discovery waits this long after the
+    /// last response before finishing. Defaults to 500 milliseconds.
+    pub fn set_timeout(&self, timeout: Duration) {
+        *self
+            .inner
+            .timeout
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = timeout;
+    }
+
+    /// Returns information about LIFX devices on the network, including any found previously.
+    /// Devices which do not respond are skipped.
+    pub async fn discover(&self) -> io::Result<HashSet<Device>> {
+        let packet = PacketBuilder::with_empty_device_message(DeviceMessageType::GetService)
+            .source(self.source())
+            .sequence(self.sequence())
+            .res_required(true)
+            .build();
+        let replies = self
+            .broadcast(SocketAddr::from((Ipv4Addr::BROADCAST, 56700)), packet)
+            .await?;
+
+        // Ignore anything other than StateService, such as replies from other controllers.
+        let device_addresses: HashSet<DeviceAddress> = replies
+            .into_iter()
+            .filter_map(|(response, addr)| device::to_device_address(&response, addr).ok())
+            .collect();
+
+        // Query each device concurrently, so one slow device does not hold up the others. Devices
+        // which do not respond are skipped.
+        let mut tasks = JoinSet::new();
+        for address in device_addresses {
+            let client = self.clone();
+            tasks.spawn(async move { client.find_device(address).await });
+        }
+        while tasks.join_next().await.is_some() {}
+        Result::Ok(self.get_devices())
+    }
+
+    pub async fn find_device(&self, device_address: DeviceAddress) -> io::Result<Device> {
+        let get = |message_type| {
+            let packet = PacketBuilder::with_empty_device_message(message_type)
+                .target(device_address.mac_address())
+                .source(self.source())
+                .sequence(self.sequence())
+                .res_required(true)
+                .build();
+            self.request(device_address.socket_address(), packet)
+        };
+        let (label, group, location) = tokio::try_join!(
+            get(DeviceMessageType::GetLabel),
+            get(DeviceMessageType::GetGroup),
+            get(DeviceMessageType::GetLocation),
+        )?;
+
+        let device = match (label, group, location) {
+            (
+                Message::StateLabel(label),
+                Message::StateGroup(group),
+                Message::StateLocation(location),
+            ) => Device::new(
+                device_address,
+                label.label().to_string(),
+                group.label().to_string(),
+                location.label().to_string(),
+            ),
+            responses => return Result::Err(unexpected(responses)),
+        };
+
+        self.devices_mut()
+            .insert(device.mac_address(), device.clone());
+        Result::Ok(device)
+    }
+
+    /// Forget all devices which have been found.
+    pub fn forget_devices(&self) {
+        self.devices_mut().clear();
+    }
+
+    /// Return all devices which have been found.
+    pub fn get_devices(&self) -> HashSet<Device> {
+        self.devices().values().cloned().collect()
+    }
+
+    /// Return the device with `mac_address`, if it has been found.
+    pub fn device_by_mac(&self, mac_address: MacAddress) -> Option<Device> {
+        self.devices().get(&mac_address).cloned()
+    }
+
+    /// Return a device with `label`, if one has been found. If several devices have the same label,
+    /// any one of them is returned.
+    pub fn device_by_label(&self, label: &str) -> Option<Device> {
+        self.devices()
+            .values()
+            .find(|device| device.label() == label)
+            .cloned()
+    }
+
+    pub async fn get_color(&self, device: &Device) -> io::Result<Color> {
+        Result::Ok(self.get_state(device).await?.color().into())
+    }
+
+    /// Return the color, power and label reported by a device.
+    pub async fn get_status(&self, device: &Device) -> io::Result<LightStatus> {
+        Result::Ok(self.get_state(device).await?.into())
+    }
+
+    pub async fn transition_on(&self, device: &Device, duration: Duration) -> io::Result<()> {
+        self.set_power(device, Power::On(0xffff), duration).await
+    }
+
+    pub async fn turn_on(&self, device: &Device) -> io::Result<()> {
+        self.transition_on(device, ZERO_DURATION).await
+    }
+
+    pub async fn transition_off(&self, device: &Device, duration: Duration) -> io::Result<()> {
+        self.set_power(device, Power::Off, duration).await
+    }
+
+    pub async fn turn_off(&self, device: &Device) -> io::Result<()> {
+        self.transition_off(device, ZERO_DURATION).await
+    }
+
+    pub async fn transition_toggle(&self, device: &Device, duration: Duration) -> io::Result<()> {
+        match self.get_state(device).await?.power() {
+            Power::Off => self.transition_on(device, duration).await,
+            Power::On(_) => self.transition_off(device, duration).await,
+        }
+    }
+
+    pub async fn toggle_power(&self, device: &Device) -> io::Result<()> {
+        self.transition_toggle(device, ZERO_DURATION).await
+    }
+
+    pub async fn transition_brightness(
+        &self,
+        device: &Device,
+        brightness: f32,
+        duration: Duration,
+    ) -> io::Result<()> {
+        if brightness <= 0.0 {
+            return self.transition_off(device, duration).await;
+        }
+
+        let state = self.get_state(device).await?;
+        let brightness_value = (f32::min(brightness, 1.0) * 0xffff as f32) as u16;
+
+        // Turn on before adjusting brightness, if necessary.
+        if state.power() == Power::Off {
+            self.turn_on(device).await?;
+        }
+        self.send_color(
+            device,
+            state.color().with_brightness(brightness_value),
+            duration,
+        )
+        .await
+    }
+
+    pub async fn set_brightness(&self, device: &Device, brightness: f32) -> io::Result<()> {
+        self.transition_brightness(device, brightness, ZERO_DURATION)
+            .await
+    }
+
+    pub async fn transition_color(
+        &self,
+        device: &Device,
+        color: Color,
+        duration: Duration,
+    ) -> io::Result<()> {
+        self.send_color(device, color.into(), duration).await
+    }
+
+    pub async fn set_color(&self, device: &Device, color: Color) -> io::Result<()> {
+        self.transition_color(device, color, ZERO_DURATION).await
+    }
+
+    pub async fn transition_temperature(
+        &self,
+        device: &Device,
+        temperature: u16,
+        duration: Duration,
+    ) -> io::Result<()> {
+        let hsbk = self.get_state(device).await?.color();
+        self.send_color(
+            device,
+            hsbk.with_hue(0).with_saturation(0).with_kelvin(temperature),
+            duration,
+        )
+        .await
+    }
+
+    pub async fn set_temperature(&self, device: &Device, temperature: u16) -> io::Result<()> {
+        self.transition_temperature(device, temperature, ZERO_DURATION)
+            .await
+    }
+
+    pub async fn transition_temperature_brightness(
+        &self,
+        device: &Device,
+        temperature: u16,
+        brightness: f32,
+        duration: Duration,
+    ) -> io::Result<()> {
+        let hsbk = self.get_state(device).await?.color();
+        let brightness_value = (f32::min(brightness, 1.0) * 0xffff as f32) as u16;
+        self.send_color(
+            device,
+            hsbk.with_hue(0)
+                .with_saturation(0)
+                .with_kelvin(temperature)
+                .with_brightness(brightness_value),
+            duration,
+        )
+        .await
+    }
+
+    pub async fn set_temperature_brightness(
+        &self,
+        device: &Device,
+        temperature: u16,
+        brightness: f32,
+    ) -> io::Result<()> {
+        self.transition_temperature_brightness(device, temperature, brightness, ZERO_DURATION)
+            .await
+    }
+
+    async fn get_state(&self, device: &Device) -> io::Result<StatePayload> {
+        let packet = PacketBuilder::with_empty_light_message(LightMessageType::Get)
+            .target(device.mac_address())
+            .source(self.source())
+            .sequence(self.sequence())
+            .res_required(true)
+            .build();
+
+        match self.request(device.socket_address(), packet).await? {
+            Message::State(state_payload) => Result::Ok(state_payload),
+            response => Result::Err(unexpected(response)),
+        }
+    }
+
+    async fn set_power(&self, device: &Device, power: Power, duration: Duration) -> io::Result<()> {
+        let payload = SetPowerPayload::new(power, to_millis(duration));
+        let packet = PacketBuilder::new(Message::SetPower(payload))
+            .target(device.mac_address())
+            .source(self.source())
+            .sequence(self.sequence())
+            .build();
+
+        self.inner
+            .socket
+            .send_to(&packet.as_bytes(), device.socket_address())
+            .await?;
+        Result::Ok(())
+    }
+
+    async fn send_color(&self, device: &Device, color: Hsbk, duration: Duration) -> io::Result<()> {
+        let payload = SetColorPayload::new(color, to_millis(duration));
+        let packet = PacketBuilder::new(Message::SetColor(payload))
+            .target(device.mac_address())
+            .source(self.source())
+            .sequence(self.sequence())
+            .res_required(true)
+            .build();
+
+        self.request(device.socket_address(), packet).await?;
+        Result::Ok(())
+    }
+
+    /// Send a packet and return the message from the response.
+    async fn request(&self, socket_address: SocketAddr, packet: Packet) -> io::Result<Message> {
+        let (mut registration, packet) = self.register(packet).await?;
+        self.inner
+            .socket
+            .send_to(&packet.as_bytes(), socket_address)
+            .await?;
+        match tokio::time::timeout(self.timeout(), registration.receiver.recv()).await {
+            Ok(Some((response, _))) => Result::Ok(response.message().clone()),
+            _ => Result::Err(timed_out()),
+        }
+    }
+
+    /// Send a packet to a broadcast address and return all responses, along with the address they
+    /// were received from. Waits for the timeout after the last response.
+    async fn broadcast(
+        &self,
+        socket_address: SocketAddr,
+        packet: Packet,
+    ) -> io::Result<Vec<Reply>> {
+        let (mut registration, packet) = self.register(packet).await?;
+        self.inner
+            .socket
+            .send_to(&packet.as_bytes(), socket_address)
+            .await?;
+
+        let mut replies = Vec::new();
+        while let Ok(Some(reply)) =
+            tokio::time::timeout(self.timeout(), registration.receiver.recv()).await
+        {
+            replies.push(reply);
+        }
+        Result::Ok(replies)
+    }
+
+    /// Register to receive replies to `packet` until the registration is dropped, and return the
+    /// packet to send. The packet is given a sequence which no other waiting request is using,
+    /// waiting up to the timeout for one to be freed if every one is in use.
+    async fn register(&self, packet: Packet) -> io::Result<(Registration, Packet)> {
+        let pending = &self.inner.pending;
+        let deadline = tokio::time::Instant::now() + self.timeout();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let mut packet = packet;
+        loop {
+            // Start listening before checking, so a sequence freed in between is not missed.
+            let freed = pending.freed.notified();
+            match lock_replies(pending).insert(packet, sender.clone()) {
+                Ok((ticket, packet)) => {
+                    let registration = Registration {
+                        pending: pending.clone(),
+                        ticket,
+                        receiver,
+                    };
+                    return Result::Ok((registration, packet));
+                }
+                Err(unsent) => packet = unsent,
+            }
+            if tokio::time::timeout_at(deadline, freed).await.is_err() {
+                return Result::Err(timed_out());
+            }
+        }
+    }
+
+    fn timeout(&self) -> Duration {
+        *self
+            .inner
+            .timeout
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn source(&self) -> u32 {
+        self.inner.source
+    }
+
+    /// Return current sequence value then increment.
+    fn sequence(&self) -> u8 {
+        self.inner.sequence.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn devices(&self) -> RwLockReadGuard<'_, HashMap<MacAddress, Device>> {
+        // Devices are only inserted and removed, so the map is always valid.
+        self.inner
+            .devices
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn devices_mut(&self) -> RwLockWriteGuard<'_, HashMap<MacAddress, Device>> {
+        self.inner
+            .devices
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// A request waiting for replies. Stops receiving replies when dropped, including when the future
+/// which owns it is cancelled.
+struct Registration {
+    pending: Arc<Pending>,
+    ticket: Ticket,
+    receiver: mpsc::UnboundedReceiver<Reply>,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        lock_replies(&self.pending).remove(&self.ticket);
+        self.pending.freed.notify_waiters();
+    }
+}
+
+fn lock_replies(pending: &Pending) -> MutexGuard<'_, Replies> {
+    // Requests are only inserted and removed, so the map is always valid.
+    pending
+        .replies
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Receive packets and send them to the requests waiting for them, until `stopped` completes.
+async fn receive_loop(
+    socket: Arc<UdpSocket>,
+    pending: Arc<Pending>,
+    mut stopped: oneshot::Receiver<()>,
+) {
+    let mut buf = [0u8; 1024];
+    loop {
+        tokio::select! {
+            _ = &mut stopped => return,
+            received = socket.recv_from(&mut buf) => {
+                // Errors such as ICMP port unreachable only affect a single datagram.
+                if let Ok((n, socket_address)) = received {
+                    if let Ok(packet) = Packet::try_from(&buf[..n]) {
+                        if let Some(sender) = lock_replies(&pending).get(&packet) {
+                            let _ = sender.send((packet, socket_address));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn timed_out() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "Timed out waiting for a response.")
+}
+
+fn unexpected<M: std::fmt::Debug>(response: M) -> io::Error {
+    io::Error::other(format!("Unexpected response. {:?}", response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{
+        header::MessageType,
+        message::{StateGroupPayload, StateLabelPayload, StateLocationPayload},
+    };
+    use crate::testing::mac;
+
+    async fn device_socket() -> (UdpSocket, SocketAddr) {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let address = socket.local_addr().unwrap();
+        (socket, address)
+    }
+
+    async fn client() -> AsyncClient {
+        let client = AsyncClient::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        client.set_timeout(Duration::from_secs(5));
+        client
+    }
+
+    #[tokio::test]
+    async fn dispatch_replies_by_sequence() {
+        let (device, device_address) = device_socket().await;
+        // Respond to two requests in reverse order, with the sequence as the label.
+        let responder = tokio::spawn(async move {
+            let mut buf = [0u8; 128];
+            let mut requests = Vec::new();
+            for _ in 0..2 {
+                let (n, client_address) = device.recv_from(&mut buf).await.unwrap();
+                requests.push((Packet::try_from(&buf[..n]).unwrap(), client_address));
+            }
+            for (request, client_address) in requests.iter().rev() {
+                let sequence = request.frame_address().sequence;
+                let label = StateLabelPayload::new(sequence.to_string());
+                let response = PacketBuilder::new(Message::StateLabel(label))
+                    .source(request.frame().source)
+                    .sequence(sequence)
+                    .build();
+                device
+                    .send_to(&response.as_bytes(), client_address)
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let client = client().await;
+        let request = |sequence| {
+            let packet = PacketBuilder::with_empty_device_message(DeviceMessageType::GetLabel)
+                .source(client.source())
+                .sequence(sequence)
+                .build();
+            client.request(device_address, packet)
+        };
+        let (first, second) = tokio::try_join!(request(0), request(1)).unwrap();
+        responder.await.unwrap();
+
+        for (sequence, response) in [(0, first), (1, second)].iter() {
+            match response {
+                Message::StateLabel(payload) => assert_eq!(payload.label(), sequence.to_string()),
+                message => panic!("Unexpected response. {:?}", message),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn more_requests_than_sequences() {
+        const REQUESTS: u16 = 300;
+        let (device, device_address) = device_socket().await;
+        // Answer each batch of requests once no more arrive, labelled with the device the request
+        // was sent to.
+        let responder = tokio::spawn(async move {
+            let mut buf = [0u8; 128];
+            let mut answered = 0;
+            while answered < REQUESTS {
+                let mut requests = Vec::new();
+                while let Ok(Ok((n, client_address))) =
+                    tokio::time::timeout(Duration::from_millis(100), device.recv_from(&mut buf))
+                        .await
+                {
+                    requests.push((Packet::try_from(&buf[..n]).unwrap(), client_address));
+                }
+                for (request, client_address) in requests.iter().rev() {
+                    let target = request.frame_address().target;
+                    let label = StateLabelPayload::new(format!("{:?}", target.address));
+                    let response = PacketBuilder::new(Message::StateLabel(label))
+                        .source(request.frame().source)
+                        .sequence(request.frame_address().sequence)
+                        .target(target)
+                        .build();
+                    device
+                        .send_to(&response.as_bytes(), client_address)
+                        .await
+                        .unwrap();
+                    answered += 1;
+                }
+            }
+        });
+
+        let client = client().await;
+        let mut requests = JoinSet::new();
+        for n in 0..REQUESTS {
+            let client = client.clone();
+            requests.spawn(async move {
+                let [high, low] = n.to_be_bytes();
+                let target = MacAddress {
+                    address: [0xd0, 0x73, 0xd5, 0x00, high, low],
+                };
+                // Every request starts with the same sequence.
+                let packet = PacketBuilder::with_empty_device_message(DeviceMessageType::GetLabel)
+                    .source(client.source())
+                    .target(target)
+                    .build();
+                let response = client.request(device_address, packet).await.unwrap();
+                let expected = StateLabelPayload::new(format!("{:?}", target.address));
+                assert_eq!(response, Message::StateLabel(expected));
+            });
+        }
+        while let Some(result) = requests.join_next().await {
+            result.unwrap();
+        }
+        responder.await.unwrap();
+        assert!(lock_replies(&client.inner.pending).is_empty());
+    }
+
+    // Act as `count` lights behind one socket, labelled "Bulb <n>", until no request arrives for a
+    // second.
+    async fn serve_lights(device: UdpSocket, count: u8) {
+        let mut lights: HashMap<MacAddress, (Hsbk, Power)> = (1..=count)
+            .map(|n| (mac(n), (Hsbk::new(0, 0, 0xffff, 3500), Power::Off)))
+            .collect();
+        let mut buf = [0u8; 128];
+        while let Ok(Ok((n, client_address))) =
+            tokio::time::timeout(Duration::from_secs(1), device.recv_from(&mut buf)).await
+        {
+            let request = Packet::try_from(&buf[..n]).unwrap();
+            let target = request.frame_address().target;
+            let label = format!("Bulb {}", target.address[5]);
+            let (color, power) = lights.get_mut(&target).unwrap();
+            let message = match request.message() {
+                Message::SetColor(payload) => {
+                    *color = payload.color();
+                    Message::State(StatePayload::new(*color, *power, label))
+                }
+                Message::SetPower(payload) => {
+                    *power = payload.power();
+                    continue;
+                }
+                message => match message.message_type() {
+                    MessageType::Device(DeviceMessageType::GetLabel) => {
+                        Message::StateLabel(StateLabelPayload::new(label))
+                    }
+                    MessageType::Device(DeviceMessageType::GetGroup) => {
+                        Message::StateGroup(StateGroupPayload::new([0; 16], "Group".into(), 0))
+                    }
+                    MessageType::Device(DeviceMessageType::GetLocation) => Message::StateLocation(
+                        StateLocationPayload::new([0; 16], "Location".into(), 0),
+                    ),
+                    MessageType::Light(LightMessageType::Get) => {
+                        Message::State(StatePayload::new(*color, *power, label))
+                    }
+                    message_type => panic!("Unexpected request. {}", message_type),
+                },
+            };
+            let response = PacketBuilder::new(message)
+                .source(request.frame().source)
+                .sequence(request.frame_address().sequence)
+                .target(target)
+                .build();
+            device
+                .send_to(&response.as_bytes(), client_address)
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn control_lights_concurrently() {
+        let (device, device_address) = device_socket().await;
+        let lights = tokio::spawn(serve_lights(device, 8));
+        let client = client().await;
+
+        let mut devices = Vec::new();
+        for n in 1..=8 {
+            let address = format!("{}#{}", mac(n), device_address);
+            devices.push(client.find_device(address.parse().unwrap()).await.unwrap());
+        }
+        assert_eq!(devices[2].label(), "Bulb 3");
+        assert_eq!(client.get_devices().len(), 8);
+
+        // Use a different hue for each light, so misdirected replies are detected.
+        let mut tasks = JoinSet::new();
+        for (n, device) in devices.iter().cloned().enumerate() {
+            let client = client.clone();
+            tasks.spawn(async move {
+                for saturation in 0..10u16 {
+                    let color = Hsbk::new(n as u16 * 1000, saturation * 1000, 0xffff, 3500);
+                    client.set_color(&device, color.into()).await.unwrap();
+                    assert_eq!(Hsbk::from(client.get_color(&device).await.unwrap()), color);
+                }
+            });
+        }
+        while let Some(result) = tasks.join_next().await {
+            result.unwrap();
+        }
+
+        // Turns the light on before setting the brightness.
+        client.set_brightness(&devices[0], 0.5).await.unwrap();
+        let status = client.get_status(&devices[0]).await.unwrap();
+        assert!(status.power());
+        assert_eq!(Hsbk::from(status.color()).brightness(), 0x7fff);
+
+        drop(client);
+        lights.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn request_timeout() {
+        let (_device, device_address) = device_socket().await;
+        let client = client().await;
+        client.set_timeout(Duration::from_millis(50));
+
+        let packet = PacketBuilder::with_empty_device_message(DeviceMessageType::GetLabel).build();
+        let error = client.request(device_address, packet).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert!(lock_replies(&client.inner.pending).is_empty());
+    }
+
+    #[tokio::test]
+    async fn cancelled_request() {
+        let (_device, device_address) = device_socket().await;
+        let client = client().await;
+
+        let packet = PacketBuilder::with_empty_device_message(DeviceMessageType::GetLabel).build();
+        let request = client.request(device_address, packet);
+        let cancelled = tokio::time::timeout(Duration::from_millis(50), request).await;
+        assert!(cancelled.is_err());
+        assert!(lock_replies(&client.inner.pending).is_empty());
+    }
+
+    #[tokio::test]
+    async fn client_is_send_sync_clone() {
+        fn assert_send_sync_clone<C: Send + Sync + Clone>(_: &C) {}
+        assert_send_sync_clone(&client().await);
+    }
+}
@@ -0,0 +1,224 @@
+use super::protocol::{
+    header::MacAddress,
+    message::{Hsbk, Power, SetWaveformOptionalPayload, StatePayload},
+};
+use std::{
+    collections::HashMap,
+    sync::{Mutex, MutexGuard, RwLock},
+    time::{Duration, Instant},
+};
+
+/// Caches the last known state of each device, so that relative changes do not have to query the
+/// device first.
+///
+/// States are cached for a fixed time to live. A command which starts a transition also expires
+/// the state when the transition completes, so the next request reads the state the device
+/// actually reached.
+pub(crate) struct StateCache {
+    ttl: RwLock<Option<Duration>>,
+    states: Mutex<HashMap<MacAddress, CachedState>>,
+}
+
+struct CachedState {
+    state: StatePayload,
+    expires: Instant,
+}
+
+impl StateCache {
+    pub(crate) fn new(ttl: Option<Duration>) -> StateCache {
+        StateCache {
+            ttl: RwLock::new(ttl),
+            states: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Set how long states are cached, and remove every cached state. `None` disables the cache.
+    pub(crate) fn set_ttl(&self, ttl: Option<Duration>) {
+        *self
+            .ttl
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = ttl;
+        self.clear();
+    }
+
+    /// Return the cached state of a device, if it has not expired.
+    pub(crate) fn get(&self, mac_address: MacAddress) -> Option<StatePayload> {
+        let mut states = self.lock_states();
+        match states.get(&mac_address) {
+            Some(cached) if cached.expires > Instant::now() => Option::Some(cached.state.clone()),
+            Some(_) => {
+                states.remove(&mac_address);
+                Option::None
+            }
+            None => Option::None,
+        }
+    }
+
+    /// Cache a state reported by a device.
+    pub(crate) fn update(&self, mac_address: MacAddress, state: StatePayload) {
+        if let Some(ttl) = self.ttl() {
+            self.lock_states().insert(
+                mac_address,
+                CachedState {
+                    state,
+                    expires: Instant::now() + ttl,
+                },
+            );
+        }
+    }
+
+    /// Update the cached power of a device after sending it a command.
+    pub(crate) fn set_power(&self, mac_address: MacAddress, power: Power, duration: Duration) {
+        self.modify(mac_address, duration, |state| {
+            StatePayload::new(state.color(), power, state.label().to_string())
+        });
+    }
+
+    /// Update the cached color of a device after sending it a command.
+    pub(crate) fn set_color(&self, mac_address: MacAddress, color: Hsbk, duration: Duration) {
+        self.modify(mac_address, duration, |state| {
+            StatePayload::new(color, state.power(), state.label().to_string())
+        });
+    }
+
+    /// Update the cached color of a device after sending it a waveform. A transient waveform
+    /// returns to the original color, so the cached color does not change.
+    pub(crate) fn set_waveform(
+        &self,
+        mac_address: MacAddress,
+        payload: &SetWaveformOptionalPayload,
+    ) {
+        if payload.transient() {
+            return;
+        }
+        let duration = Duration::from_millis(payload.period() as u64);
+        self.modify(mac_address, duration, |state| {
+            StatePayload::new(
+                payload.apply_to(state.color()),
+                state.power(),
+                state.label().to_string(),
+            )
+        });
+    }
+
+    /// Remove the cached state of a device, such as after a command to it fails.
+    pub(crate) fn invalidate(&self, mac_address: MacAddress) {
+        self.lock_states().remove(&mac_address);
+    }
+
+    /// Remove every cached state, such as after a command is broadcast to every device.
+    pub(crate) fn clear(&self) {
+        self.lock_states().clear();
+    }
+
+    // Replace a cached state which has not expired. A state which is not cached stays uncached,
+    // since the rest of it is unknown.
+    fn modify<F>(&self, mac_address: MacAddress, duration: Duration, f: F)
+    where
+        F: FnOnce(&StatePayload) -> StatePayload,
+    {
+        let ttl = match self.ttl() {
+            Some(ttl) => ttl,
+            None => return,
+        };
+        let now = Instant::now();
+        let mut states = self.lock_states();
+        if let Some(cached) = states.get_mut(&mac_address) {
+            if cached.expires <= now {
+                states.remove(&mac_address);
+                return;
+            }
+            cached.state = f(&cached.state);
+            cached.expires = if duration > Duration::from_secs(0) {
+                now + Duration::min(ttl, duration)
+            } else {
+                now + ttl
+            };
+        }
+    }
+
+    fn ttl(&self) -> Option<Duration> {
+        *self
+            .ttl
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn lock_states(&self) -> MutexGuard<'_, HashMap<MacAddress, CachedState>> {
+        // States are only inserted, replaced and removed, so the map is always valid.
+        self.states
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    const MAC: MacAddress = MacAddress {
+        address: [0xd0, 0x73, 0xd5, 0x00, 0x00, 0x01],
+    };
+
+    fn state(power: Power) -> StatePayload {
+        StatePayload::new(Hsbk::new(0, 0, 0xffff, 3500), power, "Lamp".to_string())
+    }
+
+    #[test]
+    fn disabled() {
+        let cache = StateCache::new(Option::None);
+        cache.update(MAC, state(Power::Off));
+        assert_eq!(cache.get(MAC), Option::None);
+    }
+
+    #[test]
+    fn expire_after_ttl() {
+        let cache = StateCache::new(Option::Some(Duration::from_millis(50)));
+        cache.update(MAC, state(Power::Off));
+        assert_eq!(cache.get(MAC), Option::Some(state(Power::Off)));
+
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(cache.get(MAC), Option::None);
+    }
+
+    #[test]
+    fn update_from_commands() {
+        let cache = StateCache::new(Option::Some(Duration::from_secs(60)));
+
+        // Commands to a device whose state is unknown are not cached.
+        cache.set_power(MAC, Power::On(0xffff), Duration::from_secs(0));
+        assert_eq!(cache.get(MAC), Option::None);
+
+        cache.update(MAC, state(Power::Off));
+        let color = Hsbk::new(100, 200, 300, 4000);
+        cache.set_power(MAC, Power::On(0xffff), Duration::from_secs(0));
+        cache.set_color(MAC, color, Duration::from_secs(0));
+        let cached = cache.get(MAC).unwrap();
+        assert_eq!(cached.power(), Power::On(0xffff));
+        assert_eq!(cached.color(), color);
+        assert_eq!(cached.label(), "Lamp");
+
+        cache.invalidate(MAC);
+        assert_eq!(cache.get(MAC), Option::None);
+    }
+
+    #[test]
+    fn expire_when_transition_completes() {
+        let cache = StateCache::new(Option::Some(Duration::from_secs(60)));
+        cache.update(MAC, state(Power::Off));
+        cache.set_power(MAC, Power::On(0xffff), Duration::from_millis(50));
+        assert_eq!(cache.get(MAC).unwrap().power(), Power::On(0xffff));
+
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(cache.get(MAC), Option::None);
+    }
+
+    #[test]
+    fn disable_clears_states() {
+        let cache = StateCache::new(Option::Some(Duration::from_secs(60)));
+        cache.update(MAC, state(Power::Off));
+        cache.set_ttl(Option::None);
+        assert_eq!(cache.get(MAC), Option::None);
+    }
+}
@@ -1,85 +1,357 @@
 use super::{
+    cache::StateCache,
     color::Color,
+    connection::Connection,
     device::{self, Device},
+    discovery::{DiscoveryOptions, DiscoveryReport},
     light,
-    protocol::message::{Power, StatePayload},
+    pcap::PcapWriter,
+    protocol::{
+        header::MacAddress,
+        message::{Hsbk, Message, Power, SetWaveformOptionalPayload, StatePayload},
+    },
+    registry::DeviceRegistry,
+    state::{LightState, LightStatus},
+    stream::ColorStream,
+    transport::Transport,
+};
+use device::{DeviceAddress, DeviceUpdate};
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, Write},
+    net::UdpSocket,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    thread,
+    time::Duration,
 };
-use device::DeviceAddress;
-use std::{cell::Cell, collections::HashSet, io, net::UdpSocket, time::Duration};
 
-const ZERO_DURATION: Duration = Duration::from_secs(0);
+pub(crate) const ZERO_DURATION: Duration = Duration::from_secs(0);
 const MAX_DURATION: Duration = Duration::from_millis(u32::MAX as u64);
+const DEFAULT_RELOCATE_AFTER: u32 = 2;
+
+/// A client for controlling LIFX devices. Communicates over a [`UdpSocket`] by default, but any
+/// [`Transport`] can be used.
+///
+/// Clients are cheap to clone and can be shared between threads. Clones share the same transport,
+/// devices and settings. Responses are received by a background thread, so requests to different
+/// devices can be made from many threads at once.
+pub struct Client<T: Transport = UdpSocket> {
+    inner: Arc<Inner<T>>,
+}
 
-pub struct Client {
-    socket: UdpSocket,
+struct Inner<T: Transport> {
+    connection: Arc<Connection<T>>,
+    discovery: RwLock<DiscoveryOptions>,
     source: u32,
-    sequence: Cell<u8>,
-    devices: HashSet<Device>,
+    sequence: AtomicU8,
+    // Devices which have been found, by MAC address.
+    devices: RwLock<HashMap<MacAddress, Device>>,
+    relocation: Mutex<Relocation>,
+    collections: Mutex<Collections>,
+    states: StateCache,
+}
+
+// The newest label reported for each group and location, by ID. A device may still report the old
+// label of a group or location which was renamed from another device, so the label with the latest
+// `updated_at` timestamp is used.
+#[derive(Default)]
+struct Collections {
+    groups: HashMap<[u8; 16], Collection>,
+    locations: HashMap<[u8; 16], Collection>,
+}
+
+struct Collection {
+    label: String,
+    updated_at: u64,
+}
+
+// Tracks devices which stop responding, so they can be found again if their address changes.
+struct Relocation {
+    // The number of consecutive timeouts after which a device is relocated, if enabled.
+    after: Option<u32>,
+    // Consecutive timeouts for each device.
+    timeouts: HashMap<MacAddress, u32>,
+}
+
+impl<T: Transport> Clone for Client<T> {
+    fn clone(&self) -> Self {
+        Client {
+            inner: self.inner.clone(),
+        }
+    }
 }
 
 impl Client {
-    pub fn new(socket: UdpSocket) -> Client {
+    /// Create a client with a socket bound to the interface in `options`, which discovers devices
+    /// using `options`.
+    pub fn with_discovery_options(options: DiscoveryOptions) -> io::Result<Client> {
+        let client = Client::new(options.bind()?);
+        client.set_discovery_options(options);
+        Result::Ok(client)
+    }
+}
+
+impl<T: Transport> Client<T> {
+    /// Create a client which communicates using `transport`. Messages are not rate limited unless
+    /// a limit is set with [`set_rate_limit`](Client::set_rate_limit).
+    pub fn new(transport: T) -> Client<T> {
         Client {
-            socket,
-            source: rand::random::<u32>(),
-            sequence: Cell::new(0),
-            devices: HashSet::new(),
+            inner: Arc::new(Inner {
+                connection: Connection::new(transport),
+                discovery: RwLock::new(DiscoveryOptions::default()),
+                source: rand::random::<u32>(),
+                sequence: AtomicU8::new(0),
+                devices: RwLock::new(HashMap::new()),
+                relocation: Mutex::new(Relocation {
+                    after: Option::Some(DEFAULT_RELOCATE_AFTER),
+                    timeouts: HashMap::new(),
+                }),
+                collections: Mutex::new(Collections::default()),
+                states: StateCache::new(Option::None),
+            }),
         }
     }
 
-    /// Returns information about LIFX devices on the network.
-    pub fn discover(&mut self) -> Result<HashSet<Device>, io::Error> {
-        let device_addresses =
-            device::get_device_address(&self.socket, self.source, self.sequence())?;
+    /// Set the time to wait for each response from a device. Unless the discovery options set a
+    /// window, discovery also collects responses for this long. Defaults to 500 milliseconds.
+    pub fn set_timeout(&self, timeout: Duration) {
+        self.inner.connection.set_timeout(timeout);
+    }
+
+    /// Set the maximum number of messages per second sent to each device, or `None` to send
+    /// messages as soon as possible. Disabled by default. LIFX recommends no more than 20.
+    ///
+    /// Changes of color or power which have to wait are coalesced, so only the latest of each is
+    /// sent to a device when its budget allows. A change which is replaced this way is never sent,
+    /// but the call which made it still returns `Ok`.
+    pub fn set_rate_limit(&self, messages_per_second: Option<u32>) {
+        self.connection().set_rate_limit(messages_per_second);
+    }
+
+    /// Cache the state of each device for `ttl`, or `None` to disable the cache. Disabled by
+    /// default.
+    ///
+    /// The cache is updated from every State reply and every command sent, so relative changes
+    /// such as [`transition_brightness`](Client::transition_brightness) and
+    /// [`transition_toggle`](Client::transition_toggle) use the last known state instead of
+    /// querying the device first. A cached state also expires when a transition completes.
+    /// Changes made by other controllers are not seen until the cached state expires.
+    pub fn set_state_cache(&self, ttl: Option<Duration>) {
+        self.states().set_ttl(ttl);
+    }
+
+    /// Find a device again by its MAC address after it fails to respond `timeouts` times in a row,
+    /// such as after its IP address changes, or `None` to never look for it. The device's new
+    /// address is used for all later requests, and the request which timed out is retried once.
+    /// Defaults to 2.
+    pub fn set_relocate_after(&self, timeouts: Option<u32>) {
+        let mut relocation = self.relocation();
+        relocation.after = timeouts;
+        relocation.timeouts.clear();
+    }
+
+    /// Set the options used to discover devices. Broadcast commands are also sent to the
+    /// discovery targets.
+    pub fn set_discovery_options(&self, options: DiscoveryOptions) {
+        *self
+            .inner
+            .discovery
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = options;
+    }
+
+    /// Return the options used to discover devices.
+    pub fn discovery_options(&self) -> DiscoveryOptions {
+        self.inner
+            .discovery
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    /// Returns information about LIFX devices on the network, including any found previously.
+    /// Devices which do not respond are skipped. Use [`discover_report`](Client::discover_report)
+    /// to find out which devices failed.
+    pub fn discover(&self) -> Result<HashSet<Device>, io::Error> {
+        self.discover_report()?;
+        Result::Ok(self.get_devices())
+    }
+
+    /// Discover devices on the network and return those which were found, along with the error
+    /// for each device which responded to discovery but could not be queried. Devices are queried
+    /// concurrently.
+    pub fn discover_report(&self) -> io::Result<DiscoveryReport> {
+        let device_addresses = device::get_device_address(
+            self.connection(),
+            self.source(),
+            self.sequence(),
+            &self.discovery_options(),
+        )?;
 
-        for address in device_addresses {
-            self.find_device(address)?;
+        let results = concurrently(device_addresses, |address| self.find_device(address));
+
+        let mut devices = HashSet::new();
+        let mut failures = HashMap::new();
+        for (address, result) in results {
+            match result {
+                Ok(device) => {
+                    devices.insert(device);
+                }
+                Err(e) => {
+                    failures.insert(address, e);
+                }
+            }
         }
-        Result::Ok(self.devices.clone())
+        Result::Ok(DiscoveryReport::new(devices, failures))
     }
 
-    pub fn find_device(&mut self, device_address: DeviceAddress) -> io::Result<Device> {
-        let label = device::get_label(&self.socket, &device_address, self.source, self.sequence())?;
-        let group = device::get_group(&self.socket, &device_address, self.source, self.sequence())?;
-        let location =
-            device::get_location(&self.socket, &device_address, self.source, self.sequence())?;
-        let device = Device::new(
-            device_address,
-            trim_trailing_null(label.label),
-            trim_trailing_null(group.label),
-            trim_trailing_null(location.label),
-        );
+    pub fn find_device(&self, device_address: DeviceAddress) -> io::Result<Device> {
+        let device = self.query_device(device_address)?;
 
-        self.devices.insert(device.clone());
+        // Replace any previous information about the device, such as an old address or label.
+        self.devices_mut()
+            .insert(device.mac_address(), device.clone());
         Result::Ok(device)
     }
 
-    pub fn forget_devices(&mut self) {
-        self.devices.clear();
+    /// Query the label, group and location of a device again, in case it has been renamed or
+    /// moved to a different group or location. Returns the change, if any.
+    ///
+    /// Groups and locations whose `updated_at` timestamp has not changed keep their current label.
+    pub fn refresh(&self, device: &Device) -> io::Result<Option<DeviceUpdate>> {
+        let old = self
+            .device_by_mac(device.mac_address())
+            .unwrap_or_else(|| device.clone());
+        let current = self.with_relocation(device, |device| self.query_device(device.address()))?;
+
+        self.devices_mut()
+            .insert(current.mac_address(), current.clone());
+        if current == old {
+            Result::Ok(Option::None)
+        } else {
+            Result::Ok(Option::Some(DeviceUpdate::new(old, current)))
+        }
+    }
+
+    /// Refresh every device which has been found, concurrently, and return the result for each
+    /// device.
+    pub fn refresh_all(&self) -> HashMap<Device, io::Result<Option<DeviceUpdate>>> {
+        self.apply(&self.get_devices(), |client, device| client.refresh(device))
+    }
+
+    /// Record all packets sent and received by this client to `writer` in the pcap format,
+    /// replacing any existing capture. Packets are written with synthetic IP and UDP headers.
+    ///
+    /// The local address in each packet is the address the transport is bound to. For a socket
+    /// bound to an unspecified address such as `0.0.0.0`, that is the address recorded, since the
+    /// interface each packet used is not known. Bind to a specific interface, for example with
+    /// [`DiscoveryOptions::with_interface`], to record the real address.
+    ///
+    /// Only pcap is written. [`PcapReader`](crate::pcap::PcapReader) reads both pcap and pcapng.
+    pub fn capture<W: Write + Send + 'static>(&self, writer: W) -> io::Result<()> {
+        let writer: Box<dyn Write + Send> = Box::new(writer);
+        self.connection().capture(PcapWriter::new(writer)?)
+    }
+
+    /// Stop recording packets.
+    pub fn stop_capture(&self) -> io::Result<()> {
+        self.connection().stop_capture()
+    }
+
+    pub fn forget_devices(&self) {
+        self.devices_mut().clear();
     }
 
-    pub fn get_devices(&self) -> &HashSet<Device> {
-        &self.devices
+    /// Return all devices which have been found.
+    pub fn get_devices(&self) -> HashSet<Device> {
+        self.devices().values().cloned().collect()
+    }
+
+    /// Add the devices in `registry` to the devices which have been found, without discovering
+    /// them. Their addresses are used as-is, and are updated if a device is later relocated.
+    pub fn load_registry(&self, registry: &DeviceRegistry) {
+        let mut devices = self.devices_mut();
+        for registered in registry.devices() {
+            let device = registered.device();
+            devices.insert(device.mac_address(), device.clone());
+        }
+    }
+
+    /// Return the device with `mac_address`, if it has been found.
+    pub fn device_by_mac(&self, mac_address: MacAddress) -> Option<Device> {
+        self.devices().get(&mac_address).cloned()
+    }
+
+    /// Return a device with `label`, if one has been found. If several devices have the same label,
+    /// any one of them is returned.
+    pub fn device_by_label(&self, label: &str) -> Option<Device> {
+        self.devices()
+            .values()
+            .find(|device| device.label() == label)
+            .cloned()
+    }
+
+    /// Apply `operation` to each device concurrently and return the result for each device.
+    ///
+    /// Each device is handled on its own thread, but all requests share this client's transport,
+    /// so a device which does not respond only delays its own result.
+    pub fn apply<'a, I, F, R>(&self, devices: I, operation: F) -> HashMap<Device, io::Result<R>>
+    where
+        I: IntoIterator<Item = &'a Device>,
+        F: Fn(&Client<T>, &Device) -> io::Result<R> + Sync,
+        R: Send,
+    {
+        concurrently(devices, |device| operation(self, device))
+            .into_iter()
+            .map(|(device, result)| (device.clone(), result))
+            .collect()
     }
 
     pub(crate) fn get_state(&self, device: &Device) -> io::Result<StatePayload> {
-        let state = light::get_state(&self.socket, device, self.source, self.sequence())?;
+        if let Some(state) = self.states().get(device.mac_address()) {
+            return Result::Ok(state);
+        }
+        self.query_state(device)
+    }
+
+    /// Query the state of a device, even if it is cached.
+    pub(crate) fn query_state(&self, device: &Device) -> io::Result<StatePayload> {
+        let state = self.with_relocation(device, |device| {
+            light::get_state(self.connection(), device, self.source(), self.sequence())
+        })?;
+        self.inner
+            .states
+            .update(device.mac_address(), state.clone());
         Result::Ok(state)
     }
 
+    /// Return the LIFX product ID of a device.
+    pub fn get_product(&self, device: &Device) -> io::Result<u32> {
+        let version = self.with_relocation(device, |device| {
+            device::get_version(
+                self.connection(),
+                &device.address(),
+                self.source(),
+                self.sequence(),
+            )
+        })?;
+        Result::Ok(version.product())
+    }
+
     pub fn get_color(&self, device: &Device) -> io::Result<Color> {
         Result::Ok(self.get_state(device)?.color().into())
     }
 
+    /// Return the color, power and label reported by a device.
+    pub fn get_status(&self, device: &Device) -> io::Result<LightStatus> {
+        Result::Ok(self.get_state(device)?.into())
+    }
+
     pub fn transition_on(&self, device: &Device, duration: Duration) -> io::Result<()> {
-        light::set_power(
-            &self.socket,
-            device,
-            self.source,
-            self.sequence(),
-            Power::On(0xffff),
-            to_millis(duration),
-        )?;
+        self.send_power(device, Power::On(0xffff), to_millis(duration))?;
         Result::Ok(())
     }
 
@@ -88,14 +360,7 @@ impl Client {
     }
 
     pub fn transition_off(&self, device: &Device, duration: Duration) -> io::Result<()> {
-        light::set_power(
-            &self.socket,
-            device,
-            self.source,
-            self.sequence(),
-            Power::Off,
-            to_millis(duration),
-        )?;
+        self.send_power(device, Power::Off, to_millis(duration))?;
         Result::Ok(())
     }
 
@@ -131,11 +396,8 @@ impl Client {
             if state.power() == Power::Off {
                 self.turn_on(device)?;
             }
-            light::set_color(
-                &self.socket,
+            self.send_color(
                 device,
-                self.source,
-                self.sequence(),
                 color.with_brightness(brightness_value),
                 to_millis(duration),
             )?;
@@ -154,14 +416,7 @@ impl Client {
         color: Color,
         duration: Duration,
     ) -> io::Result<()> {
-        light::set_color(
-            &self.socket,
-            &device,
-            self.source,
-            self.sequence(),
-            color.into(),
-            to_millis(duration),
-        )?;
+        self.send_color(device, color.into(), to_millis(duration))?;
         Result::Ok(())
     }
 
@@ -177,11 +432,8 @@ impl Client {
     ) -> io::Result<()> {
         let hsbk = self.get_state(device)?.color();
 
-        light::set_color(
-            &self.socket,
+        self.send_color(
             device,
-            self.source,
-            self.sequence(),
             hsbk.with_hue(0).with_saturation(0).with_kelvin(temperature),
             to_millis(duration),
         )?;
@@ -202,11 +454,8 @@ impl Client {
         let hsbk = self.get_state(device)?.color();
         let brightness_value = (f32::min(brightness, 1.0) * 0xffff as f32) as u16;
 
-        light::set_color(
-            &self.socket,
+        self.send_color(
             device,
-            self.source,
-            self.sequence(),
             hsbk.with_hue(0)
                 .with_saturation(0)
                 .with_kelvin(temperature)
@@ -225,15 +474,347 @@ impl Client {
         self.transition_temperature_brightness(device, temperature, brightness, ZERO_DURATION)
     }
 
+    /// Change the fields of a device which are set in `state`, over `duration`.
+    ///
+    /// The device is not queried first. If only part of the color is set, the rest is taken from
+    /// the state cache, if the device's state is cached, or else left unchanged on the device by
+    /// sending a SetWaveformOptional message. The color is set before the power, so a light which
+    /// is turned on does not show its old color first.
+    pub fn apply_state(
+        &self,
+        device: &Device,
+        state: &LightState,
+        duration: Duration,
+    ) -> io::Result<()> {
+        let duration = to_millis(duration);
+        if state.changes_color() {
+            let color = state.color().or_else(|| {
+                self.states()
+                    .get(device.mac_address())
+                    .map(|cached| state.apply_to(cached.color()))
+            });
+            match color {
+                Some(color) => self.send_color(device, color, duration)?,
+                None => self.send_color_components(device, state.components(duration))?,
+            }
+        }
+
+        match state.power() {
+            Some(true) => self.send_power(device, Power::On(0xffff), duration),
+            Some(false) => self.send_power(device, Power::Off, duration),
+            None => Result::Ok(()),
+        }
+    }
+
+    /// Turn every device on the network on or off at once, by broadcasting a single tagged packet
+    /// to each discovery target. Devices do not respond, so the packet is sent `sends` times in
+    /// total to make up for any which are lost. It is always sent at least once.
+    pub fn broadcast_power(&self, on: bool, duration: Duration, sends: usize) -> io::Result<()> {
+        let power = if on { Power::On(0xffff) } else { Power::Off };
+        self.states().clear();
+        light::broadcast_power(
+            self.connection(),
+            &self.discovery_options().target_addresses(),
+            self.source(),
+            self.sequence(),
+            power,
+            to_millis(duration),
+            sends,
+        )
+    }
+
+    /// Set the color of every device on the network at once, by broadcasting a single tagged
+    /// packet to each discovery target. Devices do not respond, so the packet is sent `sends`
+    /// times in total to make up for any which are lost. It is always sent at least once.
+    pub fn broadcast_color(
+        &self,
+        color: Color,
+        duration: Duration,
+        sends: usize,
+    ) -> io::Result<()> {
+        self.states().clear();
+        light::broadcast_color(
+            self.connection(),
+            &self.discovery_options().target_addresses(),
+            self.source(),
+            self.sequence(),
+            color.into(),
+            to_millis(duration),
+            sends,
+        )
+    }
+
+    /// Return a stream for sending colors to devices in real time, without waiting for responses.
+    pub fn color_stream(&self) -> ColorStream<T> {
+        ColorStream::new(self.clone())
+    }
+
+    // Return information about the device at `device_address`, without adding it to the devices
+    // which have been found.
+    fn query_device(&self, device_address: DeviceAddress) -> io::Result<Device> {
+        let label = device::get_label(
+            self.connection(),
+            &device_address,
+            self.source(),
+            self.sequence(),
+        )?;
+        let group = device::get_group(
+            self.connection(),
+            &device_address,
+            self.source(),
+            self.sequence(),
+        )?;
+        let location = device::get_location(
+            self.connection(),
+            &device_address,
+            self.source(),
+            self.sequence(),
+        )?;
+
+        let mut collections = self.collections();
+        let group = newest_label(
+            &mut collections.groups,
+            group.group(),
+            group.label(),
+            group.updated_at(),
+        );
+        let location = newest_label(
+            &mut collections.locations,
+            location.location(),
+            location.label(),
+            location.updated_at(),
+        );
+        Result::Ok(Device::new(
+            device_address,
+            label.label().to_string(),
+            group,
+            location,
+        ))
+    }
+
+    fn send_power(&self, device: &Device, power: Power, duration: u32) -> io::Result<()> {
+        let result = self.with_relocation(device, |device| {
+            light::set_power(
+                self.connection(),
+                device,
+                self.source(),
+                self.sequence(),
+                power,
+                duration,
+            )
+        });
+        self.update_state(device, &result);
+        result.map(|_| ())
+    }
+
+    fn send_color(&self, device: &Device, color: Hsbk, duration: u32) -> io::Result<()> {
+        let result = self.with_relocation(device, |device| {
+            light::set_color(
+                self.connection(),
+                device,
+                self.source(),
+                self.sequence(),
+                color,
+                duration,
+            )
+        });
+        self.update_state(device, &result);
+        result.map(|_| ())
+    }
+
+    fn send_color_components(
+        &self,
+        device: &Device,
+        payload: SetWaveformOptionalPayload,
+    ) -> io::Result<()> {
+        let result = self.with_relocation(device, |device| {
+            light::set_color_components(
+                self.connection(),
+                device,
+                self.source(),
+                self.sequence(),
+                payload.clone(),
+            )
+        });
+        self.update_state(device, &result);
+        result.map(|_| ())
+    }
+
+    // Update the cached state of `device` from the command which was actually sent, which may have
+    // been combined with later commands while waiting for the rate limit. The state is unknown if
+    // the command failed or was replaced by a later command which has not been sent yet.
+    fn update_state(&self, device: &Device, sent: &io::Result<Option<Message>>) {
+        let mac_address = device.mac_address();
+        let millis = |duration| Duration::from_millis(duration as u64);
+        match sent {
+            Ok(Some(Message::SetPower(payload))) => {
+                self.states()
+                    .set_power(mac_address, payload.power(), millis(payload.duration()))
+            }
+            Ok(Some(Message::SetColor(payload))) => {
+                self.states()
+                    .set_color(mac_address, payload.color(), millis(payload.duration()))
+            }
+            Ok(Some(Message::SetWaveformOptional(payload))) => {
+                self.states().set_waveform(mac_address, payload)
+            }
+            _ => self.states().invalidate(mac_address),
+        }
+    }
+
+    /// Call `request` with the address at which `device` was last found. If the device has timed
+    /// out too many times in a row, find it by MAC address and retry the request at its new
+    /// address. Only a request which waits for a reply can time out, so a request sent without
+    /// waiting is never relocated.
+    fn with_relocation<F, R>(&self, device: &Device, request: F) -> io::Result<R>
+    where
+        F: Fn(&Device) -> io::Result<R>,
+    {
+        let mac_address = device.mac_address();
+        let device = match self.devices().get(&mac_address) {
+            Some(known) => device.with_address(known.address()),
+            None => device.clone(),
+        };
+
+        let error = match request(&device) {
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => e,
+            result => {
+                self.relocation().timeouts.remove(&mac_address);
+                return result;
+            }
+        };
+
+        {
+            let mut relocation = self.relocation();
+            let after = match relocation.after {
+                Some(after) => after,
+                None => return Result::Err(error),
+            };
+            let timeouts = relocation.timeouts.entry(mac_address).or_insert(0);
+            *timeouts += 1;
+            if *timeouts < after {
+                return Result::Err(error);
+            }
+            relocation.timeouts.remove(&mac_address);
+        }
+
+        // Return the original error if the device cannot be found, or has not moved.
+        let address = match device::locate_device(
+            self.connection(),
+            self.source(),
+            self.sequence(),
+            &self.discovery_options(),
+            mac_address,
+        ) {
+            Ok(Some(address)) if address != device.address() => address,
+            _ => return Result::Err(error),
+        };
+
+        if let Some(known) = self.devices_mut().get_mut(&mac_address) {
+            *known = known.with_address(address);
+        }
+
+        let result = request(&device.with_address(address));
+        if result.is_ok() {
+            self.relocation().timeouts.remove(&mac_address);
+        }
+        result
+    }
+
+    pub(crate) fn connection(&self) -> &Arc<Connection<T>> {
+        &self.inner.connection
+    }
+
+    pub(crate) fn states(&self) -> &StateCache {
+        &self.inner.states
+    }
+
+    pub(crate) fn source(&self) -> u32 {
+        self.inner.source
+    }
+
     /// Return current sequence value then increment.
-    fn sequence(&self) -> u8 {
-        let sequence = self.sequence.get();
-        self.sequence.set(sequence.wrapping_add(1));
-        sequence
+    pub(crate) fn sequence(&self) -> u8 {
+        self.inner.sequence.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn devices(&self) -> RwLockReadGuard<'_, HashMap<MacAddress, Device>> {
+        // Devices are only inserted and removed, so the map is always valid.
+        self.inner
+            .devices
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn devices_mut(&self) -> RwLockWriteGuard<'_, HashMap<MacAddress, Device>> {
+        self.inner
+            .devices
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn collections(&self) -> MutexGuard<'_, Collections> {
+        // Each label is replaced as a whole, so the collections are always valid.
+        self.inner
+            .collections
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn relocation(&self) -> MutexGuard<'_, Relocation> {
+        // Relocation state is only a hint, so it is safe to use after a panic.
+        self.inner
+            .relocation
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Record the label reported for a group or location, and return the newest label known for it.
+fn newest_label(
+    collections: &mut HashMap<[u8; 16], Collection>,
+    id: [u8; 16],
+    label: &str,
+    updated_at: u64,
+) -> String {
+    let collection = collections.entry(id).or_insert_with(|| Collection {
+        label: label.to_string(),
+        updated_at,
+    });
+    if updated_at > collection.updated_at {
+        collection.label = label.to_string();
+        collection.updated_at = updated_at;
     }
+    collection.label.clone()
+}
+
+/// Call `f` for each item, each on its own thread, and return every item along with its result.
+fn concurrently<I, F, R>(items: I, f: F) -> Vec<(I::Item, io::Result<R>)>
+where
+    I: IntoIterator,
+    I::Item: Clone + Send,
+    F: Fn(I::Item) -> io::Result<R> + Sync,
+    R: Send,
+{
+    let f = &f;
+    thread::scope(|scope| {
+        let threads: Vec<_> = items
+            .into_iter()
+            .map(|item| (item.clone(), scope.spawn(move || f(item))))
+            .collect();
+        threads
+            .into_iter()
+            .map(|(item, thread)| {
+                let result = thread
+                    .join()
+                    .unwrap_or_else(|_| Result::Err(io::Error::other("Operation panicked.")));
+                (item, result)
+            })
+            .collect()
+    })
 }
 
-fn to_millis(duration: Duration) -> u32 {
+pub(crate) fn to_millis(duration: Duration) -> u32 {
     if duration < ZERO_DURATION {
         0u32
     } else if duration > MAX_DURATION {
@@ -243,7 +824,517 @@ fn to_millis(duration: Duration) -> u32 {
     }
 }
 
-/// Trim trailing null bytes from a string.
-fn trim_trailing_null(s: String) -> String {
-    s.trim_end_matches('\0').to_string()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        protocol::{
+            header::{DeviceMessageType, LightMessageType, MacAddress, MessageType},
+            message::{
+                Message, SetColorPayload, SetPowerPayload, StateGroupPayload, StateLabelPayload,
+                StateLocationPayload, StateServicePayload,
+            },
+            packet::{Packet, PacketBuilder},
+        },
+        transport::{MemoryNetwork, MemoryTransport},
+    };
+    use std::{convert::TryFrom, net::SocketAddr, time::Instant};
+
+    const MAC: MacAddress = MacAddress {
+        address: [0xd0, 0x73, 0xd5, 0x00, 0x00, 0x01],
+    };
+
+    // Respond to the expected number of requests as a device with the specified label.
+    fn respond(device: MemoryTransport, label: &str, requests: usize) {
+        respond_as(device, MAC, label, requests);
+    }
+
+    // Respond to the expected number of requests as a device with the specified MAC address and
+    // label.
+    fn respond_as(device: MemoryTransport, mac: MacAddress, label: &str, requests: usize) {
+        let port = device.local_addr().unwrap().port();
+        let mut buf = [0u8; 128];
+        for _ in 0..requests {
+            let (n, addr) = device.recv_from(&mut buf, Duration::from_secs(5)).unwrap();
+            let request = Packet::try_from(&buf[..n]).unwrap();
+            let message = match request.message().message_type() {
+                MessageType::Device(DeviceMessageType::GetService) => {
+                    Message::StateService(StateServicePayload::new(1, port))
+                }
+                MessageType::Device(DeviceMessageType::GetLabel) => {
+                    Message::StateLabel(StateLabelPayload::new(label.to_string()))
+                }
+                MessageType::Device(DeviceMessageType::GetGroup) => {
+                    Message::StateGroup(StateGroupPayload::new([0; 16], "Group".into(), 0))
+                }
+                MessageType::Device(DeviceMessageType::GetLocation) => {
+                    Message::StateLocation(StateLocationPayload::new([0; 16], "Location".into(), 0))
+                }
+                message_type => panic!("Unexpected request. {}", message_type),
+            };
+            let response = PacketBuilder::new(message)
+                .source(request.frame().source)
+                .sequence(request.frame_address().sequence)
+                .target(mac)
+                .build();
+            device.send_to(&response.as_bytes(), addr).unwrap();
+        }
+    }
+
+    #[test]
+    fn discover_over_memory_transport() {
+        let network = MemoryNetwork::new();
+        let device_address: SocketAddr = "10.0.0.2:56700".parse().unwrap();
+        let device = network.bind(device_address).unwrap();
+        let responder = thread::spawn(move || respond(device, "Lamp", 4));
+
+        let client = Client::new(network.bind("10.0.0.1:0".parse().unwrap()).unwrap());
+        client.set_timeout(Duration::from_millis(100));
+        let devices = client.discover().unwrap();
+        responder.join().unwrap();
+
+        assert_eq!(devices.len(), 1);
+        let device = devices.iter().next().unwrap();
+        assert_eq!(device.label(), "Lamp");
+        assert_eq!(device.group(), "Group");
+        assert_eq!(device.location(), "Location");
+        assert_eq!(device.address().mac_address(), MAC);
+        assert_eq!(device.address().socket_address(), device_address);
+    }
+
+    #[test]
+    fn rediscover_moved_and_renamed_device() {
+        let network = MemoryNetwork::new();
+        let client = Client::new(network.bind("10.0.0.1:0".parse().unwrap()).unwrap());
+        client.set_timeout(Duration::from_millis(100));
+
+        let device = network.bind("10.0.0.2:56700".parse().unwrap()).unwrap();
+        let responder = thread::spawn(move || respond(device, "Lamp", 4));
+        client.discover().unwrap();
+        responder.join().unwrap();
+        assert_eq!(
+            client
+                .device_by_label("Lamp")
+                .unwrap()
+                .address()
+                .mac_address(),
+            MAC
+        );
+
+        // The same device is found at a new address with a new label.
+        let new_address: SocketAddr = "10.0.0.3:56700".parse().unwrap();
+        let device = network.bind(new_address).unwrap();
+        let responder = thread::spawn(move || respond(device, "Desk", 4));
+        let devices = client.discover().unwrap();
+        responder.join().unwrap();
+
+        assert_eq!(devices.len(), 1);
+        let device = client.device_by_mac(MAC).unwrap();
+        assert_eq!(device.label(), "Desk");
+        assert_eq!(device.address().socket_address(), new_address);
+        assert_eq!(client.device_by_label("Desk"), Option::Some(device));
+        assert_eq!(client.device_by_label("Lamp"), Option::None);
+    }
+
+    #[test]
+    fn newest_collection_label() {
+        let mut groups = HashMap::new();
+        assert_eq!(newest_label(&mut groups, [1; 16], "Kitchen", 10), "Kitchen");
+        assert_eq!(newest_label(&mut groups, [1; 16], "Pantry", 20), "Pantry");
+
+        // A device which has not been updated yet reports the old label.
+        assert_eq!(newest_label(&mut groups, [1; 16], "Kitchen", 10), "Pantry");
+        assert_eq!(newest_label(&mut groups, [2; 16], "Kitchen", 10), "Kitchen");
+    }
+
+    #[test]
+    fn discover_with_options() {
+        let network = MemoryNetwork::new();
+        let device_address: SocketAddr = "10.0.0.2:56800".parse().unwrap();
+        let device = network.bind(device_address).unwrap();
+        // Three GetService messages, then label, group and location.
+        let responder = thread::spawn(move || respond(device, "Lamp", 6));
+
+        let client = Client::new(network.bind("10.0.0.1:0".parse().unwrap()).unwrap());
+        client.set_discovery_options(
+            DiscoveryOptions::new()
+                .with_targets(vec!["10.0.0.255".parse().unwrap()])
+                .with_port(56800)
+                .with_retransmits(2)
+                .with_window(Duration::from_millis(300)),
+        );
+        let start = Instant::now();
+        let devices = client.discover().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        responder.join().unwrap();
+
+        assert_eq!(devices.len(), 1);
+        let device = devices.iter().next().unwrap();
+        assert_eq!(device.label(), "Lamp");
+        assert_eq!(device.address().socket_address(), device_address);
+    }
+
+    #[test]
+    fn discover_skips_failing_devices() {
+        let network = MemoryNetwork::new();
+        let working = network.bind("10.0.0.2:56700".parse().unwrap()).unwrap();
+        let responder = thread::spawn(move || respond(working, "Lamp", 4));
+
+        // Respond to GetService along with an unexpected message, then stop responding.
+        let failing_address: SocketAddr = "10.0.0.3:56700".parse().unwrap();
+        let failing = network.bind(failing_address).unwrap();
+        let failing_responder = thread::spawn(move || {
+            let mut buf = [0u8; 128];
+            let (n, addr) = failing.recv_from(&mut buf, Duration::from_secs(5)).unwrap();
+            let request = Packet::try_from(&buf[..n]).unwrap();
+            let messages = vec![
+                Message::StateLabel(StateLabelPayload::new("Unexpected".to_string())),
+                Message::StateService(StateServicePayload::new(1, 56700)),
+            ];
+            for message in messages {
+                let response = PacketBuilder::new(message)
+                    .source(request.frame().source)
+                    .sequence(request.frame_address().sequence)
+                    .target(MAC)
+                    .build();
+                failing.send_to(&response.as_bytes(), addr).unwrap();
+            }
+            // Keep the address bound until the queries time out.
+            thread::sleep(Duration::from_millis(300));
+        });
+
+        let client = Client::new(network.bind("10.0.0.1:0".parse().unwrap()).unwrap());
+        client.set_timeout(Duration::from_millis(100));
+        let report = client.discover_report().unwrap();
+        responder.join().unwrap();
+        failing_responder.join().unwrap();
+
+        assert_eq!(report.devices().len(), 1);
+        assert_eq!(report.devices().iter().next().unwrap().label(), "Lamp");
+        assert_eq!(report.failures().len(), 1);
+        let (address, error) = report.failures().iter().next().unwrap();
+        assert_eq!(address.socket_address(), failing_address);
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(client.get_devices().len(), 1);
+    }
+
+    #[test]
+    fn discover_by_sweep() {
+        let network = MemoryNetwork::new();
+        let first = network.bind("10.0.0.2:56700".parse().unwrap()).unwrap();
+        let second = network.bind("10.0.0.5:56700".parse().unwrap()).unwrap();
+        let responders = vec![
+            thread::spawn(move || respond(first, "First", 4)),
+            thread::spawn(move || {
+                let mac = MacAddress {
+                    address: [0xd0, 0x73, 0xd5, 0x00, 0x00, 0x02],
+                };
+                respond_as(second, mac, "Second", 4)
+            }),
+        ];
+
+        let client = Client::new(network.bind("10.0.0.1:0".parse().unwrap()).unwrap());
+        client.set_timeout(Duration::from_millis(100));
+        client.set_discovery_options(
+            DiscoveryOptions::new()
+                .with_targets(vec![])
+                .with_sweep("10.0.0.0/29".parse().unwrap())
+                .with_pacing(Duration::from_millis(1)),
+        );
+        let devices = client.discover().unwrap();
+        for responder in responders {
+            responder.join().unwrap();
+        }
+
+        let mut addresses: Vec<SocketAddr> = devices
+            .iter()
+            .map(|device| device.address().socket_address())
+            .collect();
+        addresses.sort();
+        assert_eq!(
+            addresses,
+            vec![
+                "10.0.0.2:56700".parse().unwrap(),
+                "10.0.0.5:56700".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn discover_without_devices() {
+        let network = MemoryNetwork::new();
+        let client = Client::new(network.bind("10.0.0.1:0".parse().unwrap()).unwrap());
+        client.set_timeout(Duration::from_millis(10));
+        assert!(client.discover().unwrap().is_empty());
+    }
+
+    #[test]
+    fn apply_to_each_device() {
+        let network = MemoryNetwork::new();
+        let client = Client::new(network.bind("10.0.0.1:0".parse().unwrap()).unwrap());
+        let devices: Vec<Device> = (1..=5)
+            .map(|n| {
+                let address = format!("{}#10.0.0.{}:56700", MAC, n).parse().unwrap();
+                let label = format!("Lamp {}", n);
+                Device::new(address, label, String::new(), String::new())
+            })
+            .collect();
+
+        let start = Instant::now();
+        let results = client.apply(&devices, |_, device| {
+            thread::sleep(Duration::from_millis(200));
+            if device.label() == "Lamp 3" {
+                Result::Err(io::Error::new(io::ErrorKind::TimedOut, "Timed out."))
+            } else {
+                Result::Ok(device.label().len())
+            }
+        });
+        // Devices are handled concurrently, so the total time is close to that of a single device.
+        assert!(start.elapsed() < Duration::from_millis(800));
+
+        assert_eq!(results.len(), 5);
+        for device in devices.iter() {
+            match &results[device] {
+                Ok(len) => assert_eq!(*len, 6),
+                Err(e) => {
+                    assert_eq!(device.label(), "Lamp 3");
+                    assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn broadcast_power() {
+        let network = MemoryNetwork::new();
+        let devices: Vec<MemoryTransport> = (2..=3)
+            .map(|n| {
+                network
+                    .bind(format!("10.0.0.{}:56700", n).parse().unwrap())
+                    .unwrap()
+            })
+            .collect();
+        let client = Client::new(network.bind("10.0.0.1:0".parse().unwrap()).unwrap());
+
+        client
+            .broadcast_power(false, Duration::from_millis(1000), 3)
+            .unwrap();
+
+        let mut buf = [0u8; 128];
+        for device in devices.iter() {
+            for _ in 0..3 {
+                let (n, _) = device.recv_from(&mut buf, Duration::from_secs(5)).unwrap();
+                let packet = Packet::try_from(&buf[..n]).unwrap();
+                assert!(packet.frame().tagged);
+                assert_eq!(packet.frame_address().target.address, [0u8; 6]);
+                assert_eq!(
+                    packet.message(),
+                    &Message::SetPower(SetPowerPayload::new(Power::Off, 1000))
+                );
+            }
+            let timeout = device.recv_from(&mut buf, Duration::from_millis(10));
+            assert!(timeout.is_err());
+        }
+    }
+
+    #[test]
+    fn relative_changes_use_cached_state() {
+        let network = MemoryNetwork::new();
+        let device_address: SocketAddr = "10.0.0.2:56700".parse().unwrap();
+        let transport = network.bind(device_address).unwrap();
+        let device = Device::new(
+            format!("{}#{}", MAC, device_address).parse().unwrap(),
+            "Lamp".to_string(),
+            String::new(),
+            String::new(),
+        );
+
+        // Only the first request for the state is sent to the device.
+        let responder = thread::spawn(move || {
+            let mut buf = [0u8; 128];
+            let mut received = Vec::new();
+            while let Ok((n, addr)) = transport.recv_from(&mut buf, Duration::from_millis(300)) {
+                let request = Packet::try_from(&buf[..n]).unwrap();
+                received.push(request.message().message_type());
+                if request.frame_address().res_required {
+                    let white = Hsbk::new(0, 0, 0xffff, 3500);
+                    let state = StatePayload::new(white, Power::Off, "Lamp".to_string());
+                    let response = PacketBuilder::new(Message::State(state))
+                        .source(request.frame().source)
+                        .sequence(request.frame_address().sequence)
+                        .target(MAC)
+                        .build();
+                    transport.send_to(&response.as_bytes(), addr).unwrap();
+                } else if request.frame_address().ack_required {
+                    let ack = PacketBuilder::with_empty_device_message(
+                        DeviceMessageType::Acknowledgement,
+                    )
+                    .source(request.frame().source)
+                    .sequence(request.frame_address().sequence)
+                    .target(MAC)
+                    .build();
+                    transport.send_to(&ack.as_bytes(), addr).unwrap();
+                }
+            }
+            received
+        });
+
+        let client = Client::new(network.bind("10.0.0.1:0".parse().unwrap()).unwrap());
+        client.set_timeout(Duration::from_millis(100));
+        client.set_state_cache(Option::Some(Duration::from_secs(60)));
+        client.get_color(&device).unwrap();
+        client.set_brightness(&device, 0.5).unwrap();
+        client.set_temperature(&device, 2700).unwrap();
+
+        let state = client.get_state(&device).unwrap();
+        assert_eq!(state.power(), Power::On(0xffff));
+        assert_eq!(state.color(), Hsbk::new(0, 0, 0x7fff, 2700));
+        assert_eq!(
+            responder.join().unwrap(),
+            vec![
+                MessageType::Light(LightMessageType::Get),
+                MessageType::Light(LightMessageType::SetPower),
+                MessageType::Light(LightMessageType::SetColor),
+                MessageType::Light(LightMessageType::SetColor),
+            ]
+        );
+    }
+
+    #[test]
+    fn cache_the_color_which_was_sent() {
+        let network = MemoryNetwork::new();
+        let device_address: SocketAddr = "10.0.0.2:56700".parse().unwrap();
+        let transport = network.bind(device_address).unwrap();
+        let device = Device::new(
+            format!("{}#{}", MAC, device_address).parse().unwrap(),
+            "Lamp".to_string(),
+            String::new(),
+            String::new(),
+        );
+
+        // Respond to every request with the last color set.
+        let responder = thread::spawn(move || {
+            let mut buf = [0u8; 128];
+            let mut color = Hsbk::new(0, 0, 0xffff, 3500);
+            let mut received = Vec::new();
+            while let Ok((n, addr)) = transport.recv_from(&mut buf, Duration::from_millis(500)) {
+                let request = Packet::try_from(&buf[..n]).unwrap();
+                if let Message::SetColor(payload) = request.message() {
+                    color = payload.color();
+                }
+                received.push(request.message().clone());
+                let state = StatePayload::new(color, Power::Off, "Lamp".to_string());
+                let response = PacketBuilder::new(Message::State(state))
+                    .source(request.frame().source)
+                    .sequence(request.frame_address().sequence)
+                    .target(MAC)
+                    .build();
+                transport.send_to(&response.as_bytes(), addr).unwrap();
+            }
+            received
+        });
+
+        let client = Client::new(network.bind("10.0.0.1:0".parse().unwrap()).unwrap());
+        client.set_rate_limit(Option::Some(5));
+        client.set_state_cache(Option::Some(Duration::from_secs(60)));
+        client.get_color(&device).unwrap();
+
+        // Red waits for the rate limit and is replaced by blue, so blue is sent in its place.
+        let red = Hsbk::new(0, 0xffff, 0xffff, 3500);
+        let blue = Hsbk::new(0xaaaa, 0xffff, 0xffff, 3500);
+        let waiting = {
+            let client = client.clone();
+            let device = device.clone();
+            thread::spawn(move || client.send_color(&device, red, 0))
+        };
+        thread::sleep(Duration::from_millis(50));
+        client.send_color(&device, blue, 0).unwrap();
+        waiting.join().unwrap().unwrap();
+
+        // The cache never holds red, which was never sent.
+        assert_eq!(client.get_state(&device).unwrap().color(), blue);
+        let received = responder.join().unwrap();
+        assert_eq!(
+            received[1],
+            Message::SetColor(SetColorPayload::new(blue, 0))
+        );
+        assert!(!received.contains(&Message::SetColor(SetColorPayload::new(red, 0))));
+    }
+
+    #[test]
+    fn apply_state_without_reading() {
+        let network = MemoryNetwork::new();
+        let device_address: SocketAddr = "10.0.0.2:56700".parse().unwrap();
+        let transport = network.bind(device_address).unwrap();
+        let device = Device::new(
+            format!("{}#{}", MAC, device_address).parse().unwrap(),
+            "Lamp".to_string(),
+            String::new(),
+            String::new(),
+        );
+
+        let responder = thread::spawn(move || {
+            let mut buf = [0u8; 128];
+            let mut received = Vec::new();
+            while let Ok((n, addr)) = transport.recv_from(&mut buf, Duration::from_millis(300)) {
+                let request = Packet::try_from(&buf[..n]).unwrap();
+                received.push(request.message().clone());
+                if request.frame_address().res_required {
+                    let white = Hsbk::new(0, 0, 0xffff, 3500);
+                    let state = StatePayload::new(white, Power::Off, "Lamp".to_string());
+                    let response = PacketBuilder::new(Message::State(state))
+                        .source(request.frame().source)
+                        .sequence(request.frame_address().sequence)
+                        .target(MAC)
+                        .build();
+                    transport.send_to(&response.as_bytes(), addr).unwrap();
+                } else if request.frame_address().ack_required {
+                    let ack = PacketBuilder::with_empty_device_message(
+                        DeviceMessageType::Acknowledgement,
+                    )
+                    .source(request.frame().source)
+                    .sequence(request.frame_address().sequence)
+                    .target(MAC)
+                    .build();
+                    transport.send_to(&ack.as_bytes(), addr).unwrap();
+                }
+            }
+            received
+        });
+
+        let client = Client::new(network.bind("10.0.0.1:0".parse().unwrap()).unwrap());
+        client.set_timeout(Duration::from_millis(100));
+
+        // The rest of the color is unknown, so only the brightness is sent.
+        let state = LightState::new().with_brightness(0.5).with_power(true);
+        client
+            .apply_state(&device, &state, Duration::from_secs(1))
+            .unwrap();
+
+        // Once the state is cached, the whole color is sent.
+        client.set_state_cache(Option::Some(Duration::from_secs(60)));
+        client.get_color(&device).unwrap();
+        let state = LightState::new().with_kelvin(2700);
+        client
+            .apply_state(&device, &state, Duration::from_secs(0))
+            .unwrap();
+
+        let brightness = LightState::new().with_brightness(0.5).components(1000);
+        assert_eq!(
+            responder.join().unwrap(),
+            vec![
+                Message::SetWaveformOptional(brightness),
+                Message::SetPower(SetPowerPayload::new(Power::On(0xffff), 1000)),
+                Message::Empty(MessageType::Light(LightMessageType::Get)),
+                Message::SetColor(SetColorPayload::new(Hsbk::new(0, 0, 0xffff, 2700), 0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn client_is_send_sync_clone() {
+        fn assert_send_sync_clone<C: Send + Sync + Clone>() {}
+        assert_send_sync_clone::<Client>();
+        assert_send_sync_clone::<Client<MemoryTransport>>();
+    }
 }
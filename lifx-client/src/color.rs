@@ -1,4 +1,5 @@
 use super::protocol::message::Hsbk;
+use serde::{ser::SerializeStruct, Serialize, Serializer};
 
 #[derive(Debug, Copy, Clone)]
 pub struct Color {
@@ -106,13 +107,43 @@ impl Color {
             kelvin: self.kelvin,
         }
     }
+
+    /// The hue in degrees, between 0.0 and 360.0.
+    pub fn hue(&self) -> f32 {
+        self.hue as f32 / 0x10000 as f32 * 360.0
+    }
+
+    pub fn saturation(&self) -> f32 {
+        self.saturation as f32 / 0xffff as f32
+    }
+
+    pub fn brightness(&self) -> f32 {
+        self.brightness as f32 / 0xffff as f32
+    }
+
+    /// The color temperature, if it is known.
+    pub fn kelvin(&self) -> Option<u16> {
+        self.kelvin
+    }
+}
+
+// Serialize the same values as the getters, rather than the values sent to devices.
+impl Serialize for Color {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut color = serializer.serialize_struct("Color", 4)?;
+        color.serialize_field("hue", &self.hue())?;
+        color.serialize_field("saturation", &self.saturation())?;
+        color.serialize_field("brightness", &self.brightness())?;
+        color.serialize_field("kelvin", &self.kelvin())?;
+        color.end()
+    }
 }
 
 fn normalize(n: u8) -> f32 {
     n as f32 / 0xff as f32
 }
 
-fn denormalize(n: f32) -> u16 {
+pub(crate) fn denormalize(n: f32) -> u16 {
     if n < 0.0 {
         0x00
     } else if n > 1.0 {
@@ -122,7 +153,7 @@ fn denormalize(n: f32) -> u16 {
     }
 }
 
-fn degrees_to_u16(degrees: f32) -> u16 {
+pub(crate) fn degrees_to_u16(degrees: f32) -> u16 {
     // Scale between 0.0 and 360.0.
     let scaled_degrees = (degrees % 360.0 + 360.0) % 360.0;
     let normalized_degrees = scaled_degrees / 360.0;
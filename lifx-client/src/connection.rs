@@ -0,0 +1,694 @@
+use super::{
+    pcap::{Datagram, PcapWriter},
+    pending::{PendingReplies, Ticket},
+    protocol::{message::Message, packet::Packet},
+    rate_limit::RateLimiter,
+    transport::Transport,
+};
+use std::{
+    convert::TryFrom,
+    io::{self, Write},
+    net::SocketAddr,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Condvar, Mutex, MutexGuard, RwLock, Weak,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+type Capture = PcapWriter<Box<dyn Write + Send>>;
+
+type Reply = (Packet, SocketAddr);
+
+/// The default time to wait for a response.
+pub(crate) const DEFAULT_TIMEOUT: Duration = Duration::from_millis(500);
+
+// How often the receive thread checks whether the connection has been dropped.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A transport used to communicate with LIFX devices. All packets sent and received can optionally
+/// be recorded to a pcap file.
+///
+/// A background thread receives all packets and dispatches each reply to the request with the
+/// same source and sequence, so requests can be made from many threads at once. If every sequence
+/// is in use, a request waits for one to be freed. The thread finishes shortly after the
+/// connection is dropped.
+///
+/// Packets sent to each device can be rate limited, in which case commands waiting to be sent are
+/// coalesced.
+pub(crate) struct Connection<T: Transport> {
+    transport: T,
+    timeout: RwLock<Duration>,
+    limiter: RateLimiter,
+    pending: Mutex<PendingReplies<Sender<Reply>>>,
+    // Signalled when a request stops waiting, freeing its sequence.
+    freed: Condvar,
+    capture: Mutex<Option<Capture>>,
+}
+
+impl<T: Transport> Connection<T> {
+    pub(crate) fn new(transport: T) -> Arc<Connection<T>> {
+        // Broadcasts fail if broadcasting cannot be enabled, but other requests still work.
+        let _ = transport.enable_broadcast();
+        let connection = Arc::new(Connection {
+            transport,
+            timeout: RwLock::new(DEFAULT_TIMEOUT),
+            limiter: RateLimiter::new(Option::None),
+            pending: Mutex::new(PendingReplies::new()),
+            freed: Condvar::new(),
+            capture: Mutex::new(Option::None),
+        });
+
+        let weak = Arc::downgrade(&connection);
+        thread::Builder::new()
+            .name("lifx-client-receive".to_string())
+            .spawn(move || receive_loop(weak))
+            .expect("Could not start receive thread");
+        connection
+    }
+
+    /// Set the time to wait for each response.
+    pub(crate) fn set_timeout(&self, timeout: Duration) {
+        *self
+            .timeout
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = timeout;
+    }
+
+    pub(crate) fn timeout(&self) -> Duration {
+        *self
+            .timeout
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Set the maximum number of messages per second sent to each device. `None` disables the
+    /// limit.
+    pub(crate) fn set_rate_limit(&self, rate: Option<u32>) {
+        self.limiter.set_rate(rate);
+    }
+
+    /// Start recording packets, replacing any existing capture.
+    pub(crate) fn capture(&self, capture: Capture) -> io::Result<()> {
+        let previous = self.lock_capture().replace(capture);
+        if let Some(mut previous) = previous {
+            previous.flush()?;
+        }
+        Result::Ok(())
+    }
+
+    /// Stop recording packets.
+    pub(crate) fn stop_capture(&self) -> io::Result<()> {
+        let previous = self.lock_capture().take();
+        if let Some(mut previous) = previous {
+            previous.flush()?;
+        }
+        Result::Ok(())
+    }
+
+    /// Send a packet and return the message from the response.
+    pub(crate) fn send_packet(
+        &self,
+        socket_address: SocketAddr,
+        packet: Packet,
+    ) -> io::Result<Message> {
+        let packet = self.limiter.acquire(packet);
+        self.request(socket_address, packet)
+    }
+
+    /// Send a command which changes the state of a device, such as its power or color. If the
+    /// command has to wait to be sent, it is replaced by any later command of the same type, in
+    /// which case this returns as soon as it is replaced.
+    ///
+    /// Waits for the response or acknowledgement if the packet requires one. Returns the message
+    /// which was actually sent, which may have been combined with later commands while waiting, or
+    /// `None` if the command was replaced.
+    pub(crate) fn send_command(
+        &self,
+        socket_address: SocketAddr,
+        packet: Packet,
+    ) -> io::Result<Option<Message>> {
+        let packet = match self.limiter.acquire_command(packet) {
+            Some(packet) => packet,
+            None => return Result::Ok(Option::None),
+        };
+        let frame_address = packet.frame_address();
+        if frame_address.res_required || frame_address.ack_required {
+            self.request(socket_address, packet.clone())?;
+        } else {
+            self.send_to(&packet.as_bytes(), socket_address)?;
+        }
+        Result::Ok(Option::Some(packet.message().clone()))
+    }
+
+    /// Send a packet only if it can be sent without waiting for the rate limit. Returns whether it
+    /// was sent.
+    pub(crate) fn try_send(&self, socket_address: SocketAddr, packet: &Packet) -> io::Result<bool> {
+        if !self.limiter.try_acquire(packet) {
+            return Result::Ok(false);
+        }
+        self.send_to(&packet.as_bytes(), socket_address)?;
+        Result::Ok(true)
+    }
+
+    fn request(&self, socket_address: SocketAddr, packet: Packet) -> io::Result<Message> {
+        let (registration, packet) = self.register(packet)?;
+        self.send_to(&packet.as_bytes(), socket_address)?;
+        match registration.receiver.recv_timeout(self.timeout()) {
+            Ok((response, _)) => Result::Ok(response.message().clone()),
+            Err(_) => Result::Err(timed_out()),
+        }
+    }
+
+    /// Send a packet to each of the broadcast `addresses`, `sends` times spread evenly over
+    /// `window`, and return all responses received within the window, along with the address they
+    /// were received from.
+    pub(crate) fn broadcast(
+        &self,
+        addresses: &[SocketAddr],
+        packet: Packet,
+        sends: u32,
+        window: Duration,
+    ) -> io::Result<Vec<Reply>> {
+        let (registration, packet) = self.register(packet)?;
+        let bytes = packet.as_bytes();
+        let sends = u32::max(sends, 1);
+        let start = Instant::now();
+
+        let mut replies = Vec::new();
+        for n in 0..sends {
+            registration.receive_until(start + window / sends * n, &mut replies);
+            for address in addresses {
+                self.transport.broadcast(&bytes, *address)?;
+                self.record_sent(*address, &bytes);
+            }
+        }
+        registration.receive_until(start + window, &mut replies);
+        Result::Ok(replies)
+    }
+
+    /// Send a packet to each of `addresses` in turn, waiting `pacing` between each, and return all
+    /// responses received until `window` after the last one was sent.
+    pub(crate) fn sweep(
+        &self,
+        addresses: &[SocketAddr],
+        packet: Packet,
+        pacing: Duration,
+        window: Duration,
+    ) -> io::Result<Vec<Reply>> {
+        let (registration, packet) = self.register(packet)?;
+        let bytes = packet.as_bytes();
+
+        let mut replies = Vec::new();
+        for address in addresses {
+            // One unreachable address should not end the sweep.
+            let _ = self.send_to(&bytes, *address);
+            registration.receive_until(Instant::now() + pacing, &mut replies);
+        }
+        registration.receive_until(Instant::now() + window, &mut replies);
+        Result::Ok(replies)
+    }
+
+    /// Send a packet to a broadcast address without waiting for responses.
+    pub(crate) fn broadcast_no_response(
+        &self,
+        socket_address: SocketAddr,
+        packet: Packet,
+    ) -> io::Result<()> {
+        let bytes = packet.as_bytes();
+        self.transport.broadcast(&bytes, socket_address)?;
+        self.record_sent(socket_address, &bytes);
+        Result::Ok(())
+    }
+
+    fn send_to(&self, bytes: &[u8], socket_address: SocketAddr) -> io::Result<()> {
+        self.transport.send_to(bytes, socket_address)?;
+        self.record_sent(socket_address, bytes);
+        Result::Ok(())
+    }
+
+    /// Register to receive replies to `packet` until the registration is dropped, and return the
+    /// packet to send.
+    fn register(&self, packet: Packet) -> io::Result<(Registration<'_, T>, Packet)> {
+        let (ticket, receiver, packet) = self.wait_for_replies(packet)?;
+        let registration = Registration {
+            connection: self,
+            ticket,
+            receiver,
+        };
+        Result::Ok((registration, packet))
+    }
+
+    // Start waiting for replies to `packet`, with a sequence which no other waiting request is
+    // using. Waits up to the timeout for a sequence to be freed if every one is in use.
+    fn wait_for_replies(&self, packet: Packet) -> io::Result<(Ticket, Receiver<Reply>, Packet)> {
+        let deadline = Instant::now() + self.timeout();
+        let (sender, receiver) = mpsc::channel();
+        let mut pending = self.lock_pending();
+        let mut packet = packet;
+        loop {
+            match pending.insert(packet, sender.clone()) {
+                Ok((ticket, packet)) => return Result::Ok((ticket, receiver, packet)),
+                Err(unsent) => packet = unsent,
+            }
+            let remaining = match deadline.checked_duration_since(Instant::now()) {
+                Some(remaining) => remaining,
+                None => return Result::Err(timed_out()),
+            };
+            pending = self
+                .freed
+                .wait_timeout(pending, remaining)
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .0;
+        }
+    }
+
+    fn stop_waiting(&self, ticket: &Ticket) {
+        self.lock_pending().remove(ticket);
+        self.freed.notify_one();
+    }
+
+    /// Send a received packet to the request waiting for it. Anything else is ignored.
+    fn dispatch(&self, bytes: &[u8], socket_address: SocketAddr) {
+        if let Ok(packet) = Packet::try_from(bytes) {
+            if let Some(sender) = self.lock_pending().get(&packet) {
+                let _ = sender.send((packet, socket_address));
+            }
+        }
+    }
+
+    fn record_sent(&self, destination: SocketAddr, bytes: &[u8]) {
+        self.record(bytes, |local_address| (local_address, destination));
+    }
+
+    fn record_received(&self, source: SocketAddr, bytes: &[u8]) {
+        self.record(bytes, |local_address| (source, local_address));
+    }
+
+    // Record a packet if a capture is active. `addresses` returns the source and destination, given
+    // the local address. Recording never fails a send, so a packet is skipped if the local address
+    // is unknown.
+    fn record<F>(&self, bytes: &[u8], addresses: F)
+    where
+        F: FnOnce(SocketAddr) -> (SocketAddr, SocketAddr),
+    {
+        let mut capture = self.lock_capture();
+        let writer = match capture.as_mut() {
+            Some(writer) => writer,
+            None => return,
+        };
+        let (source, destination) = match self.transport.local_addr() {
+            Ok(local_address) => addresses(local_address),
+            Err(_) => return,
+        };
+        let datagram = Datagram {
+            timestamp: SystemTime::now(),
+            source,
+            destination,
+            payload: bytes.to_vec(),
+        };
+        // A failed write would leave a corrupt file, so stop recording instead.
+        if writer.write(&datagram).is_err() {
+            capture.take();
+        }
+    }
+
+    fn lock_pending(&self) -> MutexGuard<'_, PendingReplies<Sender<Reply>>> {
+        // Requests are only inserted and removed, so the map is always valid.
+        self.pending
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn lock_capture(&self) -> MutexGuard<'_, Option<Capture>> {
+        // Recording packets cannot leave the writer in an invalid state, so ignore poisoning.
+        self.capture
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// A request waiting for replies. Stops receiving replies when dropped.
+struct Registration<'a, T: Transport> {
+    connection: &'a Connection<T>,
+    ticket: Ticket,
+    receiver: Receiver<Reply>,
+}
+
+impl<T: Transport> Registration<'_, T> {
+    /// Add all replies received before `deadline` to `replies`.
+    fn receive_until(&self, deadline: Instant, replies: &mut Vec<Reply>) {
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            match self.receiver.recv_timeout(remaining) {
+                Ok(reply) => replies.push(reply),
+                Err(_) => return,
+            }
+        }
+    }
+}
+
+impl<T: Transport> Drop for Registration<'_, T> {
+    fn drop(&mut self) {
+        self.connection.stop_waiting(&self.ticket);
+    }
+}
+
+/// Replies to a packet which are received in the background, without blocking the sender. Stops
+/// receiving replies when dropped.
+pub(crate) struct Watch<T: Transport> {
+    connection: Arc<Connection<T>>,
+    ticket: Ticket,
+    receiver: Receiver<Reply>,
+}
+
+impl<T: Transport> Watch<T> {
+    /// Start receiving replies to `packet`, and return the packet to send. Must be called before
+    /// the packet is sent. Does not wait for a sequence to be freed, so the packet is returned
+    /// unchanged in `Err` if every sequence is in use.
+    pub(crate) fn new(
+        connection: &Arc<Connection<T>>,
+        packet: Packet,
+    ) -> Result<(Watch<T>, Packet), Packet> {
+        let (sender, receiver) = mpsc::channel();
+        let (ticket, packet) = connection.lock_pending().insert(packet, sender)?;
+        let watch = Watch {
+            connection: connection.clone(),
+            ticket,
+            receiver,
+        };
+        Result::Ok((watch, packet))
+    }
+
+    /// Return a reply, if one has been received.
+    pub(crate) fn try_recv(&self) -> Option<Reply> {
+        self.receiver.try_recv().ok()
+    }
+}
+
+impl<T: Transport> Drop for Watch<T> {
+    fn drop(&mut self) {
+        self.connection.stop_waiting(&self.ticket);
+    }
+}
+
+fn timed_out() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "Timed out waiting for a response.")
+}
+
+/// Receive packets until the connection is dropped.
+fn receive_loop<T: Transport>(connection: Weak<Connection<T>>) {
+    let mut buf = [0u8; 1024];
+    while let Some(connection) = connection.upgrade() {
+        match connection.transport.recv_from(&mut buf, POLL_INTERVAL) {
+            Ok((n_bytes, socket_address)) => {
+                connection.record_received(socket_address, &buf[..n_bytes]);
+                connection.dispatch(&buf[..n_bytes], socket_address);
+            }
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+            }
+            // Avoid spinning if the transport keeps failing.
+            Err(_) => {
+                drop(connection);
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        pcap::PcapReader,
+        protocol::{
+            header::{DeviceMessageType, MacAddress},
+            message::StateLabelPayload,
+            packet::PacketBuilder,
+        },
+        transport::{MemoryNetwork, MemoryTransport},
+    };
+    use std::{
+        net::{Ipv4Addr, UdpSocket},
+        sync::{Arc, Mutex},
+        thread,
+    };
+
+    // A writer which can be inspected after it is given to a connection.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Result::Ok(())
+        }
+    }
+
+    #[test]
+    fn capture_sent_and_received_packets() {
+        let device = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let device_address = device.local_addr().unwrap();
+        let responder = thread::spawn(move || {
+            let mut buf = [0u8; 128];
+            let (_, client_address) = device.recv_from(&mut buf).unwrap();
+            let response =
+                PacketBuilder::new(Message::StateLabel(StateLabelPayload::new("Lamp".into())))
+                    .build();
+            device
+                .send_to(&response.as_bytes(), client_address)
+                .unwrap();
+        });
+
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let client_address = socket.local_addr().unwrap();
+        let connection = Connection::new(socket);
+        connection.set_timeout(Duration::from_secs(5));
+        let buffer = SharedBuffer::default();
+        let writer: Box<dyn Write + Send> = Box::new(buffer.clone());
+        connection
+            .capture(PcapWriter::new(writer).unwrap())
+            .unwrap();
+
+        let request = PacketBuilder::with_empty_device_message(DeviceMessageType::GetLabel)
+            .res_required(true)
+            .build();
+        let response = connection
+            .send_packet(device_address, request.clone())
+            .unwrap();
+        responder.join().unwrap();
+        connection.stop_capture().unwrap();
+
+        let bytes = buffer.0.lock().unwrap().clone();
+        let datagrams: Vec<Datagram> = PcapReader::new(&bytes[..])
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+        assert_eq!(datagrams.len(), 2);
+
+        assert_eq!(datagrams[0].source, client_address);
+        assert_eq!(datagrams[0].destination, device_address);
+        assert_eq!(datagrams[0].payload, request.as_bytes());
+
+        assert_eq!(datagrams[1].source, device_address);
+        assert_eq!(datagrams[1].destination, client_address);
+        let captured_response = Packet::try_from(&datagrams[1].payload[..]).unwrap();
+        assert_eq!(captured_response.message(), &response);
+    }
+
+    // A transport which does not know its local address.
+    struct UnknownAddress(MemoryTransport);
+
+    impl Transport for UnknownAddress {
+        fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+            self.0.send_to(buf, addr)
+        }
+
+        fn recv_from(&self, buf: &mut [u8], timeout: Duration) -> io::Result<(usize, SocketAddr)> {
+            self.0.recv_from(buf, timeout)
+        }
+
+        fn broadcast(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+            self.0.broadcast(buf, addr)
+        }
+
+        fn local_addr(&self) -> io::Result<SocketAddr> {
+            Result::Err(io::Error::other("Unknown address."))
+        }
+    }
+
+    #[test]
+    fn unknown_local_address_does_not_fail_sends() {
+        let network = MemoryNetwork::new();
+        let device_address = "10.0.0.2:56700".parse().unwrap();
+        let device = network.bind(device_address).unwrap();
+        let transport = UnknownAddress(network.bind("10.0.0.1:0".parse().unwrap()).unwrap());
+        let connection = Connection::new(transport);
+        let packet = PacketBuilder::with_empty_device_message(DeviceMessageType::GetLabel).build();
+
+        connection
+            .send_command(device_address, packet.clone())
+            .unwrap();
+        let buffer = SharedBuffer::default();
+        let writer: Box<dyn Write + Send> = Box::new(buffer.clone());
+        connection
+            .capture(PcapWriter::new(writer).unwrap())
+            .unwrap();
+        connection.send_command(device_address, packet).unwrap();
+        connection.stop_capture().unwrap();
+
+        // Both packets are sent, and the one which could not be recorded is skipped.
+        let mut buf = [0u8; 128];
+        for _ in 0..2 {
+            device.recv_from(&mut buf, Duration::from_secs(5)).unwrap();
+        }
+        let bytes = buffer.0.lock().unwrap().clone();
+        assert_eq!(PcapReader::new(&bytes[..]).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn dispatch_replies_by_sequence() {
+        let device = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let device_address = device.local_addr().unwrap();
+        // Respond to two requests in reverse order, with the sequence as the label.
+        let responder = thread::spawn(move || {
+            let mut buf = [0u8; 128];
+            let mut requests = Vec::new();
+            for _ in 0..2 {
+                let (n, client_address) = device.recv_from(&mut buf).unwrap();
+                requests.push((Packet::try_from(&buf[..n]).unwrap(), client_address));
+            }
+            for (request, client_address) in requests.iter().rev() {
+                let sequence = request.frame_address().sequence;
+                let label = StateLabelPayload::new(sequence.to_string());
+                let response = PacketBuilder::new(Message::StateLabel(label))
+                    .source(request.frame().source)
+                    .sequence(sequence)
+                    .build();
+                device
+                    .send_to(&response.as_bytes(), client_address)
+                    .unwrap();
+            }
+        });
+
+        let connection = Connection::new(UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap());
+        connection.set_timeout(Duration::from_secs(5));
+        let requests: Vec<_> = (0..2u8)
+            .map(|sequence| {
+                let connection = connection.clone();
+                thread::spawn(move || {
+                    let request =
+                        PacketBuilder::with_empty_device_message(DeviceMessageType::GetLabel)
+                            .source(42)
+                            .sequence(sequence)
+                            .res_required(true)
+                            .build();
+                    let response = connection.send_packet(device_address, request).unwrap();
+                    assert_eq!(
+                        response,
+                        Message::StateLabel(StateLabelPayload::new(sequence.to_string()))
+                    );
+                })
+            })
+            .collect();
+
+        for request in requests {
+            request.join().unwrap();
+        }
+        responder.join().unwrap();
+    }
+
+    #[test]
+    fn more_requests_than_sequences() {
+        const REQUESTS: u16 = 300;
+        let network = MemoryNetwork::new();
+        let device_address: SocketAddr = "10.0.0.2:56700".parse().unwrap();
+        let device = network.bind(device_address).unwrap();
+        // Answer each batch of requests in reverse order once no more arrive, labelled with the
+        // device the request was sent to.
+        let responder = thread::spawn(move || {
+            let mut buf = [0u8; 128];
+            let mut answered = 0;
+            while answered < REQUESTS {
+                let mut requests = Vec::new();
+                while let Ok((n, client_address)) =
+                    device.recv_from(&mut buf, Duration::from_millis(100))
+                {
+                    requests.push((Packet::try_from(&buf[..n]).unwrap(), client_address));
+                }
+                for (request, client_address) in requests.iter().rev() {
+                    let target = request.frame_address().target;
+                    let label = StateLabelPayload::new(format!("{:?}", target.address));
+                    let response = PacketBuilder::new(Message::StateLabel(label))
+                        .source(request.frame().source)
+                        .sequence(request.frame_address().sequence)
+                        .target(target)
+                        .build();
+                    device
+                        .send_to(&response.as_bytes(), *client_address)
+                        .unwrap();
+                    answered += 1;
+                }
+            }
+        });
+
+        let connection = Connection::new(network.bind("10.0.0.1:0".parse().unwrap()).unwrap());
+        connection.set_timeout(Duration::from_secs(5));
+        // Every request starts with the same source and sequence.
+        let requests: Vec<_> = (0..REQUESTS)
+            .map(|n| {
+                let connection = connection.clone();
+                thread::spawn(move || {
+                    let [high, low] = n.to_be_bytes();
+                    let target = MacAddress {
+                        address: [0xd0, 0x73, 0xd5, 0x00, high, low],
+                    };
+                    let request =
+                        PacketBuilder::with_empty_device_message(DeviceMessageType::GetLabel)
+                            .source(42)
+                            .target(target)
+                            .res_required(true)
+                            .build();
+                    let response = connection.send_packet(device_address, request).unwrap();
+                    assert_eq!(
+                        response,
+                        Message::StateLabel(StateLabelPayload::new(format!(
+                            "{:?}",
+                            target.address
+                        )))
+                    );
+                })
+            })
+            .collect();
+
+        for request in requests {
+            request.join().unwrap();
+        }
+        responder.join().unwrap();
+    }
+
+    #[test]
+    fn enable_broadcast_once() {
+        let connection = Connection::new(UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap());
+        assert!(UdpSocket::broadcast(&connection.transport).unwrap());
+    }
+
+    #[test]
+    fn request_timeout() {
+        let device = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let connection = Connection::new(UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap());
+        connection.set_timeout(Duration::from_millis(10));
+
+        let request = PacketBuilder::with_empty_device_message(DeviceMessageType::GetLabel)
+            .res_required(true)
+            .build();
+        let error = connection
+            .send_packet(device.local_addr().unwrap(), request)
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+    }
+}
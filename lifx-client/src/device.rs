@@ -1,14 +1,13 @@
 use std::collections::HashSet;
 use std::io;
-use std::{
-    fmt,
-    net::{Ipv4Addr, SocketAddr, UdpSocket},
-    str::FromStr,
-};
+use std::{fmt, net::SocketAddr, str::FromStr};
 
+use super::connection::Connection;
+use super::discovery::DiscoveryOptions;
 use super::protocol::header::*;
 use super::protocol::message::*;
 use super::protocol::packet::*;
+use super::transport::Transport;
 
 #[derive(Debug, Eq, PartialEq, Hash, Clone)]
 pub struct Device {
@@ -55,6 +54,53 @@ impl Device {
     pub(crate) fn socket_address(&self) -> SocketAddr {
         self.address.socket_address
     }
+
+    /// Return a copy of this device at a different address.
+    pub(crate) fn with_address(&self, address: DeviceAddress) -> Device {
+        Device {
+            address,
+            ..self.clone()
+        }
+    }
+}
+
+/// A change to a device's address, label, group or location, found by refreshing it.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DeviceUpdate {
+    old: Device,
+    new: Device,
+}
+
+impl DeviceUpdate {
+    pub(crate) fn new(old: Device, new: Device) -> DeviceUpdate {
+        DeviceUpdate { old, new }
+    }
+
+    /// The device as it was before it was refreshed.
+    pub fn old(&self) -> &Device {
+        &self.old
+    }
+
+    /// The device as it is now.
+    pub fn current(&self) -> &Device {
+        &self.new
+    }
+
+    pub fn address_changed(&self) -> bool {
+        self.old.address != self.new.address
+    }
+
+    pub fn label_changed(&self) -> bool {
+        self.old.label != self.new.label
+    }
+
+    pub fn group_changed(&self) -> bool {
+        self.old.group != self.new.group
+    }
+
+    pub fn location_changed(&self) -> bool {
+        self.old.location != self.new.location
+    }
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
@@ -64,11 +110,11 @@ pub struct DeviceAddress {
 }
 
 impl DeviceAddress {
-    pub(crate) fn mac_address(&self) -> MacAddress {
+    pub fn mac_address(&self) -> MacAddress {
         self.mac_address
     }
 
-    pub(crate) fn socket_address(&self) -> SocketAddr {
+    pub fn socket_address(&self) -> SocketAddr {
         self.socket_address
     }
 }
@@ -115,11 +161,13 @@ impl FromStr for DeviceAddress {
     }
 }
 
-// Return MAC and socket address of devices by broadcasting a GetService message.
-pub(crate) fn get_device_address(
-    socket: &UdpSocket,
+// Return MAC and socket address of devices by broadcasting a GetService message, and sending it to
+// each unicast address in `options`.
+pub(crate) fn get_device_address<T: Transport>(
+    connection: &Connection<T>,
     source: u32,
     sequence: u8,
+    options: &DiscoveryOptions,
 ) -> io::Result<HashSet<DeviceAddress>> {
     let get_service = PacketBuilder::with_empty_device_message(DeviceMessageType::GetService)
         .source(source)
@@ -127,40 +175,94 @@ pub(crate) fn get_device_address(
         .res_required(true)
         .build();
 
-    let get_service = get_service.as_bytes();
-    let broadcast = socket.broadcast()?;
+    find_device_addresses(connection, get_service, options)
+}
+
+/// Return the current address of the device with `mac_address`, by sending a GetService message
+/// which only that device answers to the same addresses as discovery. Returns `None` if the device
+/// does not respond.
+pub(crate) fn locate_device<T: Transport>(
+    connection: &Connection<T>,
+    source: u32,
+    sequence: u8,
+    options: &DiscoveryOptions,
+    mac_address: MacAddress,
+) -> io::Result<Option<DeviceAddress>> {
+    let get_service = PacketBuilder::with_empty_device_message(DeviceMessageType::GetService)
+        .target(mac_address)
+        .source(source)
+        .sequence(sequence)
+        .res_required(true)
+        .build();
+
+    let device_addresses = find_device_addresses(connection, get_service, options)?;
+    Result::Ok(
+        device_addresses
+            .into_iter()
+            .find(|address| address.mac_address() == mac_address),
+    )
+}
 
-    let mut buf = [0u8; 128];
+// Send a GetService `packet` to the broadcast and unicast addresses in `options` and return the
+// address of each device which responds.
+fn find_device_addresses<T: Transport>(
+    connection: &Connection<T>,
+    get_service: Packet,
+    options: &DiscoveryOptions,
+) -> io::Result<HashSet<DeviceAddress>> {
     let mut device_addresses = HashSet::new();
-    socket.set_broadcast(true)?;
-
-    socket.send_to(&get_service, (Ipv4Addr::BROADCAST, 56700))?;
-    while let Ok((n, mut addr)) = socket.recv_from(&mut buf) {
-        let response = Packet::from(&buf[..n]);
-        if let Message::StateService(service_payload) = response.message() {
-            let port = service_payload.port();
-            addr.set_port(port);
-        } else {
-            return Result::Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("Unexpected response. {:?}", &buf[..]),
-            ));
-        }
+    let window = options.window_or(connection.timeout());
 
-        let mac_address = response.frame_address.target;
-        device_addresses.insert(DeviceAddress {
-            mac_address,
-            socket_address: addr,
-        });
+    let mut replies = Vec::new();
+    let targets = options.target_addresses();
+    if !targets.is_empty() {
+        replies = connection.broadcast(
+            &targets,
+            get_service.clone(),
+            options.retransmits() + 1,
+            window,
+        )?;
+    }
+    let unicast = options.unicast_addresses()?;
+    if !unicast.is_empty() {
+        replies.extend(connection.sweep(&unicast, get_service, options.pacing(), window)?);
+    }
+
+    // Ignore anything other than StateService, such as replies from other controllers.
+    for (response, addr) in replies {
+        if let Ok(device_address) = to_device_address(&response, addr) {
+            device_addresses.insert(device_address);
+        }
     }
 
-    socket.set_broadcast(broadcast)?;
     io::Result::Ok(device_addresses)
 }
 
+/// Return the address of the device which sent a StateService `response` from `addr`.
+pub(crate) fn to_device_address(
+    response: &Packet,
+    mut addr: SocketAddr,
+) -> io::Result<DeviceAddress> {
+    if let Message::StateService(service_payload) = response.message() {
+        let port = service_payload.port();
+        addr.set_port(port);
+    } else {
+        return Result::Err(io::Error::other(format!(
+            "Unexpected response. {:?}",
+            response.message()
+        )));
+    }
+
+    let mac_address = response.frame_address().target;
+    Result::Ok(DeviceAddress {
+        mac_address,
+        socket_address: addr,
+    })
+}
+
 /// Return the label for a specific device.
-pub(crate) fn get_label(
-    socket: &UdpSocket,
+pub(crate) fn get_label<T: Transport>(
+    connection: &Connection<T>,
     device_address: &DeviceAddress,
     source: u32,
     sequence: u8,
@@ -172,20 +274,45 @@ pub(crate) fn get_label(
         .target(device_address.mac_address())
         .build();
 
-    let message = send_packet(socket, device_address.socket_address(), packet)?;
+    let message = connection.send_packet(device_address.socket_address(), packet)?;
     if let Message::StateLabel(label_payload) = message {
         Result::Ok(label_payload)
     } else {
-        Result::Err(io::Error::new(
-            io::ErrorKind::Other,
-            format!("Unexpected response. {:?}", message),
-        ))
+        Result::Err(io::Error::other(format!(
+            "Unexpected response. {:?}",
+            message
+        )))
+    }
+}
+
+/// Return the vendor and product IDs of a specific device.
+pub(crate) fn get_version<T: Transport>(
+    connection: &Connection<T>,
+    device_address: &DeviceAddress,
+    source: u32,
+    sequence: u8,
+) -> Result<StateVersionPayload, io::Error> {
+    let packet = PacketBuilder::with_empty_device_message(DeviceMessageType::GetVersion)
+        .source(source)
+        .sequence(sequence)
+        .res_required(true)
+        .target(device_address.mac_address())
+        .build();
+
+    let message = connection.send_packet(device_address.socket_address(), packet)?;
+    if let Message::StateVersion(version_payload) = message {
+        Result::Ok(version_payload)
+    } else {
+        Result::Err(io::Error::other(format!(
+            "Unexpected response. {:?}",
+            message
+        )))
     }
 }
 
 /// Return the location for a specific device.
-pub(crate) fn get_location(
-    socket: &UdpSocket,
+pub(crate) fn get_location<T: Transport>(
+    connection: &Connection<T>,
     device_address: &DeviceAddress,
     source: u32,
     sequence: u8,
@@ -197,20 +324,20 @@ pub(crate) fn get_location(
         .target(device_address.mac_address())
         .build();
 
-    let message = send_packet(socket, device_address.socket_address(), packet)?;
+    let message = connection.send_packet(device_address.socket_address(), packet)?;
     if let Message::StateLocation(location_payload) = message {
         Result::Ok(location_payload)
     } else {
-        Result::Err(io::Error::new(
-            io::ErrorKind::Other,
-            format!("Unexpected response. {:?}", message),
-        ))
+        Result::Err(io::Error::other(format!(
+            "Unexpected response. {:?}",
+            message
+        )))
     }
 }
 
 /// Return the group for a specific device.
-pub(crate) fn get_group(
-    socket: &UdpSocket,
+pub(crate) fn get_group<T: Transport>(
+    connection: &Connection<T>,
     device_address: &DeviceAddress,
     source: u32,
     sequence: u8,
@@ -223,13 +350,13 @@ pub(crate) fn get_group(
         .target(device_address.mac_address())
         .build();
 
-    let message = send_packet(socket, device_address.socket_address(), packet)?;
+    let message = connection.send_packet(device_address.socket_address(), packet)?;
     if let Message::StateGroup(group_payload) = message {
         Result::Ok(group_payload)
     } else {
-        Result::Err(io::Error::new(
-            io::ErrorKind::Other,
-            format!("Unexpected response. {:?}", message),
-        ))
+        Result::Err(io::Error::other(format!(
+            "Unexpected response. {:?}",
+            message
+        )))
     }
 }
@@ -0,0 +1,643 @@
+use super::{
+    client::Client,
+    device::{Device, DeviceAddress},
+    protocol::header::MacAddress,
+    transport::Transport,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, fs, io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+/// The port on which LIFX devices listen.
+pub const LIFX_PORT: u16 = 56700;
+
+// The default time to wait between unicast GetService messages.
+const DEFAULT_PACING: Duration = Duration::from_millis(5);
+
+// The default number of discovery rounds a device can miss before it has disappeared.
+const DEFAULT_MISSED_ROUNDS: u32 = 3;
+
+/// Options which control how devices are discovered.
+///
+/// By default, a single GetService message is broadcast to `255.255.255.255:56700` and responses
+/// are collected for the client's timeout.
+///
+/// On networks which block broadcasts, GetService can instead be sent to every address in a range,
+/// or to every address in a neighbor table. Unicast addresses are swept after broadcasting, so
+/// remove the broadcast targets to only sweep.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DiscoveryOptions {
+    targets: Vec<IpAddr>,
+    port: u16,
+    interface: Option<IpAddr>,
+    retransmits: u32,
+    window: Option<Duration>,
+    sweeps: Vec<Ipv4Cidr>,
+    neighbor_table: Option<PathBuf>,
+    pacing: Duration,
+}
+
+impl Default for DiscoveryOptions {
+    fn default() -> Self {
+        DiscoveryOptions {
+            targets: vec![IpAddr::V4(Ipv4Addr::BROADCAST)],
+            port: LIFX_PORT,
+            interface: Option::None,
+            retransmits: 0,
+            window: Option::None,
+            sweeps: Vec::new(),
+            neighbor_table: Option::None,
+            pacing: DEFAULT_PACING,
+        }
+    }
+}
+
+impl DiscoveryOptions {
+    pub fn new() -> DiscoveryOptions {
+        DiscoveryOptions::default()
+    }
+
+    /// Broadcast to each of `targets` instead of `255.255.255.255`. Use subnet broadcast addresses,
+    /// such as `192.168.1.255`, to reach devices on networks which a global broadcast does not.
+    pub fn with_targets<I: IntoIterator<Item = IpAddr>>(mut self, targets: I) -> Self {
+        self.targets = targets.into_iter().collect();
+        self
+    }
+
+    /// Send to `port` instead of 56700.
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Bind to the local address of a specific interface. Only used when the client binds its own
+    /// socket, with [`Client::with_discovery_options`].
+    ///
+    /// [`Client::with_discovery_options`]: crate::client::Client::with_discovery_options
+    pub fn with_interface(mut self, interface: IpAddr) -> Self {
+        self.interface = Option::Some(interface);
+        self
+    }
+
+    /// Send GetService `retransmits` more times, spread evenly over the discovery window, to make
+    /// up for lost packets.
+    pub fn with_retransmits(mut self, retransmits: u32) -> Self {
+        self.retransmits = retransmits;
+        self
+    }
+
+    /// Collect responses for `window`, instead of the client's timeout.
+    pub fn with_window(mut self, window: Duration) -> Self {
+        self.window = Option::Some(window);
+        self
+    }
+
+    /// Send GetService to every host address in `range`.
+    pub fn with_sweep(mut self, range: Ipv4Cidr) -> Self {
+        self.sweeps.push(range);
+        self
+    }
+
+    /// Send GetService to every address in a neighbor table file, which is read each time devices
+    /// are discovered. Both `/proc/net/arp` and the output of `ip neigh` are supported.
+    pub fn with_neighbor_table<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.neighbor_table = Option::Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Wait `pacing` between each unicast GetService message, to avoid flooding the network.
+    /// Defaults to 5 milliseconds.
+    pub fn with_pacing(mut self, pacing: Duration) -> Self {
+        self.pacing = pacing;
+        self
+    }
+
+    /// Return the addresses to which discovery messages are sent.
+    pub fn target_addresses(&self) -> Vec<SocketAddr> {
+        self.targets
+            .iter()
+            .map(|ip| SocketAddr::new(*ip, self.port))
+            .collect()
+    }
+
+    /// Return the addresses to which unicast discovery messages are sent, without duplicates.
+    pub fn unicast_addresses(&self) -> io::Result<Vec<SocketAddr>> {
+        let mut addresses: Vec<IpAddr> = self
+            .sweeps
+            .iter()
+            .flat_map(|range| range.hosts())
+            .map(IpAddr::V4)
+            .collect();
+        if let Some(path) = &self.neighbor_table {
+            addresses.extend(parse_neighbor_table(&fs::read_to_string(path)?));
+        }
+
+        let mut seen = HashSet::new();
+        Result::Ok(
+            addresses
+                .into_iter()
+                .filter(|ip| seen.insert(*ip))
+                .map(|ip| SocketAddr::new(ip, self.port))
+                .collect(),
+        )
+    }
+
+    pub fn pacing(&self) -> Duration {
+        self.pacing
+    }
+
+    pub fn retransmits(&self) -> u32 {
+        self.retransmits
+    }
+
+    /// Return the discovery window, or `default` if none has been set.
+    pub fn window_or(&self, default: Duration) -> Duration {
+        self.window.unwrap_or(default)
+    }
+
+    /// Bind a socket to the configured interface, on any free port, with broadcasting enabled.
+    pub fn bind(&self) -> io::Result<UdpSocket> {
+        let interface = self.interface.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        let socket = UdpSocket::bind(SocketAddr::new(interface, 0))?;
+        socket.set_broadcast(true)?;
+        Result::Ok(socket)
+    }
+}
+
+/// A range of IPv4 addresses, such as `192.168.1.0/24`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct Ipv4Cidr {
+    address: Ipv4Addr,
+    prefix: u8,
+}
+
+impl Ipv4Cidr {
+    /// The shortest prefix allowed. Larger ranges would take too long to sweep.
+    pub const MIN_PREFIX: u8 = 16;
+
+    pub fn new(address: Ipv4Addr, prefix: u8) -> Result<Ipv4Cidr, String> {
+        if !(Ipv4Cidr::MIN_PREFIX..=32).contains(&prefix) {
+            return Result::Err(format!(
+                "Prefix length must be between {} and 32.",
+                Ipv4Cidr::MIN_PREFIX
+            ));
+        }
+        Result::Ok(Ipv4Cidr { address, prefix })
+    }
+
+    /// Return every host address in the range. The network and broadcast addresses are excluded,
+    /// except in /31 and /32 ranges, which have none.
+    pub fn hosts(&self) -> impl Iterator<Item = Ipv4Addr> {
+        let mask = u32::MAX << (32 - self.prefix as u32);
+        let network = u32::from(self.address) & mask;
+        let broadcast = network | !mask;
+        let (first, last) = if self.prefix >= 31 {
+            (network, broadcast)
+        } else {
+            (network + 1, broadcast - 1)
+        };
+        (first..=last).map(Ipv4Addr::from)
+    }
+}
+
+impl fmt::Display for Ipv4Cidr {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "{}/{}", self.address, self.prefix)
+    }
+}
+
+// Inverse of Display implementation.
+impl FromStr for Ipv4Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = s
+            .split_once('/')
+            .ok_or_else(|| String::from("String must be in the format <address>/<prefix>."))?;
+        let address = address
+            .parse()
+            .map_err(|_| format!("Could not parse IPv4 address {}.", address))?;
+        let prefix = prefix
+            .parse()
+            .map_err(|_| format!("Could not parse prefix length {}.", prefix))?;
+        Ipv4Cidr::new(address, prefix)
+    }
+}
+
+/// Return the IP address of each entry in a neighbor table, in the format of `/proc/net/arp` or
+/// `ip neigh`. Lines which do not start with an IP address, such as headers, are ignored.
+fn parse_neighbor_table(table: &str) -> Vec<IpAddr> {
+    table
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .filter_map(|ip| ip.parse().ok())
+        .collect()
+}
+
+/// The result of discovering devices. Devices which responded to GetService but could not be
+/// queried are reported separately, so they do not prevent the others from being found.
+#[derive(Debug)]
+pub struct DiscoveryReport {
+    devices: HashSet<Device>,
+    failures: HashMap<DeviceAddress, io::Error>,
+}
+
+impl DiscoveryReport {
+    pub(crate) fn new(
+        devices: HashSet<Device>,
+        failures: HashMap<DeviceAddress, io::Error>,
+    ) -> DiscoveryReport {
+        DiscoveryReport { devices, failures }
+    }
+
+    /// Return the devices which were found.
+    pub fn devices(&self) -> &HashSet<Device> {
+        &self.devices
+    }
+
+    /// Return the error for each device which could not be queried.
+    pub fn failures(&self) -> &HashMap<DeviceAddress, io::Error> {
+        &self.failures
+    }
+
+    pub fn into_devices(self) -> HashSet<Device> {
+        self.devices
+    }
+}
+
+/// A change to the devices on the network, found by a [`DiscoveryService`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum DeviceEvent {
+    /// A device was found for the first time.
+    Appeared(Device),
+    /// A device has not responded to discovery for several rounds.
+    Disappeared(Device),
+    /// A device responded from a different address.
+    AddressChanged { device: Device, old: DeviceAddress },
+    /// A device's label changed.
+    Renamed { device: Device, old: String },
+}
+
+/// Discovers devices on a background thread, periodically, and reports changes to the devices on
+/// the network. The thread stops when the service is stopped or dropped.
+pub struct DiscoveryService {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl DiscoveryService {
+    /// Discover devices every `interval` and send each change to the returned receiver.
+    pub fn start<T: Transport>(
+        client: Client<T>,
+        interval: Duration,
+    ) -> (DiscoveryService, Receiver<DeviceEvent>) {
+        let (sender, receiver) = mpsc::channel();
+        let service = DiscoveryService::with_callback(client, interval, move |event| {
+            let _ = sender.send(event);
+        });
+        (service, receiver)
+    }
+
+    /// Discover devices every `interval` and call `callback` with each change.
+    pub fn with_callback<T, F>(
+        client: Client<T>,
+        interval: Duration,
+        callback: F,
+    ) -> DiscoveryService
+    where
+        T: Transport,
+        F: FnMut(DeviceEvent) + Send + 'static,
+    {
+        DiscoveryService::with_options(client, interval, DEFAULT_MISSED_ROUNDS, callback)
+    }
+
+    /// Discover devices every `interval` and call `callback` with each change. A device has
+    /// disappeared once it misses `missed_rounds` consecutive rounds of discovery.
+    pub fn with_options<T, F>(
+        client: Client<T>,
+        interval: Duration,
+        missed_rounds: u32,
+        mut callback: F,
+    ) -> DiscoveryService
+    where
+        T: Transport,
+        F: FnMut(DeviceEvent) + Send + 'static,
+    {
+        let (stop, stopped) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name("lifx-client-discovery".to_string())
+            .spawn(move || {
+                let mut tracker = Tracker::new(missed_rounds);
+                loop {
+                    // A failed round, such as from a network which is down, is the same as a round
+                    // in which no devices respond.
+                    let report = client
+                        .discover_report()
+                        .unwrap_or_else(|_| DiscoveryReport::new(HashSet::new(), HashMap::new()));
+                    tracker.update(&report).into_iter().for_each(&mut callback);
+
+                    match stopped.recv_timeout(interval) {
+                        Err(RecvTimeoutError::Timeout) => continue,
+                        _ => return,
+                    }
+                }
+            })
+            .expect("Could not start discovery thread");
+
+        DiscoveryService {
+            stop: Option::Some(stop),
+            thread: Option::Some(thread),
+        }
+    }
+
+    /// Stop discovering devices and wait for the background thread to finish.
+    pub fn stop(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for DiscoveryService {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Tracks the devices found by each round of discovery.
+struct Tracker {
+    known: HashMap<MacAddress, Tracked>,
+    missed_rounds: u32,
+}
+
+struct Tracked {
+    device: Device,
+    missed: u32,
+}
+
+impl Tracker {
+    fn new(missed_rounds: u32) -> Tracker {
+        Tracker {
+            known: HashMap::new(),
+            missed_rounds: u32::max(missed_rounds, 1),
+        }
+    }
+
+    /// Update the known devices and return what changed.
+    fn update(&mut self, report: &DiscoveryReport) -> Vec<DeviceEvent> {
+        let mut events = Vec::new();
+
+        // Devices which could not be queried are still on the network.
+        let mut responded: HashSet<MacAddress> = report
+            .failures()
+            .keys()
+            .map(|address| address.mac_address())
+            .collect();
+
+        for device in report.devices() {
+            let mac_address = device.address().mac_address();
+            responded.insert(mac_address);
+            match self.known.get_mut(&mac_address) {
+                Some(tracked) => {
+                    let old = &tracked.device;
+                    if old.address() != device.address() {
+                        events.push(DeviceEvent::AddressChanged {
+                            device: device.clone(),
+                            old: old.address(),
+                        });
+                    }
+                    if old.label() != device.label() {
+                        events.push(DeviceEvent::Renamed {
+                            device: device.clone(),
+                            old: old.label().clone(),
+                        });
+                    }
+                    tracked.device = device.clone();
+                }
+                None => {
+                    events.push(DeviceEvent::Appeared(device.clone()));
+                    self.known.insert(
+                        mac_address,
+                        Tracked {
+                            device: device.clone(),
+                            missed: 0,
+                        },
+                    );
+                }
+            }
+        }
+
+        let missed_rounds = self.missed_rounds;
+        self.known.retain(|mac_address, tracked| {
+            if responded.contains(mac_address) {
+                tracked.missed = 0;
+                return true;
+            }
+            tracked.missed += 1;
+            if tracked.missed >= missed_rounds {
+                events.push(DeviceEvent::Disappeared(tracked.device.clone()));
+                false
+            } else {
+                true
+            }
+        });
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_options() {
+        let options = DiscoveryOptions::new();
+        assert_eq!(
+            options.target_addresses(),
+            vec!["255.255.255.255:56700".parse().unwrap()]
+        );
+        assert_eq!(options.retransmits(), 0);
+        assert_eq!(
+            options.window_or(Duration::from_millis(500)),
+            Duration::from_millis(500)
+        );
+    }
+
+    #[test]
+    fn cidr_hosts() {
+        let range: Ipv4Cidr = "192.168.1.17/29".parse().unwrap();
+        assert_eq!(range.to_string(), "192.168.1.17/29");
+        let hosts: Vec<Ipv4Addr> = range.hosts().collect();
+        assert_eq!(hosts.len(), 6);
+        assert_eq!(hosts[0], Ipv4Addr::new(192, 168, 1, 17));
+        assert_eq!(hosts[5], Ipv4Addr::new(192, 168, 1, 22));
+
+        assert_eq!(
+            "10.0.0.0/24".parse::<Ipv4Cidr>().unwrap().hosts().count(),
+            254
+        );
+        assert_eq!(
+            "10.0.0.1/32".parse::<Ipv4Cidr>().unwrap().hosts().count(),
+            1
+        );
+        assert_eq!(
+            "10.0.0.0/31".parse::<Ipv4Cidr>().unwrap().hosts().count(),
+            2
+        );
+    }
+
+    #[test]
+    fn invalid_cidr() {
+        assert!("10.0.0.0".parse::<Ipv4Cidr>().is_err());
+        assert!("10.0.0/24".parse::<Ipv4Cidr>().is_err());
+        assert!("10.0.0.0/33".parse::<Ipv4Cidr>().is_err());
+        assert!("10.0.0.0/8".parse::<Ipv4Cidr>().is_err());
+    }
+
+    #[test]
+    fn neighbor_tables() {
+        let arp = "IP address       HW type     Flags       HW address            Mask     Device
+192.168.1.1      0x1         0x2         00:11:22:33:44:55     *        eth0
+192.168.1.20     0x1         0x2         d0:73:d5:00:00:01     *        eth0
+";
+        let neigh = "192.168.1.20 dev eth0 lladdr d0:73:d5:00:00:01 REACHABLE
+fe80::1 dev eth0 lladdr 00:11:22:33:44:55 router STALE
+";
+        assert_eq!(
+            parse_neighbor_table(arp),
+            vec![
+                "192.168.1.1".parse::<IpAddr>().unwrap(),
+                "192.168.1.20".parse().unwrap()
+            ]
+        );
+        assert_eq!(
+            parse_neighbor_table(neigh),
+            vec![
+                "192.168.1.20".parse::<IpAddr>().unwrap(),
+                "fe80::1".parse().unwrap()
+            ]
+        );
+
+        let path = std::env::temp_dir().join(format!("lifx-neighbors-{}", std::process::id()));
+        fs::write(&path, neigh).unwrap();
+        let options = DiscoveryOptions::new()
+            .with_sweep("192.168.1.16/30".parse().unwrap())
+            .with_neighbor_table(&path);
+        let addresses = options.unicast_addresses();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            addresses.unwrap(),
+            vec![
+                "192.168.1.17:56700".parse().unwrap(),
+                "192.168.1.18:56700".parse().unwrap(),
+                "192.168.1.20:56700".parse().unwrap(),
+                "[fe80::1]:56700".parse().unwrap()
+            ]
+        );
+    }
+
+    fn device(n: u8, ip: &str, label: &str) -> Device {
+        let address = format!("d0:73:d5:00:00:{:02x}#{}:56700", n, ip)
+            .parse()
+            .unwrap();
+        Device::new(address, label.to_string(), String::new(), String::new())
+    }
+
+    fn report(devices: Vec<Device>) -> DiscoveryReport {
+        DiscoveryReport::new(devices.into_iter().collect(), HashMap::new())
+    }
+
+    #[test]
+    fn track_device_events() {
+        let mut tracker = Tracker::new(2);
+        let kitchen = device(1, "10.0.0.2", "Kitchen");
+        let bedroom = device(2, "10.0.0.3", "Bedroom");
+
+        let events = tracker.update(&report(vec![kitchen.clone(), bedroom.clone()]));
+        assert_eq!(events.len(), 2);
+        assert!(events.contains(&DeviceEvent::Appeared(kitchen.clone())));
+        assert!(events.contains(&DeviceEvent::Appeared(bedroom.clone())));
+        assert!(tracker
+            .update(&report(vec![kitchen.clone(), bedroom.clone()]))
+            .is_empty());
+
+        let moved = device(1, "10.0.0.4", "Kitchen");
+        let renamed = device(2, "10.0.0.3", "Guest Room");
+        let events = tracker.update(&report(vec![moved.clone(), renamed.clone()]));
+        assert_eq!(events.len(), 2);
+        assert!(events.contains(&DeviceEvent::AddressChanged {
+            device: moved.clone(),
+            old: kitchen.address(),
+        }));
+        assert!(events.contains(&DeviceEvent::Renamed {
+            device: renamed.clone(),
+            old: "Bedroom".to_string(),
+        }));
+
+        // A device disappears once it misses two rounds.
+        assert!(tracker.update(&report(vec![moved.clone()])).is_empty());
+        assert_eq!(
+            tracker.update(&report(vec![moved.clone()])),
+            vec![DeviceEvent::Disappeared(renamed)]
+        );
+    }
+
+    #[test]
+    fn failed_devices_have_not_disappeared() {
+        let mut tracker = Tracker::new(1);
+        let kitchen = device(1, "10.0.0.2", "Kitchen");
+        tracker.update(&report(vec![kitchen.clone()]));
+
+        let mut failures = HashMap::new();
+        failures.insert(
+            kitchen.address(),
+            io::Error::new(io::ErrorKind::TimedOut, "Timed out."),
+        );
+        let events = tracker.update(&DiscoveryReport::new(HashSet::new(), failures));
+        assert!(events.is_empty());
+        assert_eq!(
+            tracker.update(&report(vec![])),
+            vec![DeviceEvent::Disappeared(kitchen)]
+        );
+    }
+
+    #[test]
+    fn custom_options() {
+        let options = DiscoveryOptions::new()
+            .with_targets(vec![
+                "192.168.1.255".parse().unwrap(),
+                "10.0.0.255".parse().unwrap(),
+            ])
+            .with_port(56800)
+            .with_interface("127.0.0.1".parse().unwrap())
+            .with_retransmits(2)
+            .with_window(Duration::from_secs(2));
+        assert_eq!(
+            options.target_addresses(),
+            vec![
+                "192.168.1.255:56800".parse().unwrap(),
+                "10.0.0.255:56800".parse().unwrap()
+            ]
+        );
+        assert_eq!(options.retransmits(), 2);
+        assert_eq!(
+            options.window_or(Duration::from_millis(500)),
+            Duration::from_secs(2)
+        );
+        assert!(options
+            .bind()
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .ip()
+            .is_loopback());
+    }
+}
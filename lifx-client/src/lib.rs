@@ -1,5 +1,41 @@
+#[cfg(feature = "tokio")]
+pub mod async_client;
+pub(crate) mod cache;
 pub mod client;
 pub mod color;
+pub(crate) mod connection;
 pub mod device;
+pub mod discovery;
 pub(crate) mod light;
+pub mod listener;
+pub mod pcap;
+pub(crate) mod pending;
+// The wire format is only public for tools which work with raw packets, such as lifx-emulator and
+// lifx-dump. It is not a stable API.
+#[cfg(feature = "protocol")]
+pub mod protocol;
+#[cfg(not(feature = "protocol"))]
 pub(crate) mod protocol;
+pub(crate) mod rate_limit;
+pub mod registry;
+pub mod state;
+pub mod stream;
+#[cfg(test)]
+pub(crate) mod testing;
+pub mod transport;
+pub mod watcher;
+
+pub use protocol::{
+    header::MacAddress,
+    message::{Hsbk, Power, StatePayload},
+};
+
+/// Decode `data` as a packet and encode it again. Used by the fuzz target.
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub fn fuzz_packet(data: &[u8]) {
+    use std::convert::TryFrom;
+    if let Ok(packet) = protocol::packet::Packet::try_from(data) {
+        let _ = packet.as_bytes();
+    }
+}
@@ -1,15 +1,20 @@
 use super::{
+    connection::Connection,
     device::Device,
     protocol::{
         header::LightMessageType,
-        message::{Hsbk, Message, Power, SetColorPayload, SetPowerPayload, StatePayload},
-        packet::{send_packet, send_packet_no_response, PacketBuilder},
+        message::{
+            Hsbk, Message, Power, SetColorPayload, SetPowerPayload, SetWaveformOptionalPayload,
+            StatePayload,
+        },
+        packet::{Packet, PacketBuilder},
     },
+    transport::Transport,
 };
-use std::{io, net::UdpSocket};
+use std::{io, net::SocketAddr};
 
-pub(crate) fn get_state(
-    socket: &UdpSocket,
+pub(crate) fn get_state<T: Transport>(
+    connection: &Connection<T>,
     device: &Device,
     source: u32,
     sequence: u8,
@@ -21,44 +26,45 @@ pub(crate) fn get_state(
         .res_required(true)
         .build();
 
-    let response = send_packet(socket, device.socket_address(), packet)?;
+    let response = connection.send_packet(device.socket_address(), packet)?;
 
     if let Message::State(state_payload) = response {
         Result::Ok(state_payload)
     } else {
-        Result::Err(io::Error::new(
-            io::ErrorKind::Other,
-            format!("Unexpected response. {:?}", response),
-        ))
+        Result::Err(io::Error::other(format!(
+            "Unexpected response. {:?}",
+            response
+        )))
     }
 }
 
-pub(crate) fn set_power(
-    socket: &UdpSocket,
+pub(crate) fn set_power<T: Transport>(
+    connection: &Connection<T>,
     device: &Device,
     source: u32,
     sequence: u8,
     power: Power,
     duration: u32,
-) -> io::Result<()> {
+) -> io::Result<Option<Message>> {
+    // Wait for an acknowledgement, so a device which has moved times out and can be relocated.
     let packet = PacketBuilder::new(Message::SetPower(SetPowerPayload::new(power, duration)))
         .target(device.mac_address())
         .source(source)
         .sequence(sequence)
+        .ack_required(true)
         .build();
 
-    send_packet_no_response(socket, device.socket_address(), packet)?;
-    Result::Ok(())
+    connection.send_command(device.socket_address(), packet)
 }
 
-pub(crate) fn set_color(
-    socket: &UdpSocket,
+pub(crate) fn set_color<T: Transport>(
+    connection: &Connection<T>,
     device: &Device,
     source: u32,
     sequence: u8,
     color: Hsbk,
     duration: u32,
-) -> io::Result<()> {
+) -> io::Result<Option<Message>> {
     let packet = PacketBuilder::new(Message::SetColor(SetColorPayload::new(color, duration)))
         .target(device.mac_address())
         .source(source)
@@ -66,6 +72,76 @@ pub(crate) fn set_color(
         .res_required(true)
         .build();
 
-    send_packet(socket, device.socket_address(), packet)?;
+    connection.send_command(device.socket_address(), packet)
+}
+
+/// Change only the components of a device's color which are set in `payload`.
+pub(crate) fn set_color_components<T: Transport>(
+    connection: &Connection<T>,
+    device: &Device,
+    source: u32,
+    sequence: u8,
+    payload: SetWaveformOptionalPayload,
+) -> io::Result<Option<Message>> {
+    let packet = PacketBuilder::new(Message::SetWaveformOptional(payload))
+        .target(device.mac_address())
+        .source(source)
+        .sequence(sequence)
+        .res_required(true)
+        .build();
+
+    connection.send_command(device.socket_address(), packet)
+}
+
+/// Set the power of every device on the network with a single tagged packet, sent to each of
+/// `addresses` `sends` times in total.
+pub(crate) fn broadcast_power<T: Transport>(
+    connection: &Connection<T>,
+    addresses: &[SocketAddr],
+    source: u32,
+    sequence: u8,
+    power: Power,
+    duration: u32,
+    sends: usize,
+) -> io::Result<()> {
+    let packet = PacketBuilder::new(Message::SetPower(SetPowerPayload::new(power, duration)))
+        .source(source)
+        .sequence(sequence)
+        .build();
+
+    broadcast(connection, addresses, packet, sends)
+}
+
+/// Set the color of every device on the network with a single tagged packet, sent to each of
+/// `addresses` `sends` times in total.
+pub(crate) fn broadcast_color<T: Transport>(
+    connection: &Connection<T>,
+    addresses: &[SocketAddr],
+    source: u32,
+    sequence: u8,
+    color: Hsbk,
+    duration: u32,
+    sends: usize,
+) -> io::Result<()> {
+    let packet = PacketBuilder::new(Message::SetColor(SetColorPayload::new(color, duration)))
+        .source(source)
+        .sequence(sequence)
+        .build();
+
+    broadcast(connection, addresses, packet, sends)
+}
+
+fn broadcast<T: Transport>(
+    connection: &Connection<T>,
+    addresses: &[SocketAddr],
+    packet: Packet,
+    sends: usize,
+) -> io::Result<()> {
+    // Setting the same power or color again has no effect, so every copy can share a sequence.
+    for _ in 0..usize::max(sends, 1) {
+        for address in addresses {
+            connection.broadcast_no_response(*address, packet.clone())?;
+        }
+    }
     Result::Ok(())
 }
@@ -0,0 +1,314 @@
+use super::{
+    client::Client,
+    protocol::{
+        header::MacAddress,
+        message::{Hsbk, Message, Power},
+        packet::Packet,
+    },
+    transport::Transport,
+};
+use std::{
+    convert::TryFrom,
+    io,
+    sync::mpsc::{self, Receiver, Sender, TryRecvError},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+// How long to wait for each packet before checking whether the listener has stopped.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A change to a light observed by a [`Listener`], made by another controller or reported by a
+/// device.
+///
+/// Colors are the exact values sent or reported, which can be converted to a
+/// [`Color`](crate::color::Color).
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ObservedChange {
+    /// A device reported its color and power.
+    State {
+        device: MacAddress,
+        color: Hsbk,
+        power: Power,
+    },
+    /// A device reported its power.
+    Power { device: MacAddress, power: Power },
+    /// A controller set the color of a device, or of every device if `device` is `None`.
+    SetColor {
+        device: Option<MacAddress>,
+        color: Hsbk,
+        duration: Duration,
+    },
+    /// A controller set the power of a device, or of every device if `device` is `None`.
+    SetPower {
+        device: Option<MacAddress>,
+        power: Power,
+        duration: Duration,
+    },
+}
+
+/// Listens passively for LIFX traffic on a background thread, to follow changes made by other
+/// controllers such as the LIFX app. The thread stops when the listener is stopped or dropped.
+///
+/// The transport should be bound to the LIFX port, 56700, to observe commands sent by other
+/// controllers and State messages broadcast by devices. Each observed State, StatePower, SetColor
+/// and SetPower message updates the client's state cache, if it is enabled, and is reported as an
+/// [`ObservedChange`]. SetWaveformOptional messages also update the cache, but are not reported.
+/// A tagged command changes every device, so it removes every cached state. Packets sent by the
+/// client itself are ignored.
+pub struct Listener {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Listener {
+    /// Listen on `transport` and send each observed change to the returned receiver.
+    pub fn start<T, L>(client: Client<T>, transport: L) -> (Listener, Receiver<ObservedChange>)
+    where
+        T: Transport,
+        L: Transport,
+    {
+        let (sender, receiver) = mpsc::channel();
+        let listener = Listener::with_callback(client, transport, move |change| {
+            let _ = sender.send(change);
+        });
+        (listener, receiver)
+    }
+
+    /// Listen on `transport` and call `callback` with each observed change.
+    pub fn with_callback<T, L, F>(client: Client<T>, transport: L, mut callback: F) -> Listener
+    where
+        T: Transport,
+        L: Transport,
+        F: FnMut(ObservedChange) + Send + 'static,
+    {
+        let (stop, stopped) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name("lifx-client-listener".to_string())
+            .spawn(move || {
+                let mut buf = [0u8; 1024];
+                while let Err(TryRecvError::Empty) = stopped.try_recv() {
+                    let n = match transport.recv_from(&mut buf, POLL_INTERVAL) {
+                        Ok((n, _)) => n,
+                        Err(e)
+                            if e.kind() == io::ErrorKind::WouldBlock
+                                || e.kind() == io::ErrorKind::TimedOut =>
+                        {
+                            continue
+                        }
+                        // Avoid spinning if the transport keeps failing.
+                        Err(_) => {
+                            thread::sleep(POLL_INTERVAL);
+                            continue;
+                        }
+                    };
+
+                    // Ignore anything which is not a LIFX packet.
+                    if let Ok(packet) = Packet::try_from(&buf[..n]) {
+                        if packet.frame().source == client.source() {
+                            continue;
+                        }
+                        if let Some(change) = observe(&client, &packet) {
+                            callback(change);
+                        }
+                    }
+                }
+            })
+            .expect("Could not start listener thread");
+
+        Listener {
+            stop: Option::Some(stop),
+            thread: Option::Some(thread),
+        }
+    }
+
+    /// Stop listening and wait for the background thread to finish.
+    pub fn stop(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Update the client's cached state from an observed packet, and return the change it describes.
+fn observe<T: Transport>(client: &Client<T>, packet: &Packet) -> Option<ObservedChange> {
+    let states = client.states();
+    let target = packet.frame_address().target;
+    let device = if packet.frame().tagged || target.address == [0u8; 6] {
+        Option::None
+    } else {
+        Option::Some(target)
+    };
+
+    match (packet.message(), device) {
+        (Message::State(state), Some(device)) => {
+            states.update(device, state.clone());
+            Option::Some(ObservedChange::State {
+                device,
+                color: state.color(),
+                power: state.power(),
+            })
+        }
+        (Message::StatePower(state), Some(device)) => {
+            states.set_power(device, state.power(), Duration::from_secs(0));
+            Option::Some(ObservedChange::Power {
+                device,
+                power: state.power(),
+            })
+        }
+        (Message::SetColor(payload), device) => {
+            let duration = Duration::from_millis(payload.duration() as u64);
+            match device {
+                Some(device) => states.set_color(device, payload.color(), duration),
+                None => states.clear(),
+            }
+            Option::Some(ObservedChange::SetColor {
+                device,
+                color: payload.color(),
+                duration,
+            })
+        }
+        (Message::SetPower(payload), device) => {
+            let duration = Duration::from_millis(payload.duration() as u64);
+            match device {
+                Some(device) => states.set_power(device, payload.power(), duration),
+                None => states.clear(),
+            }
+            Option::Some(ObservedChange::SetPower {
+                device,
+                power: payload.power(),
+                duration,
+            })
+        }
+        // Only part of the color may be set, so the cache is updated but no change is reported.
+        (Message::SetWaveformOptional(payload), device) => {
+            match device {
+                Some(device) => states.set_waveform(device, payload),
+                None => states.clear(),
+            }
+            Option::None
+        }
+        _ => Option::None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        color::Color,
+        device::Device,
+        protocol::{
+            message::{SetColorPayload, SetPowerPayload, StatePayload},
+            packet::PacketBuilder,
+        },
+        transport::MemoryNetwork,
+    };
+    use std::net::SocketAddr;
+
+    const MAC: MacAddress = MacAddress {
+        address: [0xd0, 0x73, 0xd5, 0x00, 0x00, 0x01],
+    };
+
+    #[test]
+    fn follow_other_controllers() {
+        let network = MemoryNetwork::new();
+        let client = Client::new(network.bind("10.0.0.1:0".parse().unwrap()).unwrap());
+        client.set_timeout(Duration::from_millis(50));
+        client.set_state_cache(Option::Some(Duration::from_secs(60)));
+        let listen_address: SocketAddr = "10.0.0.9:56700".parse().unwrap();
+        let (_listener, changes) =
+            Listener::start(client.clone(), network.bind(listen_address).unwrap());
+        let next = || changes.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        // The device is not on the network, so its state is only known from observed packets.
+        let device = Device::new(
+            format!("{}#10.0.0.2:56700", MAC).parse().unwrap(),
+            "Lamp".to_string(),
+            String::new(),
+            String::new(),
+        );
+        let other = network.bind("10.0.0.5:56700".parse().unwrap()).unwrap();
+        let send = |message: Message, target: Option<MacAddress>| {
+            let mut builder = PacketBuilder::new(message).source(42);
+            if let Some(target) = target {
+                builder = builder.target(target);
+            }
+            other
+                .send_to(&builder.build().as_bytes(), listen_address)
+                .unwrap();
+        };
+
+        let white = Hsbk::new(0, 0, 0xffff, 3500);
+        let state = StatePayload::new(white, Power::Off, "Lamp".to_string());
+        send(Message::State(state), Option::Some(MAC));
+        assert_eq!(
+            next(),
+            ObservedChange::State {
+                device: MAC,
+                color: white,
+                power: Power::Off
+            }
+        );
+        assert_eq!(Hsbk::from(client.get_color(&device).unwrap()), white);
+
+        let red = Hsbk::from(Color::RED);
+        send(
+            Message::SetColor(SetColorPayload::new(red, 0)),
+            Option::Some(MAC),
+        );
+        assert_eq!(
+            next(),
+            ObservedChange::SetColor {
+                device: Option::Some(MAC),
+                color: red,
+                duration: Duration::from_secs(0)
+            }
+        );
+        assert_eq!(Hsbk::from(client.get_color(&device).unwrap()), red);
+
+        // A tagged command changes every device, so the cached state is no longer known.
+        let power = Power::On(0xffff);
+        send(
+            Message::SetPower(SetPowerPayload::new(power, 1000)),
+            Option::None,
+        );
+        assert_eq!(
+            next(),
+            ObservedChange::SetPower {
+                device: Option::None,
+                power,
+                duration: Duration::from_secs(1)
+            }
+        );
+        let error = client.get_color(&device).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn ignore_own_packets() {
+        let network = MemoryNetwork::new();
+        let client = Client::new(network.bind("10.0.0.1:0".parse().unwrap()).unwrap());
+        let listen_address: SocketAddr = "10.0.0.9:56700".parse().unwrap();
+        let (mut listener, changes) =
+            Listener::start(client.clone(), network.bind(listen_address).unwrap());
+
+        let color = Hsbk::from(Color::GREEN);
+        let packet = PacketBuilder::new(Message::SetColor(SetColorPayload::new(color, 0)))
+            .target(MAC)
+            .source(client.source())
+            .build();
+        let other = network.bind("10.0.0.5:56700".parse().unwrap()).unwrap();
+        other.send_to(&packet.as_bytes(), listen_address).unwrap();
+
+        assert!(changes.recv_timeout(Duration::from_millis(300)).is_err());
+        listener.stop();
+    }
+}
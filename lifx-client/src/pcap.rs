@@ -0,0 +1,709 @@
+//! Reading and writing UDP datagrams in packet capture files.
+//!
+//! Both the classic pcap format and the pcapng format can be read. Only UDP datagrams over IPv4
+//! or IPv6 are returned; all other captured packets are skipped. Files are always written in the
+//! classic pcap format with synthetic IP and UDP headers.
+use std::{
+    convert::TryInto,
+    io::{self, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const PCAP_MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+const PCAP_MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+const PCAPNG_SECTION_HEADER: u32 = 0x0a0d_0d0a;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+const PCAPNG_INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const PCAPNG_SIMPLE_PACKET: u32 = 0x0000_0003;
+const PCAPNG_ENHANCED_PACKET: u32 = 0x0000_0006;
+
+// Larger frames are assumed to be the result of a corrupt file.
+const MAX_FRAME_LENGTH: usize = 0x40000;
+
+const LINKTYPE_NULL: u32 = 0;
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_LOOP: u32 = 108;
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+/// A captured UDP datagram.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Datagram {
+    pub timestamp: SystemTime,
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Debug, Copy, Clone)]
+enum Format {
+    Pcap {
+        big_endian: bool,
+        nanos: bool,
+        link_type: u32,
+    },
+    PcapNg {
+        big_endian: bool,
+    },
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Interface {
+    link_type: u32,
+    // Number of timestamp units per second.
+    units_per_second: u64,
+}
+
+/// Reads UDP datagrams from a pcap or pcapng file.
+pub struct PcapReader<R: Read> {
+    reader: R,
+    format: Format,
+    interfaces: Vec<Interface>,
+}
+
+impl<R: Read> PcapReader<R> {
+    /// Create a reader, detecting the file format from its header.
+    pub fn new(mut reader: R) -> io::Result<PcapReader<R>> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+
+        if u32::from_le_bytes(magic) == PCAPNG_SECTION_HEADER {
+            let mut length = [0u8; 4];
+            reader.read_exact(&mut length)?;
+            let mut pcap_reader = PcapReader {
+                reader,
+                format: Format::PcapNg { big_endian: false },
+                interfaces: Vec::new(),
+            };
+            pcap_reader.read_section_header(length)?;
+            return Result::Ok(pcap_reader);
+        }
+
+        let (big_endian, nanos) = match (u32::from_le_bytes(magic), u32::from_be_bytes(magic)) {
+            (PCAP_MAGIC_MICROS, _) => (false, false),
+            (PCAP_MAGIC_NANOS, _) => (false, true),
+            (_, PCAP_MAGIC_MICROS) => (true, false),
+            (_, PCAP_MAGIC_NANOS) => (true, true),
+            _ => return Result::Err(invalid_data("Not a pcap or pcapng file.")),
+        };
+
+        // Version (4 bytes), time zone (4 bytes), accuracy (4 bytes), snapshot length (4 bytes),
+        // link type (4 bytes).
+        let mut header = [0u8; 20];
+        reader.read_exact(&mut header)?;
+        let link_type = read_u32(&header[16..20], big_endian);
+
+        Result::Ok(PcapReader {
+            reader,
+            format: Format::Pcap {
+                big_endian,
+                nanos,
+                link_type,
+            },
+            interfaces: Vec::new(),
+        })
+    }
+
+    /// Read the remainder of a pcapng section header block, after the block type and length.
+    fn read_section_header(&mut self, length: [u8; 4]) -> io::Result<()> {
+        let mut magic = [0u8; 4];
+        self.reader.read_exact(&mut magic)?;
+        let big_endian = if u32::from_le_bytes(magic) == PCAPNG_BYTE_ORDER_MAGIC {
+            false
+        } else if u32::from_be_bytes(magic) == PCAPNG_BYTE_ORDER_MAGIC {
+            true
+        } else {
+            return Result::Err(invalid_data("Invalid pcapng byte order magic."));
+        };
+        let total_length = read_u32(&length, big_endian) as usize;
+        if !(12..=MAX_FRAME_LENGTH).contains(&total_length) {
+            return Result::Err(invalid_data("Invalid pcapng block length."));
+        }
+
+        // Skip the rest of the block; interfaces are scoped to a section.
+        self.skip(total_length - 12)?;
+        self.format = Format::PcapNg { big_endian };
+        self.interfaces.clear();
+        Result::Ok(())
+    }
+
+    /// Read the next captured frame. Returns the link type, timestamp and frame data, or `None`
+    /// at the end of the file.
+    fn next_frame(&mut self) -> io::Result<Option<(u32, SystemTime, Vec<u8>)>> {
+        match self.format {
+            Format::Pcap {
+                big_endian,
+                nanos,
+                link_type,
+            } => {
+                let mut header = [0u8; 16];
+                if !self.read_or_eof(&mut header)? {
+                    return Result::Ok(Option::None);
+                }
+                let seconds = read_u32(&header[0..4], big_endian) as u64;
+                let fraction = read_u32(&header[4..8], big_endian) as u64;
+                let captured_length = read_u32(&header[8..12], big_endian) as usize;
+                if captured_length > MAX_FRAME_LENGTH {
+                    return Result::Err(invalid_data("Invalid pcap record length."));
+                }
+
+                let mut data = vec![0u8; captured_length];
+                self.reader.read_exact(&mut data)?;
+
+                let units_per_second = if nanos { 1_000_000_000 } else { 1_000_000 };
+                let timestamp =
+                    to_system_time(seconds * units_per_second + fraction, units_per_second)?;
+                Result::Ok(Option::Some((link_type, timestamp, data)))
+            }
+            Format::PcapNg { big_endian } => loop {
+                let mut block_header = [0u8; 8];
+                if !self.read_or_eof(&mut block_header)? {
+                    return Result::Ok(Option::None);
+                }
+                let block_type = read_u32(&block_header[0..4], big_endian);
+                if block_type == PCAPNG_SECTION_HEADER {
+                    self.read_section_header(block_header[4..8].try_into().unwrap())?;
+                    continue;
+                }
+
+                let total_length = read_u32(&block_header[4..8], big_endian) as usize;
+                if !(12..=MAX_FRAME_LENGTH).contains(&total_length) {
+                    return Result::Err(invalid_data("Invalid pcapng block length."));
+                }
+                let mut body = vec![0u8; total_length - 12];
+                self.reader.read_exact(&mut body)?;
+                self.skip(4)?;
+
+                match block_type {
+                    PCAPNG_INTERFACE_DESCRIPTION => {
+                        self.interfaces.push(read_interface(&body, big_endian)?)
+                    }
+                    PCAPNG_ENHANCED_PACKET => {
+                        if body.len() < 20 {
+                            return Result::Err(invalid_data("Invalid enhanced packet block."));
+                        }
+                        let interface = self.interface(read_u32(&body[0..4], big_endian))?;
+                        let high = read_u32(&body[4..8], big_endian) as u64;
+                        let low = read_u32(&body[8..12], big_endian) as u64;
+                        let captured_length = read_u32(&body[12..16], big_endian) as usize;
+                        let data = body
+                            .get(20..20 + captured_length)
+                            .ok_or_else(|| invalid_data("Invalid enhanced packet length."))?
+                            .to_vec();
+
+                        let timestamp =
+                            to_system_time(high << 32 | low, interface.units_per_second)?;
+                        return Result::Ok(Option::Some((interface.link_type, timestamp, data)));
+                    }
+                    PCAPNG_SIMPLE_PACKET => {
+                        if body.len() < 4 {
+                            return Result::Err(invalid_data("Invalid simple packet block."));
+                        }
+                        let interface = self.interface(0)?;
+                        let original_length = read_u32(&body[0..4], big_endian) as usize;
+                        let data = &body[4..];
+                        let data = data[..usize::min(original_length, data.len())].to_vec();
+
+                        // Simple packet blocks have no timestamp.
+                        return Result::Ok(Option::Some((interface.link_type, UNIX_EPOCH, data)));
+                    }
+                    // Ignore other block types.
+                    _ => {}
+                }
+            },
+        }
+    }
+
+    fn interface(&self, id: u32) -> io::Result<Interface> {
+        self.interfaces
+            .get(id as usize)
+            .copied()
+            .ok_or_else(|| invalid_data(format!("Unknown pcapng interface: {}.", id)))
+    }
+
+    /// Fill `buf` completely, returning `false` if the reader is already at the end of the file.
+    fn read_or_eof(&mut self, buf: &mut [u8]) -> io::Result<bool> {
+        let mut read = 0;
+        while read < buf.len() {
+            match self.reader.read(&mut buf[read..]) {
+                Ok(0) if read == 0 => return Result::Ok(false),
+                Ok(0) => return Result::Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                Ok(n) => read += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Result::Err(e),
+            }
+        }
+        Result::Ok(true)
+    }
+
+    fn skip(&mut self, n: usize) -> io::Result<()> {
+        io::copy(&mut (&mut self.reader).take(n as u64), &mut io::sink())?;
+        Result::Ok(())
+    }
+}
+
+impl<R: Read> Iterator for PcapReader<R> {
+    type Item = io::Result<Datagram>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.next_frame() {
+                Ok(Some((link_type, timestamp, data))) => {
+                    if let Some(datagram) = parse_frame(link_type, timestamp, &data) {
+                        return Option::Some(Result::Ok(datagram));
+                    }
+                }
+                Ok(None) => return Option::None,
+                Err(e) => return Option::Some(Result::Err(e)),
+            }
+        }
+    }
+}
+
+/// Writes UDP datagrams to a pcap file.
+pub struct PcapWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// Create a writer and write the pcap file header.
+    pub fn new(mut writer: W) -> io::Result<PcapWriter<W>> {
+        let mut header = Vec::with_capacity(24);
+        header.extend(PCAP_MAGIC_MICROS.to_le_bytes().iter());
+        // Version 2.4
+        header.extend(2u16.to_le_bytes().iter());
+        header.extend(4u16.to_le_bytes().iter());
+        // Time zone and timestamp accuracy; always 0.
+        header.extend([0u8; 8].iter());
+        header.extend((MAX_FRAME_LENGTH as u32).to_le_bytes().iter());
+        header.extend(LINKTYPE_RAW.to_le_bytes().iter());
+        writer.write_all(&header)?;
+
+        Result::Ok(PcapWriter { writer })
+    }
+
+    /// Write a datagram as an IP packet with the datagram's timestamp.
+    pub fn write(&mut self, datagram: &Datagram) -> io::Result<()> {
+        let frame = ip_packet(datagram)?;
+        let timestamp = datagram
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        let mut record = Vec::with_capacity(16 + frame.len());
+        record.extend((timestamp.as_secs() as u32).to_le_bytes().iter());
+        record.extend(timestamp.subsec_micros().to_le_bytes().iter());
+        record.extend((frame.len() as u32).to_le_bytes().iter());
+        record.extend((frame.len() as u32).to_le_bytes().iter());
+        record.extend(frame);
+        self.writer.write_all(&record)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// Return the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// Build an IPv4 or IPv6 packet containing a UDP datagram. IPv6 is used if either address is an
+/// IPv6 address.
+fn ip_packet(datagram: &Datagram) -> io::Result<Vec<u8>> {
+    let udp_length = 8 + datagram.payload.len();
+    if udp_length > u16::MAX as usize - 40 {
+        return Result::Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Datagram is too large.",
+        ));
+    }
+
+    let mut bytes = Vec::new();
+    match (datagram.source.ip(), datagram.destination.ip()) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            bytes.extend(&[0x45, 0x00]);
+            bytes.extend(((20 + udp_length) as u16).to_be_bytes().iter());
+            // Identification, don't fragment, TTL, protocol (UDP), checksum.
+            bytes.extend(&[0x00, 0x00, 0x40, 0x00, 0x40, 17, 0x00, 0x00]);
+            bytes.extend(source.octets().iter());
+            bytes.extend(destination.octets().iter());
+            let checksum = ipv4_checksum(&bytes);
+            bytes[10..12].copy_from_slice(&checksum.to_be_bytes());
+        }
+        (source, destination) => {
+            bytes.extend(&[0x60, 0x00, 0x00, 0x00]);
+            bytes.extend((udp_length as u16).to_be_bytes().iter());
+            // Next header (UDP), hop limit.
+            bytes.extend(&[17, 64]);
+            bytes.extend(to_ipv6(source).octets().iter());
+            bytes.extend(to_ipv6(destination).octets().iter());
+        }
+    }
+
+    bytes.extend(datagram.source.port().to_be_bytes().iter());
+    bytes.extend(datagram.destination.port().to_be_bytes().iter());
+    bytes.extend((udp_length as u16).to_be_bytes().iter());
+    // A zero UDP checksum means that no checksum was computed.
+    bytes.extend(&[0x00, 0x00]);
+    bytes.extend(&datagram.payload);
+    Result::Ok(bytes)
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum = header
+        .chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], word[1]]) as u32)
+        .sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+fn read_interface(body: &[u8], big_endian: bool) -> io::Result<Interface> {
+    if body.len() < 8 {
+        return Result::Err(invalid_data("Invalid interface description block."));
+    }
+    let link_type = read_u16(&body[0..2], big_endian) as u32;
+    let mut units_per_second = 1_000_000;
+
+    // Look for the if_tsresol option.
+    let mut options = &body[8..];
+    while options.len() >= 4 {
+        let code = read_u16(&options[0..2], big_endian);
+        let length = read_u16(&options[2..4], big_endian) as usize;
+        let padded_length = length.div_ceil(4) * 4;
+        if code == 0 || options.len() < 4 + padded_length {
+            break;
+        }
+        if code == 9 && length == 1 {
+            let resolution = options[4];
+            let exponent = (resolution & 0x7f) as u32;
+            units_per_second = if resolution & 0x80 == 0 {
+                10u64.checked_pow(exponent)
+            } else {
+                2u64.checked_pow(exponent)
+            }
+            .ok_or_else(|| invalid_data("Unsupported timestamp resolution."))?;
+        }
+        options = &options[4 + padded_length..];
+    }
+
+    Result::Ok(Interface {
+        link_type,
+        units_per_second,
+    })
+}
+
+/// Extract a UDP datagram from a link layer frame.
+fn parse_frame(link_type: u32, timestamp: SystemTime, data: &[u8]) -> Option<Datagram> {
+    let ip_packet = match link_type {
+        LINKTYPE_NULL | LINKTYPE_LOOP => data.get(4..)?,
+        LINKTYPE_ETHERNET => {
+            let mut ether_type = u16::from_be_bytes(data.get(12..14)?.try_into().ok()?);
+            let mut offset = 14;
+            // Skip VLAN tags.
+            while ether_type == 0x8100 || ether_type == 0x88a8 {
+                ether_type = u16::from_be_bytes(data.get(offset + 2..offset + 4)?.try_into().ok()?);
+                offset += 4;
+            }
+            data.get(offset..)?
+        }
+        LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => data,
+        LINKTYPE_LINUX_SLL => data.get(16..)?,
+        LINKTYPE_LINUX_SLL2 => data.get(20..)?,
+        _ => return Option::None,
+    };
+
+    let (source_ip, destination_ip, udp) = match ip_packet.first()? >> 4 {
+        4 => {
+            let header_length = ((ip_packet[0] & 0x0f) as usize) * 4;
+            let total_length = u16::from_be_bytes(ip_packet.get(2..4)?.try_into().ok()?) as usize;
+            let fragment = u16::from_be_bytes(ip_packet.get(6..8)?.try_into().ok()?);
+            // Only unfragmented UDP packets are supported.
+            if *ip_packet.get(9)? != 17 || fragment & 0x3fff != 0 {
+                return Option::None;
+            }
+            let source: [u8; 4] = ip_packet.get(12..16)?.try_into().ok()?;
+            let destination: [u8; 4] = ip_packet.get(16..20)?.try_into().ok()?;
+            let end = usize::min(total_length, ip_packet.len());
+            (
+                IpAddr::V4(Ipv4Addr::from(source)),
+                IpAddr::V4(Ipv4Addr::from(destination)),
+                ip_packet.get(header_length..end)?,
+            )
+        }
+        6 => {
+            // Extension headers are not supported.
+            if *ip_packet.get(6)? != 17 {
+                return Option::None;
+            }
+            let payload_length = u16::from_be_bytes(ip_packet.get(4..6)?.try_into().ok()?) as usize;
+            let source: [u8; 16] = ip_packet.get(8..24)?.try_into().ok()?;
+            let destination: [u8; 16] = ip_packet.get(24..40)?.try_into().ok()?;
+            let end = usize::min(40 + payload_length, ip_packet.len());
+            (
+                IpAddr::V6(Ipv6Addr::from(source)),
+                IpAddr::V6(Ipv6Addr::from(destination)),
+                ip_packet.get(40..end)?,
+            )
+        }
+        _ => return Option::None,
+    };
+
+    let source_port = u16::from_be_bytes(udp.get(0..2)?.try_into().ok()?);
+    let destination_port = u16::from_be_bytes(udp.get(2..4)?.try_into().ok()?);
+    let udp_length = u16::from_be_bytes(udp.get(4..6)?.try_into().ok()?) as usize;
+    let end = usize::min(usize::max(udp_length, 8), udp.len());
+
+    Option::Some(Datagram {
+        timestamp,
+        source: SocketAddr::new(source_ip, source_port),
+        destination: SocketAddr::new(destination_ip, destination_port),
+        payload: udp.get(8..end)?.to_vec(),
+    })
+}
+
+fn to_system_time(units: u64, units_per_second: u64) -> io::Result<SystemTime> {
+    let seconds = units / units_per_second;
+    let nanos = (units % units_per_second) as u128 * 1_000_000_000 / units_per_second as u128;
+    // With a resolution of one second, a timestamp can be later than the system can represent.
+    UNIX_EPOCH
+        .checked_add(Duration::new(seconds, nanos as u32))
+        .ok_or_else(|| invalid_data("Timestamp out of range."))
+}
+
+fn read_u16(bytes: &[u8], big_endian: bool) -> u16 {
+    let bytes = bytes.try_into().unwrap();
+    if big_endian {
+        u16::from_be_bytes(bytes)
+    } else {
+        u16::from_le_bytes(bytes)
+    }
+}
+
+fn read_u32(bytes: &[u8], big_endian: bool) -> u32 {
+    let bytes = bytes.try_into().unwrap();
+    if big_endian {
+        u32::from_be_bytes(bytes)
+    } else {
+        u32::from_le_bytes(bytes)
+    }
+}
+
+fn invalid_data<E>(error: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn udp_ipv4(source: SocketAddr, destination: SocketAddr, payload: &[u8]) -> Vec<u8> {
+        let (source_ip, destination_ip) = match (source.ip(), destination.ip()) {
+            (IpAddr::V4(s), IpAddr::V4(d)) => (s, d),
+            _ => unreachable!(),
+        };
+        let mut bytes = vec![0x45, 0x00];
+        bytes.extend(((20 + 8 + payload.len()) as u16).to_be_bytes().iter());
+        bytes.extend(&[0x00, 0x00, 0x40, 0x00, 0x40, 17, 0x00, 0x00]);
+        bytes.extend(source_ip.octets().iter());
+        bytes.extend(destination_ip.octets().iter());
+        bytes.extend(source.port().to_be_bytes().iter());
+        bytes.extend(destination.port().to_be_bytes().iter());
+        bytes.extend(((8 + payload.len()) as u16).to_be_bytes().iter());
+        bytes.extend(&[0x00, 0x00]);
+        bytes.extend(payload);
+        bytes
+    }
+
+    fn ethernet(ip_packet: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0xff; 6];
+        bytes.extend(&[0xd0, 0x73, 0xd5, 0x01, 0x02, 0x03]);
+        bytes.extend(&[0x08, 0x00]);
+        bytes.extend(ip_packet);
+        bytes
+    }
+
+    #[test]
+    fn read_pcap() {
+        let source: SocketAddr = "192.168.1.20:56700".parse().unwrap();
+        let destination: SocketAddr = "192.168.1.10:51234".parse().unwrap();
+        let frame = ethernet(&udp_ipv4(source, destination, b"lifx"));
+
+        let mut bytes = Vec::new();
+        bytes.extend(PCAP_MAGIC_MICROS.to_le_bytes().iter());
+        bytes.extend(&[0x02, 0x00, 0x04, 0x00]);
+        bytes.extend(&[0u8; 8]);
+        bytes.extend(65535u32.to_le_bytes().iter());
+        bytes.extend(LINKTYPE_ETHERNET.to_le_bytes().iter());
+        // Record header.
+        bytes.extend(1_600_000_000u32.to_le_bytes().iter());
+        bytes.extend(250_000u32.to_le_bytes().iter());
+        bytes.extend((frame.len() as u32).to_le_bytes().iter());
+        bytes.extend((frame.len() as u32).to_le_bytes().iter());
+        bytes.extend(&frame);
+
+        let datagrams: Vec<Datagram> = PcapReader::new(&bytes[..])
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+
+        assert_eq!(
+            datagrams,
+            vec![Datagram {
+                timestamp: UNIX_EPOCH + Duration::from_millis(1_600_000_000_250),
+                source,
+                destination,
+                payload: b"lifx".to_vec(),
+            }]
+        );
+    }
+
+    #[test]
+    fn read_pcapng() {
+        let source: SocketAddr = "10.0.0.1:51234".parse().unwrap();
+        let destination: SocketAddr = "10.0.0.255:56700".parse().unwrap();
+        let frame = udp_ipv4(source, destination, &[1, 2, 3]);
+
+        let mut bytes = Vec::new();
+        // Section header block.
+        bytes.extend(PCAPNG_SECTION_HEADER.to_le_bytes().iter());
+        bytes.extend(28u32.to_le_bytes().iter());
+        bytes.extend(PCAPNG_BYTE_ORDER_MAGIC.to_le_bytes().iter());
+        bytes.extend(&[0x01, 0x00, 0x00, 0x00]);
+        bytes.extend(&[0xff; 8]);
+        bytes.extend(28u32.to_le_bytes().iter());
+        // Interface description block with nanosecond timestamps.
+        bytes.extend(PCAPNG_INTERFACE_DESCRIPTION.to_le_bytes().iter());
+        bytes.extend(32u32.to_le_bytes().iter());
+        bytes.extend((LINKTYPE_RAW as u16).to_le_bytes().iter());
+        bytes.extend(&[0x00, 0x00]);
+        bytes.extend(0u32.to_le_bytes().iter());
+        bytes.extend(&[0x09, 0x00, 0x01, 0x00, 0x09, 0x00, 0x00, 0x00]);
+        bytes.extend(&[0x00; 4]);
+        bytes.extend(32u32.to_le_bytes().iter());
+        // Enhanced packet block.
+        let padded_length = frame.len().div_ceil(4) * 4;
+        let block_length = (32 + padded_length) as u32;
+        let timestamp: u64 = 1_600_000_000_123_456_789;
+        bytes.extend(PCAPNG_ENHANCED_PACKET.to_le_bytes().iter());
+        bytes.extend(block_length.to_le_bytes().iter());
+        bytes.extend(0u32.to_le_bytes().iter());
+        bytes.extend(((timestamp >> 32) as u32).to_le_bytes().iter());
+        bytes.extend((timestamp as u32).to_le_bytes().iter());
+        bytes.extend((frame.len() as u32).to_le_bytes().iter());
+        bytes.extend((frame.len() as u32).to_le_bytes().iter());
+        bytes.extend(&frame);
+        bytes.resize(bytes.len() + padded_length - frame.len(), 0);
+        bytes.extend(block_length.to_le_bytes().iter());
+
+        let datagrams: Vec<Datagram> = PcapReader::new(&bytes[..])
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+
+        assert_eq!(
+            datagrams,
+            vec![Datagram {
+                timestamp: UNIX_EPOCH + Duration::from_nanos(timestamp),
+                source,
+                destination,
+                payload: vec![1, 2, 3],
+            }]
+        );
+    }
+
+    #[test]
+    fn skips_non_udp() {
+        let mut ip_packet = udp_ipv4(
+            "10.0.0.1:1".parse().unwrap(),
+            "10.0.0.2:2".parse().unwrap(),
+            &[],
+        );
+        // TCP
+        ip_packet[9] = 6;
+        assert_eq!(
+            parse_frame(LINKTYPE_RAW, UNIX_EPOCH, &ip_packet),
+            Option::None
+        );
+        assert_eq!(
+            parse_frame(LINKTYPE_ETHERNET, UNIX_EPOCH, &[0u8; 10]),
+            Option::None
+        );
+    }
+
+    #[test]
+    fn write_and_read() {
+        let datagrams = vec![
+            Datagram {
+                timestamp: UNIX_EPOCH + Duration::from_micros(1_600_000_000_000_001),
+                source: "192.168.1.10:51234".parse().unwrap(),
+                destination: "255.255.255.255:56700".parse().unwrap(),
+                payload: vec![0x24, 0x00, 0x00, 0x34],
+            },
+            Datagram {
+                timestamp: UNIX_EPOCH + Duration::from_secs(1_600_000_001),
+                source: "[fe80::1]:56700".parse().unwrap(),
+                destination: "[fe80::2]:51234".parse().unwrap(),
+                payload: vec![0xff; 100],
+            },
+        ];
+
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        for datagram in datagrams.iter() {
+            writer.write(datagram).unwrap();
+        }
+        let bytes = writer.into_inner();
+
+        let read: Vec<Datagram> = PcapReader::new(&bytes[..])
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+        assert_eq!(read, datagrams);
+    }
+
+    #[test]
+    fn ipv4_header_checksum() {
+        let datagram = Datagram {
+            timestamp: UNIX_EPOCH,
+            source: "192.168.1.10:51234".parse().unwrap(),
+            destination: "192.168.1.20:56700".parse().unwrap(),
+            payload: vec![0; 36],
+        };
+        let packet = ip_packet(&datagram).unwrap();
+        // The checksum of a header including its checksum is zero.
+        assert_eq!(ipv4_checksum(&packet[..20]), 0);
+    }
+
+    #[test]
+    fn timestamp_out_of_range() {
+        assert_eq!(
+            to_system_time(1_600_000_000, 1).unwrap(),
+            UNIX_EPOCH + Duration::from_secs(1_600_000_000)
+        );
+        let error = to_system_time(u64::MAX, 1).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn invalid_header() {
+        assert!(PcapReader::new(&[0u8; 24][..]).is_err());
+    }
+}
@@ -0,0 +1,171 @@
+use super::protocol::{header::MacAddress, packet::Packet};
+use std::collections::HashMap;
+
+// Replies are dispatched to waiting requests by source and sequence.
+type Key = (u32, u8);
+
+/// Requests waiting for replies, each with a sender for its replies.
+///
+/// Each waiting request has its own source and sequence, so a reply can only reach the request it
+/// answers. A request whose sequence is already in use is given the next free sequence instead.
+/// Replies from a device are only given to a request sent to that device.
+pub(crate) struct PendingReplies<S> {
+    waiting: HashMap<Key, Waiting<S>>,
+    next_id: u64,
+}
+
+struct Waiting<S> {
+    id: u64,
+    // The device the request was sent to, unless it was sent to every device.
+    target: Option<MacAddress>,
+    sender: S,
+}
+
+/// Identifies a waiting request, so it can be removed without removing any other request.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) struct Ticket {
+    key: Key,
+    id: u64,
+}
+
+impl<S> PendingReplies<S> {
+    pub(crate) fn new() -> PendingReplies<S> {
+        PendingReplies {
+            waiting: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Start waiting for replies to `packet`, and return the packet to send, whose sequence may
+    /// have changed. Returns the packet unchanged in `Err` if every sequence is in use for its
+    /// source.
+    pub(crate) fn insert(&mut self, packet: Packet, sender: S) -> Result<(Ticket, Packet), Packet> {
+        let source = packet.frame().source;
+        let first = packet.frame_address().sequence;
+        let sequence = match (0..=u8::MAX)
+            .map(|offset| first.wrapping_add(offset))
+            .find(|sequence| !self.waiting.contains_key(&(source, *sequence)))
+        {
+            Some(sequence) => sequence,
+            None => return Result::Err(packet),
+        };
+
+        let target = packet.frame_address().target;
+        let target = if packet.frame().tagged || target.address == [0u8; 6] {
+            Option::None
+        } else {
+            Option::Some(target)
+        };
+        let ticket = Ticket {
+            key: (source, sequence),
+            id: self.next_id,
+        };
+        self.next_id += 1;
+        self.waiting.insert(
+            ticket.key,
+            Waiting {
+                id: ticket.id,
+                target,
+                sender,
+            },
+        );
+        Result::Ok((ticket, packet.with_sequence(sequence)))
+    }
+
+    /// Stop waiting for replies. Does nothing if the request is no longer waiting.
+    pub(crate) fn remove(&mut self, ticket: &Ticket) {
+        if let Some(waiting) = self.waiting.get(&ticket.key) {
+            if waiting.id == ticket.id {
+                self.waiting.remove(&ticket.key);
+            }
+        }
+    }
+
+    /// Return the sender for the request which `reply` answers, if any. A reply which is not
+    /// tagged must come from the device the request was sent to.
+    pub(crate) fn get(&self, reply: &Packet) -> Option<&S> {
+        let key = (reply.frame().source, reply.frame_address().sequence);
+        let waiting = self.waiting.get(&key)?;
+        match waiting.target {
+            Some(target) if !reply.frame().tagged && reply.frame_address().target != target => {
+                Option::None
+            }
+            _ => Option::Some(&waiting.sender),
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.waiting.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{header::DeviceMessageType, packet::PacketBuilder};
+    use crate::testing::mac;
+
+    fn request(target: MacAddress, sequence: u8) -> Packet {
+        PacketBuilder::with_empty_device_message(DeviceMessageType::GetLabel)
+            .source(42)
+            .target(target)
+            .sequence(sequence)
+            .build()
+    }
+
+    fn reply(target: MacAddress, sequence: u8) -> Packet {
+        PacketBuilder::with_empty_device_message(DeviceMessageType::StateLabel)
+            .source(42)
+            .target(target)
+            .sequence(sequence)
+            .build()
+    }
+
+    #[test]
+    fn skip_sequences_in_use() {
+        let mut pending = PendingReplies::new();
+        let (_, first) = pending.insert(request(mac(1), 7), 1).unwrap();
+        let (_, second) = pending.insert(request(mac(2), 7), 2).unwrap();
+        assert_eq!(first.frame_address().sequence, 7);
+        assert_eq!(second.frame_address().sequence, 8);
+        assert_eq!(pending.get(&reply(mac(1), 7)), Option::Some(&1));
+        assert_eq!(pending.get(&reply(mac(2), 8)), Option::Some(&2));
+
+        // Every sequence wraps around, until none are left.
+        for n in 2..=u8::MAX {
+            assert!(pending.insert(request(mac(1), 7), n as i32).is_ok());
+        }
+        assert!(pending.insert(request(mac(1), 7), 0).is_err());
+    }
+
+    #[test]
+    fn remove_only_own_request() {
+        let mut pending = PendingReplies::new();
+        let (ticket, _) = pending.insert(request(mac(1), 0), 1).unwrap();
+        pending.remove(&ticket);
+        let (other, _) = pending.insert(request(mac(1), 0), 2).unwrap();
+
+        // Removing a request twice does not remove the request which replaced it.
+        pending.remove(&ticket);
+        assert_eq!(pending.get(&reply(mac(1), 0)), Option::Some(&2));
+        pending.remove(&other);
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn ignore_replies_from_other_devices() {
+        let mut pending = PendingReplies::new();
+        pending.insert(request(mac(1), 0), 1).unwrap();
+        assert_eq!(pending.get(&reply(mac(2), 0)), Option::None);
+        assert_eq!(pending.get(&reply(mac(1), 0)), Option::Some(&1));
+
+        // Requests to every device accept replies from any device.
+        let broadcast = PacketBuilder::with_empty_device_message(DeviceMessageType::GetService)
+            .source(42)
+            .sequence(1)
+            .build();
+        pending.insert(broadcast, 2).unwrap();
+        assert_eq!(pending.get(&reply(mac(2), 1)), Option::Some(&2));
+    }
+}
@@ -1,18 +1,23 @@
-use std::{convert::TryInto, fmt, str::FromStr};
+use serde::{Serialize, Serializer};
+use std::{
+    convert::{TryFrom, TryInto},
+    fmt,
+    str::FromStr,
+};
 
 // A LIFX packet header frame.
-#[derive(Debug, Copy, Clone)]
-pub(crate) struct Frame {
-    pub(crate) size: u16,
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Frame {
+    pub size: u16,
     // protocol - 12 bits, must be 1024 == 0x400
-    pub(crate) addressable: bool,
-    pub(crate) tagged: bool,
+    pub addressable: bool,
+    pub tagged: bool,
     // origin - 2 bits - must be 0
-    pub(crate) source: u32,
+    pub source: u32,
 }
 
 impl Frame {
-    pub(crate) fn as_bytes(&self) -> Vec<u8> {
+    pub fn as_bytes(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
         bytes.extend(self.size.to_le_bytes().iter());
         let mut protocol = 1024u16;
@@ -24,47 +29,49 @@ impl Frame {
     }
 }
 
-impl From<&[u8]> for Frame {
-    fn from(bytes: &[u8]) -> Self {
+impl TryFrom<&[u8]> for Frame {
+    type Error = String;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
         if bytes.len() != 8 {
-            panic!();
+            return Result::Err(format!("Frame must be 8 bytes, found {}.", bytes.len()));
         }
         let size = u16::from_le_bytes(bytes[0..2].try_into().expect(""));
         let protocol = u16::from_le_bytes(bytes[2..4].try_into().expect(""));
         if (protocol & 0x0fff) != 1024 {
-            panic!();
+            return Result::Err(format!("Unknown protocol: {}.", protocol & 0x0fff));
         }
         if (protocol & 0b1100_0000_0000_0000) != 0 {
-            panic!("{}", protocol);
+            return Result::Err(format!("Origin must be zero: {:#06x}.", protocol));
         }
         let addressable = (protocol & 0b0001_0000_0000_0000) != 0;
         let tagged = (protocol & 0b0010_0000_0000_0000) != 0;
         let source = u32::from_le_bytes(bytes[4..8].try_into().expect(""));
 
-        Frame {
+        Result::Ok(Frame {
             size,
             addressable,
             tagged,
             source,
-        }
+        })
     }
 }
 
 // A LIFX packet header frame address.
-#[derive(Debug, Copy, Clone)]
-pub(crate) struct FrameAddress {
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct FrameAddress {
     // target consists of a 6 byte MAC address, plus two 0 bytes.
     // `MacAddress.as_bytes()` returns only 6 bytes.
-    pub(crate) target: MacAddress,
+    pub target: MacAddress,
     // reserved - 48 bits, must all be zero
-    pub(crate) res_required: bool,
-    pub(crate) ack_required: bool,
+    pub res_required: bool,
+    pub ack_required: bool,
     // reserved - 6 bits
-    pub(crate) sequence: u8,
+    pub sequence: u8,
 }
 
 impl FrameAddress {
-    pub(crate) fn as_bytes(&self) -> Vec<u8> {
+    pub fn as_bytes(&self) -> Vec<u8> {
         let mut bytes: Vec<u8> = self.target.as_bytes();
         // Combine two 0 bytes from `target` with following reserved 48 bits (6 bytes).
         bytes.extend([0u8; 8].iter());
@@ -74,13 +81,18 @@ impl FrameAddress {
     }
 }
 
-impl From<&[u8]> for FrameAddress {
-    fn from(bytes: &[u8]) -> Self {
+impl TryFrom<&[u8]> for FrameAddress {
+    type Error = String;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
         if bytes.len() != 16 {
-            panic!();
+            return Result::Err(format!(
+                "Frame address must be 16 bytes, found {}.",
+                bytes.len()
+            ));
         }
 
-        let target = MacAddress::from(&bytes[0..6]);
+        let target = MacAddress::try_from(&bytes[0..6])?;
         // Bytes 6-7 are last two bytes of target; should be zero.
         // Bytes 8-13 are reserved; must all be zero.
         let res_ack = bytes[14];
@@ -88,19 +100,19 @@ impl From<&[u8]> for FrameAddress {
         let ack_required = (res_ack & 0b0000_0010) != 0;
         let sequence = bytes[15];
 
-        FrameAddress {
+        Result::Ok(FrameAddress {
             target,
             res_required,
             ack_required,
             sequence,
-        }
+        })
     }
 }
 
 /// A device MAC address.
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
-pub(crate) struct MacAddress {
-    pub(crate) address: [u8; 6],
+pub struct MacAddress {
+    pub address: [u8; 6],
 }
 
 impl fmt::Display for MacAddress {
@@ -118,6 +130,12 @@ impl fmt::Display for MacAddress {
     }
 }
 
+impl Serialize for MacAddress {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
 impl FromStr for MacAddress {
     type Err = String;
 
@@ -128,7 +146,7 @@ impl FromStr for MacAddress {
             if n >= 6 {
                 return Result::Err(format!("Could not parse MAC address: {}.", s));
             }
-            address[n] = u8::from_str_radix(&byte, 16)
+            address[n] = u8::from_str_radix(byte, 16)
                 .map_err(|_| format!("Could not parse MAC Address: {}.", s))?;
         }
 
@@ -137,49 +155,71 @@ impl FromStr for MacAddress {
 }
 
 impl MacAddress {
-    pub(crate) fn as_bytes(&self) -> Vec<u8> {
+    pub fn as_bytes(&self) -> Vec<u8> {
         self.address.to_vec()
     }
 }
 
-impl From<&[u8]> for MacAddress {
-    fn from(bytes: &[u8]) -> Self {
+impl TryFrom<&[u8]> for MacAddress {
+    type Error = String;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
         if bytes.len() != 6 {
-            panic!();
+            return Result::Err(format!(
+                "MAC address must be 6 bytes, found {}.",
+                bytes.len()
+            ));
         }
-        MacAddress {
+        Result::Ok(MacAddress {
             address: bytes[0..6].try_into().expect(""),
-        }
+        })
     }
 }
 
 /// A LIFX packet protocol header.
-#[derive(Debug)]
-pub(crate) struct ProtocolHeader {
-    pub(crate) message_type: MessageType,
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ProtocolHeader {
+    pub message_type: MessageType,
 }
 
-impl From<&[u8]> for ProtocolHeader {
-    fn from(bytes: &[u8]) -> Self {
+impl ProtocolHeader {
+    pub fn as_bytes(&self) -> Vec<u8> {
+        // 8 bytes reserved, 2 byte type, 2 bytes reserved
+        let mut bytes = vec![0u8; 8];
+        bytes.extend(self.message_type.value().to_le_bytes().iter());
+        bytes.extend([0u8; 2].iter());
+        bytes
+    }
+}
+
+impl TryFrom<&[u8]> for ProtocolHeader {
+    type Error = String;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
         if bytes.len() != 12 {
-            panic!();
+            return Result::Err(format!(
+                "Protocol header must be 12 bytes, found {}.",
+                bytes.len()
+            ));
         }
 
         let message_value = u16::from_le_bytes(bytes[8..10].try_into().unwrap());
-        ProtocolHeader {
+        Result::Ok(ProtocolHeader {
             message_type: MessageType::from_value(message_value),
-        }
+        })
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-pub(crate) enum MessageType {
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum MessageType {
     Device(DeviceMessageType),
     Light(LightMessageType),
+    // A message type which is not (yet) known to this library.
+    Unknown(u16),
 }
 
 impl MessageType {
-    pub(crate) fn from_value(value: u16) -> Self {
+    pub fn from_value(value: u16) -> Self {
         match value {
             2 => MessageType::Device(DeviceMessageType::GetService),
             3 => MessageType::Device(DeviceMessageType::StateService),
@@ -208,7 +248,8 @@ impl MessageType {
             51 => MessageType::Device(DeviceMessageType::GetGroup),
             52 => MessageType::Device(DeviceMessageType::SetGroup),
             53 => MessageType::Device(DeviceMessageType::StateGroup),
-            59 => MessageType::Device(DeviceMessageType::EchoRequest),
+            58 => MessageType::Device(DeviceMessageType::EchoRequest),
+            59 => MessageType::Device(DeviceMessageType::EchoResponse),
 
             101 => MessageType::Light(LightMessageType::Get),
             102 => MessageType::Light(LightMessageType::SetColor),
@@ -221,11 +262,11 @@ impl MessageType {
             120 => MessageType::Light(LightMessageType::GetInfrared),
             121 => MessageType::Light(LightMessageType::StateInfrared),
             122 => MessageType::Light(LightMessageType::SetInfrared),
-            _ => panic!("Unknown message type: {}", value),
+            _ => MessageType::Unknown(value),
         }
     }
 
-    pub(crate) fn value(&self) -> u16 {
+    pub fn value(&self) -> u16 {
         match self {
             MessageType::Device(device_message_type) => match *device_message_type {
                 DeviceMessageType::GetService => 2,
@@ -255,7 +296,8 @@ impl MessageType {
                 DeviceMessageType::GetGroup => 51,
                 DeviceMessageType::SetGroup => 52,
                 DeviceMessageType::StateGroup => 53,
-                DeviceMessageType::EchoRequest => 59,
+                DeviceMessageType::EchoRequest => 58,
+                DeviceMessageType::EchoResponse => 59,
             },
             MessageType::Light(light_message_type) => match *light_message_type {
                 LightMessageType::Get => 101,
@@ -270,12 +312,23 @@ impl MessageType {
                 LightMessageType::StateInfrared => 121,
                 LightMessageType::SetInfrared => 122,
             },
+            MessageType::Unknown(value) => *value,
         }
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-pub(crate) enum DeviceMessageType {
+impl fmt::Display for MessageType {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MessageType::Device(device_message_type) => write!(fmt, "{:?}", device_message_type),
+            MessageType::Light(light_message_type) => write!(fmt, "{:?}", light_message_type),
+            MessageType::Unknown(value) => write!(fmt, "Unknown({})", value),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum DeviceMessageType {
     GetService,
     StateService,
     GetHostInfo,
@@ -304,10 +357,11 @@ pub(crate) enum DeviceMessageType {
     SetGroup,
     StateGroup,
     EchoRequest,
+    EchoResponse,
 }
 
-#[derive(Debug, Clone, Copy)]
-pub(crate) enum LightMessageType {
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum LightMessageType {
     Get,
     SetColor,
     SetWaveform,
@@ -320,3 +374,184 @@ pub(crate) enum LightMessageType {
     StateInfrared,
     SetInfrared,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::strategies;
+    use super::*;
+    use proptest::prelude::*;
+
+    // Message type values from the LIFX LAN protocol documentation.
+    #[rustfmt::skip]
+    const MESSAGE_TYPES: [(MessageType, u16); 40] = [
+        (MessageType::Device(DeviceMessageType::GetService), 2),
+        (MessageType::Device(DeviceMessageType::StateService), 3),
+        (MessageType::Device(DeviceMessageType::GetHostInfo), 12),
+        (MessageType::Device(DeviceMessageType::StateHostInfo), 13),
+        (MessageType::Device(DeviceMessageType::GetHostFirmware), 14),
+        (MessageType::Device(DeviceMessageType::StateHostFirmware), 15),
+        (MessageType::Device(DeviceMessageType::GetWifiInfo), 16),
+        (MessageType::Device(DeviceMessageType::StateWifiInfo), 17),
+        (MessageType::Device(DeviceMessageType::GetWifiFirmware), 18),
+        (MessageType::Device(DeviceMessageType::StateWifiFirmware), 19),
+        (MessageType::Device(DeviceMessageType::GetPower), 20),
+        (MessageType::Device(DeviceMessageType::SetPower), 21),
+        (MessageType::Device(DeviceMessageType::StatePower), 22),
+        (MessageType::Device(DeviceMessageType::GetLabel), 23),
+        (MessageType::Device(DeviceMessageType::SetLabel), 24),
+        (MessageType::Device(DeviceMessageType::StateLabel), 25),
+        (MessageType::Device(DeviceMessageType::GetVersion), 32),
+        (MessageType::Device(DeviceMessageType::StateVersion), 33),
+        (MessageType::Device(DeviceMessageType::GetInfo), 34),
+        (MessageType::Device(DeviceMessageType::StateInfo), 35),
+        (MessageType::Device(DeviceMessageType::Acknowledgement), 45),
+        (MessageType::Device(DeviceMessageType::GetLocation), 48),
+        (MessageType::Device(DeviceMessageType::SetLocation), 49),
+        (MessageType::Device(DeviceMessageType::StateLocation), 50),
+        (MessageType::Device(DeviceMessageType::GetGroup), 51),
+        (MessageType::Device(DeviceMessageType::SetGroup), 52),
+        (MessageType::Device(DeviceMessageType::StateGroup), 53),
+        (MessageType::Device(DeviceMessageType::EchoRequest), 58),
+        (MessageType::Device(DeviceMessageType::EchoResponse), 59),
+        (MessageType::Light(LightMessageType::Get), 101),
+        (MessageType::Light(LightMessageType::SetColor), 102),
+        (MessageType::Light(LightMessageType::SetWaveform), 103),
+        (MessageType::Light(LightMessageType::State), 107),
+        (MessageType::Light(LightMessageType::GetPower), 116),
+        (MessageType::Light(LightMessageType::SetPower), 117),
+        (MessageType::Light(LightMessageType::StatePower), 118),
+        (MessageType::Light(LightMessageType::SetWaveformOptional), 119),
+        (MessageType::Light(LightMessageType::GetInfrared), 120),
+        (MessageType::Light(LightMessageType::StateInfrared), 121),
+        (MessageType::Light(LightMessageType::SetInfrared), 122),
+    ];
+
+    #[test]
+    fn message_type_values() {
+        for (message_type, value) in MESSAGE_TYPES.iter() {
+            assert_eq!(message_type.value(), *value);
+            assert_eq!(MessageType::from_value(*value), *message_type);
+        }
+    }
+
+    #[test]
+    fn protocol_header_golden_bytes() {
+        for (message_type, value) in MESSAGE_TYPES.iter() {
+            let [low, high] = value.to_le_bytes();
+            let bytes = [0, 0, 0, 0, 0, 0, 0, 0, low, high, 0, 0];
+            let header = ProtocolHeader {
+                message_type: *message_type,
+            };
+
+            assert_eq!(header.as_bytes(), bytes);
+            assert_eq!(ProtocolHeader::try_from(&bytes[..]), Ok(header));
+        }
+    }
+
+    #[test]
+    fn unknown_message_type() {
+        assert_eq!(MessageType::from_value(506), MessageType::Unknown(506));
+        assert_eq!(MessageType::Unknown(506).value(), 506);
+    }
+
+    #[test]
+    fn frame_golden_bytes() {
+        // Frame for a tagged SetColor message from the LIFX documentation.
+        let bytes = [0x31, 0x00, 0x00, 0x34, 0x00, 0x00, 0x00, 0x00];
+        let frame = Frame {
+            size: 49,
+            addressable: true,
+            tagged: true,
+            source: 0,
+        };
+
+        assert_eq!(frame.as_bytes(), bytes);
+        assert_eq!(Frame::try_from(&bytes[..]), Ok(frame));
+    }
+
+    #[test]
+    fn frame_with_source() {
+        let bytes = [0x24, 0x00, 0x00, 0x14, 0x78, 0x56, 0x34, 0x12];
+        let frame = Frame {
+            size: 36,
+            addressable: true,
+            tagged: false,
+            source: 0x1234_5678,
+        };
+
+        assert_eq!(frame.as_bytes(), bytes);
+        assert_eq!(Frame::try_from(&bytes[..]), Ok(frame));
+    }
+
+    #[test]
+    fn frame_invalid() {
+        // Wrong length.
+        assert!(Frame::try_from(&[0x24, 0x00, 0x00, 0x14][..]).is_err());
+        // Protocol is not 1024.
+        assert!(Frame::try_from(&[0x24, 0x00, 0x01, 0x14, 0x00, 0x00, 0x00, 0x00][..]).is_err());
+        // Origin is not 0.
+        assert!(Frame::try_from(&[0x24, 0x00, 0x00, 0x54, 0x00, 0x00, 0x00, 0x00][..]).is_err());
+    }
+
+    #[test]
+    fn frame_address_golden_bytes() {
+        let bytes = [
+            0xd0, 0x73, 0xd5, 0x01, 0x02, 0x03, 0x00, 0x00, // target
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // reserved
+            0x03, // ack_required, res_required
+            0x2a, // sequence
+        ];
+        let frame_address = FrameAddress {
+            target: MacAddress {
+                address: [0xd0, 0x73, 0xd5, 0x01, 0x02, 0x03],
+            },
+            res_required: true,
+            ack_required: true,
+            sequence: 42,
+        };
+
+        assert_eq!(frame_address.as_bytes(), bytes);
+        assert_eq!(FrameAddress::try_from(&bytes[..]), Ok(frame_address));
+    }
+
+    #[test]
+    fn frame_address_invalid() {
+        assert!(FrameAddress::try_from(&[0u8; 15][..]).is_err());
+        assert!(FrameAddress::try_from(&[0u8; 17][..]).is_err());
+    }
+
+    #[test]
+    fn mac_address_display() {
+        let mac_address = MacAddress {
+            address: [0xd0, 0x73, 0xd5, 0x01, 0x02, 0x0a],
+        };
+        assert_eq!(mac_address.to_string(), "d0:73:d5:01:02:0a");
+    }
+
+    proptest! {
+        #[test]
+        fn frame_round_trip(frame in strategies::frame()) {
+            prop_assert_eq!(Frame::try_from(&frame.as_bytes()[..]), Ok(frame));
+        }
+
+        #[test]
+        fn frame_address_round_trip(frame_address in strategies::frame_address()) {
+            prop_assert_eq!(
+                FrameAddress::try_from(&frame_address.as_bytes()[..]),
+                Ok(frame_address)
+            );
+        }
+
+        #[test]
+        fn protocol_header_round_trip(message_type in strategies::message_type()) {
+            let header = ProtocolHeader { message_type };
+            prop_assert_eq!(ProtocolHeader::try_from(&header.as_bytes()[..]), Ok(header));
+        }
+
+        #[test]
+        fn mac_address_round_trip(mac_address in strategies::mac_address()) {
+            prop_assert_eq!(MacAddress::try_from(&mac_address.as_bytes()[..]), Ok(mac_address));
+            prop_assert_eq!(mac_address.to_string().parse(), Ok(mac_address));
+        }
+    }
+}
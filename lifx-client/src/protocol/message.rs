@@ -1,169 +1,438 @@
 use super::header::{DeviceMessageType, LightMessageType, MessageType};
-use std::convert::TryInto;
+use serde::{Serialize, Serializer};
+use std::{convert::TryInto, fmt};
 
 /// A LIFX packet message.
-#[derive(Debug, Clone)]
-pub(crate) enum Message {
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Message {
     Empty(MessageType),
     Bytes(MessageType, Vec<u8>),
     StateService(StateServicePayload),
+    StatePower(StatePowerPayload),
     StateLabel(StateLabelPayload),
+    StateVersion(StateVersionPayload),
     StateGroup(StateGroupPayload),
     StateLocation(StateLocationPayload),
+    EchoRequest(EchoPayload),
+    EchoResponse(EchoPayload),
     State(StatePayload),
     SetColor(SetColorPayload),
     SetPower(SetPowerPayload),
+    SetWaveformOptional(SetWaveformOptionalPayload),
 }
 
 impl Message {
-    pub(crate) fn from(message_type: MessageType, bytes: &[u8]) -> Message {
+    pub fn from(message_type: MessageType, bytes: &[u8]) -> Result<Message, String> {
         if bytes.is_empty() {
-            return Message::Empty(message_type);
+            return Result::Ok(Message::Empty(message_type));
         }
 
         use DeviceMessageType::*;
         use LightMessageType::*;
         use MessageType::Device;
         use MessageType::Light;
-        match message_type {
-            Device(StateService) => Message::StateService(StateServicePayload::from_bytes(bytes)),
-            Device(StateLabel) => Message::StateLabel(StateLabelPayload::from_bytes(bytes)),
+        let message = match message_type {
+            Device(StateService) => Message::StateService(StateServicePayload::from_bytes(bytes)?),
+            Device(DeviceMessageType::StatePower) => {
+                Message::StatePower(StatePowerPayload::from_bytes(bytes)?)
+            }
+            Device(StateLabel) => Message::StateLabel(StateLabelPayload::from_bytes(bytes)?),
+            Device(StateVersion) => Message::StateVersion(StateVersionPayload::from_bytes(bytes)?),
             Device(StateLocation) => {
-                Message::StateLocation(StateLocationPayload::from_bytes(bytes))
+                Message::StateLocation(StateLocationPayload::from_bytes(bytes)?)
+            }
+            Device(StateGroup) => Message::StateGroup(StateGroupPayload::from_bytes(bytes)?),
+            Device(EchoRequest) => Message::EchoRequest(EchoPayload::from_bytes(bytes)?),
+            Device(EchoResponse) => Message::EchoResponse(EchoPayload::from_bytes(bytes)?),
+            Light(State) => Message::State(StatePayload::from_bytes(bytes)?),
+            Light(SetColor) => Message::SetColor(SetColorPayload::from_bytes(bytes)?),
+            Light(LightMessageType::SetPower) => {
+                Message::SetPower(SetPowerPayload::from_bytes(bytes)?)
+            }
+            Light(SetWaveformOptional) => {
+                Message::SetWaveformOptional(SetWaveformOptionalPayload::from_bytes(bytes)?)
             }
-            Device(StateGroup) => Message::StateGroup(StateGroupPayload::from_bytes(bytes)),
-            Light(State) => Message::State(StatePayload::from_bytes(bytes)),
             _ => Message::Bytes(message_type, bytes.to_vec()),
-        }
+        };
+        Result::Ok(message)
     }
 
-    pub(crate) fn message_type(&self) -> MessageType {
+    pub fn message_type(&self) -> MessageType {
         match self {
             Message::Empty(message_type) => *message_type,
             Message::Bytes(message_type, _) => *message_type,
             Message::StateService(_) => MessageType::Device(DeviceMessageType::StateService),
+            Message::StatePower(_) => MessageType::Device(DeviceMessageType::StatePower),
             Message::StateLabel(_) => MessageType::Device(DeviceMessageType::StateLabel),
+            Message::StateVersion(_) => MessageType::Device(DeviceMessageType::StateVersion),
             Message::StateLocation(_) => MessageType::Device(DeviceMessageType::StateLocation),
             Message::StateGroup(_) => MessageType::Device(DeviceMessageType::StateGroup),
+            Message::EchoRequest(_) => MessageType::Device(DeviceMessageType::EchoRequest),
+            Message::EchoResponse(_) => MessageType::Device(DeviceMessageType::EchoResponse),
             Message::State(_) => MessageType::Light(LightMessageType::State),
             Message::SetColor(_) => MessageType::Light(LightMessageType::SetColor),
             Message::SetPower(_) => MessageType::Light(LightMessageType::SetPower),
+            Message::SetWaveformOptional(_) => {
+                MessageType::Light(LightMessageType::SetWaveformOptional)
+            }
         }
     }
 
-    pub(crate) fn as_bytes(&self) -> Vec<u8> {
+    pub fn as_bytes(&self) -> Vec<u8> {
         match self {
             Message::Empty(_) => Vec::new(),
             Message::Bytes(_, bytes) => bytes.clone(),
+            Message::StateService(state_service_payload) => state_service_payload.as_bytes(),
+            Message::StatePower(state_power_payload) => state_power_payload.as_bytes(),
+            Message::StateLabel(state_label_payload) => state_label_payload.as_bytes(),
+            Message::StateVersion(state_version_payload) => state_version_payload.as_bytes(),
+            Message::StateLocation(state_location_payload) => state_location_payload.as_bytes(),
+            Message::StateGroup(state_group_payload) => state_group_payload.as_bytes(),
+            Message::EchoRequest(echo_payload) => echo_payload.as_bytes(),
+            Message::EchoResponse(echo_payload) => echo_payload.as_bytes(),
+            Message::State(state_payload) => state_payload.as_bytes(),
             Message::SetPower(set_power_payload) => set_power_payload.as_bytes(),
             Message::SetColor(set_color_payload) => set_color_payload.as_bytes(),
-            _ => panic!("Unrecognized message"),
+            Message::SetWaveformOptional(set_waveform_optional_payload) => {
+                set_waveform_optional_payload.as_bytes()
+            }
         }
     }
 }
 
-/// A payload sent by a client. Can be converted to bytes.
-trait ClientPayload {
+/// A message payload. Can be converted to and from bytes.
+trait Payload: Sized {
     fn as_bytes(&self) -> Vec<u8>;
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, String>;
 }
 
-/// A payload sent by a device. Can be created from bytes.
-trait DevicePayload {
-    fn from_bytes(bytes: &[u8]) -> Self;
+/// Return an error if `bytes` is not exactly `expected` bytes long.
+fn check_length(name: &str, bytes: &[u8], expected: usize) -> Result<(), String> {
+    if bytes.len() != expected {
+        Result::Err(format!(
+            "{} payload must be {} bytes, found {}.",
+            name,
+            expected,
+            bytes.len()
+        ))
+    } else {
+        Result::Ok(())
+    }
+}
+
+/// Convert a string to a fixed size, null padded label. Labels longer than 32 bytes are truncated.
+fn label_as_bytes(label: &str) -> Vec<u8> {
+    let mut end = usize::min(label.len(), 32);
+    while !label.is_char_boundary(end) {
+        end -= 1;
+    }
+    let mut bytes = label.as_bytes()[..end].to_vec();
+    bytes.resize(32, 0);
+    bytes
+}
+
+/// Read a null padded label, ignoring anything after the first null byte.
+fn label_from_bytes(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+// Serialize a byte array which is too large to derive `Serialize`.
+fn serialize_bytes<S: Serializer>(bytes: &[u8; 64], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(bytes.iter())
+}
+
+// Serialize a float which is stored as its bits, so that payloads can be compared exactly.
+fn serialize_f32_bits<S: Serializer>(bits: &u32, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f32(f32::from_bits(*bits))
 }
 
 /// The payload for a StateService message.
-#[derive(Debug, Copy, Clone)]
-pub(crate) struct StateServicePayload {
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
+pub struct StateServicePayload {
     service: u8,
     port: u16,
 }
 
 impl StateServicePayload {
-    pub(crate) fn port(&self) -> u16 {
+    pub fn new(service: u8, port: u16) -> StateServicePayload {
+        StateServicePayload { service, port }
+    }
+
+    pub fn service(&self) -> u8 {
+        self.service
+    }
+
+    pub fn port(&self) -> u16 {
         self.port
     }
 }
 
-impl DevicePayload for StateServicePayload {
-    fn from_bytes(bytes: &[u8]) -> Self {
-        if bytes.len() != 5 {
-            panic!("{:?}", bytes);
-        }
+impl Payload for StateServicePayload {
+    fn as_bytes(&self) -> Vec<u8> {
+        let mut result = vec![self.service];
+        result.extend((self.port as u32).to_le_bytes().iter());
+        result
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        check_length("StateService", bytes, 5)?;
 
         let service = bytes[0];
         let port = u32::from_le_bytes(bytes[1..5].try_into().unwrap());
 
         if port > u16::MAX as u32 {
-            panic!("Unknown port: {}", port);
+            return Result::Err(format!("Unknown port: {}", port));
         }
-        StateServicePayload {
+        Result::Ok(StateServicePayload {
             service,
             port: port as u16,
-        }
+        })
+    }
+}
+
+/// The payload for a StatePower message.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
+pub struct StatePowerPayload {
+    power: Power,
+}
+
+impl StatePowerPayload {
+    pub fn new(power: Power) -> StatePowerPayload {
+        StatePowerPayload { power }
+    }
+
+    pub fn power(&self) -> Power {
+        self.power
+    }
+}
+
+impl Payload for StatePowerPayload {
+    fn as_bytes(&self) -> Vec<u8> {
+        self.power.level().to_le_bytes().to_vec()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        check_length("StatePower", bytes, 2)?;
+
+        Result::Ok(StatePowerPayload {
+            power: Power::from_level(u16::from_le_bytes(bytes[0..2].try_into().unwrap())),
+        })
     }
 }
 
 /// The payload for a StateLabel message.
-#[derive(Debug, Clone)]
-pub(crate) struct StateLabelPayload {
-    pub(crate) label: String,
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct StateLabelPayload {
+    label: String,
 }
 
-impl DevicePayload for StateLabelPayload {
-    fn from_bytes(bytes: &[u8]) -> Self {
-        StateLabelPayload {
-            label: String::from_utf8(bytes.to_vec()).unwrap(),
-        }
+impl StateLabelPayload {
+    pub fn new(label: String) -> StateLabelPayload {
+        StateLabelPayload { label }
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+}
+
+impl Payload for StateLabelPayload {
+    fn as_bytes(&self) -> Vec<u8> {
+        label_as_bytes(&self.label)
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        check_length("StateLabel", bytes, 32)?;
+
+        Result::Ok(StateLabelPayload {
+            label: label_from_bytes(bytes),
+        })
+    }
+}
+
+/// The payload for a StateVersion message.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
+pub struct StateVersionPayload {
+    vendor: u32,
+    product: u32,
+}
+
+impl StateVersionPayload {
+    pub fn new(vendor: u32, product: u32) -> StateVersionPayload {
+        StateVersionPayload { vendor, product }
+    }
+
+    pub fn vendor(&self) -> u32 {
+        self.vendor
+    }
+
+    pub fn product(&self) -> u32 {
+        self.product
+    }
+}
+
+impl Payload for StateVersionPayload {
+    fn as_bytes(&self) -> Vec<u8> {
+        // vendor, product, 4 bytes reserved
+        let mut result = Vec::new();
+        result.extend(self.vendor.to_le_bytes().iter());
+        result.extend(self.product.to_le_bytes().iter());
+        result.extend([0u8; 4].iter());
+        result
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        check_length("StateVersion", bytes, 12)?;
+
+        Result::Ok(StateVersionPayload {
+            vendor: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+            product: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+        })
     }
 }
 
 /// The payload for a StateLocation message.
-#[derive(Debug, Clone)]
-pub(crate) struct StateLocationPayload {
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct StateLocationPayload {
     location: [u8; 16],
-    pub(crate) label: String,
+    label: String,
     updated_at: u64,
 }
 
-impl DevicePayload for StateLocationPayload {
-    fn from_bytes(bytes: &[u8]) -> Self {
+impl StateLocationPayload {
+    pub fn new(location: [u8; 16], label: String, updated_at: u64) -> StateLocationPayload {
+        StateLocationPayload {
+            location,
+            label,
+            updated_at,
+        }
+    }
+
+    pub fn location(&self) -> [u8; 16] {
+        self.location
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    pub fn updated_at(&self) -> u64 {
+        self.updated_at
+    }
+}
+
+impl Payload for StateLocationPayload {
+    fn as_bytes(&self) -> Vec<u8> {
+        let mut result = self.location.to_vec();
+        result.extend(label_as_bytes(&self.label));
+        result.extend(self.updated_at.to_le_bytes().iter());
+        result
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        check_length("StateLocation", bytes, 56)?;
+
         let location = bytes[0..16].try_into().expect("");
-        let label = String::from_utf8(bytes[16..48].to_vec()).unwrap();
+        let label = label_from_bytes(&bytes[16..48]);
         let updated_at = u64::from_le_bytes(bytes[48..56].try_into().expect(""));
 
-        StateLocationPayload {
+        Result::Ok(StateLocationPayload {
             location,
             label,
             updated_at,
-        }
+        })
     }
 }
 
 /// The payload for a StateGroup message.
-#[derive(Debug, Clone)]
-pub(crate) struct StateGroupPayload {
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct StateGroupPayload {
     group: [u8; 16],
-    pub(crate) label: String,
+    label: String,
     updated_at: u64,
 }
 
-impl DevicePayload for StateGroupPayload {
-    fn from_bytes(bytes: &[u8]) -> Self {
+impl StateGroupPayload {
+    pub fn new(group: [u8; 16], label: String, updated_at: u64) -> StateGroupPayload {
+        StateGroupPayload {
+            group,
+            label,
+            updated_at,
+        }
+    }
+
+    pub fn group(&self) -> [u8; 16] {
+        self.group
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    pub fn updated_at(&self) -> u64 {
+        self.updated_at
+    }
+}
+
+impl Payload for StateGroupPayload {
+    fn as_bytes(&self) -> Vec<u8> {
+        let mut result = self.group.to_vec();
+        result.extend(label_as_bytes(&self.label));
+        result.extend(self.updated_at.to_le_bytes().iter());
+        result
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        check_length("StateGroup", bytes, 56)?;
+
         let group = bytes[0..16].try_into().expect("");
-        let label = String::from_utf8(bytes[16..48].to_vec()).unwrap();
+        let label = label_from_bytes(&bytes[16..48]);
         let updated_at = u64::from_le_bytes(bytes[48..56].try_into().expect(""));
 
-        StateGroupPayload {
+        Result::Ok(StateGroupPayload {
             group,
             label,
             updated_at,
-        }
+        })
+    }
+}
+
+/// The payload for EchoRequest and EchoResponse messages. A device responds to an EchoRequest
+/// with an EchoResponse containing the same payload.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
+pub struct EchoPayload {
+    #[serde(serialize_with = "serialize_bytes")]
+    payload: [u8; 64],
+}
+
+impl EchoPayload {
+    pub fn new(payload: [u8; 64]) -> EchoPayload {
+        EchoPayload { payload }
+    }
+
+    pub fn payload(&self) -> [u8; 64] {
+        self.payload
+    }
+}
+
+impl Payload for EchoPayload {
+    fn as_bytes(&self) -> Vec<u8> {
+        self.payload.to_vec()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        check_length("Echo", bytes, 64)?;
+
+        Result::Ok(EchoPayload {
+            payload: bytes.try_into().expect(""),
+        })
     }
 }
 
-#[derive(Debug, Clone)]
-pub(crate) struct Hsbk {
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
+pub struct Hsbk {
     hue: u16,
     saturation: u16,
     brightness: u16,
@@ -171,7 +440,7 @@ pub(crate) struct Hsbk {
 }
 
 impl Hsbk {
-    pub(crate) fn new(hue: u16, saturation: u16, brightness: u16, kelvin: u16) -> Hsbk {
+    pub fn new(hue: u16, saturation: u16, brightness: u16, kelvin: u16) -> Hsbk {
         Hsbk {
             hue,
             saturation,
@@ -180,23 +449,23 @@ impl Hsbk {
         }
     }
 
-    pub(crate) fn hue(&self) -> u16 {
+    pub fn hue(&self) -> u16 {
         self.hue
     }
 
-    pub(crate) fn saturation(&self) -> u16 {
+    pub fn saturation(&self) -> u16 {
         self.saturation
     }
 
-    pub(crate) fn brightness(&self) -> u16 {
+    pub fn brightness(&self) -> u16 {
         self.brightness
     }
 
-    pub(crate) fn kelvin(&self) -> u16 {
+    pub fn kelvin(&self) -> u16 {
         self.kelvin
     }
 
-    pub(crate) fn with_hue(&self, hue: u16) -> Hsbk {
+    pub fn with_hue(&self, hue: u16) -> Hsbk {
         Hsbk {
             hue,
             saturation: self.saturation,
@@ -205,7 +474,7 @@ impl Hsbk {
         }
     }
 
-    pub(crate) fn with_saturation(&self, saturation: u16) -> Hsbk {
+    pub fn with_saturation(&self, saturation: u16) -> Hsbk {
         Hsbk {
             hue: self.hue,
             saturation,
@@ -214,7 +483,7 @@ impl Hsbk {
         }
     }
 
-    pub(crate) fn with_brightness(&self, brightness: u16) -> Hsbk {
+    pub fn with_brightness(&self, brightness: u16) -> Hsbk {
         Hsbk {
             hue: self.hue,
             saturation: self.saturation,
@@ -223,7 +492,7 @@ impl Hsbk {
         }
     }
 
-    pub(crate) fn with_kelvin(&self, kelvin: u16) -> Hsbk {
+    pub fn with_kelvin(&self, kelvin: u16) -> Hsbk {
         Hsbk {
             hue: self.hue,
             saturation: self.saturation,
@@ -233,7 +502,7 @@ impl Hsbk {
     }
 }
 
-impl ClientPayload for Hsbk {
+impl Payload for Hsbk {
     fn as_bytes(&self) -> Vec<u8> {
         let mut result = Vec::new();
         result.extend(self.hue.to_le_bytes().iter());
@@ -242,26 +511,22 @@ impl ClientPayload for Hsbk {
         result.extend(self.kelvin.to_le_bytes().iter());
         result
     }
-}
 
-impl DevicePayload for Hsbk {
-    fn from_bytes(bytes: &[u8]) -> Self {
-        if bytes.len() != 8 {
-            panic!();
-        }
+    fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        check_length("HSBK", bytes, 8)?;
 
-        Hsbk {
+        Result::Ok(Hsbk {
             hue: u16::from_le_bytes(bytes[0..2].try_into().unwrap()),
             saturation: u16::from_le_bytes(bytes[2..4].try_into().unwrap()),
             brightness: u16::from_le_bytes(bytes[4..6].try_into().unwrap()),
             kelvin: u16::from_le_bytes(bytes[6..8].try_into().unwrap()),
-        }
+        })
     }
 }
 
 #[rustfmt::skip]
-#[derive(Debug, Clone)]
-pub(crate) struct StatePayload {
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct StatePayload {
     color: Hsbk,
     // reserved - 16 bits
     power: u16,
@@ -270,46 +535,70 @@ pub(crate) struct StatePayload {
 }
 
 impl StatePayload {
-    pub(crate) fn color(&self) -> Hsbk {
-        self.color.clone()
+    pub fn new(color: Hsbk, power: Power, label: String) -> StatePayload {
+        StatePayload {
+            color,
+            power: power.level(),
+            label,
+        }
     }
 
-    pub(crate) fn power(&self) -> Power {
-        match self.power {
-            0 => Power::Off,
-            n => Power::On(n),
-        }
+    pub fn color(&self) -> Hsbk {
+        self.color
+    }
+
+    pub fn power(&self) -> Power {
+        Power::from_level(self.power)
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
     }
 }
 
-impl DevicePayload for StatePayload {
-    fn from_bytes(bytes: &[u8]) -> Self {
-        if bytes.len() != 52 {
-            panic!();
-        }
+impl Payload for StatePayload {
+    fn as_bytes(&self) -> Vec<u8> {
+        let mut result = self.color.as_bytes();
+        result.extend([0u8; 2].iter()); // reserved - 16 bits
+        result.extend(self.power.to_le_bytes().iter());
+        result.extend(label_as_bytes(&self.label));
+        result.extend([0u8; 8].iter()); // reserved - 64 bits
+        result
+    }
 
-        StatePayload {
-            color: Hsbk::from_bytes(&bytes[0..8]),
+    fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        check_length("State", bytes, 52)?;
+
+        Result::Ok(StatePayload {
+            color: Hsbk::from_bytes(&bytes[0..8])?,
             power: u16::from_le_bytes(bytes[10..12].try_into().unwrap()),
-            label: String::from_utf8(bytes[12..44].to_vec()).unwrap(),
-        }
+            label: label_from_bytes(&bytes[12..44]),
+        })
     }
 }
 
-#[derive(Debug, Clone)]
-pub(crate) struct SetColorPayload {
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct SetColorPayload {
     // reserved - 8 bits
     color: Hsbk,
     duration: u32,
 }
 
 impl SetColorPayload {
-    pub(crate) fn new(color: Hsbk, duration: u32) -> SetColorPayload {
+    pub fn new(color: Hsbk, duration: u32) -> SetColorPayload {
         SetColorPayload { color, duration }
     }
+
+    pub fn color(&self) -> Hsbk {
+        self.color
+    }
+
+    pub fn duration(&self) -> u32 {
+        self.duration
+    }
 }
 
-impl ClientPayload for SetColorPayload {
+impl Payload for SetColorPayload {
     fn as_bytes(&self) -> Vec<u8> {
         let mut result = Vec::new();
         result.push(0); // reserved - 8 bits
@@ -317,10 +606,19 @@ impl ClientPayload for SetColorPayload {
         result.extend(self.duration.to_le_bytes().iter());
         result
     }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        check_length("SetColor", bytes, 13)?;
+
+        Result::Ok(SetColorPayload {
+            color: Hsbk::from_bytes(&bytes[1..9])?,
+            duration: u32::from_le_bytes(bytes[9..13].try_into().unwrap()),
+        })
+    }
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
-pub(crate) enum Power {
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
+pub enum Power {
     // Officially the LIFX docs state that only 0 and 0xffff are valid values.
     // However, devices will sometimes responsd with different values, which
     // seem to indicate that they are powered on.
@@ -328,27 +626,579 @@ pub(crate) enum Power {
     On(u16),
 }
 
-#[derive(Debug, Clone)]
-pub(crate) struct SetPowerPayload {
+impl Power {
+    pub fn from_level(level: u16) -> Power {
+        match level {
+            0 => Power::Off,
+            n => Power::On(n),
+        }
+    }
+
+    pub fn level(&self) -> u16 {
+        match self {
+            Power::Off => u16::MIN,
+            Power::On(n) => *n,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct SetPowerPayload {
     power: Power,
     duration: u32,
 }
 
 impl SetPowerPayload {
-    pub(crate) fn new(power: Power, duration: u32) -> SetPowerPayload {
+    pub fn new(power: Power, duration: u32) -> SetPowerPayload {
         SetPowerPayload { power, duration }
     }
+
+    pub fn power(&self) -> Power {
+        self.power
+    }
+
+    pub fn duration(&self) -> u32 {
+        self.duration
+    }
 }
 
-impl ClientPayload for SetPowerPayload {
+impl Payload for SetPowerPayload {
     fn as_bytes(&self) -> Vec<u8> {
         let mut result = Vec::new();
-        let level = match self.power {
-            Power::Off => u16::MIN,
-            Power::On(n) => n,
-        };
-        result.extend(level.to_le_bytes().iter());
+        result.extend(self.power.level().to_le_bytes().iter());
         result.extend(self.duration.to_le_bytes().iter());
         result
     }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        check_length("SetPower", bytes, 6)?;
+
+        Result::Ok(SetPowerPayload {
+            power: Power::from_level(u16::from_le_bytes(bytes[0..2].try_into().unwrap())),
+            duration: u32::from_le_bytes(bytes[2..6].try_into().unwrap()),
+        })
+    }
+}
+
+/// The shape of a waveform effect.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
+pub enum Waveform {
+    Saw,
+    Sine,
+    HalfSine,
+    Triangle,
+    Pulse,
+}
+
+impl Waveform {
+    pub fn value(&self) -> u8 {
+        match self {
+            Waveform::Saw => 0,
+            Waveform::Sine => 1,
+            Waveform::HalfSine => 2,
+            Waveform::Triangle => 3,
+            Waveform::Pulse => 4,
+        }
+    }
+
+    pub fn from_value(value: u8) -> Result<Waveform, String> {
+        match value {
+            0 => Result::Ok(Waveform::Saw),
+            1 => Result::Ok(Waveform::Sine),
+            2 => Result::Ok(Waveform::HalfSine),
+            3 => Result::Ok(Waveform::Triangle),
+            4 => Result::Ok(Waveform::Pulse),
+            n => Result::Err(format!("Unknown waveform: {}", n)),
+        }
+    }
+}
+
+/// The payload for a SetWaveformOptional message. Only the components of `color` whose flags are
+/// set are changed, so part of a color can be set without knowing the rest.
+#[derive(Clone, Eq, PartialEq, Serialize)]
+pub struct SetWaveformOptionalPayload {
+    // reserved - 8 bits
+    transient: bool,
+    color: Hsbk,
+    period: u32,
+    #[serde(serialize_with = "serialize_f32_bits")]
+    cycles: u32,
+    skew_ratio: i16,
+    waveform: Waveform,
+    set_hue: bool,
+    set_saturation: bool,
+    set_brightness: bool,
+    set_kelvin: bool,
+}
+
+impl SetWaveformOptionalPayload {
+    /// Create a payload which sets every component of `color`.
+    pub fn new(
+        transient: bool,
+        color: Hsbk,
+        period: u32,
+        cycles: f32,
+        skew_ratio: i16,
+        waveform: Waveform,
+    ) -> SetWaveformOptionalPayload {
+        SetWaveformOptionalPayload {
+            transient,
+            color,
+            period,
+            cycles: cycles.to_bits(),
+            skew_ratio,
+            waveform,
+            set_hue: true,
+            set_saturation: true,
+            set_brightness: true,
+            set_kelvin: true,
+        }
+    }
+
+    /// Set which components of the color are changed.
+    pub fn with_components(
+        self,
+        set_hue: bool,
+        set_saturation: bool,
+        set_brightness: bool,
+        set_kelvin: bool,
+    ) -> SetWaveformOptionalPayload {
+        SetWaveformOptionalPayload {
+            set_hue,
+            set_saturation,
+            set_brightness,
+            set_kelvin,
+            ..self
+        }
+    }
+
+    pub fn transient(&self) -> bool {
+        self.transient
+    }
+
+    pub fn color(&self) -> Hsbk {
+        self.color
+    }
+
+    pub fn period(&self) -> u32 {
+        self.period
+    }
+
+    pub fn cycles(&self) -> f32 {
+        f32::from_bits(self.cycles)
+    }
+
+    pub fn skew_ratio(&self) -> i16 {
+        self.skew_ratio
+    }
+
+    pub fn waveform(&self) -> Waveform {
+        self.waveform
+    }
+
+    pub fn set_hue(&self) -> bool {
+        self.set_hue
+    }
+
+    pub fn set_saturation(&self) -> bool {
+        self.set_saturation
+    }
+
+    pub fn set_brightness(&self) -> bool {
+        self.set_brightness
+    }
+
+    pub fn set_kelvin(&self) -> bool {
+        self.set_kelvin
+    }
+
+    /// Return `current` with the components set by this payload replaced.
+    pub fn apply_to(&self, current: Hsbk) -> Hsbk {
+        let mut color = current;
+        if self.set_hue {
+            color = color.with_hue(self.color.hue);
+        }
+        if self.set_saturation {
+            color = color.with_saturation(self.color.saturation);
+        }
+        if self.set_brightness {
+            color = color.with_brightness(self.color.brightness);
+        }
+        if self.set_kelvin {
+            color = color.with_kelvin(self.color.kelvin);
+        }
+        color
+    }
+}
+
+// Show cycles as a float rather than the bits it is stored as.
+impl fmt::Debug for SetWaveformOptionalPayload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SetWaveformOptionalPayload")
+            .field("transient", &self.transient)
+            .field("color", &self.color)
+            .field("period", &self.period)
+            .field("cycles", &self.cycles())
+            .field("skew_ratio", &self.skew_ratio)
+            .field("waveform", &self.waveform)
+            .field("set_hue", &self.set_hue)
+            .field("set_saturation", &self.set_saturation)
+            .field("set_brightness", &self.set_brightness)
+            .field("set_kelvin", &self.set_kelvin)
+            .finish()
+    }
+}
+
+impl Payload for SetWaveformOptionalPayload {
+    fn as_bytes(&self) -> Vec<u8> {
+        let mut result = Vec::new();
+        result.push(0); // reserved - 8 bits
+        result.push(self.transient as u8);
+        result.extend(self.color.as_bytes());
+        result.extend(self.period.to_le_bytes().iter());
+        result.extend(self.cycles.to_le_bytes().iter());
+        result.extend(self.skew_ratio.to_le_bytes().iter());
+        result.push(self.waveform.value());
+        result.push(self.set_hue as u8);
+        result.push(self.set_saturation as u8);
+        result.push(self.set_brightness as u8);
+        result.push(self.set_kelvin as u8);
+        result
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        check_length("SetWaveformOptional", bytes, 25)?;
+
+        Result::Ok(SetWaveformOptionalPayload {
+            transient: bytes[1] != 0,
+            color: Hsbk::from_bytes(&bytes[2..10])?,
+            period: u32::from_le_bytes(bytes[10..14].try_into().unwrap()),
+            cycles: u32::from_le_bytes(bytes[14..18].try_into().unwrap()),
+            skew_ratio: i16::from_le_bytes(bytes[18..20].try_into().unwrap()),
+            waveform: Waveform::from_value(bytes[20])?,
+            set_hue: bytes[21] != 0,
+            set_saturation: bytes[22] != 0,
+            set_brightness: bytes[23] != 0,
+            set_kelvin: bytes[24] != 0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::strategies;
+    use super::*;
+    use proptest::prelude::*;
+
+    fn padded_label(label: &str) -> Vec<u8> {
+        let mut bytes = label.as_bytes().to_vec();
+        bytes.resize(32, 0);
+        bytes
+    }
+
+    /// Assert that `message` encodes to `bytes` and that `bytes` decode to `message`.
+    fn assert_golden(message: Message, bytes: &[u8]) {
+        assert_eq!(message.as_bytes(), bytes);
+        assert_eq!(Message::from(message.message_type(), bytes), Ok(message));
+    }
+
+    #[test]
+    fn state_service_golden_bytes() {
+        let payload = StateServicePayload::new(1, 56700);
+        assert_golden(
+            Message::StateService(payload),
+            &[0x01, 0x7c, 0xdd, 0x00, 0x00],
+        );
+    }
+
+    #[test]
+    fn state_service_invalid_port() {
+        let bytes = [0x01, 0x00, 0x00, 0x01, 0x00];
+        assert!(StateServicePayload::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn state_power_golden_bytes() {
+        let payload = StatePowerPayload::new(Power::On(0xffff));
+        assert_golden(Message::StatePower(payload), &[0xff, 0xff]);
+
+        let payload = StatePowerPayload::new(Power::Off);
+        assert_golden(Message::StatePower(payload), &[0x00, 0x00]);
+    }
+
+    #[test]
+    fn state_version_golden_bytes() {
+        let payload = StateVersionPayload::new(1, 27);
+        assert_golden(
+            Message::StateVersion(payload),
+            &[
+                0x01, 0x00, 0x00, 0x00, 0x1b, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            ],
+        );
+    }
+
+    #[test]
+    fn state_label_golden_bytes() {
+        let payload = StateLabelPayload::new(String::from("Kitchen"));
+        assert_golden(Message::StateLabel(payload), &padded_label("Kitchen"));
+    }
+
+    #[test]
+    fn state_label_ignores_bytes_after_null() {
+        let mut bytes = padded_label("Kitchen");
+        bytes[10] = b'x';
+        assert_eq!(
+            StateLabelPayload::from_bytes(&bytes).unwrap().label(),
+            "Kitchen"
+        );
+    }
+
+    #[test]
+    fn state_label_truncates_long_labels() {
+        let label = "A label which is longer than thirty-two bytes";
+        let payload = StateLabelPayload::new(String::from(label));
+        assert_eq!(payload.as_bytes(), label.as_bytes()[..32].to_vec());
+    }
+
+    #[test]
+    fn state_location_golden_bytes() {
+        let location = [
+            0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e,
+            0x0f, 0x10,
+        ];
+        let mut bytes = location.to_vec();
+        bytes.extend(padded_label("Home"));
+        bytes.extend(&[0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01]);
+
+        let payload =
+            StateLocationPayload::new(location, String::from("Home"), 0x0102_0304_0506_0708);
+        assert_golden(Message::StateLocation(payload), &bytes);
+    }
+
+    #[test]
+    fn state_group_golden_bytes() {
+        let group = [
+            0x10, 0x0f, 0x0e, 0x0d, 0x0c, 0x0b, 0x0a, 0x09, 0x08, 0x07, 0x06, 0x05, 0x04, 0x03,
+            0x02, 0x01,
+        ];
+        let mut bytes = group.to_vec();
+        bytes.extend(padded_label("Upstairs"));
+        bytes.extend(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80]);
+
+        let payload = StateGroupPayload::new(group, String::from("Upstairs"), 1 << 63);
+        assert_golden(Message::StateGroup(payload), &bytes);
+    }
+
+    #[test]
+    fn echo_golden_bytes() {
+        let mut bytes = [0u8; 64];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+
+        assert_golden(Message::EchoRequest(EchoPayload::new(bytes)), &bytes);
+        assert_golden(Message::EchoResponse(EchoPayload::new(bytes)), &bytes);
+    }
+
+    #[test]
+    fn echo_message_types() {
+        // Values from the LIFX documentation. Both were previously encoded as 59.
+        let payload = EchoPayload::new([0u8; 64]);
+        assert_eq!(Message::EchoRequest(payload).message_type().value(), 58);
+        assert_eq!(Message::EchoResponse(payload).message_type().value(), 59);
+    }
+
+    #[test]
+    fn state_golden_bytes() {
+        let mut bytes = vec![
+            0x55, 0x55, 0xff, 0xff, 0xff, 0xff, 0xac, 0x0d, // color
+            0x00, 0x00, // reserved
+            0xff, 0xff, // power
+        ];
+        bytes.extend(padded_label("Bulb"));
+        bytes.extend(&[0u8; 8]);
+
+        let payload = StatePayload::new(
+            Hsbk::new(0x5555, 0xffff, 0xffff, 3500),
+            Power::On(0xffff),
+            String::from("Bulb"),
+        );
+        assert_golden(Message::State(payload), &bytes);
+    }
+
+    #[test]
+    fn state_off() {
+        let mut bytes = vec![0u8; 52];
+        bytes[6] = 0xac;
+        bytes[7] = 0x0d;
+        let payload = StatePayload::from_bytes(&bytes).unwrap();
+        assert_eq!(payload.power(), Power::Off);
+        assert_eq!(payload.color(), Hsbk::new(0, 0, 0, 3500));
+        assert_eq!(payload.label(), "");
+    }
+
+    #[test]
+    fn set_color_golden_bytes() {
+        // SetColor payload from the LIFX documentation: green, 3500K over 1024ms.
+        let bytes = [
+            0x00, 0x55, 0x55, 0xff, 0xff, 0xff, 0xff, 0xac, 0x0d, 0x00, 0x04, 0x00, 0x00,
+        ];
+        let payload = SetColorPayload::new(Hsbk::new(0x5555, 0xffff, 0xffff, 3500), 1024);
+        assert_golden(Message::SetColor(payload), &bytes);
+    }
+
+    #[test]
+    fn set_power_golden_bytes() {
+        let payload = SetPowerPayload::new(Power::On(0xffff), 1000);
+        assert_golden(
+            Message::SetPower(payload),
+            &[0xff, 0xff, 0xe8, 0x03, 0x00, 0x00],
+        );
+
+        let payload = SetPowerPayload::new(Power::Off, 0);
+        assert_golden(Message::SetPower(payload), &[0x00; 6]);
+    }
+
+    #[test]
+    fn set_waveform_optional_golden_bytes() {
+        // Set only the brightness to 50% over 1024ms.
+        let bytes = [
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x7f, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00,
+            0x00, 0x00, 0x80, 0x3f, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00,
+        ];
+        let payload = SetWaveformOptionalPayload::new(
+            false,
+            Hsbk::new(0, 0, 0x7fff, 0),
+            1024,
+            1.0,
+            0,
+            Waveform::Saw,
+        )
+        .with_components(false, false, true, false);
+        assert_golden(Message::SetWaveformOptional(payload.clone()), &bytes);
+
+        let current = Hsbk::new(0x5555, 0xffff, 0xffff, 3500);
+        assert_eq!(payload.apply_to(current), current.with_brightness(0x7fff));
+    }
+
+    #[test]
+    fn set_waveform_optional_invalid_waveform() {
+        let mut bytes = [0u8; 25];
+        bytes[20] = 5;
+        assert!(SetWaveformOptionalPayload::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn empty_and_unknown_messages() {
+        let message_type = MessageType::Device(DeviceMessageType::GetService);
+        assert_golden(Message::Empty(message_type), &[]);
+
+        let message_type = MessageType::Unknown(506);
+        assert_golden(Message::Bytes(message_type, vec![1, 2, 3]), &[1, 2, 3]);
+    }
+
+    #[test]
+    fn invalid_lengths() {
+        use DeviceMessageType::*;
+        use LightMessageType::*;
+        let message_types = [
+            MessageType::Device(StateService),
+            MessageType::Device(DeviceMessageType::StatePower),
+            MessageType::Device(StateLabel),
+            MessageType::Device(StateVersion),
+            MessageType::Device(StateLocation),
+            MessageType::Device(StateGroup),
+            MessageType::Device(EchoRequest),
+            MessageType::Device(EchoResponse),
+            MessageType::Light(State),
+            MessageType::Light(SetColor),
+            MessageType::Light(LightMessageType::SetPower),
+            MessageType::Light(SetWaveformOptional),
+        ];
+        for message_type in message_types.iter() {
+            assert!(Message::from(*message_type, &[0u8; 3]).is_err());
+            assert!(Message::from(*message_type, &[0u8; 100]).is_err());
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn hsbk_round_trip(hsbk in strategies::hsbk()) {
+            prop_assert_eq!(Hsbk::from_bytes(&hsbk.as_bytes()), Ok(hsbk));
+        }
+
+        #[test]
+        fn state_service_round_trip(payload in strategies::state_service_payload()) {
+            prop_assert_eq!(StateServicePayload::from_bytes(&payload.as_bytes()), Ok(payload));
+        }
+
+        #[test]
+        fn state_power_round_trip(payload in strategies::state_power_payload()) {
+            prop_assert_eq!(StatePowerPayload::from_bytes(&payload.as_bytes()), Ok(payload));
+        }
+
+        #[test]
+        fn state_version_round_trip(payload in strategies::state_version_payload()) {
+            prop_assert_eq!(StateVersionPayload::from_bytes(&payload.as_bytes()), Ok(payload));
+        }
+
+        #[test]
+        fn state_label_round_trip(payload in strategies::state_label_payload()) {
+            prop_assert_eq!(StateLabelPayload::from_bytes(&payload.as_bytes()), Ok(payload));
+        }
+
+        #[test]
+        fn state_location_round_trip(payload in strategies::state_location_payload()) {
+            prop_assert_eq!(StateLocationPayload::from_bytes(&payload.as_bytes()), Ok(payload));
+        }
+
+        #[test]
+        fn state_group_round_trip(payload in strategies::state_group_payload()) {
+            prop_assert_eq!(StateGroupPayload::from_bytes(&payload.as_bytes()), Ok(payload));
+        }
+
+        #[test]
+        fn echo_round_trip(payload in strategies::echo_payload()) {
+            prop_assert_eq!(EchoPayload::from_bytes(&payload.as_bytes()), Ok(payload));
+        }
+
+        #[test]
+        fn state_round_trip(payload in strategies::state_payload()) {
+            prop_assert_eq!(StatePayload::from_bytes(&payload.as_bytes()), Ok(payload));
+        }
+
+        #[test]
+        fn set_color_round_trip(payload in strategies::set_color_payload()) {
+            prop_assert_eq!(SetColorPayload::from_bytes(&payload.as_bytes()), Ok(payload));
+        }
+
+        #[test]
+        fn set_power_round_trip(payload in strategies::set_power_payload()) {
+            prop_assert_eq!(SetPowerPayload::from_bytes(&payload.as_bytes()), Ok(payload));
+        }
+
+        #[test]
+        fn set_waveform_optional_round_trip(
+            payload in strategies::set_waveform_optional_payload()
+        ) {
+            prop_assert_eq!(
+                SetWaveformOptionalPayload::from_bytes(&payload.as_bytes()),
+                Ok(payload)
+            );
+        }
+
+        #[test]
+        fn message_round_trip(message in strategies::message()) {
+            let bytes = message.as_bytes();
+            prop_assert_eq!(Message::from(message.message_type(), &bytes), Ok(message));
+        }
+
+        #[test]
+        fn decoding_arbitrary_payload_does_not_panic(
+            message_type in strategies::message_type(),
+            bytes in prop::collection::vec(any::<u8>(), 0..128),
+        ) {
+            let _ = Message::from(message_type, &bytes);
+        }
+    }
 }
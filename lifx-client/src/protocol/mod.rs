@@ -1,3 +1,9 @@
-pub(crate) mod header;
-pub(crate) mod message;
-pub(crate) mod packet;
+// Some of the wire format is only used by tools which enable the `protocol` feature.
+#![cfg_attr(not(feature = "protocol"), allow(dead_code))]
+
+pub mod header;
+pub mod message;
+pub mod packet;
+
+#[cfg(test)]
+mod strategies;
@@ -1,55 +1,82 @@
 use super::{header::*, message::*};
-// use super::protocol::{message::Message, packet::Packet};
-use std::{
-    io,
-    net::{SocketAddr, UdpSocket},
-};
+use std::convert::TryFrom;
 
 /// A LIFX packet.
-#[derive(Debug)]
-pub(crate) struct Packet {
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Packet {
     frame: Frame,
-    pub(crate) frame_address: FrameAddress,
+    frame_address: FrameAddress,
     protocol_header: ProtocolHeader,
     message: Message,
 }
 
 impl Packet {
-    pub(crate) fn as_bytes(&self) -> Vec<u8> {
+    pub fn as_bytes(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
         bytes.extend(self.frame.as_bytes());
         bytes.extend(self.frame_address.as_bytes());
-        // protocol header: 8 bytes reserved, 2 byte type, 2 bytes reserved
-        bytes.extend(&[0u8; 8]);
-        bytes.extend(&self.message.message_type().value().to_le_bytes());
-        bytes.extend(&[0u8; 2]);
+        bytes.extend(self.protocol_header.as_bytes());
         bytes.extend(self.message.as_bytes());
         bytes
     }
 
-    pub(crate) fn message(&self) -> &Message {
+    pub fn frame(&self) -> &Frame {
+        &self.frame
+    }
+
+    pub fn frame_address(&self) -> &FrameAddress {
+        &self.frame_address
+    }
+
+    pub fn protocol_header(&self) -> &ProtocolHeader {
+        &self.protocol_header
+    }
+
+    pub fn message(&self) -> &Message {
         &self.message
     }
+
+    /// Return this packet with a different sequence number.
+    pub(crate) fn with_sequence(mut self, sequence: u8) -> Packet {
+        self.frame_address.sequence = sequence;
+        self
+    }
 }
 
-impl From<&[u8]> for Packet {
-    fn from(bytes: &[u8]) -> Self {
-        let frame = Frame::from(&bytes[0..8]);
-        let frame_address = FrameAddress::from(&bytes[8..24]);
-        let protocol_header = ProtocolHeader::from(&bytes[24..36]);
-        let payload_size = frame.size as usize - 36;
-        let message = Message::from(protocol_header.message_type, &bytes[36..36 + payload_size]);
-        Packet {
+impl TryFrom<&[u8]> for Packet {
+    type Error = String;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        // header == 36 bytes.
+        if bytes.len() < 36 {
+            return Result::Err(format!(
+                "Packet must be at least 36 bytes, found {}.",
+                bytes.len()
+            ));
+        }
+        let frame = Frame::try_from(&bytes[0..8])?;
+        let frame_address = FrameAddress::try_from(&bytes[8..24])?;
+        let protocol_header = ProtocolHeader::try_from(&bytes[24..36])?;
+        let size = frame.size as usize;
+        if size < 36 || size > bytes.len() {
+            return Result::Err(format!(
+                "Invalid packet size {} for {} bytes.",
+                size,
+                bytes.len()
+            ));
+        }
+        let message = Message::from(protocol_header.message_type, &bytes[36..size])?;
+        Result::Ok(Packet {
             frame,
             frame_address,
             protocol_header,
             message,
-        }
+        })
     }
 }
 
 /// A LIFX packet builder.
-pub(crate) struct PacketBuilder {
+pub struct PacketBuilder {
     tagged: bool,
     source: u32,
     target: MacAddress,
@@ -60,7 +87,7 @@ pub(crate) struct PacketBuilder {
 }
 
 impl PacketBuilder {
-    pub(crate) fn new(message: Message) -> PacketBuilder {
+    pub fn new(message: Message) -> PacketBuilder {
         PacketBuilder {
             tagged: true,
             source: 0u32,
@@ -72,42 +99,42 @@ impl PacketBuilder {
         }
     }
 
-    pub(crate) fn with_empty_device_message(message_type: DeviceMessageType) -> PacketBuilder {
+    pub fn with_empty_device_message(message_type: DeviceMessageType) -> PacketBuilder {
         Self::new(Message::Empty(MessageType::Device(message_type)))
     }
 
-    pub(crate) fn with_empty_light_message(message_type: LightMessageType) -> PacketBuilder {
+    pub fn with_empty_light_message(message_type: LightMessageType) -> PacketBuilder {
         Self::new(Message::Empty(MessageType::Light(message_type)))
     }
 
     /// A source identifier to uniquely identify a client.
-    pub(crate) fn source(mut self, source: u32) -> Self {
+    pub fn source(mut self, source: u32) -> Self {
         self.source = source;
         self
     }
 
-    pub(crate) fn target(mut self, target: MacAddress) -> Self {
+    pub fn target(mut self, target: MacAddress) -> Self {
         self.target = target;
         self.tagged = false;
         self
     }
 
-    pub(crate) fn res_required(mut self, res_required: bool) -> Self {
+    pub fn res_required(mut self, res_required: bool) -> Self {
         self.res_required = res_required;
         self
     }
 
-    pub(crate) fn ack_required(mut self, ack_required: bool) -> Self {
+    pub fn ack_required(mut self, ack_required: bool) -> Self {
         self.ack_required = ack_required;
         self
     }
 
-    pub(crate) fn sequence(mut self, sequence: u8) -> Self {
+    pub fn sequence(mut self, sequence: u8) -> Self {
         self.sequence = sequence;
         self
     }
 
-    pub(crate) fn build(self) -> Packet {
+    pub fn build(self) -> Packet {
         // header == 36 bytes.
         let size = 36 + self.message.as_bytes().len();
         Packet {
@@ -131,31 +158,313 @@ impl PacketBuilder {
     }
 }
 
-pub(crate) fn send_packet(
-    socket: &UdpSocket,
-    socket_address: SocketAddr,
-    packet: Packet,
-) -> io::Result<Message> {
-    let mut buf = [0u8; 128];
-    let broadcast = socket.broadcast()?;
-    socket.set_broadcast(false)?;
-    socket.send_to(&packet.as_bytes(), socket_address)?;
-    let n_bytes = socket.recv(&mut buf)?;
-    let response = Packet::from(&buf[..n_bytes]);
-    let message = response.message();
-
-    socket.set_broadcast(broadcast)?;
-    Result::Ok(message.clone())
-}
+#[cfg(test)]
+mod tests {
+    use super::super::strategies;
+    use super::*;
+    use proptest::prelude::*;
+
+    // Tagged SetColor packet from the LIFX documentation which sets all devices to green.
+    #[rustfmt::skip]
+    const SET_COLOR_GREEN: [u8; 49] = [
+        0x31, 0x00, 0x00, 0x34, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x66, 0x00, 0x00, 0x00,
+        0x00, 0x55, 0x55, 0xff, 0xff, 0xff, 0xff, 0xac, 0x0d, 0x00, 0x04, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn set_color_golden_bytes() {
+        let color = Hsbk::new(0x5555, 0xffff, 0xffff, 3500);
+        let packet =
+            PacketBuilder::new(Message::SetColor(SetColorPayload::new(color, 1024))).build();
+
+        assert_eq!(packet.as_bytes(), SET_COLOR_GREEN.to_vec());
+        assert_eq!(Packet::try_from(&SET_COLOR_GREEN[..]), Ok(packet));
+    }
+
+    #[test]
+    fn get_service_golden_bytes() {
+        #[rustfmt::skip]
+        let bytes = [
+            0x24, 0x00, 0x00, 0x34, 0x78, 0x56, 0x34, 0x12,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x07,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x02, 0x00, 0x00, 0x00,
+        ];
+        let packet = PacketBuilder::with_empty_device_message(DeviceMessageType::GetService)
+            .source(0x1234_5678)
+            .sequence(7)
+            .res_required(true)
+            .build();
+
+        assert_eq!(packet.as_bytes(), bytes.to_vec());
+        assert_eq!(Packet::try_from(&bytes[..]), Ok(packet));
+    }
+
+    #[test]
+    fn state_service_golden_bytes() {
+        #[rustfmt::skip]
+        let bytes = [
+            0x29, 0x00, 0x00, 0x14, 0x78, 0x56, 0x34, 0x12,
+            0xd0, 0x73, 0xd5, 0x01, 0x02, 0x03, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x07,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x03, 0x00, 0x00, 0x00,
+            0x01, 0x7c, 0xdd, 0x00, 0x00,
+        ];
+
+        let packet = Packet::try_from(&bytes[..]).unwrap();
+        assert_eq!(packet.frame().source, 0x1234_5678);
+        assert_eq!(
+            packet.frame_address().target.to_string(),
+            "d0:73:d5:01:02:03"
+        );
+        assert_eq!(packet.frame_address().sequence, 7);
+        assert_eq!(
+            packet.message(),
+            &Message::StateService(StateServicePayload::new(1, 56700))
+        );
+        assert_eq!(packet.as_bytes(), bytes.to_vec());
+    }
+
+    // The header of a tagged packet with no source, target or sequence, from the LIFX documentation.
+    fn tagged_header(message_type: u16, payload_length: usize) -> Vec<u8> {
+        let mut bytes = ((36 + payload_length) as u16).to_le_bytes().to_vec();
+        bytes.extend(&[0x00, 0x34]);
+        bytes.extend(&[0u8; 28]);
+        bytes.extend(message_type.to_le_bytes().iter());
+        bytes.extend(&[0x00, 0x00]);
+        bytes
+    }
+
+    fn padded_label(label: &str) -> Vec<u8> {
+        let mut bytes = label.as_bytes().to_vec();
+        bytes.resize(32, 0);
+        bytes
+    }
+
+    // Each variant of `Message` has a distinct index, so a new variant without a golden case is
+    // noticed.
+    fn variant_index(message: &Message) -> usize {
+        match message {
+            Message::Empty(_) => 0,
+            Message::Bytes(_, _) => 1,
+            Message::StateService(_) => 2,
+            Message::StatePower(_) => 3,
+            Message::StateLabel(_) => 4,
+            Message::StateVersion(_) => 5,
+            Message::StateGroup(_) => 6,
+            Message::StateLocation(_) => 7,
+            Message::EchoRequest(_) => 8,
+            Message::EchoResponse(_) => 9,
+            Message::State(_) => 10,
+            Message::SetColor(_) => 11,
+            Message::SetPower(_) => 12,
+            Message::SetWaveformOptional(_) => 13,
+        }
+    }
+
+    #[test]
+    fn every_message_golden_bytes() {
+        let green = Hsbk::new(0x5555, 0xffff, 0xffff, 3500);
+        let mut state = vec![
+            0x55, 0x55, 0xff, 0xff, 0xff, 0xff, 0xac, 0x0d, 0x00, 0x00, 0xff, 0xff,
+        ];
+        state.extend(padded_label("Bulb"));
+        state.extend(&[0u8; 8]);
+        let mut location = vec![0x01; 16];
+        location.extend(padded_label("Home"));
+        location.extend(&[0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        let mut group = vec![0x02; 16];
+        group.extend(padded_label("Upstairs"));
+        group.extend(&[0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        let waveform = SetWaveformOptionalPayload::new(
+            false,
+            Hsbk::new(0, 0, 0x7fff, 0),
+            1024,
+            1.0,
+            0,
+            Waveform::Saw,
+        )
+        .with_components(false, false, true, false);
+
+        let cases = vec![
+            (
+                Message::Empty(MessageType::Device(DeviceMessageType::GetService)),
+                2,
+                vec![],
+            ),
+            (
+                Message::Bytes(MessageType::Unknown(506), vec![1, 2, 3]),
+                506,
+                vec![1, 2, 3],
+            ),
+            (
+                Message::StateService(StateServicePayload::new(1, 56700)),
+                3,
+                vec![0x01, 0x7c, 0xdd, 0x00, 0x00],
+            ),
+            (
+                Message::StatePower(StatePowerPayload::new(Power::On(0xffff))),
+                22,
+                vec![0xff, 0xff],
+            ),
+            (
+                Message::StateLabel(StateLabelPayload::new("Kitchen".to_string())),
+                25,
+                padded_label("Kitchen"),
+            ),
+            (
+                Message::StateVersion(StateVersionPayload::new(1, 27)),
+                33,
+                vec![0x01, 0, 0, 0, 0x1b, 0, 0, 0, 0, 0, 0, 0],
+            ),
+            (
+                Message::StateLocation(StateLocationPayload::new([0x01; 16], "Home".into(), 1)),
+                50,
+                location,
+            ),
+            (
+                Message::StateGroup(StateGroupPayload::new([0x02; 16], "Upstairs".into(), 2)),
+                53,
+                group,
+            ),
+            (
+                Message::EchoRequest(EchoPayload::new([0x07; 64])),
+                58,
+                vec![0x07; 64],
+            ),
+            (
+                Message::EchoResponse(EchoPayload::new([0x07; 64])),
+                59,
+                vec![0x07; 64],
+            ),
+            (
+                Message::State(StatePayload::new(green, Power::On(0xffff), "Bulb".into())),
+                107,
+                state,
+            ),
+            (
+                Message::SetColor(SetColorPayload::new(green, 1024)),
+                102,
+                vec![
+                    0x00, 0x55, 0x55, 0xff, 0xff, 0xff, 0xff, 0xac, 0x0d, 0x00, 0x04, 0x00, 0x00,
+                ],
+            ),
+            (
+                Message::SetPower(SetPowerPayload::new(Power::On(0xffff), 1000)),
+                117,
+                vec![0xff, 0xff, 0xe8, 0x03, 0x00, 0x00],
+            ),
+            (
+                Message::SetWaveformOptional(waveform),
+                119,
+                vec![
+                    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x7f, 0x00, 0x00, 0x00, 0x04, 0x00,
+                    0x00, 0x00, 0x00, 0x80, 0x3f, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00,
+                ],
+            ),
+        ];
+
+        let mut covered: Vec<usize> = cases.iter().map(|(m, _, _)| variant_index(m)).collect();
+        covered.sort_unstable();
+        assert_eq!(covered, (0..cases.len()).collect::<Vec<_>>());
+        for (message, message_type, payload) in cases {
+            let mut bytes = tagged_header(message_type, payload.len());
+            bytes.extend(&payload);
+            let packet = PacketBuilder::new(message).build();
+
+            assert_eq!(packet.as_bytes(), bytes);
+            assert_eq!(Packet::try_from(&bytes[..]), Ok(packet));
+        }
+    }
+
+    #[test]
+    fn target_clears_tagged() {
+        let target = MacAddress {
+            address: [0xd0, 0x73, 0xd5, 0x01, 0x02, 0x03],
+        };
+        let packet = PacketBuilder::with_empty_light_message(LightMessageType::Get)
+            .target(target)
+            .build();
 
-pub(crate) fn send_packet_no_response(
-    socket: &UdpSocket,
-    socket_address: SocketAddr,
-    packet: Packet,
-) -> io::Result<()> {
-    let broadcast = socket.broadcast()?;
-    socket.set_broadcast(false)?;
-    socket.send_to(&packet.as_bytes(), socket_address)?;
-    socket.set_broadcast(broadcast)?;
-    Result::Ok(())
+        assert!(!packet.frame().tagged);
+        assert_eq!(packet.frame_address().target, target);
+    }
+
+    #[test]
+    fn invalid_size() {
+        // Too short to contain a header.
+        assert!(Packet::try_from(&SET_COLOR_GREEN[..35]).is_err());
+        // Size is larger than the number of bytes.
+        assert!(Packet::try_from(&SET_COLOR_GREEN[..48]).is_err());
+        // Size is smaller than the header.
+        let mut bytes = SET_COLOR_GREEN;
+        bytes[0] = 0x20;
+        assert!(Packet::try_from(&bytes[..]).is_err());
+    }
+
+    #[test]
+    fn ignores_trailing_bytes() {
+        let mut bytes = SET_COLOR_GREEN.to_vec();
+        bytes.extend(&[0xff; 8]);
+        assert_eq!(
+            Packet::try_from(&bytes[..]).map(|p| p.as_bytes()),
+            Ok(SET_COLOR_GREEN.to_vec())
+        );
+    }
+
+    fn packet() -> impl Strategy<Value = Packet> {
+        (
+            any::<u32>(),
+            proptest::option::of(strategies::mac_address()),
+            any::<bool>(),
+            any::<bool>(),
+            any::<u8>(),
+            strategies::message(),
+        )
+            .prop_map(
+                |(source, target, res_required, ack_required, sequence, message)| {
+                    let mut builder = PacketBuilder::new(message)
+                        .source(source)
+                        .res_required(res_required)
+                        .ack_required(ack_required)
+                        .sequence(sequence);
+                    if let Some(target) = target {
+                        builder = builder.target(target);
+                    }
+                    builder.build()
+                },
+            )
+    }
+
+    proptest! {
+        #[test]
+        fn packet_round_trip(packet in packet()) {
+            prop_assert_eq!(Packet::try_from(&packet.as_bytes()[..]), Ok(packet));
+        }
+
+        #[test]
+        fn decoding_arbitrary_bytes_does_not_panic(
+            bytes in prop::collection::vec(any::<u8>(), 0..256),
+        ) {
+            let _ = Packet::try_from(&bytes[..]);
+        }
+
+        #[test]
+        fn decoding_corrupted_packet_does_not_panic(
+            packet in packet(),
+            index in any::<prop::sample::Index>(),
+            byte in any::<u8>(),
+        ) {
+            let mut bytes = packet.as_bytes();
+            let index = index.index(bytes.len());
+            bytes[index] = byte;
+            let _ = Packet::try_from(&bytes[..]);
+        }
+    }
 }
@@ -0,0 +1,144 @@
+// proptest strategies for generating arbitrary protocol values.
+use super::{header::*, message::*};
+use proptest::prelude::*;
+
+pub(crate) fn mac_address() -> impl Strategy<Value = MacAddress> {
+    any::<[u8; 6]>().prop_map(|address| MacAddress { address })
+}
+
+pub(crate) fn frame() -> impl Strategy<Value = Frame> {
+    (any::<u16>(), any::<bool>(), any::<bool>(), any::<u32>()).prop_map(
+        |(size, addressable, tagged, source)| Frame {
+            size,
+            addressable,
+            tagged,
+            source,
+        },
+    )
+}
+
+pub(crate) fn frame_address() -> impl Strategy<Value = FrameAddress> {
+    (mac_address(), any::<bool>(), any::<bool>(), any::<u8>()).prop_map(
+        |(target, res_required, ack_required, sequence)| FrameAddress {
+            target,
+            res_required,
+            ack_required,
+            sequence,
+        },
+    )
+}
+
+pub(crate) fn message_type() -> impl Strategy<Value = MessageType> {
+    any::<u16>().prop_map(MessageType::from_value)
+}
+
+// Labels are at most 32 bytes and may not contain null bytes.
+pub(crate) fn label() -> impl Strategy<Value = String> {
+    "[a-zA-Z0-9 '_-]{0,32}"
+}
+
+pub(crate) fn hsbk() -> impl Strategy<Value = Hsbk> {
+    any::<(u16, u16, u16, u16)>().prop_map(|(h, s, b, k)| Hsbk::new(h, s, b, k))
+}
+
+pub(crate) fn power() -> impl Strategy<Value = Power> {
+    any::<u16>().prop_map(Power::from_level)
+}
+
+pub(crate) fn state_service_payload() -> impl Strategy<Value = StateServicePayload> {
+    any::<(u8, u16)>().prop_map(|(service, port)| StateServicePayload::new(service, port))
+}
+
+pub(crate) fn state_power_payload() -> impl Strategy<Value = StatePowerPayload> {
+    power().prop_map(StatePowerPayload::new)
+}
+
+pub(crate) fn state_version_payload() -> impl Strategy<Value = StateVersionPayload> {
+    any::<(u32, u32)>().prop_map(|(vendor, product)| StateVersionPayload::new(vendor, product))
+}
+
+pub(crate) fn state_label_payload() -> impl Strategy<Value = StateLabelPayload> {
+    label().prop_map(StateLabelPayload::new)
+}
+
+pub(crate) fn state_location_payload() -> impl Strategy<Value = StateLocationPayload> {
+    (any::<[u8; 16]>(), label(), any::<u64>()).prop_map(|(location, label, updated_at)| {
+        StateLocationPayload::new(location, label, updated_at)
+    })
+}
+
+pub(crate) fn state_group_payload() -> impl Strategy<Value = StateGroupPayload> {
+    (any::<[u8; 16]>(), label(), any::<u64>())
+        .prop_map(|(group, label, updated_at)| StateGroupPayload::new(group, label, updated_at))
+}
+
+pub(crate) fn echo_payload() -> impl Strategy<Value = EchoPayload> {
+    prop::collection::vec(any::<u8>(), 64).prop_map(|bytes| {
+        let mut payload = [0u8; 64];
+        payload.copy_from_slice(&bytes);
+        EchoPayload::new(payload)
+    })
+}
+
+pub(crate) fn state_payload() -> impl Strategy<Value = StatePayload> {
+    (hsbk(), power(), label())
+        .prop_map(|(color, power, label)| StatePayload::new(color, power, label))
+}
+
+pub(crate) fn set_color_payload() -> impl Strategy<Value = SetColorPayload> {
+    (hsbk(), any::<u32>()).prop_map(|(color, duration)| SetColorPayload::new(color, duration))
+}
+
+pub(crate) fn set_power_payload() -> impl Strategy<Value = SetPowerPayload> {
+    (power(), any::<u32>()).prop_map(|(power, duration)| SetPowerPayload::new(power, duration))
+}
+
+pub(crate) fn waveform() -> impl Strategy<Value = Waveform> {
+    (0u8..5).prop_map(|value| Waveform::from_value(value).unwrap())
+}
+
+pub(crate) fn set_waveform_optional_payload() -> impl Strategy<Value = SetWaveformOptionalPayload> {
+    (
+        any::<bool>(),
+        hsbk(),
+        any::<u32>(),
+        any::<f32>(),
+        any::<i16>(),
+        waveform(),
+        any::<[bool; 4]>(),
+    )
+        .prop_map(
+            |(transient, color, period, cycles, skew_ratio, waveform, set)| {
+                SetWaveformOptionalPayload::new(
+                    transient, color, period, cycles, skew_ratio, waveform,
+                )
+                .with_components(set[0], set[1], set[2], set[3])
+            },
+        )
+}
+
+pub(crate) fn message() -> impl Strategy<Value = Message> {
+    prop_oneof![
+        message_type().prop_map(Message::Empty),
+        (message_type(), prop::collection::vec(any::<u8>(), 1..64)).prop_filter_map(
+            "bytes must not decode to a typed payload",
+            |(message_type, bytes)| match Message::from(message_type, &bytes) {
+                Ok(Message::Bytes(message_type, bytes)) =>
+                    Some(Message::Bytes(message_type, bytes)),
+                _ => None,
+            }
+        ),
+        state_service_payload().prop_map(Message::StateService),
+        state_power_payload().prop_map(Message::StatePower),
+        state_label_payload().prop_map(Message::StateLabel),
+        state_version_payload().prop_map(Message::StateVersion),
+        state_location_payload().prop_map(Message::StateLocation),
+        state_group_payload().prop_map(Message::StateGroup),
+        echo_payload().prop_map(Message::EchoRequest),
+        echo_payload().prop_map(Message::EchoResponse),
+        state_payload().prop_map(Message::State),
+        set_color_payload().prop_map(Message::SetColor),
+        set_power_payload().prop_map(Message::SetPower),
+        set_waveform_optional_payload().prop_map(Message::SetWaveformOptional),
+    ]
+}
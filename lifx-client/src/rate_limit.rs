@@ -0,0 +1,358 @@
+use super::protocol::{
+    header::{LightMessageType, MacAddress, MessageType},
+    message::{Message, SetColorPayload, SetWaveformOptionalPayload},
+    packet::{Packet, PacketBuilder},
+};
+use std::{
+    collections::HashMap,
+    sync::{Mutex, MutexGuard, RwLock},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Limits the rate at which messages are sent to each device.
+///
+/// Messages to a device which has used its budget wait for the next free slot. A command which is
+/// waiting is replaced by any later command of the same type to the same device, so only the
+/// latest color or power is sent. SetColor and SetWaveformOptional both set the color, so they
+/// replace each other. Tagged packets are sent to every device and are never limited.
+pub(crate) struct RateLimiter {
+    interval: RwLock<Option<Duration>>,
+    devices: Mutex<HashMap<MacAddress, DeviceState>>,
+}
+
+struct DeviceState {
+    // The earliest time at which the next message can be sent.
+    next: Instant,
+    // Commands waiting to be sent, by `command_key`.
+    queued: HashMap<u16, Packet>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(rate: Option<u32>) -> RateLimiter {
+        RateLimiter {
+            interval: RwLock::new(to_interval(rate)),
+            devices: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Set the maximum number of messages per second sent to each device. `None` disables the
+    /// limit.
+    pub(crate) fn set_rate(&self, rate: Option<u32>) {
+        *self
+            .interval
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = to_interval(rate);
+    }
+
+    /// Wait until `packet` can be sent and return it.
+    pub(crate) fn acquire(&self, packet: Packet) -> Packet {
+        let interval = match self.interval() {
+            Some(interval) if !packet.frame().tagged => interval,
+            _ => return packet,
+        };
+
+        let slot = self
+            .lock_devices()
+            .entry(packet.frame_address().target)
+            .or_insert_with(DeviceState::new)
+            .reserve(interval);
+        if let Some(slot) = slot {
+            sleep_until(slot);
+        }
+        packet
+    }
+
+    /// Wait until the command in `packet` can be sent, then return the latest command of the same
+    /// type to the same device. Returns `None` if the command replaced one which is already
+    /// waiting, in which case the waiting caller sends it instead.
+    pub(crate) fn acquire_command(&self, packet: Packet) -> Option<Packet> {
+        let interval = match self.interval() {
+            Some(interval) if !packet.frame().tagged => interval,
+            _ => return Option::Some(packet),
+        };
+
+        let target = packet.frame_address().target;
+        let key = command_key(packet.message());
+        let slot = {
+            let mut devices = self.lock_devices();
+            let state = devices.entry(target).or_insert_with(DeviceState::new);
+            if let Some(queued) = state.queued.get_mut(&key) {
+                *queued = replace(queued, packet);
+                return Option::None;
+            }
+            match state.reserve(interval) {
+                Some(slot) => {
+                    state.queued.insert(key, packet);
+                    slot
+                }
+                None => return Option::Some(packet),
+            }
+        };
+
+        sleep_until(slot);
+        self.lock_devices()
+            .get_mut(&target)
+            .and_then(|state| state.queued.remove(&key))
+    }
+
+    /// Reserve a slot for `packet` only if it can be sent now. Returns whether it can be sent.
+    pub(crate) fn try_acquire(&self, packet: &Packet) -> bool {
+        let interval = match self.interval() {
+            Some(interval) if !packet.frame().tagged => interval,
+            _ => return true,
+        };
+
+        let mut devices = self.lock_devices();
+        let state = devices
+            .entry(packet.frame_address().target)
+            .or_insert_with(DeviceState::new);
+        if state.next > Instant::now() {
+            false
+        } else {
+            state.reserve(interval);
+            true
+        }
+    }
+
+    fn interval(&self) -> Option<Duration> {
+        *self
+            .interval
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn lock_devices(&self) -> MutexGuard<'_, HashMap<MacAddress, DeviceState>> {
+        // Every update leaves the map valid, so it is safe to use after a panic.
+        let mut devices = self
+            .devices
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        // A device which can send now and has nothing waiting is the same as a new one, so forget
+        // it rather than keeping every device ever seen.
+        let now = Instant::now();
+        devices.retain(|_, state| state.next > now || !state.queued.is_empty());
+        devices
+    }
+}
+
+impl DeviceState {
+    fn new() -> DeviceState {
+        DeviceState {
+            next: Instant::now(),
+            queued: HashMap::new(),
+        }
+    }
+
+    /// Reserve the next free slot. Returns `None` if a message can be sent now.
+    fn reserve(&mut self, interval: Duration) -> Option<Instant> {
+        let now = Instant::now();
+        let slot = Instant::max(now, self.next);
+        self.next = slot + interval;
+
+        if slot > now {
+            Option::Some(slot)
+        } else {
+            Option::None
+        }
+    }
+}
+
+// Commands with the same key replace each other while waiting. Every command which sets the color
+// shares a key, so an earlier color can never be sent after a later one. Transient waveforms return
+// to the original color, so they do not replace other commands.
+fn command_key(message: &Message) -> u16 {
+    match message {
+        Message::SetWaveformOptional(payload) if !payload.transient() => {
+            MessageType::Light(LightMessageType::SetColor).value()
+        }
+        message => message.message_type().value(),
+    }
+}
+
+// Combine a waiting command with a later command which replaces it. A later command which sets only
+// part of the color keeps the rest of the color set by the waiting command.
+fn replace(queued: &Packet, packet: Packet) -> Packet {
+    let message = match (queued.message(), packet.message()) {
+        (Message::SetColor(queued), Message::SetWaveformOptional(later)) => Message::SetColor(
+            SetColorPayload::new(later.apply_to(queued.color()), later.period()),
+        ),
+        (Message::SetWaveformOptional(queued), Message::SetWaveformOptional(later)) => {
+            let payload = SetWaveformOptionalPayload::new(
+                later.transient(),
+                later.apply_to(queued.color()),
+                later.period(),
+                later.cycles(),
+                later.skew_ratio(),
+                later.waveform(),
+            )
+            .with_components(
+                queued.set_hue() || later.set_hue(),
+                queued.set_saturation() || later.set_saturation(),
+                queued.set_brightness() || later.set_brightness(),
+                queued.set_kelvin() || later.set_kelvin(),
+            );
+            Message::SetWaveformOptional(payload)
+        }
+        _ => return packet,
+    };
+
+    let frame_address = packet.frame_address();
+    PacketBuilder::new(message)
+        .target(frame_address.target)
+        .source(packet.frame().source)
+        .sequence(frame_address.sequence)
+        .res_required(frame_address.res_required)
+        .ack_required(frame_address.ack_required)
+        .build()
+}
+
+fn to_interval(rate: Option<u32>) -> Option<Duration> {
+    rate.map(|rate| Duration::from_secs(1) / u32::max(rate, 1))
+}
+
+fn sleep_until(instant: Instant) {
+    let now = Instant::now();
+    if instant > now {
+        thread::sleep(instant - now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::mac;
+    use crate::{protocol::message::Hsbk, state::LightState};
+    use std::sync::Arc;
+
+    fn set_color(target: MacAddress, hue: u16) -> Packet {
+        let color = Hsbk::new(hue, 0xffff, 0xffff, 3500);
+        PacketBuilder::new(Message::SetColor(SetColorPayload::new(color, 0)))
+            .target(target)
+            .build()
+    }
+
+    fn hue(packet: &Packet) -> u16 {
+        match packet.message() {
+            Message::SetColor(payload) => payload.color().hue(),
+            message => panic!("Unexpected message. {:?}", message),
+        }
+    }
+
+    #[test]
+    fn unlimited() {
+        let limiter = RateLimiter::new(Option::None);
+        let start = Instant::now();
+        for _ in 0..100 {
+            limiter.acquire(set_color(mac(1), 0));
+        }
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn limit_each_device() {
+        let limiter = RateLimiter::new(Option::Some(20));
+        let start = Instant::now();
+        for _ in 0..5 {
+            limiter.acquire(set_color(mac(1), 0));
+        }
+        // The first message is sent immediately, then one every 50 milliseconds.
+        assert!(start.elapsed() >= Duration::from_millis(200));
+
+        // Other devices have their own budget.
+        let start = Instant::now();
+        limiter.acquire(set_color(mac(2), 0));
+        assert!(start.elapsed() < Duration::from_millis(50));
+    }
+
+    #[test]
+    fn tagged_packets_are_not_limited() {
+        let limiter = RateLimiter::new(Option::Some(1));
+        let start = Instant::now();
+        for _ in 0..5 {
+            let color = Hsbk::new(0, 0, 0xffff, 3500);
+            limiter.acquire(
+                PacketBuilder::new(Message::SetColor(SetColorPayload::new(color, 0))).build(),
+            );
+        }
+        assert!(start.elapsed() < Duration::from_millis(500));
+    }
+
+    #[test]
+    fn try_acquire_without_waiting() {
+        let limiter = RateLimiter::new(Option::Some(10));
+        assert!(limiter.try_acquire(&set_color(mac(1), 0)));
+        assert!(!limiter.try_acquire(&set_color(mac(1), 0)));
+        assert!(limiter.try_acquire(&set_color(mac(2), 0)));
+
+        thread::sleep(Duration::from_millis(100));
+        assert!(limiter.try_acquire(&set_color(mac(1), 0)));
+    }
+
+    #[test]
+    fn coalesce_commands() {
+        let limiter = Arc::new(RateLimiter::new(Option::Some(5)));
+        assert_eq!(
+            hue(&limiter.acquire_command(set_color(mac(1), 1)).unwrap()),
+            1
+        );
+
+        // The second command waits for the next slot, and is replaced by the third.
+        let waiting = {
+            let limiter = limiter.clone();
+            thread::spawn(move || limiter.acquire_command(set_color(mac(1), 2)))
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(limiter.acquire_command(set_color(mac(1), 3)).is_none());
+        assert_eq!(hue(&waiting.join().unwrap().unwrap()), 3);
+
+        // Once sent, the next command waits for a new slot.
+        let start = Instant::now();
+        assert_eq!(
+            hue(&limiter.acquire_command(set_color(mac(1), 4)).unwrap()),
+            4
+        );
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn coalesce_partial_and_full_colors() {
+        let limiter = Arc::new(RateLimiter::new(Option::Some(5)));
+        let brightness = |brightness| {
+            let payload = LightState::new().with_brightness(brightness).components(0);
+            PacketBuilder::new(Message::SetWaveformOptional(payload))
+                .target(mac(1))
+                .build()
+        };
+        assert!(limiter.acquire_command(set_color(mac(1), 1)).is_some());
+
+        // A partial color waits for the next slot, and is replaced by a full color, which keeps
+        // its hue when only the brightness is set by a later command.
+        let waiting = {
+            let limiter = limiter.clone();
+            thread::spawn(move || limiter.acquire_command(brightness(0.2)))
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(limiter.acquire_command(set_color(mac(1), 2)).is_none());
+        assert!(limiter.acquire_command(brightness(0.5)).is_none());
+
+        let sent = waiting.join().unwrap().unwrap();
+        assert_eq!(
+            sent.message(),
+            &Message::SetColor(SetColorPayload::new(Hsbk::new(2, 0xffff, 0x7fff, 3500), 0))
+        );
+    }
+
+    #[test]
+    fn forget_idle_devices() {
+        let limiter = RateLimiter::new(Option::Some(20));
+        for n in 0..10 {
+            limiter.acquire(set_color(mac(n), 0));
+        }
+        assert_eq!(limiter.lock_devices().len(), 10);
+
+        thread::sleep(Duration::from_millis(60));
+        limiter.acquire(set_color(mac(10), 0));
+        assert_eq!(limiter.lock_devices().len(), 1);
+    }
+}
@@ -0,0 +1,317 @@
+use super::{
+    device::{Device, DeviceAddress},
+    protocol::header::MacAddress,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// The version of the registry file format written by this crate.
+pub const REGISTRY_VERSION: u32 = 1;
+
+/// Devices which have been found, saved to a file so they can be controlled later without
+/// discovering them again.
+///
+/// Registries are stored as TOML. Each device is identified by its MAC address, and last-seen
+/// times are in seconds since the Unix epoch.
+///
+/// ```toml
+/// version = 1
+///
+/// [[devices]]
+/// address = "d0:73:d5:00:00:01#192.168.1.20:56700"
+/// label = "Kitchen"
+/// group = "Downstairs"
+/// location = "Home"
+/// product = 27
+/// last_seen = 1700000000
+/// ```
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct DeviceRegistry {
+    devices: HashMap<MacAddress, RegisteredDevice>,
+}
+
+/// A device in a registry, along with its product ID, if known, and when it was last found.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RegisteredDevice {
+    device: Device,
+    product: Option<u32>,
+    last_seen: SystemTime,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RegistryFile {
+    version: u32,
+    #[serde(default)]
+    devices: Vec<DeviceEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DeviceEntry {
+    address: String,
+    label: String,
+    #[serde(default)]
+    group: String,
+    #[serde(default)]
+    location: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    product: Option<u32>,
+    #[serde(default)]
+    last_seen: u64,
+}
+
+impl DeviceRegistry {
+    pub fn new() -> DeviceRegistry {
+        DeviceRegistry::default()
+    }
+
+    /// Read a registry from the file at `path`.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<DeviceRegistry> {
+        let contents = fs::read_to_string(path)?;
+        DeviceRegistry::from_toml(&contents)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Write this registry to the file at `path`, replacing any existing file.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let contents = self
+            .to_toml()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(path, contents)
+    }
+
+    pub fn from_toml(s: &str) -> Result<DeviceRegistry, String> {
+        let file: RegistryFile = toml::from_str(s).map_err(|e| e.to_string())?;
+        if file.version != REGISTRY_VERSION {
+            return Result::Err(format!(
+                "Unsupported device registry version {}.",
+                file.version
+            ));
+        }
+
+        let mut devices = HashMap::new();
+        for entry in file.devices {
+            let address: DeviceAddress = entry.address.parse()?;
+            let device = Device::new(address, entry.label, entry.group, entry.location);
+            devices.insert(
+                address.mac_address(),
+                RegisteredDevice {
+                    device,
+                    product: entry.product,
+                    last_seen: UNIX_EPOCH + Duration::from_secs(entry.last_seen),
+                },
+            );
+        }
+        Result::Ok(DeviceRegistry { devices })
+    }
+
+    pub fn to_toml(&self) -> Result<String, String> {
+        // Sort by address so that saving the same devices always writes the same file.
+        let mut devices: Vec<&RegisteredDevice> = self.devices.values().collect();
+        devices.sort_by_key(|registered| registered.device.address().to_string());
+
+        let file = RegistryFile {
+            version: REGISTRY_VERSION,
+            devices: devices
+                .into_iter()
+                .map(|registered| DeviceEntry {
+                    address: registered.device.address().to_string(),
+                    label: registered.device.label().clone(),
+                    group: registered.device.group().clone(),
+                    location: registered.device.location().clone(),
+                    product: registered.product,
+                    last_seen: registered
+                        .last_seen
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs(),
+                })
+                .collect(),
+        };
+        toml::to_string(&file).map_err(|e| e.to_string())
+    }
+
+    /// Add devices which have just been found, replacing the address, label, group and location
+    /// of any which are already registered. The product of a registered device is kept.
+    pub fn merge<'a, I>(&mut self, devices: I)
+    where
+        I: IntoIterator<Item = &'a Device>,
+    {
+        let now = SystemTime::now();
+        for device in devices {
+            let product = self
+                .devices
+                .get(&device.mac_address())
+                .and_then(|registered| registered.product);
+            self.devices.insert(
+                device.mac_address(),
+                RegisteredDevice {
+                    device: device.clone(),
+                    product,
+                    last_seen: now,
+                },
+            );
+        }
+    }
+
+    /// Set the product ID of a registered device. Returns whether the device is registered.
+    pub fn set_product(&mut self, mac_address: MacAddress, product: u32) -> bool {
+        match self.devices.get_mut(&mac_address) {
+            Some(registered) => {
+                registered.product = Option::Some(product);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove a device from the registry and return it, if it was registered.
+    pub fn remove(&mut self, mac_address: MacAddress) -> Option<RegisteredDevice> {
+        self.devices.remove(&mac_address)
+    }
+
+    pub fn get(&self, mac_address: MacAddress) -> Option<&RegisteredDevice> {
+        self.devices.get(&mac_address)
+    }
+
+    /// Return every registered device.
+    pub fn devices(&self) -> impl Iterator<Item = &RegisteredDevice> {
+        self.devices.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.devices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+}
+
+impl RegisteredDevice {
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    /// The LIFX product ID, if it is known.
+    pub fn product(&self) -> Option<u32> {
+        self.product
+    }
+
+    /// When the device was last found by discovery. Registry files store whole seconds.
+    pub fn last_seen(&self) -> SystemTime {
+        self.last_seen
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::mac;
+
+    fn device(n: u8, label: &str) -> Device {
+        let address = format!("d0:73:d5:00:00:{:02x}#10.0.0.{}:56700", n, n)
+            .parse()
+            .unwrap();
+        Device::new(
+            address,
+            label.to_string(),
+            "Group".to_string(),
+            "Location".to_string(),
+        )
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut registry = DeviceRegistry::new();
+        registry.merge(&[device(1, "Kitchen"), device(2, "Bedroom")]);
+        assert!(registry.set_product(mac(1), 27));
+        assert!(!registry.set_product(mac(3), 27));
+
+        let loaded = DeviceRegistry::from_toml(&registry.to_toml().unwrap()).unwrap();
+        assert_eq!(loaded.len(), 2);
+        let kitchen = loaded.get(mac(1)).unwrap();
+        assert_eq!(kitchen.device(), &device(1, "Kitchen"));
+        assert_eq!(kitchen.product(), Option::Some(27));
+        assert_eq!(
+            kitchen
+                .last_seen()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            registry
+                .get(mac(1))
+                .unwrap()
+                .last_seen()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+        );
+        assert_eq!(loaded.get(mac(2)).unwrap().product(), Option::None);
+    }
+
+    #[test]
+    fn parse_file() {
+        let registry = DeviceRegistry::from_toml(
+            r#"
+            version = 1
+
+            [[devices]]
+            address = "d0:73:d5:00:00:01#10.0.0.1:56700"
+            label = "Kitchen"
+            product = 22
+            last_seen = 1700000000
+            "#,
+        )
+        .unwrap();
+
+        let kitchen = registry.get(mac(1)).unwrap();
+        assert_eq!(kitchen.device().label(), "Kitchen");
+        assert_eq!(kitchen.device().group(), "");
+        assert_eq!(kitchen.product(), Option::Some(22));
+        assert_eq!(
+            kitchen.last_seen(),
+            UNIX_EPOCH + Duration::from_secs(1700000000)
+        );
+    }
+
+    #[test]
+    fn invalid_files() {
+        assert!(DeviceRegistry::from_toml("version = 2").is_err());
+        assert!(DeviceRegistry::from_toml("devices = []").is_err());
+        assert!(DeviceRegistry::from_toml(
+            r#"
+            version = 1
+
+            [[devices]]
+            address = "10.0.0.1:56700"
+            label = "Kitchen"
+            "#
+        )
+        .is_err());
+        assert!(DeviceRegistry::from_toml("version = 1").unwrap().is_empty());
+    }
+
+    #[test]
+    fn merge_keeps_product() {
+        let mut registry = DeviceRegistry::new();
+        registry.merge(&[device(1, "Kitchen")]);
+        registry.set_product(mac(1), 27);
+
+        let renamed =
+            device(1, "Pantry").with_address("d0:73:d5:00:00:01#10.0.0.9:56700".parse().unwrap());
+        registry.merge(vec![&renamed]);
+
+        assert_eq!(registry.len(), 1);
+        let registered = registry.get(mac(1)).unwrap();
+        assert_eq!(registered.device(), &renamed);
+        assert_eq!(registered.product(), Option::Some(27));
+
+        assert!(registry.remove(mac(1)).is_some());
+        assert!(registry.is_empty());
+    }
+}
@@ -0,0 +1,233 @@
+use super::{
+    color::{self, Color},
+    protocol::message::{Hsbk, Power, SetWaveformOptionalPayload, StatePayload, Waveform},
+};
+use serde::Serialize;
+
+/// Changes to make to a light. Only the fields which are set are changed, so part of a color can
+/// be set without reading the rest of it first.
+///
+/// Hue is in degrees, saturation and brightness are between 0.0 and 1.0, and kelvin is the color
+/// temperature.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct LightState {
+    power: Option<bool>,
+    hue: Option<f32>,
+    saturation: Option<f32>,
+    brightness: Option<f32>,
+    kelvin: Option<u16>,
+}
+
+impl LightState {
+    /// Create a state which changes nothing.
+    pub fn new() -> LightState {
+        LightState::default()
+    }
+
+    pub fn with_power(self, power: bool) -> LightState {
+        LightState {
+            power: Option::Some(power),
+            ..self
+        }
+    }
+
+    pub fn with_hue(self, hue: f32) -> LightState {
+        LightState {
+            hue: Option::Some(hue),
+            ..self
+        }
+    }
+
+    pub fn with_saturation(self, saturation: f32) -> LightState {
+        LightState {
+            saturation: Option::Some(saturation),
+            ..self
+        }
+    }
+
+    pub fn with_brightness(self, brightness: f32) -> LightState {
+        LightState {
+            brightness: Option::Some(brightness),
+            ..self
+        }
+    }
+
+    pub fn with_kelvin(self, kelvin: u16) -> LightState {
+        LightState {
+            kelvin: Option::Some(kelvin),
+            ..self
+        }
+    }
+
+    pub fn power(&self) -> Option<bool> {
+        self.power
+    }
+
+    pub fn hue(&self) -> Option<f32> {
+        self.hue
+    }
+
+    pub fn saturation(&self) -> Option<f32> {
+        self.saturation
+    }
+
+    pub fn brightness(&self) -> Option<f32> {
+        self.brightness
+    }
+
+    pub fn kelvin(&self) -> Option<u16> {
+        self.kelvin
+    }
+
+    /// Whether any component of the color is set.
+    pub fn changes_color(&self) -> bool {
+        self.hue.is_some()
+            || self.saturation.is_some()
+            || self.brightness.is_some()
+            || self.kelvin.is_some()
+    }
+
+    /// Return the color this state sets, if every component of it is set.
+    pub(crate) fn color(&self) -> Option<Hsbk> {
+        match (self.hue, self.saturation, self.brightness, self.kelvin) {
+            (Some(hue), Some(saturation), Some(brightness), Some(kelvin)) => {
+                Option::Some(Hsbk::new(
+                    color::degrees_to_u16(hue),
+                    color::denormalize(saturation),
+                    color::denormalize(brightness),
+                    kelvin,
+                ))
+            }
+            _ => Option::None,
+        }
+    }
+
+    /// Return `current` with the components set by this state replaced.
+    pub(crate) fn apply_to(&self, current: Hsbk) -> Hsbk {
+        self.components(0).apply_to(current)
+    }
+
+    /// Return a payload which changes only the components of the color which are set, over
+    /// `duration` milliseconds.
+    pub(crate) fn components(&self, duration: u32) -> SetWaveformOptionalPayload {
+        let color = Hsbk::new(
+            self.hue.map(color::degrees_to_u16).unwrap_or(0),
+            self.saturation.map(color::denormalize).unwrap_or(0),
+            self.brightness.map(color::denormalize).unwrap_or(0),
+            self.kelvin.unwrap_or(0),
+        );
+        // A single, non-transient cycle of a saw wave is a linear transition to the new color.
+        SetWaveformOptionalPayload::new(false, color, duration, 1.0, 0, Waveform::Saw)
+            .with_components(
+                self.hue.is_some(),
+                self.saturation.is_some(),
+                self.brightness.is_some(),
+                self.kelvin.is_some(),
+            )
+    }
+}
+
+/// The state of a light, as reported by the light.
+#[derive(Debug, Clone, Serialize)]
+pub struct LightStatus {
+    power: bool,
+    power_level: u16,
+    label: String,
+    // TOML requires tables to come after other values.
+    color: Color,
+}
+
+impl LightStatus {
+    pub fn color(&self) -> Color {
+        self.color
+    }
+
+    /// Whether the light is on.
+    pub fn power(&self) -> bool {
+        self.power
+    }
+
+    /// The power level reported by the light. Lights which are on usually report 65535, but may
+    /// report other non-zero levels.
+    pub fn power_level(&self) -> u16 {
+        self.power_level
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+}
+
+impl From<StatePayload> for LightStatus {
+    fn from(state: StatePayload) -> Self {
+        LightStatus {
+            power: state.power() != Power::Off,
+            power_level: state.power().level(),
+            label: state.label().to_string(),
+            color: state.color().into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partial_color() {
+        let current = Hsbk::new(0x5555, 0xffff, 0xffff, 3500);
+        let state = LightState::new().with_brightness(0.5).with_kelvin(6500);
+        assert!(state.changes_color());
+        assert_eq!(state.color(), Option::None);
+        assert_eq!(
+            state.apply_to(current),
+            Hsbk::new(0x5555, 0xffff, 0x7fff, 6500)
+        );
+
+        let payload = state.components(1000);
+        assert_eq!(payload.period(), 1000);
+        assert!(!payload.set_hue() && !payload.set_saturation());
+        assert!(payload.set_brightness() && payload.set_kelvin());
+    }
+
+    #[test]
+    fn full_color() {
+        let state = LightState::new()
+            .with_hue(120.0)
+            .with_saturation(1.0)
+            .with_brightness(1.0)
+            .with_kelvin(3500)
+            .with_power(true);
+        assert_eq!(
+            state.color(),
+            Option::Some(Hsbk::new(0x5555, 0xffff, 0xffff, 3500))
+        );
+        assert_eq!(state.power(), Option::Some(true));
+        assert!(!LightState::new().with_power(false).changes_color());
+    }
+
+    #[test]
+    fn serialize_status() {
+        let state = StatePayload::new(
+            Hsbk::new(0x8000, 0xffff, 0, 2700),
+            Power::On(0xfffe),
+            "Lamp".to_string(),
+        );
+        let status = LightStatus::from(state);
+        assert!(status.power());
+        assert_eq!(status.power_level(), 0xfffe);
+
+        assert_eq!(
+            toml::to_string(&status).unwrap(),
+            "power = true\n\
+             power_level = 65534\n\
+             label = \"Lamp\"\n\
+             \n\
+             [color]\n\
+             hue = 180.0\n\
+             saturation = 1.0\n\
+             brightness = 0.0\n\
+             kelvin = 2700\n"
+        );
+    }
+}
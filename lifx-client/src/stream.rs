@@ -0,0 +1,336 @@
+use super::{
+    client::{to_millis, Client, ZERO_DURATION},
+    color::Color,
+    connection::Watch,
+    device::Device,
+    protocol::{
+        header::MacAddress,
+        message::{Message, SetColorPayload},
+        packet::PacketBuilder,
+    },
+    transport::Transport,
+};
+use std::{
+    collections::HashMap,
+    io,
+    net::UdpSocket,
+    time::{Duration, Instant},
+};
+
+/// Sends a stream of colors to devices for real-time effects, such as animations or music
+/// visualizations.
+///
+/// Colors are sent without waiting for a response. A frame is dropped if its device has already
+/// used its rate limit budget, rather than waiting and falling further behind. A frame can be sent
+/// to each device periodically with an acknowledgement required, to detect devices which are
+/// unreachable.
+pub struct ColorStream<T: Transport = UdpSocket> {
+    client: Client<T>,
+    smoothing: Duration,
+    ack_interval: Option<Duration>,
+    devices: HashMap<MacAddress, Sampling<T>>,
+}
+
+// The acknowledgement sampling state of a single device.
+struct Sampling<T: Transport> {
+    last_sample: Instant,
+    pending: Option<(Watch<T>, Instant)>,
+    reachable: bool,
+}
+
+impl<T: Transport> ColorStream<T> {
+    pub(crate) fn new(client: Client<T>) -> ColorStream<T> {
+        ColorStream {
+            client,
+            smoothing: ZERO_DURATION,
+            ack_interval: Option::None,
+            devices: HashMap::new(),
+        }
+    }
+
+    /// Transition to each color over `smoothing`, to smooth out changes between frames. Usually
+    /// about as long as the time between frames. Defaults to no smoothing.
+    pub fn with_smoothing(mut self, smoothing: Duration) -> Self {
+        self.smoothing = smoothing;
+        self
+    }
+
+    /// Require an acknowledgement for one frame sent to each device every `interval`. A device is
+    /// unreachable if it does not acknowledge a frame within the client's timeout.
+    pub fn with_ack_sampling(mut self, interval: Duration) -> Self {
+        self.ack_interval = Option::Some(interval);
+        self
+    }
+
+    /// Send a frame to a device. Returns whether the frame was sent, or dropped because the device
+    /// has already used its rate limit budget.
+    pub fn send(&mut self, device: &Device, color: Color) -> io::Result<bool> {
+        self.update(device);
+
+        let sample = match (self.ack_interval, self.devices.get(&device.mac_address())) {
+            (Some(_), None) => true,
+            (Some(interval), Some(sampling)) => {
+                sampling.pending.is_none() && sampling.last_sample.elapsed() >= interval
+            }
+            (None, _) => false,
+        };
+
+        let payload = SetColorPayload::new(color.into(), to_millis(self.smoothing));
+        let packet = PacketBuilder::new(Message::SetColor(payload))
+            .target(device.mac_address())
+            .source(self.client.source())
+            .sequence(self.client.sequence())
+            .ack_required(sample)
+            .build();
+
+        // Acknowledgements are received in the background, so the watch must start before sending.
+        // The frame is sent without sampling if every sequence is in use.
+        let (watch, packet) = if sample {
+            match Watch::new(self.client.connection(), packet) {
+                Ok((watch, packet)) => (Option::Some(watch), packet),
+                Err(packet) => (Option::None, packet),
+            }
+        } else {
+            (Option::None, packet)
+        };
+        let sent = self
+            .client
+            .connection()
+            .try_send(device.socket_address(), &packet)?;
+
+        if sent {
+            self.client
+                .states()
+                .set_color(device.mac_address(), color.into(), self.smoothing);
+        }
+        // A sampled frame which could not be watched still counts as a sample, so the next one is
+        // not due until the interval has passed again.
+        if sent && sample {
+            let now = Instant::now();
+            let sampling = self
+                .devices
+                .entry(device.mac_address())
+                .or_insert_with(|| Sampling {
+                    last_sample: now,
+                    pending: Option::None,
+                    reachable: true,
+                });
+            sampling.last_sample = now;
+            sampling.pending = watch.map(|watch| (watch, now));
+        }
+        Result::Ok(sent)
+    }
+
+    /// Send a frame to each device. Returns the number of frames which were sent.
+    ///
+    /// Sampled frames which have been acknowledged or have timed out are checked for every device,
+    /// including devices which are no longer sent to, so their sequences can be reused.
+    pub fn send_frame<'a, I>(&mut self, frame: I) -> io::Result<usize>
+    where
+        I: IntoIterator<Item = (&'a Device, Color)>,
+    {
+        let timeout = self.client.connection().timeout();
+        for sampling in self.devices.values_mut() {
+            sampling.update(timeout);
+        }
+
+        let mut sent = 0;
+        for (device, color) in frame {
+            if self.send(device, color)? {
+                sent += 1;
+            }
+        }
+        Result::Ok(sent)
+    }
+
+    /// Return whether the device acknowledged the last sampled frame. Devices are assumed to be
+    /// reachable until a sampled frame is not acknowledged, or if sampling is disabled.
+    pub fn is_reachable(&mut self, device: &Device) -> bool {
+        self.update(device);
+        self.devices
+            .get(&device.mac_address())
+            .map(|sampling| sampling.reachable)
+            .unwrap_or(true)
+    }
+
+    /// Check whether the sampled frame sent to `device` has been acknowledged or has timed out.
+    fn update(&mut self, device: &Device) {
+        let timeout = self.client.connection().timeout();
+        if let Some(sampling) = self.devices.get_mut(&device.mac_address()) {
+            sampling.update(timeout);
+        }
+    }
+}
+
+impl<T: Transport> Sampling<T> {
+    // Stop waiting for the sampled frame once it is acknowledged or times out.
+    fn update(&mut self, timeout: Duration) {
+        if let Some((watch, sent)) = &self.pending {
+            if watch.try_recv().is_some() {
+                self.reachable = true;
+                self.pending = Option::None;
+            } else if sent.elapsed() >= timeout {
+                self.reachable = false;
+                self.pending = Option::None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        protocol::{header::DeviceMessageType, packet::Packet},
+        transport::{MemoryNetwork, MemoryTransport},
+    };
+    use std::{convert::TryFrom, net::SocketAddr, thread};
+
+    fn device(network: &MemoryNetwork, n: u8) -> (Device, MemoryTransport) {
+        let socket_address: SocketAddr = format!("10.0.0.{}:56700", n).parse().unwrap();
+        let transport = network.bind(socket_address).unwrap();
+        let address = format!("d0:73:d5:00:00:{:02x}#{}", n, socket_address)
+            .parse()
+            .unwrap();
+        let device = Device::new(address, format!("Lamp {}", n), String::new(), String::new());
+        (device, transport)
+    }
+
+    fn client(network: &MemoryNetwork) -> Client<MemoryTransport> {
+        let client = Client::new(network.bind("10.0.0.1:0".parse().unwrap()).unwrap());
+        client.set_timeout(Duration::from_millis(50));
+        client
+    }
+
+    fn receive(transport: &MemoryTransport) -> (Packet, SocketAddr) {
+        let mut buf = [0u8; 128];
+        let (n, addr) = transport
+            .recv_from(&mut buf, Duration::from_secs(5))
+            .unwrap();
+        (Packet::try_from(&buf[..n]).unwrap(), addr)
+    }
+
+    #[test]
+    fn send_without_response() {
+        let network = MemoryNetwork::new();
+        let (device, transport) = device(&network, 2);
+        let mut stream = client(&network)
+            .color_stream()
+            .with_smoothing(Duration::from_millis(40));
+
+        assert!(stream.send(&device, Color::GREEN).unwrap());
+        let (packet, _) = receive(&transport);
+        assert!(!packet.frame_address().res_required);
+        assert!(!packet.frame_address().ack_required);
+        assert_eq!(
+            packet.message(),
+            &Message::SetColor(SetColorPayload::new(Color::GREEN.into(), 40))
+        );
+    }
+
+    #[test]
+    fn drop_frames_when_device_falls_behind() {
+        let network = MemoryNetwork::new();
+        let (first, _first) = device(&network, 2);
+        let (second, _second) = device(&network, 3);
+        let client = client(&network);
+        client.set_rate_limit(Option::Some(10));
+        let mut stream = client.color_stream();
+
+        assert!(stream.send(&first, Color::RED).unwrap());
+        assert!(!stream.send(&first, Color::GREEN).unwrap());
+        assert_eq!(
+            stream
+                .send_frame(vec![(&first, Color::BLUE), (&second, Color::BLUE)])
+                .unwrap(),
+            1
+        );
+
+        thread::sleep(Duration::from_millis(100));
+        assert!(stream.send(&first, Color::GREEN).unwrap());
+    }
+
+    #[test]
+    fn ack_sampling() {
+        let network = MemoryNetwork::new();
+        let (online, transport) = device(&network, 2);
+        let (offline, _offline) = device(&network, 3);
+        let client = client(&network);
+        let mut stream = client
+            .color_stream()
+            .with_ack_sampling(Duration::from_secs(60));
+
+        // Acknowledge the first frame, which is sampled.
+        let responder = thread::spawn(move || {
+            let (request, client_address) = receive(&transport);
+            assert!(request.frame_address().ack_required);
+            let ack = PacketBuilder::with_empty_device_message(DeviceMessageType::Acknowledgement)
+                .source(request.frame().source)
+                .sequence(request.frame_address().sequence)
+                .build();
+            transport.send_to(&ack.as_bytes(), client_address).unwrap();
+            assert!(!receive(&transport).0.frame_address().ack_required);
+        });
+
+        assert!(stream.send(&online, Color::RED).unwrap());
+        assert!(stream.send(&offline, Color::RED).unwrap());
+        thread::sleep(Duration::from_millis(100));
+        assert!(stream.send(&online, Color::GREEN).unwrap());
+        responder.join().unwrap();
+
+        assert!(stream.is_reachable(&online));
+        assert!(!stream.is_reachable(&offline));
+    }
+
+    #[test]
+    fn expire_samples_for_devices_no_longer_sent_to() {
+        let network = MemoryNetwork::new();
+        let (first, _first) = device(&network, 2);
+        let (second, _second) = device(&network, 3);
+        let client = client(&network);
+        let mut stream = client
+            .color_stream()
+            .with_ack_sampling(Duration::from_secs(60));
+
+        assert_eq!(
+            stream
+                .send_frame(vec![(&first, Color::RED), (&second, Color::RED)])
+                .unwrap(),
+            2
+        );
+        thread::sleep(Duration::from_millis(100));
+
+        // Only the second device is sent to, but the first device's sample still times out.
+        stream.send_frame(vec![(&second, Color::GREEN)]).unwrap();
+        let sampling = &stream.devices[&first.mac_address()];
+        assert!(sampling.pending.is_none());
+        assert!(!sampling.reachable);
+    }
+
+    #[test]
+    fn sample_once_when_every_sequence_is_in_use() {
+        let network = MemoryNetwork::new();
+        let (device, transport) = device(&network, 2);
+        let client = client(&network);
+        let mut stream = client
+            .color_stream()
+            .with_ack_sampling(Duration::from_secs(60));
+
+        let watches: Vec<_> = (0..=u8::MAX)
+            .map(|_| {
+                let packet = PacketBuilder::with_empty_device_message(DeviceMessageType::GetPower)
+                    .source(client.source())
+                    .build();
+                Watch::new(client.connection(), packet).ok().unwrap().0
+            })
+            .collect();
+
+        // The first frame cannot be watched, but the next frame is not sampled again.
+        assert!(stream.send(&device, Color::RED).unwrap());
+        assert!(stream.send(&device, Color::GREEN).unwrap());
+        assert!(receive(&transport).0.frame_address().ack_required);
+        assert!(!receive(&transport).0.frame_address().ack_required);
+        assert!(stream.is_reachable(&device));
+        drop(watches);
+    }
+}
@@ -0,0 +1,10 @@
+//! Helpers shared by the unit tests.
+
+use crate::protocol::header::MacAddress;
+
+/// Return a MAC address with the LIFX prefix which ends in `n`.
+pub(crate) fn mac(n: u8) -> MacAddress {
+    MacAddress {
+        address: [0xd0, 0x73, 0xd5, 0x00, 0x00, n],
+    }
+}
@@ -0,0 +1,265 @@
+//! Transports used by a [`Client`](crate::client::Client) to send and receive datagrams.
+//!
+//! [`UdpSocket`] is the transport used to communicate with real devices. [`MemoryNetwork`]
+//! provides in-memory transports which can be used to test without a network.
+use std::{
+    collections::HashMap,
+    io,
+    net::{SocketAddr, UdpSocket},
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+/// A datagram transport. Transports are shared with the background thread which receives
+/// responses, so they must be `Send` and `Sync`.
+pub trait Transport: Send + Sync + 'static {
+    /// Send a datagram to the specified address.
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize>;
+
+    /// Receive a single datagram, waiting at most `timeout`. Returns an error of kind
+    /// `WouldBlock` or `TimedOut` if no datagram was received in time.
+    fn recv_from(&self, buf: &mut [u8], timeout: Duration) -> io::Result<(usize, SocketAddr)>;
+
+    /// Send a datagram to a broadcast address.
+    fn broadcast(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize>;
+
+    /// Allow datagrams to be sent to broadcast addresses. Called once when a client is created,
+    /// since changing it while other threads are sending would race with them.
+    fn enable_broadcast(&self) -> io::Result<()> {
+        Result::Ok(())
+    }
+
+    /// Return the local address of this transport.
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+impl Transport for UdpSocket {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, buf, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8], timeout: Duration) -> io::Result<(usize, SocketAddr)> {
+        // A zero duration is not a valid read timeout.
+        let timeout = Duration::max(timeout, Duration::from_millis(1));
+        self.set_read_timeout(Option::Some(timeout))?;
+        UdpSocket::recv_from(self, buf)
+    }
+
+    // Broadcasting is enabled once by `enable_broadcast`, rather than around each send.
+    fn broadcast(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, buf, addr)
+    }
+
+    fn enable_broadcast(&self) -> io::Result<()> {
+        self.set_broadcast(true)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+}
+
+/// A shared transport, so that a transport can be inspected or reconfigured while it is in use.
+impl<T: Transport> Transport for Arc<T> {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        T::send_to(self, buf, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8], timeout: Duration) -> io::Result<(usize, SocketAddr)> {
+        T::recv_from(self, buf, timeout)
+    }
+
+    fn broadcast(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        T::broadcast(self, buf, addr)
+    }
+
+    fn enable_broadcast(&self) -> io::Result<()> {
+        T::enable_broadcast(self)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        T::local_addr(self)
+    }
+}
+
+type Endpoints = HashMap<SocketAddr, Sender<(Vec<u8>, SocketAddr)>>;
+
+// The first port assigned to transports bound to port 0.
+const EPHEMERAL_PORT_START: u16 = 49152;
+
+/// An in-memory network. Datagrams are delivered between transports bound to the same network
+/// without any loss or reordering.
+#[derive(Clone, Default)]
+pub struct MemoryNetwork {
+    endpoints: Arc<Mutex<Endpoints>>,
+}
+
+impl MemoryNetwork {
+    pub fn new() -> MemoryNetwork {
+        MemoryNetwork::default()
+    }
+
+    /// Create a transport bound to `addr`. If the port is 0, an unused port is assigned.
+    pub fn bind(&self, addr: SocketAddr) -> io::Result<MemoryTransport> {
+        let mut endpoints = self.lock();
+        let mut addr = addr;
+        if addr.port() == 0 {
+            let port = (EPHEMERAL_PORT_START..=u16::MAX)
+                .find(|port| !endpoints.contains_key(&SocketAddr::new(addr.ip(), *port)))
+                .ok_or_else(|| io::Error::from(io::ErrorKind::AddrInUse))?;
+            addr.set_port(port);
+        } else if endpoints.contains_key(&addr) {
+            return Result::Err(io::Error::from(io::ErrorKind::AddrInUse));
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        endpoints.insert(addr, sender);
+        Result::Ok(MemoryTransport {
+            addr,
+            network: self.clone(),
+            receiver: Mutex::new(receiver),
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Endpoints> {
+        // Endpoints are only inserted and removed, so the map is always valid.
+        self.endpoints
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// A transport which sends datagrams over a [`MemoryNetwork`].
+pub struct MemoryTransport {
+    addr: SocketAddr,
+    network: MemoryNetwork,
+    receiver: Mutex<Receiver<(Vec<u8>, SocketAddr)>>,
+}
+
+impl Transport for MemoryTransport {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let endpoints = self.network.lock();
+        // Fall back to a transport bound to the unspecified address, as with a real socket.
+        let endpoint = endpoints.get(&addr).or_else(|| {
+            endpoints
+                .iter()
+                .find(|(a, _)| a.ip().is_unspecified() && a.port() == addr.port())
+                .map(|(_, endpoint)| endpoint)
+        });
+        // As with UDP, datagrams sent to an address with no receiver are silently dropped.
+        if let Some(endpoint) = endpoint {
+            let _ = endpoint.send((buf.to_vec(), self.addr));
+        }
+        Result::Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8], timeout: Duration) -> io::Result<(usize, SocketAddr)> {
+        let receiver = self
+            .receiver
+            .lock()
+            .map_err(|_| io::Error::other("Could not acquire receiver lock"))?;
+        match receiver.recv_timeout(timeout) {
+            Ok((bytes, addr)) => {
+                // As with UDP, excess bytes are discarded.
+                let n = usize::min(bytes.len(), buf.len());
+                buf[..n].copy_from_slice(&bytes[..n]);
+                Result::Ok((n, addr))
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                Result::Err(io::Error::from(io::ErrorKind::WouldBlock))
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                Result::Err(io::Error::from(io::ErrorKind::NotConnected))
+            }
+        }
+    }
+
+    /// Send a datagram to every other transport bound to the same port as `addr`.
+    fn broadcast(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let endpoints = self.network.lock();
+        for (endpoint_addr, endpoint) in endpoints.iter() {
+            if endpoint_addr.port() == addr.port() && *endpoint_addr != self.addr {
+                let _ = endpoint.send((buf.to_vec(), self.addr));
+            }
+        }
+        Result::Ok(buf.len())
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Result::Ok(self.addr)
+    }
+}
+
+impl Drop for MemoryTransport {
+    fn drop(&mut self) {
+        self.network.lock().remove(&self.addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn send_and_receive() {
+        let network = MemoryNetwork::new();
+        let a = network.bind(addr("10.0.0.1:0")).unwrap();
+        let b = network.bind(addr("10.0.0.2:56700")).unwrap();
+
+        a.send_to(&[1, 2, 3], addr("10.0.0.2:56700")).unwrap();
+        let mut buf = [0u8; 8];
+        let (n, from) = b.recv_from(&mut buf, Duration::from_secs(1)).unwrap();
+        assert_eq!(&buf[..n], &[1, 2, 3]);
+        assert_eq!(from, a.local_addr().unwrap());
+    }
+
+    #[test]
+    fn receive_timeout() {
+        let network = MemoryNetwork::new();
+        let a = network.bind(addr("10.0.0.1:0")).unwrap();
+
+        let mut buf = [0u8; 8];
+        let error = a
+            .recv_from(&mut buf, Duration::from_millis(10))
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn broadcast() {
+        let network = MemoryNetwork::new();
+        let client = network.bind(addr("10.0.0.1:56700")).unwrap();
+        let device_1 = network.bind(addr("10.0.0.2:56700")).unwrap();
+        let device_2 = network.bind(addr("10.0.0.3:56700")).unwrap();
+        let other = network.bind(addr("10.0.0.4:1234")).unwrap();
+
+        client
+            .broadcast(&[42], addr("255.255.255.255:56700"))
+            .unwrap();
+
+        let mut buf = [0u8; 8];
+        let timeout = Duration::from_millis(10);
+        assert!(device_1.recv_from(&mut buf, timeout).is_ok());
+        assert!(device_2.recv_from(&mut buf, timeout).is_ok());
+        assert!(other.recv_from(&mut buf, timeout).is_err());
+        assert!(client.recv_from(&mut buf, timeout).is_err());
+    }
+
+    #[test]
+    fn address_in_use() {
+        let network = MemoryNetwork::new();
+        let a = network.bind(addr("10.0.0.1:56700")).unwrap();
+        assert!(network.bind(addr("10.0.0.1:56700")).is_err());
+
+        // The address can be reused once the transport is dropped.
+        drop(a);
+        assert!(network.bind(addr("10.0.0.1:56700")).is_ok());
+    }
+}
@@ -0,0 +1,259 @@
+use super::{
+    client::Client,
+    device::Device,
+    protocol::{header::MacAddress, message::StatePayload},
+    transport::Transport,
+};
+use std::{
+    collections::HashMap,
+    io,
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+/// A change to the state of a device, found by a [`StateWatcher`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum StateEvent {
+    /// The color or power of a device changed.
+    StateChanged {
+        device: Device,
+        old: StatePayload,
+        new: StatePayload,
+    },
+    /// A device did not respond.
+    Unreachable(Device),
+    /// A device which was unreachable responded again.
+    Reachable(Device),
+}
+
+/// Polls the state of devices on a background thread, and reports each change. The thread stops
+/// when the watcher is stopped or dropped.
+///
+/// Each device is sent a Get message every interval, which is answered with its color and power.
+/// Devices are polled concurrently, and always queried even if their state is cached. The first
+/// state of each device is not reported as a change.
+pub struct StateWatcher {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl StateWatcher {
+    /// Poll `devices` every `interval` and send each change to the returned receiver.
+    pub fn start<T: Transport>(
+        client: Client<T>,
+        devices: Vec<Device>,
+        interval: Duration,
+    ) -> (StateWatcher, Receiver<StateEvent>) {
+        let (sender, receiver) = mpsc::channel();
+        let watcher = StateWatcher::with_callback(client, devices, interval, move |event| {
+            let _ = sender.send(event);
+        });
+        (watcher, receiver)
+    }
+
+    /// Poll `devices` every `interval` and call `callback` with each change.
+    pub fn with_callback<T, F>(
+        client: Client<T>,
+        devices: Vec<Device>,
+        interval: Duration,
+        mut callback: F,
+    ) -> StateWatcher
+    where
+        T: Transport,
+        F: FnMut(StateEvent) + Send + 'static,
+    {
+        let (stop, stopped) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name("lifx-client-watcher".to_string())
+            .spawn(move || {
+                let mut tracker = Tracker::new();
+                loop {
+                    let results =
+                        client.apply(&devices, |client, device| client.query_state(device));
+                    tracker.update(results).into_iter().for_each(&mut callback);
+
+                    match stopped.recv_timeout(interval) {
+                        Err(RecvTimeoutError::Timeout) => continue,
+                        _ => return,
+                    }
+                }
+            })
+            .expect("Could not start watcher thread");
+
+        StateWatcher {
+            stop: Option::Some(stop),
+            thread: Option::Some(thread),
+        }
+    }
+
+    /// Stop polling and wait for the background thread to finish.
+    pub fn stop(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for StateWatcher {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Tracks the state of each device from one round of polling to the next.
+struct Tracker {
+    watched: HashMap<MacAddress, Watched>,
+}
+
+#[derive(Default)]
+struct Watched {
+    state: Option<StatePayload>,
+    unreachable: bool,
+}
+
+impl Tracker {
+    fn new() -> Tracker {
+        Tracker {
+            watched: HashMap::new(),
+        }
+    }
+
+    /// Record the result of polling each device and return what changed. Results are handled in
+    /// order of label, so events are reported in a consistent order.
+    fn update(&mut self, results: HashMap<Device, io::Result<StatePayload>>) -> Vec<StateEvent> {
+        let mut results: Vec<_> = results.into_iter().collect();
+        results.sort_by(|(a, _), (b, _)| a.label().cmp(b.label()));
+
+        let mut events = Vec::new();
+        for (device, result) in results {
+            let watched = self.watched.entry(device.mac_address()).or_default();
+            match result {
+                Ok(state) => {
+                    if watched.unreachable {
+                        watched.unreachable = false;
+                        events.push(StateEvent::Reachable(device.clone()));
+                    }
+                    if let Some(old) = watched.state.replace(state.clone()) {
+                        if old != state {
+                            events.push(StateEvent::StateChanged {
+                                device,
+                                old,
+                                new: state,
+                            });
+                        }
+                    }
+                }
+                Err(_) => {
+                    if !watched.unreachable {
+                        watched.unreachable = true;
+                        events.push(StateEvent::Unreachable(device));
+                    }
+                }
+            }
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::message::{Hsbk, Power};
+
+    fn device(n: u8, label: &str) -> Device {
+        let address = format!("d0:73:d5:00:00:{:02x}#10.0.0.{}:56700", n, n)
+            .parse()
+            .unwrap();
+        Device::new(address, label.to_string(), String::new(), String::new())
+    }
+
+    fn state(power: Power, brightness: u16) -> StatePayload {
+        let color = Hsbk::new(0, 0, brightness, 3500);
+        StatePayload::new(color, power, String::new())
+    }
+
+    fn timed_out() -> io::Error {
+        io::Error::new(io::ErrorKind::TimedOut, "Timed out.")
+    }
+
+    #[test]
+    fn track_state_changes() {
+        let kitchen = device(1, "Kitchen");
+        let office = device(2, "Office");
+        let mut tracker = Tracker::new();
+
+        let mut results = HashMap::new();
+        results.insert(kitchen.clone(), Result::Ok(state(Power::Off, 0xffff)));
+        results.insert(office.clone(), Result::Ok(state(Power::Off, 0xffff)));
+        assert_eq!(tracker.update(results), vec![]);
+
+        let mut results = HashMap::new();
+        results.insert(
+            kitchen.clone(),
+            Result::Ok(state(Power::On(0xffff), 0xffff)),
+        );
+        results.insert(office.clone(), Result::Ok(state(Power::Off, 0x7fff)));
+        assert_eq!(
+            tracker.update(results),
+            vec![
+                StateEvent::StateChanged {
+                    device: kitchen.clone(),
+                    old: state(Power::Off, 0xffff),
+                    new: state(Power::On(0xffff), 0xffff),
+                },
+                StateEvent::StateChanged {
+                    device: office.clone(),
+                    old: state(Power::Off, 0xffff),
+                    new: state(Power::Off, 0x7fff),
+                },
+            ]
+        );
+
+        let mut results = HashMap::new();
+        results.insert(kitchen, Result::Ok(state(Power::On(0xffff), 0xffff)));
+        results.insert(office, Result::Ok(state(Power::Off, 0x7fff)));
+        assert_eq!(tracker.update(results), vec![]);
+    }
+
+    #[test]
+    fn track_reachability() {
+        let kitchen = device(1, "Kitchen");
+        let mut tracker = Tracker::new();
+        let mut poll = |result: io::Result<StatePayload>| {
+            let mut results = HashMap::new();
+            results.insert(kitchen.clone(), result);
+            tracker.update(results)
+        };
+
+        // Devices which never respond are unreachable, but only reported once.
+        assert_eq!(
+            poll(Result::Err(timed_out())),
+            vec![StateEvent::Unreachable(kitchen.clone())]
+        );
+        assert_eq!(poll(Result::Err(timed_out())), vec![]);
+
+        assert_eq!(
+            poll(Result::Ok(state(Power::Off, 0xffff))),
+            vec![StateEvent::Reachable(kitchen.clone())]
+        );
+        assert_eq!(
+            poll(Result::Err(timed_out())),
+            vec![StateEvent::Unreachable(kitchen.clone())]
+        );
+
+        // A device which changed while it was unreachable reports both.
+        assert_eq!(
+            poll(Result::Ok(state(Power::On(0xffff), 0xffff))),
+            vec![
+                StateEvent::Reachable(kitchen.clone()),
+                StateEvent::StateChanged {
+                    device: kitchen.clone(),
+                    old: state(Power::Off, 0xffff),
+                    new: state(Power::On(0xffff), 0xffff),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,176 @@
+mod options;
+mod output;
+
+use lifx_client::{
+    pcap::{Datagram, PcapReader},
+    protocol::packet::Packet,
+};
+use options::{Options, USAGE};
+use std::{
+    convert::TryFrom,
+    fs::File,
+    io::{self, BufReader, Write},
+    net::{Ipv4Addr, UdpSocket},
+    path::Path,
+    process,
+    time::SystemTime,
+};
+
+fn main() {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            process::exit(1);
+        }
+    };
+
+    if options.help {
+        println!("{}", USAGE);
+        return;
+    }
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let result = match &options.read {
+        Some(path) => dump_file(path, &options, &mut out),
+        None => listen(&options, &mut out),
+    };
+
+    match result {
+        Ok(()) => {}
+        // Stop quietly if the output is closed, e.g. when piped to `head`.
+        Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => {}
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Print all LIFX packets in a capture file.
+fn dump_file(path: &Path, options: &Options, out: &mut impl Write) -> io::Result<()> {
+    let reader = PcapReader::new(BufReader::new(File::open(path)?))?;
+    for datagram in reader {
+        let datagram = datagram?;
+        if datagram.source.port() == options.port || datagram.destination.port() == options.port {
+            print_datagram(&datagram, options, out)?;
+        }
+    }
+    Result::Ok(())
+}
+
+/// Print LIFX packets as they are received.
+fn listen(options: &Options, out: &mut impl Write) -> io::Result<()> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, options.port))?;
+    let destination = socket.local_addr()?;
+    eprintln!("Listening on {}.", destination);
+
+    let mut buf = [0u8; 1024];
+    loop {
+        let (n, source) = socket.recv_from(&mut buf)?;
+        let datagram = Datagram {
+            timestamp: SystemTime::now(),
+            source,
+            destination,
+            payload: buf[..n].to_vec(),
+        };
+        print_datagram(&datagram, options, out)?;
+        out.flush()?;
+    }
+}
+
+fn print_datagram(datagram: &Datagram, options: &Options, out: &mut impl Write) -> io::Result<()> {
+    match Packet::try_from(&datagram.payload[..]) {
+        Ok(packet) if options.matches(&packet) => {
+            let line = if options.json {
+                output::json(datagram, &packet)
+            } else {
+                output::text(datagram, &packet)
+            };
+            writeln!(out, "{}", line)
+        }
+        Ok(_) => Result::Ok(()),
+        Err(e) => {
+            eprintln!(
+                "{} -> {}: could not decode packet. {}",
+                datagram.source, datagram.destination, e
+            );
+            Result::Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lifx_client::{
+        pcap::PcapWriter,
+        protocol::{
+            header::{DeviceMessageType, LightMessageType},
+            packet::PacketBuilder,
+        },
+    };
+    use std::time::UNIX_EPOCH;
+
+    fn datagram(destination: &str, payload: Vec<u8>) -> Datagram {
+        Datagram {
+            timestamp: UNIX_EPOCH,
+            source: "10.0.0.1:51234".parse().unwrap(),
+            destination: destination.parse().unwrap(),
+            payload,
+        }
+    }
+
+    fn get_service() -> Vec<u8> {
+        PacketBuilder::with_empty_device_message(DeviceMessageType::GetService)
+            .build()
+            .as_bytes()
+    }
+
+    fn print(datagram: &Datagram, args: &[&str]) -> String {
+        let options = Options::parse(args.iter().map(|arg| arg.to_string())).unwrap();
+        let mut out = Vec::new();
+        print_datagram(datagram, &options, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn print_matching_packets() {
+        let datagram = datagram("10.0.0.255:56700", get_service());
+        assert!(print(&datagram, &[]).contains(" GetService (2) "));
+        assert!(print(&datagram, &["-j"]).starts_with('{'));
+        assert_eq!(print(&datagram, &["-t", "GetLabel"]), "");
+    }
+
+    #[test]
+    fn skip_undecodable_packets() {
+        let datagram = datagram("10.0.0.255:56700", vec![1, 2, 3]);
+        assert_eq!(print(&datagram, &[]), "");
+    }
+
+    #[test]
+    fn dump_lifx_port_from_file() {
+        let get = PacketBuilder::with_empty_light_message(LightMessageType::Get)
+            .build()
+            .as_bytes();
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        writer
+            .write(&datagram("10.0.0.255:56700", get_service()))
+            .unwrap();
+        // Another port is skipped, even if it carries a LIFX packet.
+        writer.write(&datagram("10.0.0.2:56800", get)).unwrap();
+        let path = std::env::temp_dir().join(format!("lifx-dump-{}.pcap", process::id()));
+        std::fs::write(&path, writer.into_inner()).unwrap();
+
+        let options = Options::parse(std::iter::empty()).unwrap();
+        let mut out = Vec::new();
+        let result = dump_file(&path, &options, &mut out);
+        std::fs::remove_file(&path).unwrap();
+        result.unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.lines().count(), 1);
+        assert!(out.contains(" GetService (2) "));
+    }
+}
@@ -0,0 +1,172 @@
+use lifx_client::protocol::{header::MacAddress, packet::Packet};
+use std::path::PathBuf;
+
+pub(crate) const USAGE: &str = "\
+Usage: lifx-dump [OPTIONS]
+
+Decode LIFX packets received on a UDP port or read from a pcap or pcapng file.
+
+Options:
+  -r, --read <FILE>  Read packets from a pcap or pcapng file instead of listening.
+  -p, --port <PORT>  The LIFX UDP port. [default: 56700]
+  -j, --json         Print one JSON object per packet instead of text.
+  -m, --mac <MAC>    Only show packets targeting this MAC address. Can be repeated.
+  -t, --type <TYPE>  Only show messages of this type, by name or number. Can be repeated.
+  -h, --help         Print this message.";
+
+pub(crate) struct Options {
+    pub(crate) help: bool,
+    pub(crate) read: Option<PathBuf>,
+    pub(crate) port: u16,
+    pub(crate) json: bool,
+    macs: Vec<MacAddress>,
+    types: Vec<String>,
+}
+
+impl Options {
+    pub(crate) fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+        let mut options = Options {
+            help: false,
+            read: Option::None,
+            port: 56700,
+            json: false,
+            macs: Vec::new(),
+            types: Vec::new(),
+        };
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-h" | "--help" => options.help = true,
+                "-j" | "--json" => options.json = true,
+                "-r" | "--read" => {
+                    options.read = Option::Some(PathBuf::from(value(&arg, &mut args)?))
+                }
+                "-p" | "--port" => {
+                    let port = value(&arg, &mut args)?;
+                    options.port = port
+                        .parse()
+                        .map_err(|_| format!("Invalid port: {}.", port))?;
+                }
+                "-m" | "--mac" => options.macs.push(value(&arg, &mut args)?.parse()?),
+                "-t" | "--type" => options.types.push(value(&arg, &mut args)?),
+                _ => return Result::Err(format!("Unknown option: {}.", arg)),
+            }
+        }
+
+        Result::Ok(options)
+    }
+
+    /// Whether a packet passes the MAC address and message type filters.
+    pub(crate) fn matches(&self, packet: &Packet) -> bool {
+        let target = packet.frame_address().target;
+        let message_type = packet.message().message_type();
+
+        let mac_matches = self.macs.is_empty() || self.macs.contains(&target);
+        let type_matches = self.types.is_empty()
+            || self.types.iter().any(|t| {
+                t.eq_ignore_ascii_case(&message_type.to_string())
+                    || t.parse() == Ok(message_type.value())
+            });
+        mac_matches && type_matches
+    }
+}
+
+fn value<I: Iterator<Item = String>>(option: &str, args: &mut I) -> Result<String, String> {
+    args.next()
+        .ok_or_else(|| format!("Missing value for {}.", option))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lifx_client::protocol::{
+        header::{DeviceMessageType, LightMessageType},
+        packet::PacketBuilder,
+    };
+    use lifx_emulator::testing::mac;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        Options::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn get(target: MacAddress) -> Packet {
+        PacketBuilder::with_empty_light_message(LightMessageType::Get)
+            .target(target)
+            .build()
+    }
+
+    #[test]
+    fn defaults() {
+        let options = parse(&[]).unwrap();
+        assert!(!options.help);
+        assert!(!options.json);
+        assert_eq!(options.read, Option::None);
+        assert_eq!(options.port, 56700);
+    }
+
+    #[test]
+    fn parse_options() {
+        let options = parse(&["-j", "--read", "capture.pcap", "-p", "56800", "--help"]).unwrap();
+        assert!(options.help);
+        assert!(options.json);
+        assert_eq!(options.read, Option::Some(PathBuf::from("capture.pcap")));
+        assert_eq!(options.port, 56800);
+    }
+
+    #[test]
+    fn invalid_options() {
+        assert_eq!(
+            parse(&["--verbose"]).err(),
+            Option::Some("Unknown option: --verbose.".to_string())
+        );
+        assert_eq!(
+            parse(&["--port"]).err(),
+            Option::Some("Missing value for --port.".to_string())
+        );
+        assert_eq!(
+            parse(&["-p", "70000"]).err(),
+            Option::Some("Invalid port: 70000.".to_string())
+        );
+        assert!(parse(&["--mac", "d0:73:d5:00:00:zz"]).is_err());
+        assert!(parse(&["-t"]).is_err());
+    }
+
+    #[test]
+    fn match_everything_without_filters() {
+        let options = parse(&[]).unwrap();
+        assert!(options.matches(&get(mac(1))));
+    }
+
+    #[test]
+    fn match_macs() {
+        let options = parse(&["-m", "d0:73:d5:00:00:01", "--mac", "d0:73:d5:00:00:02"]).unwrap();
+        assert!(options.matches(&get(mac(1))));
+        assert!(options.matches(&get(mac(2))));
+        assert!(!options.matches(&get(mac(3))));
+    }
+
+    #[test]
+    fn match_types_by_name_or_number() {
+        let options = parse(&["-t", "getservice", "--type", "101"]).unwrap();
+        let get_service =
+            PacketBuilder::with_empty_device_message(DeviceMessageType::GetService).build();
+        let get_label =
+            PacketBuilder::with_empty_device_message(DeviceMessageType::GetLabel).build();
+        assert!(options.matches(&get_service));
+        assert!(options.matches(&get(mac(1))));
+        assert!(!options.matches(&get_label));
+    }
+
+    #[test]
+    fn match_macs_and_types() {
+        let options = parse(&["-m", "d0:73:d5:00:00:01", "-t", "GetLabel"]).unwrap();
+        let get_label = |target| {
+            PacketBuilder::with_empty_device_message(DeviceMessageType::GetLabel)
+                .target(target)
+                .build()
+        };
+        assert!(options.matches(&get_label(mac(1))));
+        assert!(!options.matches(&get_label(mac(2))));
+        assert!(!options.matches(&get(mac(1))));
+    }
+}
@@ -0,0 +1,310 @@
+use lifx_client::{
+    pcap::Datagram,
+    protocol::{
+        message::{Hsbk, Message, Waveform},
+        packet::Packet,
+    },
+};
+use serde_json::{json, Value};
+use std::time::UNIX_EPOCH;
+
+/// Format a packet as a single line of text.
+pub(crate) fn text(datagram: &Datagram, packet: &Packet) -> String {
+    let frame_address = packet.frame_address();
+    let message = packet.message();
+
+    let mut flags = Vec::new();
+    if packet.frame().tagged {
+        flags.push("tagged");
+    }
+    if frame_address.res_required {
+        flags.push("res_required");
+    }
+    if frame_address.ack_required {
+        flags.push("ack_required");
+    }
+
+    let mut line = format!(
+        "{:.6} {} -> {} {} ({}) source={:08x} sequence={} target={}",
+        timestamp(datagram),
+        datagram.source,
+        datagram.destination,
+        message.message_type(),
+        message.message_type().value(),
+        packet.frame().source,
+        frame_address.sequence,
+        frame_address.target,
+    );
+    if !flags.is_empty() {
+        line.push(' ');
+        line.push_str(&flags.join(","));
+    }
+    if let Some(payload) = payload_text(message) {
+        line.push(' ');
+        line.push_str(&payload);
+    }
+    line
+}
+
+/// Format a packet as a single line of JSON.
+pub(crate) fn json(datagram: &Datagram, packet: &Packet) -> String {
+    let frame_address = packet.frame_address();
+    let message = packet.message();
+
+    json!({
+        "timestamp": timestamp(datagram),
+        "source_address": datagram.source.to_string(),
+        "destination_address": datagram.destination.to_string(),
+        "source": packet.frame().source,
+        "sequence": frame_address.sequence,
+        "target": frame_address.target,
+        "tagged": packet.frame().tagged,
+        "res_required": frame_address.res_required,
+        "ack_required": frame_address.ack_required,
+        "type": message.message_type().to_string(),
+        "type_value": message.message_type().value(),
+        "payload": payload_json(message),
+    })
+    .to_string()
+}
+
+fn timestamp(datagram: &Datagram) -> f64 {
+    datagram
+        .timestamp
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or(0.0)
+}
+
+fn payload_text(message: &Message) -> Option<String> {
+    let text = match message {
+        Message::Empty(_) => return Option::None,
+        Message::Bytes(_, bytes) => format!("payload={}", hex(bytes)),
+        Message::StateService(payload) => {
+            format!("service={} port={}", payload.service(), payload.port())
+        }
+        Message::StatePower(payload) => format!("power={}", payload.power().level()),
+        Message::StateLabel(payload) => format!("label=\"{}\"", payload.label()),
+        Message::StateVersion(payload) => {
+            format!("vendor={} product={}", payload.vendor(), payload.product())
+        }
+        Message::StateGroup(payload) => format!(
+            "group={} label=\"{}\" updated_at={}",
+            hex(&payload.group()),
+            payload.label(),
+            payload.updated_at()
+        ),
+        Message::StateLocation(payload) => format!(
+            "location={} label=\"{}\" updated_at={}",
+            hex(&payload.location()),
+            payload.label(),
+            payload.updated_at()
+        ),
+        Message::EchoRequest(payload) => format!("payload={}", hex(&payload.payload())),
+        Message::EchoResponse(payload) => format!("payload={}", hex(&payload.payload())),
+        Message::State(payload) => format!(
+            "{} power={} label=\"{}\"",
+            hsbk(payload.color()),
+            payload.power().level(),
+            payload.label()
+        ),
+        Message::SetColor(payload) => {
+            format!("{} duration={}", hsbk(payload.color()), payload.duration())
+        }
+        Message::SetPower(payload) => format!(
+            "power={} duration={}",
+            payload.power().level(),
+            payload.duration()
+        ),
+        Message::SetWaveformOptional(payload) => {
+            let color = payload.color();
+            let mut text = format!(
+                "transient={} waveform={} period={} cycles={} skew_ratio={}",
+                payload.transient(),
+                waveform(payload.waveform()),
+                payload.period(),
+                payload.cycles(),
+                payload.skew_ratio()
+            );
+            // Only the components which are set are changed, so the others are omitted.
+            let components = [
+                (payload.set_hue(), "hue", color.hue()),
+                (payload.set_saturation(), "saturation", color.saturation()),
+                (payload.set_brightness(), "brightness", color.brightness()),
+                (payload.set_kelvin(), "kelvin", color.kelvin()),
+            ];
+            for (set, name, value) in components.iter() {
+                if *set {
+                    text.push_str(&format!(" {}={}", name, value));
+                }
+            }
+            text
+        }
+    };
+    Option::Some(text)
+}
+
+fn hsbk(color: Hsbk) -> String {
+    format!(
+        "hue={} saturation={} brightness={} kelvin={}",
+        color.hue(),
+        color.saturation(),
+        color.brightness(),
+        color.kelvin()
+    )
+}
+
+fn waveform(waveform: Waveform) -> &'static str {
+    match waveform {
+        Waveform::Saw => "saw",
+        Waveform::Sine => "sine",
+        Waveform::HalfSine => "half_sine",
+        Waveform::Triangle => "triangle",
+        Waveform::Pulse => "pulse",
+    }
+}
+
+fn payload_json(message: &Message) -> Value {
+    let value = match message {
+        Message::Empty(_) => return Value::Null,
+        Message::Bytes(_, bytes) => return Value::String(hex(bytes)),
+        Message::StateService(payload) => serde_json::to_value(payload),
+        Message::StatePower(payload) => serde_json::to_value(payload),
+        Message::StateLabel(payload) => serde_json::to_value(payload),
+        Message::StateVersion(payload) => serde_json::to_value(payload),
+        Message::StateGroup(payload) => serde_json::to_value(payload),
+        Message::StateLocation(payload) => serde_json::to_value(payload),
+        Message::EchoRequest(payload) => serde_json::to_value(payload),
+        Message::EchoResponse(payload) => serde_json::to_value(payload),
+        Message::State(payload) => serde_json::to_value(payload),
+        Message::SetColor(payload) => serde_json::to_value(payload),
+        Message::SetPower(payload) => serde_json::to_value(payload),
+        Message::SetWaveformOptional(payload) => serde_json::to_value(payload),
+    };
+    value.unwrap_or(Value::Null)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lifx_client::protocol::{
+        header::{DeviceMessageType, MacAddress},
+        message::{SetColorPayload, SetWaveformOptionalPayload, StatePayload, StateServicePayload},
+        packet::PacketBuilder,
+    };
+    use lifx_client::Power;
+    use std::time::Duration;
+
+    const TARGET: MacAddress = MacAddress {
+        address: [0xd0, 0x73, 0xd5, 0x01, 0x02, 0x03],
+    };
+
+    fn datagram() -> Datagram {
+        Datagram {
+            timestamp: UNIX_EPOCH + Duration::from_millis(1_600_000_000_250),
+            source: "192.168.1.10:51234".parse().unwrap(),
+            destination: "192.168.1.20:56700".parse().unwrap(),
+            payload: Vec::new(),
+        }
+    }
+
+    fn set_color() -> Packet {
+        let color = Hsbk::new(0x5555, 0xffff, 0x7fff, 3500);
+        PacketBuilder::new(Message::SetColor(SetColorPayload::new(color, 1024)))
+            .source(0x1234_5678)
+            .sequence(7)
+            .target(TARGET)
+            .res_required(true)
+            .build()
+    }
+
+    #[test]
+    fn text_line() {
+        assert_eq!(
+            text(&datagram(), &set_color()),
+            "1600000000.250000 192.168.1.10:51234 -> 192.168.1.20:56700 SetColor (102) \
+             source=12345678 sequence=7 target=d0:73:d5:01:02:03 res_required \
+             hue=21845 saturation=65535 brightness=32767 kelvin=3500 duration=1024"
+        );
+    }
+
+    #[test]
+    fn text_without_payload() {
+        let packet = PacketBuilder::with_empty_device_message(DeviceMessageType::GetService)
+            .source(1)
+            .build();
+        assert_eq!(
+            text(&datagram(), &packet),
+            "1600000000.250000 192.168.1.10:51234 -> 192.168.1.20:56700 GetService (2) \
+             source=00000001 sequence=0 target=00:00:00:00:00:00 tagged"
+        );
+    }
+
+    #[test]
+    fn payload_fields() {
+        let state = StatePayload::new(Hsbk::new(1, 2, 3, 4), Power::On(0xffff), "Lamp".into());
+        assert_eq!(
+            payload_text(&Message::State(state)).unwrap(),
+            "hue=1 saturation=2 brightness=3 kelvin=4 power=65535 label=\"Lamp\""
+        );
+        assert_eq!(
+            payload_text(&Message::StateService(StateServicePayload::new(1, 56700))).unwrap(),
+            "service=1 port=56700"
+        );
+
+        // Only the components which are set are shown.
+        let waveform = SetWaveformOptionalPayload::new(
+            false,
+            Hsbk::new(0, 0, 0x7fff, 0),
+            1024,
+            1.0,
+            0,
+            Waveform::Saw,
+        )
+        .with_components(false, false, true, false);
+        assert_eq!(
+            payload_text(&Message::SetWaveformOptional(waveform)).unwrap(),
+            "transient=false waveform=saw period=1024 cycles=1 skew_ratio=0 brightness=32767"
+        );
+
+        let message_type = lifx_client::protocol::header::MessageType::Unknown(506);
+        assert_eq!(
+            payload_text(&Message::Bytes(message_type, vec![0x01, 0xab])).unwrap(),
+            "payload=01ab"
+        );
+    }
+
+    #[test]
+    fn json_line() {
+        let value: Value = serde_json::from_str(&json(&datagram(), &set_color())).unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "timestamp": 1_600_000_000.25,
+                "source_address": "192.168.1.10:51234",
+                "destination_address": "192.168.1.20:56700",
+                "source": 0x1234_5678,
+                "sequence": 7,
+                "target": "d0:73:d5:01:02:03",
+                "tagged": false,
+                "res_required": true,
+                "ack_required": false,
+                "type": "SetColor",
+                "type_value": 102,
+                "payload": {
+                    "color": {
+                        "hue": 0x5555,
+                        "saturation": 0xffff,
+                        "brightness": 0x7fff,
+                        "kelvin": 3500,
+                    },
+                    "duration": 1024,
+                },
+            })
+        );
+    }
+}
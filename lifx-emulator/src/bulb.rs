@@ -0,0 +1,478 @@
+use lifx_client::protocol::{
+    header::{DeviceMessageType, LightMessageType, MacAddress, MessageType},
+    message::{
+        EchoPayload, Hsbk, Message, Power, StateGroupPayload, StateLabelPayload,
+        StateLocationPayload, StatePayload, StatePowerPayload, StateServicePayload,
+        StateVersionPayload,
+    },
+};
+use std::{
+    convert::TryInto,
+    time::{Duration, Instant},
+};
+
+/// The vendor ID for LIFX devices.
+pub const VENDOR: u32 = 1;
+/// The product ID used if none is specified. LIFX A19.
+pub const DEFAULT_PRODUCT: u32 = 27;
+
+// When every group and location was last updated, in nanoseconds since the Unix epoch. Real bulbs
+// in the same group or location report the same time, so it must not depend on when each bulb was
+// created.
+const UPDATED_AT: u64 = 1_600_000_000_000_000_000;
+
+/// A virtual LIFX bulb.
+#[derive(Debug, Clone)]
+pub struct Bulb {
+    mac_address: MacAddress,
+    product: u32,
+    label: String,
+    group: Collection,
+    location: Collection,
+    power: Transition<u16>,
+    color: Transition<Hsbk>,
+}
+
+// A group or location.
+#[derive(Debug, Clone)]
+struct Collection {
+    id: [u8; 16],
+    label: String,
+    updated_at: u64,
+}
+
+impl Collection {
+    fn new(label: &str) -> Collection {
+        // Bulbs in a collection with the same label should share the same ID.
+        let mut id = [0u8; 16];
+        for (i, byte) in label.bytes().enumerate() {
+            id[i % 16] ^= byte;
+        }
+        Collection {
+            id,
+            label: label.to_string(),
+            updated_at: UPDATED_AT,
+        }
+    }
+}
+
+impl Bulb {
+    /// Create a bulb which is off and set to white.
+    pub fn new(mac_address: MacAddress, label: &str) -> Bulb {
+        Bulb {
+            mac_address,
+            product: DEFAULT_PRODUCT,
+            label: label.to_string(),
+            group: Collection::new(""),
+            location: Collection::new(""),
+            power: Transition::fixed(0),
+            color: Transition::fixed(Hsbk::new(0, 0, 0xffff, 3500)),
+        }
+    }
+
+    pub fn with_product(mut self, product: u32) -> Bulb {
+        self.product = product;
+        self
+    }
+
+    pub fn with_group(mut self, group: &str) -> Bulb {
+        self.group = Collection::new(group);
+        self
+    }
+
+    pub fn with_location(mut self, location: &str) -> Bulb {
+        self.location = Collection::new(location);
+        self
+    }
+
+    pub fn with_power(mut self, power: Power) -> Bulb {
+        self.power = Transition::fixed(power.level());
+        self
+    }
+
+    pub fn with_color(mut self, color: Hsbk) -> Bulb {
+        self.color = Transition::fixed(color);
+        self
+    }
+
+    pub fn mac_address(&self) -> MacAddress {
+        self.mac_address
+    }
+
+    pub fn product(&self) -> u32 {
+        self.product
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    pub fn group(&self) -> &str {
+        &self.group.label
+    }
+
+    pub fn location(&self) -> &str {
+        &self.location.label
+    }
+
+    /// Return the current power, which may be part way through a transition.
+    pub fn power(&self) -> Power {
+        self.power_at(Instant::now())
+    }
+
+    /// Return the current color, which may be part way through a transition.
+    pub fn color(&self) -> Hsbk {
+        self.color_at(Instant::now())
+    }
+
+    fn power_at(&self, now: Instant) -> Power {
+        Power::from_level(self.power.value_at(now))
+    }
+
+    fn color_at(&self, now: Instant) -> Hsbk {
+        self.color.value_at(now)
+    }
+
+    /// Handle a message sent to this bulb and return the response, if any. Get messages are always
+    /// answered, while set messages are only answered if `res_required` is set.
+    pub(crate) fn handle(
+        &mut self,
+        message: &Message,
+        res_required: bool,
+        port: u16,
+        now: Instant,
+    ) -> Option<Message> {
+        use DeviceMessageType as D;
+        use LightMessageType as L;
+        let response = match message.message_type() {
+            MessageType::Device(D::GetService) => {
+                Message::StateService(StateServicePayload::new(1, port))
+            }
+            MessageType::Device(D::GetPower) => self.state_power(now),
+            MessageType::Light(L::GetPower) => self.light_state_power(now),
+            MessageType::Device(D::GetLabel) => {
+                Message::StateLabel(StateLabelPayload::new(self.label.clone()))
+            }
+            MessageType::Device(D::GetVersion) => {
+                Message::StateVersion(StateVersionPayload::new(VENDOR, self.product))
+            }
+            MessageType::Device(D::GetGroup) => Message::StateGroup(StateGroupPayload::new(
+                self.group.id,
+                self.group.label.clone(),
+                self.group.updated_at,
+            )),
+            MessageType::Device(D::GetLocation) => {
+                Message::StateLocation(StateLocationPayload::new(
+                    self.location.id,
+                    self.location.label.clone(),
+                    self.location.updated_at,
+                ))
+            }
+            MessageType::Light(L::Get) => self.state(now),
+            _ => return self.handle_set(message, res_required, now),
+        };
+        Option::Some(response)
+    }
+
+    fn handle_set(
+        &mut self,
+        message: &Message,
+        res_required: bool,
+        now: Instant,
+    ) -> Option<Message> {
+        let response = match message {
+            Message::EchoRequest(payload) => {
+                return Option::Some(Message::EchoResponse(EchoPayload::new(payload.payload())))
+            }
+            Message::Bytes(MessageType::Device(DeviceMessageType::SetPower), bytes)
+                if bytes.len() == 2 =>
+            {
+                let level = u16::from_le_bytes(bytes[..].try_into().unwrap());
+                self.power = Transition::fixed(level);
+                self.state_power(now)
+            }
+            Message::SetPower(payload) => {
+                self.power = self.power.to(
+                    payload.power().level(),
+                    Duration::from_millis(payload.duration() as u64),
+                    now,
+                );
+                self.light_state_power(now)
+            }
+            Message::SetColor(payload) => {
+                self.color = self.color.to(
+                    payload.color(),
+                    Duration::from_millis(payload.duration() as u64),
+                    now,
+                );
+                self.state(now)
+            }
+            // Waveforms are not emulated. A transient effect returns to the original color, so it
+            // is ignored, and any other changes to the new color over the period.
+            Message::SetWaveformOptional(payload) => {
+                if !payload.transient() {
+                    self.color = self.color.to(
+                        payload.apply_to(self.color_at(now)),
+                        Duration::from_millis(payload.period() as u64),
+                        now,
+                    );
+                }
+                self.state(now)
+            }
+            _ => return Option::None,
+        };
+
+        if res_required {
+            Option::Some(response)
+        } else {
+            Option::None
+        }
+    }
+
+    fn state(&self, now: Instant) -> Message {
+        Message::State(StatePayload::new(
+            self.color_at(now),
+            self.power_at(now),
+            self.label.clone(),
+        ))
+    }
+
+    fn state_power(&self, now: Instant) -> Message {
+        Message::StatePower(StatePowerPayload::new(self.power_at(now)))
+    }
+
+    // Light messages are answered with a light StatePower message, which has the same payload as
+    // the device StatePower message.
+    fn light_state_power(&self, now: Instant) -> Message {
+        Message::Bytes(
+            MessageType::Light(LightMessageType::StatePower),
+            self.power_at(now).level().to_le_bytes().to_vec(),
+        )
+    }
+}
+
+/// A value which changes linearly from one value to another over time.
+#[derive(Debug, Clone, Copy)]
+struct Transition<V: Interpolate> {
+    from: V,
+    to: V,
+    start: Instant,
+    duration: Duration,
+}
+
+impl<V: Interpolate> Transition<V> {
+    fn fixed(value: V) -> Transition<V> {
+        Transition {
+            from: value,
+            to: value,
+            start: Instant::now(),
+            duration: Duration::from_secs(0),
+        }
+    }
+
+    /// Return a transition from the value at `now` to `to`.
+    fn to(&self, to: V, duration: Duration, now: Instant) -> Transition<V> {
+        Transition {
+            from: self.value_at(now),
+            to,
+            start: now,
+            duration,
+        }
+    }
+
+    fn value_at(&self, now: Instant) -> V {
+        let elapsed = now.saturating_duration_since(self.start);
+        if elapsed >= self.duration {
+            self.to
+        } else {
+            let t = elapsed.as_secs_f32() / self.duration.as_secs_f32();
+            self.from.interpolate(self.to, t)
+        }
+    }
+}
+
+trait Interpolate: Copy {
+    /// Return the value `t` of the way from `self` to `to`, where `t` is between 0 and 1.
+    fn interpolate(self, to: Self, t: f32) -> Self;
+}
+
+impl Interpolate for u16 {
+    fn interpolate(self, to: u16, t: f32) -> u16 {
+        (self as f32 + (to as f32 - self as f32) * t).round() as u16
+    }
+}
+
+impl Interpolate for Hsbk {
+    fn interpolate(self, to: Hsbk, t: f32) -> Hsbk {
+        // Hue wraps around, so take the shortest path.
+        let mut hue_delta = to.hue() as i32 - self.hue() as i32;
+        if hue_delta > 0x8000 {
+            hue_delta -= 0x10000;
+        } else if hue_delta < -0x8000 {
+            hue_delta += 0x10000;
+        }
+        let hue = (self.hue() as f32 + hue_delta as f32 * t).round() as i32;
+
+        Hsbk::new(
+            hue.rem_euclid(0x10000) as u16,
+            self.saturation().interpolate(to.saturation(), t),
+            self.brightness().interpolate(to.brightness(), t),
+            self.kelvin().interpolate(to.kelvin(), t),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lifx_client::protocol::message::{
+        SetColorPayload, SetPowerPayload, SetWaveformOptionalPayload, Waveform,
+    };
+
+    const MAC: MacAddress = MacAddress {
+        address: [0xd0, 0x73, 0xd5, 0x00, 0x00, 0x01],
+    };
+
+    #[test]
+    fn get_messages() {
+        let mut bulb = Bulb::new(MAC, "Lamp")
+            .with_group("Upstairs")
+            .with_location("Home")
+            .with_product(22);
+        let now = Instant::now();
+        let get = |message_type| Message::Empty(MessageType::Device(message_type));
+
+        let response = bulb.handle(&get(DeviceMessageType::GetService), false, 56700, now);
+        assert_eq!(
+            response,
+            Option::Some(Message::StateService(StateServicePayload::new(1, 56700)))
+        );
+
+        let response = bulb.handle(&get(DeviceMessageType::GetVersion), false, 56700, now);
+        assert_eq!(
+            response,
+            Option::Some(Message::StateVersion(StateVersionPayload::new(1, 22)))
+        );
+
+        match bulb.handle(&get(DeviceMessageType::GetGroup), false, 56700, now) {
+            Some(Message::StateGroup(payload)) => assert_eq!(payload.label(), "Upstairs"),
+            response => panic!("Unexpected response. {:?}", response),
+        }
+        match bulb.handle(&get(DeviceMessageType::GetLocation), false, 56700, now) {
+            Some(Message::StateLocation(payload)) => assert_eq!(payload.label(), "Home"),
+            response => panic!("Unexpected response. {:?}", response),
+        }
+    }
+
+    #[test]
+    fn bulbs_in_a_group_agree() {
+        let get_group = Message::Empty(MessageType::Device(DeviceMessageType::GetGroup));
+        let group = |mac_address| {
+            let mut bulb = Bulb::new(mac_address, "Lamp").with_group("Upstairs");
+            match bulb.handle(&get_group, false, 56700, Instant::now()) {
+                Some(Message::StateGroup(payload)) => payload,
+                response => panic!("Unexpected response. {:?}", response),
+            }
+        };
+
+        let first = group(MAC);
+        std::thread::sleep(Duration::from_millis(1));
+        let second = group(MacAddress {
+            address: [0xd0, 0x73, 0xd5, 0x00, 0x00, 0x02],
+        });
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn set_messages_respond_only_if_required() {
+        let mut bulb = Bulb::new(MAC, "Lamp");
+        let now = Instant::now();
+        let set_color = Message::SetColor(SetColorPayload::new(
+            Hsbk::new(0x5555, 0xffff, 0xffff, 3500),
+            0,
+        ));
+
+        assert_eq!(bulb.handle(&set_color, false, 56700, now), Option::None);
+        match bulb.handle(&set_color, true, 56700, now) {
+            Some(Message::State(payload)) => {
+                assert_eq!(payload.color(), Hsbk::new(0x5555, 0xffff, 0xffff, 3500))
+            }
+            response => panic!("Unexpected response. {:?}", response),
+        }
+    }
+
+    #[test]
+    fn color_transition() {
+        let mut bulb = Bulb::new(MAC, "Lamp").with_color(Hsbk::new(0, 0, 0, 2500));
+        let start = Instant::now();
+        let set_color = Message::SetColor(SetColorPayload::new(
+            Hsbk::new(0x1000, 0xffff, 0xffff, 6500),
+            1000,
+        ));
+        bulb.handle(&set_color, false, 56700, start);
+
+        assert_eq!(bulb.color_at(start), Hsbk::new(0, 0, 0, 2500));
+        assert_eq!(
+            bulb.color_at(start + Duration::from_millis(500)),
+            Hsbk::new(0x0800, 0x8000, 0x8000, 4500)
+        );
+        assert_eq!(
+            bulb.color_at(start + Duration::from_secs(2)),
+            Hsbk::new(0x1000, 0xffff, 0xffff, 6500)
+        );
+    }
+
+    #[test]
+    fn set_optional_components() {
+        let mut bulb = Bulb::new(MAC, "Lamp").with_color(Hsbk::new(0x5555, 0xffff, 0xffff, 3500));
+        let now = Instant::now();
+        let payload = SetWaveformOptionalPayload::new(
+            false,
+            Hsbk::new(0, 0, 0x7fff, 6500),
+            0,
+            1.0,
+            0,
+            Waveform::Saw,
+        )
+        .with_components(false, false, true, true);
+        bulb.handle(&Message::SetWaveformOptional(payload), false, 56700, now);
+
+        assert_eq!(bulb.color_at(now), Hsbk::new(0x5555, 0xffff, 0x7fff, 6500));
+    }
+
+    #[test]
+    fn hue_takes_shortest_path() {
+        let from = Hsbk::new(0xf000, 0, 0, 3500);
+        let to = Hsbk::new(0x1000, 0, 0, 3500);
+        assert_eq!(from.interpolate(to, 0.5).hue(), 0);
+        assert_eq!(to.interpolate(from, 0.25).hue(), 0x0800);
+    }
+
+    #[test]
+    fn power_transition() {
+        let mut bulb = Bulb::new(MAC, "Lamp");
+        let start = Instant::now();
+        let set_power = Message::SetPower(SetPowerPayload::new(Power::On(0xffff), 2000));
+        bulb.handle(&set_power, false, 56700, start);
+
+        assert_eq!(bulb.power_at(start), Power::Off);
+        assert_eq!(
+            bulb.power_at(start + Duration::from_secs(1)),
+            Power::On(0x8000)
+        );
+        assert_eq!(
+            bulb.power_at(start + Duration::from_secs(2)),
+            Power::On(0xffff)
+        );
+    }
+
+    #[test]
+    fn echo() {
+        let mut bulb = Bulb::new(MAC, "Lamp");
+        let payload = EchoPayload::new([7u8; 64]);
+        assert_eq!(
+            bulb.handle(&Message::EchoRequest(payload), false, 56700, Instant::now()),
+            Option::Some(Message::EchoResponse(payload))
+        );
+    }
+}
@@ -0,0 +1,155 @@
+use super::bulb::{Bulb, DEFAULT_PRODUCT};
+use lifx_client::protocol::message::{Hsbk, Power};
+use serde::Deserialize;
+use std::{convert::TryFrom, net::SocketAddr};
+
+/// Emulator configuration, usually read from a TOML file.
+///
+/// ```toml
+/// address = "0.0.0.0:56700"
+///
+/// [[bulbs]]
+/// mac = "d0:73:d5:00:00:01"
+/// label = "Kitchen"
+/// group = "Downstairs"
+/// location = "Home"
+/// product = 27
+/// power = true
+/// hue = 0
+/// saturation = 0
+/// brightness = 65535
+/// kelvin = 3500
+/// ```
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    #[serde(default = "default_address")]
+    pub address: SocketAddr,
+    #[serde(default)]
+    pub bulbs: Vec<BulbConfig>,
+}
+
+/// The initial state of a virtual bulb. Colors use the same 16-bit values as the LAN protocol.
+#[derive(Debug, Deserialize)]
+pub struct BulbConfig {
+    pub mac: String,
+    pub label: String,
+    #[serde(default)]
+    pub group: String,
+    #[serde(default)]
+    pub location: String,
+    #[serde(default = "default_product")]
+    pub product: u32,
+    #[serde(default)]
+    pub power: bool,
+    #[serde(default)]
+    pub hue: u16,
+    #[serde(default)]
+    pub saturation: u16,
+    #[serde(default = "default_brightness")]
+    pub brightness: u16,
+    #[serde(default = "default_kelvin")]
+    pub kelvin: u16,
+}
+
+fn default_address() -> SocketAddr {
+    SocketAddr::from(([0, 0, 0, 0], 56700))
+}
+
+fn default_product() -> u32 {
+    DEFAULT_PRODUCT
+}
+
+fn default_brightness() -> u16 {
+    0xffff
+}
+
+fn default_kelvin() -> u16 {
+    3500
+}
+
+impl Config {
+    pub fn from_toml(s: &str) -> Result<Config, String> {
+        toml::from_str(s).map_err(|e| e.to_string())
+    }
+
+    /// Create the bulbs described by this configuration.
+    pub fn bulbs(&self) -> Result<Vec<Bulb>, String> {
+        self.bulbs.iter().map(Bulb::try_from).collect()
+    }
+}
+
+impl TryFrom<&BulbConfig> for Bulb {
+    type Error = String;
+
+    fn try_from(config: &BulbConfig) -> Result<Self, Self::Error> {
+        let power = if config.power {
+            Power::On(0xffff)
+        } else {
+            Power::Off
+        };
+        Result::Ok(
+            Bulb::new(config.mac.parse()?, &config.label)
+                .with_group(&config.group)
+                .with_location(&config.location)
+                .with_product(config.product)
+                .with_power(power)
+                .with_color(Hsbk::new(
+                    config.hue,
+                    config.saturation,
+                    config.brightness,
+                    config.kelvin,
+                )),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_config() {
+        let config = Config::from_toml(
+            r#"
+            address = "127.0.0.1:56700"
+
+            [[bulbs]]
+            mac = "d0:73:d5:00:00:01"
+            label = "Kitchen"
+            group = "Downstairs"
+            power = true
+            hue = 21845
+
+            [[bulbs]]
+            mac = "d0:73:d5:00:00:02"
+            label = "Bedroom"
+            product = 22
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.address, "127.0.0.1:56700".parse().unwrap());
+
+        let bulbs = config.bulbs().unwrap();
+        assert_eq!(bulbs.len(), 2);
+        assert_eq!(bulbs[0].label(), "Kitchen");
+        assert_eq!(bulbs[0].group(), "Downstairs");
+        assert_eq!(bulbs[0].power(), Power::On(0xffff));
+        assert_eq!(bulbs[0].color(), Hsbk::new(21845, 0, 0xffff, 3500));
+        assert_eq!(bulbs[1].product(), 22);
+        assert_eq!(bulbs[1].power(), Power::Off);
+    }
+
+    #[test]
+    fn invalid_mac_address() {
+        let config = Config::from_toml(
+            r#"
+            [[bulbs]]
+            mac = "not a mac address"
+            label = "Kitchen"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.address, default_address());
+        assert!(config.bulbs().is_err());
+    }
+}
@@ -0,0 +1,266 @@
+use super::bulb::Bulb;
+use lifx_client::{
+    protocol::{
+        header::{DeviceMessageType, MacAddress, MessageType},
+        message::Message,
+        packet::{Packet, PacketBuilder},
+    },
+    transport::Transport,
+};
+use std::{
+    convert::TryFrom,
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+// How often the receive loop checks whether it has been stopped.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// A set of virtual bulbs which share a single transport. Requests are dispatched to bulbs by
+/// their target MAC address, and tagged requests are answered by every bulb.
+///
+/// The emulator runs on a background thread until it is stopped or dropped.
+pub struct Emulator {
+    address: SocketAddr,
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+struct Shared {
+    bulbs: Mutex<Vec<Bulb>>,
+    running: AtomicBool,
+}
+
+impl Emulator {
+    /// Start answering requests received by `transport`.
+    pub fn start<T: Transport + Send + 'static>(
+        transport: T,
+        bulbs: Vec<Bulb>,
+    ) -> io::Result<Emulator> {
+        let address = transport.local_addr()?;
+        let shared = Arc::new(Shared {
+            bulbs: Mutex::new(bulbs),
+            running: AtomicBool::new(true),
+        });
+
+        let thread_shared = shared.clone();
+        let thread = thread::spawn(move || run(transport, &thread_shared));
+
+        Result::Ok(Emulator {
+            address,
+            shared,
+            thread: Option::Some(thread),
+        })
+    }
+
+    /// Return the address on which the emulator receives requests.
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Return a snapshot of all bulbs.
+    pub fn bulbs(&self) -> Vec<Bulb> {
+        self.shared.lock_bulbs().clone()
+    }
+
+    /// Return a snapshot of the bulb with the specified MAC address.
+    pub fn bulb(&self, mac_address: MacAddress) -> Option<Bulb> {
+        self.shared
+            .lock_bulbs()
+            .iter()
+            .find(|bulb| bulb.mac_address() == mac_address)
+            .cloned()
+    }
+
+    /// Stop answering requests and wait for the background thread to finish.
+    pub fn stop(&mut self) {
+        self.shared.running.store(false, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+
+    /// Wait for the background thread to finish. The thread only finishes if the transport fails.
+    pub fn join(mut self) {
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Emulator {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+impl Shared {
+    fn lock_bulbs(&self) -> MutexGuard<'_, Vec<Bulb>> {
+        // A panic while handling a request cannot leave a bulb in an invalid state.
+        self.bulbs
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+fn run<T: Transport>(transport: T, shared: &Shared) {
+    let port = match transport.local_addr() {
+        Ok(address) => address.port(),
+        Err(_) => return,
+    };
+
+    let mut buf = [0u8; 1024];
+    while shared.running.load(Ordering::SeqCst) {
+        match transport.recv_from(&mut buf, POLL_INTERVAL) {
+            Ok((n, address)) => {
+                // Ignore anything which is not a valid LIFX packet, as a real device would.
+                if let Ok(request) = Packet::try_from(&buf[..n]) {
+                    for response in handle(&request, shared, port) {
+                        let _ = transport.send_to(&response.as_bytes(), address);
+                    }
+                }
+            }
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+            }
+            Err(_) => return,
+        }
+    }
+}
+
+/// Return the packets sent in response to `request` by all of the bulbs it targets.
+fn handle(request: &Packet, shared: &Shared, port: u16) -> Vec<Packet> {
+    let frame_address = request.frame_address();
+    let all = request.frame().tagged || frame_address.target.address == [0u8; 6];
+    let now = Instant::now();
+
+    let mut responses = Vec::new();
+    for bulb in shared
+        .lock_bulbs()
+        .iter_mut()
+        .filter(|bulb| all || bulb.mac_address() == frame_address.target)
+    {
+        let mac_address = bulb.mac_address();
+        let builder = |message| {
+            PacketBuilder::new(message)
+                .source(request.frame().source)
+                .sequence(frame_address.sequence)
+                .target(mac_address)
+        };
+        if frame_address.ack_required {
+            let ack = Message::Empty(MessageType::Device(DeviceMessageType::Acknowledgement));
+            responses.push(builder(ack).build());
+        }
+        let response = bulb.handle(request.message(), frame_address.res_required, port, now);
+        if let Some(response) = response {
+            responses.push(builder(response).build());
+        }
+    }
+    responses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{client, mac, start};
+    use lifx_client::{
+        color::Color,
+        protocol::message::{Hsbk, Power},
+        transport::MemoryNetwork,
+    };
+
+    #[test]
+    fn discover() {
+        let network = MemoryNetwork::new();
+        let emulator = start(&network);
+        let client = client(&network);
+
+        let devices = client.discover().unwrap();
+        let mut labels: Vec<&str> = devices.iter().map(|d| d.label().as_str()).collect();
+        labels.sort_unstable();
+        assert_eq!(labels, vec!["Bedroom", "Kitchen", "Office"]);
+        for device in devices.iter() {
+            assert_eq!(device.address().socket_address(), emulator.address());
+        }
+    }
+
+    #[test]
+    fn control_bulbs() {
+        let network = MemoryNetwork::new();
+        let emulator = start(&network);
+        let client = client(&network);
+
+        let devices = client.discover().unwrap();
+        let kitchen = devices.iter().find(|d| d.label() == "Kitchen").unwrap();
+        let office = devices.iter().find(|d| d.label() == "Office").unwrap();
+
+        client.turn_on(kitchen).unwrap();
+        client.set_color(kitchen, Color::GREEN).unwrap();
+        client.toggle_power(office).unwrap();
+
+        let get_power = |mac_address| emulator.bulb(mac_address).unwrap().power();
+        assert_eq!(get_power(mac(1)), Power::On(0xffff));
+        assert_eq!(get_power(mac(2)), Power::Off);
+        assert_eq!(get_power(mac(3)), Power::Off);
+        assert_eq!(
+            emulator.bulb(mac(1)).unwrap().color(),
+            Hsbk::from(Color::GREEN)
+        );
+        assert_eq!(
+            Hsbk::from(client.get_color(kitchen).unwrap()),
+            Hsbk::from(Color::GREEN)
+        );
+
+        let status = client.get_status(kitchen).unwrap();
+        assert!(status.power());
+        assert_eq!(status.power_level(), 0xffff);
+        assert_eq!(status.label(), "Kitchen");
+        assert_eq!(Hsbk::from(status.color()), Hsbk::from(Color::GREEN));
+        assert!(!client.get_status(office).unwrap().power());
+    }
+
+    #[test]
+    fn acknowledgement() {
+        let network = MemoryNetwork::new();
+        let _emulator = start(&network);
+        let transport = network.bind("10.0.0.1:0".parse().unwrap()).unwrap();
+
+        let request = PacketBuilder::with_empty_device_message(DeviceMessageType::GetLabel)
+            .target(mac(2))
+            .sequence(7)
+            .ack_required(true)
+            .build();
+        transport
+            .send_to(&request.as_bytes(), "10.0.0.2:56700".parse().unwrap())
+            .unwrap();
+
+        let mut buf = [0u8; 128];
+        let mut receive = || {
+            let (n, _) = transport
+                .recv_from(&mut buf, Duration::from_secs(5))
+                .unwrap();
+            Packet::try_from(&buf[..n]).unwrap()
+        };
+        let ack = receive();
+        assert_eq!(
+            ack.message().message_type(),
+            MessageType::Device(DeviceMessageType::Acknowledgement)
+        );
+        assert_eq!(ack.frame_address().sequence, 7);
+        assert_eq!(ack.frame_address().target, mac(2));
+
+        let response = receive();
+        match response.message() {
+            Message::StateLabel(payload) => {
+                assert_eq!(payload.label(), "Bedroom")
+            }
+            message => panic!("Unexpected response. {:?}", message),
+        }
+    }
+}
@@ -0,0 +1,415 @@
+use lifx_client::{
+    protocol::{header::MacAddress, packet::Packet},
+    transport::Transport,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    collections::{HashSet, VecDeque},
+    convert::TryFrom,
+    io,
+    net::SocketAddr,
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+/// The faults injected by a [`FaultyTransport`]. Probabilities are between 0 and 1.
+#[derive(Debug, Clone, Default)]
+pub struct Faults {
+    loss: f64,
+    duplication: f64,
+    reordering: f64,
+    latency: Duration,
+    jitter: Duration,
+}
+
+impl Faults {
+    /// No faults.
+    pub fn new() -> Faults {
+        Faults::default()
+    }
+
+    /// The probability that a datagram is dropped.
+    pub fn loss(mut self, probability: f64) -> Faults {
+        self.loss = probability;
+        self
+    }
+
+    /// The probability that a datagram is delivered twice.
+    pub fn duplication(mut self, probability: f64) -> Faults {
+        self.duplication = probability;
+        self
+    }
+
+    /// The probability that a received datagram is delivered after the datagram which follows it.
+    pub fn reordering(mut self, probability: f64) -> Faults {
+        self.reordering = probability;
+        self
+    }
+
+    /// The delay before a received datagram is delivered.
+    pub fn latency(mut self, latency: Duration) -> Faults {
+        self.latency = latency;
+        self
+    }
+
+    /// The maximum random delay added to the latency of each received datagram.
+    pub fn jitter(mut self, jitter: Duration) -> Faults {
+        self.jitter = jitter;
+        self
+    }
+}
+
+/// A transport which wraps another transport and misbehaves on purpose.
+///
+/// Sent datagrams may be dropped or duplicated. Received datagrams may also be dropped or
+/// duplicated, and are delayed by the latency and jitter or reordered. Packets to and from
+/// offline devices are dropped in both directions, based on the packet's target MAC address.
+///
+/// Wrap the client's transport to test a client against real or emulated devices, or wrap the
+/// emulator's transport to simulate faulty devices.
+pub struct FaultyTransport<T: Transport> {
+    inner: T,
+    state: Mutex<State>,
+}
+
+struct State {
+    faults: Faults,
+    offline: HashSet<MacAddress>,
+    rng: StdRng,
+    // Received datagrams which are waiting to be delivered, in order of delivery.
+    pending: VecDeque<Pending>,
+    // A received datagram which will be delivered after the next one.
+    held: Option<(Vec<u8>, SocketAddr)>,
+}
+
+struct Pending {
+    due: Instant,
+    bytes: Vec<u8>,
+    address: SocketAddr,
+}
+
+impl<T: Transport> FaultyTransport<T> {
+    pub fn new(inner: T, faults: Faults) -> FaultyTransport<T> {
+        FaultyTransport::with_rng(inner, faults, StdRng::from_entropy())
+    }
+
+    /// Create a transport whose faults are reproducible for a given seed.
+    pub fn with_seed(inner: T, faults: Faults, seed: u64) -> FaultyTransport<T> {
+        FaultyTransport::with_rng(inner, faults, StdRng::seed_from_u64(seed))
+    }
+
+    fn with_rng(inner: T, faults: Faults, rng: StdRng) -> FaultyTransport<T> {
+        FaultyTransport {
+            inner,
+            state: Mutex::new(State {
+                faults,
+                offline: HashSet::new(),
+                rng,
+                pending: VecDeque::new(),
+                held: Option::None,
+            }),
+        }
+    }
+
+    /// Replace the faults injected by this transport.
+    pub fn set_faults(&self, faults: Faults) {
+        self.lock().faults = faults;
+    }
+
+    /// Set whether the device with the specified MAC address is offline.
+    pub fn set_offline(&self, mac_address: MacAddress, offline: bool) {
+        let mut state = self.lock();
+        if offline {
+            state.offline.insert(mac_address);
+        } else {
+            state.offline.remove(&mac_address);
+        }
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // The state is always valid between operations, so ignore poisoning.
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl State {
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.rng.gen_bool(f64::min(probability, 1.0))
+    }
+
+    /// Return the number of copies of a datagram to deliver.
+    fn copies(&mut self, bytes: &[u8]) -> usize {
+        let (loss, duplication) = (self.faults.loss, self.faults.duplication);
+        if self.is_offline(bytes) || self.chance(loss) {
+            0
+        } else if self.chance(duplication) {
+            2
+        } else {
+            1
+        }
+    }
+
+    fn is_offline(&self, bytes: &[u8]) -> bool {
+        !self.offline.is_empty()
+            && Packet::try_from(bytes)
+                .map(|packet| self.offline.contains(&packet.frame_address().target))
+                .unwrap_or(false)
+    }
+
+    /// Apply faults to a received datagram.
+    fn receive(&mut self, bytes: Vec<u8>, address: SocketAddr, now: Instant) {
+        let copies = self.copies(&bytes);
+        if copies == 0 {
+            return;
+        }
+
+        let reordering = self.faults.reordering;
+        if self.held.is_none() && self.chance(reordering) {
+            self.held = Option::Some((bytes, address));
+            return;
+        }
+
+        for _ in 0..copies {
+            self.delay(bytes.clone(), address, now);
+        }
+        if let Some((bytes, address)) = self.held.take() {
+            self.delay(bytes, address, now);
+        }
+    }
+
+    fn delay(&mut self, bytes: Vec<u8>, address: SocketAddr, now: Instant) {
+        let jitter = self.faults.jitter.mul_f64(self.rng.gen::<f64>());
+        let due = now + self.faults.latency + jitter;
+        // Keep the queue in order of delivery, so jitter can also reorder datagrams.
+        let index = self
+            .pending
+            .iter()
+            .position(|p| p.due > due)
+            .unwrap_or(self.pending.len());
+        self.pending.insert(
+            index,
+            Pending {
+                due,
+                bytes,
+                address,
+            },
+        );
+    }
+
+    /// Remove and return the next datagram if it is due.
+    fn next_due(&mut self, now: Instant) -> Option<Pending> {
+        if self.pending.front().map(|p| p.due <= now).unwrap_or(false) {
+            self.pending.pop_front()
+        } else {
+            Option::None
+        }
+    }
+}
+
+impl<T: Transport> Transport for FaultyTransport<T> {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let copies = self.lock().copies(buf);
+        for _ in 0..copies {
+            self.inner.send_to(buf, addr)?;
+        }
+        Result::Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8], timeout: Duration) -> io::Result<(usize, SocketAddr)> {
+        let deadline = Instant::now() + timeout;
+        let mut inner_buf = vec![0u8; usize::max(buf.len(), 1024)];
+        loop {
+            let now = Instant::now();
+            let next_due = {
+                let mut state = self.lock();
+                if let Some(pending) = state.next_due(now) {
+                    let n = usize::min(pending.bytes.len(), buf.len());
+                    buf[..n].copy_from_slice(&pending.bytes[..n]);
+                    return Result::Ok((n, pending.address));
+                }
+                if now >= deadline {
+                    // Do not lose a held datagram if nothing else is received.
+                    if let Some((bytes, address)) = state.held.take() {
+                        let n = usize::min(bytes.len(), buf.len());
+                        buf[..n].copy_from_slice(&bytes[..n]);
+                        return Result::Ok((n, address));
+                    }
+                    return Result::Err(io::Error::from(io::ErrorKind::WouldBlock));
+                }
+                state.pending.front().map(|p| p.due)
+            };
+
+            // Receive until the next pending datagram is due or the deadline passes.
+            let until = next_due.map_or(deadline, |due| Instant::min(due, deadline));
+            let wait = until.saturating_duration_since(now);
+            match self.inner.recv_from(&mut inner_buf, wait) {
+                Ok((n, address)) => {
+                    self.lock()
+                        .receive(inner_buf[..n].to_vec(), address, Instant::now());
+                }
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => return Result::Err(e),
+            }
+        }
+    }
+
+    fn broadcast(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let copies = self.lock().copies(buf);
+        for _ in 0..copies {
+            self.inner.broadcast(buf, addr)?;
+        }
+        Result::Ok(buf.len())
+    }
+
+    fn enable_broadcast(&self) -> io::Result<()> {
+        self.inner.enable_broadcast()
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::mac;
+    use crate::{bulb::Bulb, emulator::Emulator};
+    use lifx_client::{
+        client::Client,
+        transport::{MemoryNetwork, MemoryTransport},
+    };
+    use std::sync::Arc;
+
+    const TIMEOUT: Duration = Duration::from_millis(50);
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    // Return a faulty transport and a transport which sends datagrams to it.
+    fn transports(
+        network: &MemoryNetwork,
+        faults: Faults,
+    ) -> (FaultyTransport<MemoryTransport>, MemoryTransport) {
+        let faulty =
+            FaultyTransport::with_seed(network.bind(addr("10.0.0.1:0")).unwrap(), faults, 1);
+        let sender = network.bind(addr("10.0.0.2:56700")).unwrap();
+        (faulty, sender)
+    }
+
+    fn receive_all(transport: &impl Transport) -> Vec<u8> {
+        let mut received = Vec::new();
+        let mut buf = [0u8; 8];
+        while let Ok((n, _)) = transport.recv_from(&mut buf, TIMEOUT) {
+            received.extend(&buf[..n]);
+        }
+        received
+    }
+
+    #[test]
+    fn no_faults() {
+        let network = MemoryNetwork::new();
+        let (faulty, sender) = transports(&network, Faults::new());
+        let faulty_address = faulty.local_addr().unwrap();
+        for n in 0..10u8 {
+            sender.send_to(&[n], faulty_address).unwrap();
+        }
+        assert_eq!(receive_all(&faulty), (0..10).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn loss() {
+        let network = MemoryNetwork::new();
+        let (faulty, sender) = transports(&network, Faults::new().loss(1.0));
+        sender.send_to(&[1], faulty.local_addr().unwrap()).unwrap();
+        faulty.send_to(&[2], sender.local_addr().unwrap()).unwrap();
+
+        assert!(receive_all(&faulty).is_empty());
+        assert!(receive_all(&sender).is_empty());
+    }
+
+    #[test]
+    fn duplication() {
+        let network = MemoryNetwork::new();
+        let (faulty, sender) = transports(&network, Faults::new().duplication(1.0));
+        sender.send_to(&[1], faulty.local_addr().unwrap()).unwrap();
+        faulty.send_to(&[2], sender.local_addr().unwrap()).unwrap();
+
+        assert_eq!(receive_all(&faulty), vec![1, 1]);
+        assert_eq!(receive_all(&sender), vec![2, 2]);
+    }
+
+    #[test]
+    fn reordering() {
+        let network = MemoryNetwork::new();
+        let (faulty, sender) = transports(&network, Faults::new().reordering(1.0));
+        let faulty_address = faulty.local_addr().unwrap();
+        for n in 0..4u8 {
+            sender.send_to(&[n], faulty_address).unwrap();
+        }
+        assert_eq!(receive_all(&faulty), vec![1, 0, 3, 2]);
+
+        // A held datagram is still delivered if no other datagram is received.
+        sender.send_to(&[4], faulty_address).unwrap();
+        assert_eq!(receive_all(&faulty), vec![4]);
+    }
+
+    #[test]
+    fn latency() {
+        let network = MemoryNetwork::new();
+        let latency = Duration::from_millis(100);
+        let (faulty, sender) = transports(&network, Faults::new().latency(latency));
+
+        let start = Instant::now();
+        sender.send_to(&[1], faulty.local_addr().unwrap()).unwrap();
+        let mut buf = [0u8; 8];
+        assert!(faulty.recv_from(&mut buf, latency / 2).is_err());
+        assert!(faulty.recv_from(&mut buf, latency).is_ok());
+        assert!(start.elapsed() >= latency);
+    }
+
+    #[test]
+    fn offline_device() {
+        let network = MemoryNetwork::new();
+        let bulbs = vec![Bulb::new(mac(1), "Kitchen"), Bulb::new(mac(2), "Bedroom")];
+        let transport = network.bind(addr("10.0.0.2:56700")).unwrap();
+        let faulty = Arc::new(FaultyTransport::new(transport, Faults::new()));
+        faulty.set_offline(mac(2), true);
+        let _emulator = Emulator::start(faulty.clone(), bulbs).unwrap();
+
+        let client = Client::new(network.bind(addr("10.0.0.1:0")).unwrap());
+        client.set_timeout(Duration::from_millis(100));
+        let devices = client.discover().unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices.iter().next().unwrap().label(), "Kitchen");
+
+        // The device is found once it comes back online.
+        faulty.set_offline(mac(2), false);
+        assert_eq!(client.discover().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn client_times_out_when_requests_are_lost() {
+        let network = MemoryNetwork::new();
+        let bulbs = vec![Bulb::new(mac(1), "Kitchen")];
+        let transport = network.bind(addr("10.0.0.2:56700")).unwrap();
+        let emulator = Emulator::start(transport, bulbs).unwrap();
+
+        let transport = network.bind(addr("10.0.0.1:0")).unwrap();
+        let client = Client::new(FaultyTransport::new(transport, Faults::new().loss(1.0)));
+        client.set_timeout(TIMEOUT);
+        let address = format!("{}#{}", mac(1), emulator.address())
+            .parse()
+            .unwrap();
+        assert!(client.find_device(address).is_err());
+    }
+}
@@ -0,0 +1,5 @@
+pub mod bulb;
+pub mod config;
+pub mod emulator;
+pub mod fault;
+pub mod testing;
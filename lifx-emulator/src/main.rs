@@ -0,0 +1,43 @@
+use lifx_emulator::{config::Config, emulator::Emulator};
+use std::{fs, net::UdpSocket, process};
+
+const USAGE: &str = "Usage: lifx-emulator <CONFIG>
+
+Emulate the LIFX bulbs described by a TOML configuration file.";
+
+fn main() {
+    let path = match std::env::args().nth(1) {
+        Some(path) if path != "-h" && path != "--help" => path,
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(1);
+        }
+    };
+
+    if let Err(e) = run(&path) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+fn run(path: &str) -> Result<(), String> {
+    let config = fs::read_to_string(path).map_err(|e| format!("Could not read {}. {}", path, e))?;
+    let config = Config::from_toml(&config)?;
+    let bulbs = config.bulbs()?;
+
+    let socket = UdpSocket::bind(config.address).map_err(|e| e.to_string())?;
+    let emulator = Emulator::start(socket, bulbs).map_err(|e| e.to_string())?;
+    eprintln!("Listening on {}.", emulator.address());
+    for bulb in emulator.bulbs() {
+        eprintln!(
+            "{} {} ({} / {})",
+            bulb.mac_address(),
+            bulb.label(),
+            bulb.group(),
+            bulb.location()
+        );
+    }
+
+    emulator.join();
+    Result::Ok(())
+}
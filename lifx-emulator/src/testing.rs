@@ -0,0 +1,61 @@
+//! Fixtures for testing clients against emulated bulbs.
+
+use super::{bulb::Bulb, emulator::Emulator};
+use lifx_client::{
+    client::Client,
+    protocol::{header::MacAddress, message::Power},
+    transport::{MemoryNetwork, MemoryTransport},
+};
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+/// The address of the emulator started by [`start`].
+pub const EMULATOR_ADDRESS: &str = "10.0.0.2:56700";
+
+// How often to check the condition passed to `wait_until`.
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+// How long to wait for the condition passed to `wait_until`.
+const WAIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Return a MAC address with the LIFX prefix which ends in `n`.
+pub fn mac(n: u8) -> MacAddress {
+    MacAddress {
+        address: [0xd0, 0x73, 0xd5, 0x00, 0x00, n],
+    }
+}
+
+/// Start an emulator on `network` with three bulbs:
+///
+/// * `mac(1)`: "Kitchen" in "Downstairs", off.
+/// * `mac(2)`: "Bedroom" in "Upstairs", off.
+/// * `mac(3)`: "Office" in "Upstairs", on.
+pub fn start(network: &MemoryNetwork) -> Emulator {
+    let bulbs = vec![
+        Bulb::new(mac(1), "Kitchen").with_group("Downstairs"),
+        Bulb::new(mac(2), "Bedroom").with_group("Upstairs"),
+        Bulb::new(mac(3), "Office")
+            .with_group("Upstairs")
+            .with_power(Power::On(0xffff)),
+    ];
+    let transport = network.bind(EMULATOR_ADDRESS.parse().unwrap()).unwrap();
+    Emulator::start(transport, bulbs).unwrap()
+}
+
+/// Return a client on `network` with a short timeout.
+pub fn client(network: &MemoryNetwork) -> Client<MemoryTransport> {
+    let client = Client::new(network.bind("10.0.0.1:0".parse().unwrap()).unwrap());
+    client.set_timeout(Duration::from_millis(200));
+    client
+}
+
+/// Wait until `condition` returns true, or give up after a few seconds. Used to wait for the
+/// emulator to handle commands which have no response. The caller should assert the expected
+/// state afterwards.
+pub fn wait_until(mut condition: impl FnMut() -> bool) {
+    let deadline = Instant::now() + WAIT_TIMEOUT;
+    while !condition() && Instant::now() < deadline {
+        thread::sleep(POLL_INTERVAL);
+    }
+}
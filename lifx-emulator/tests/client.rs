@@ -0,0 +1,324 @@
+//! Tests of client features against emulated bulbs.
+
+use lifx_client::{
+    client::Client,
+    color::Color,
+    discovery::{DeviceEvent, DiscoveryOptions, DiscoveryService},
+    protocol::message::{Hsbk, Power},
+    registry::DeviceRegistry,
+    state::LightState,
+    transport::MemoryNetwork,
+    watcher::{StateEvent, StateWatcher},
+};
+use lifx_emulator::{
+    bulb::Bulb,
+    emulator::Emulator,
+    testing::{client, mac, start, wait_until},
+};
+use std::{
+    io, thread,
+    time::{Duration, Instant},
+};
+
+#[test]
+fn apply_partial_state() {
+    let network = MemoryNetwork::new();
+    let emulator = start(&network);
+    let client = client(&network);
+
+    let devices = client.discover().unwrap();
+    let kitchen = devices.iter().find(|d| d.label() == "Kitchen").unwrap();
+    client.set_color(kitchen, Color::GREEN).unwrap();
+
+    // Only the brightness changes, without reading the rest of the color first.
+    let state = LightState::new().with_brightness(0.5).with_power(true);
+    client
+        .apply_state(kitchen, &state, Duration::from_secs(0))
+        .unwrap();
+
+    let bulb = emulator.bulb(mac(1)).unwrap();
+    assert_eq!(bulb.power(), Power::On(0xffff));
+    assert_eq!(
+        bulb.color(),
+        Hsbk::from(Color::GREEN).with_brightness(0x7fff)
+    );
+}
+
+#[test]
+fn discovery_service() {
+    let network = MemoryNetwork::new();
+    let bind = |address: &str| network.bind(address.parse().unwrap()).unwrap();
+    let emulator = Emulator::start(
+        bind("10.0.0.2:56700"),
+        vec![Bulb::new(mac(1), "Kitchen"), Bulb::new(mac(2), "Bedroom")],
+    )
+    .unwrap();
+    let client = client(&network);
+    client.set_timeout(Duration::from_millis(50));
+    let (_service, events) = DiscoveryService::start(client, Duration::from_millis(20));
+    let next = || events.recv_timeout(Duration::from_secs(5)).unwrap();
+
+    let mut appeared: Vec<String> = (0..2)
+        .map(|_| match next() {
+            DeviceEvent::Appeared(device) => device.label().clone(),
+            event => panic!("Unexpected event. {:?}", event),
+        })
+        .collect();
+    appeared.sort();
+    assert_eq!(appeared, vec!["Bedroom", "Kitchen"]);
+
+    // The kitchen moves to a new address and is renamed, and the bedroom goes away.
+    drop(emulator);
+    let _emulator =
+        Emulator::start(bind("10.0.0.3:56700"), vec![Bulb::new(mac(1), "Pantry")]).unwrap();
+
+    let mut changes = Vec::new();
+    while !changes
+        .iter()
+        .any(|event| matches!(event, DeviceEvent::Disappeared(_)))
+    {
+        changes.push(next());
+    }
+    assert_eq!(changes.len(), 3);
+    for event in changes {
+        match event {
+            DeviceEvent::AddressChanged { device, old } => {
+                assert_eq!(device.label(), "Pantry");
+                assert_eq!(old.socket_address(), "10.0.0.2:56700".parse().unwrap());
+                assert_eq!(
+                    device.address().socket_address(),
+                    "10.0.0.3:56700".parse().unwrap()
+                );
+            }
+            DeviceEvent::Renamed { device, old } => {
+                assert_eq!(device.label(), "Pantry");
+                assert_eq!(old, "Kitchen");
+            }
+            DeviceEvent::Disappeared(device) => assert_eq!(device.label(), "Bedroom"),
+            event => panic!("Unexpected event. {:?}", event),
+        }
+    }
+}
+
+#[test]
+fn relocate_device() {
+    let network = MemoryNetwork::new();
+    let bind = |address: &str| network.bind(address.parse().unwrap()).unwrap();
+    let emulator =
+        Emulator::start(bind("10.0.0.2:56700"), vec![Bulb::new(mac(1), "Kitchen")]).unwrap();
+    let client = client(&network);
+    let devices = client.discover().unwrap();
+    let kitchen = devices.iter().next().unwrap();
+
+    // The kitchen moves to a new address.
+    drop(emulator);
+    let emulator =
+        Emulator::start(bind("10.0.0.3:56700"), vec![Bulb::new(mac(1), "Kitchen")]).unwrap();
+
+    // The first timeout is reported, and the second finds the device again and retries.
+    let error = client.get_color(kitchen).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+    client.set_color(kitchen, Color::GREEN).unwrap();
+    assert_eq!(
+        emulator.bulb(mac(1)).unwrap().color(),
+        Hsbk::from(Color::GREEN)
+    );
+
+    // Later requests go straight to the new address.
+    let start = Instant::now();
+    client.get_color(kitchen).unwrap();
+    assert!(start.elapsed() < Duration::from_millis(200));
+    let devices = client.get_devices();
+    assert_eq!(devices.len(), 1);
+    assert_eq!(
+        devices.iter().next().unwrap().address().socket_address(),
+        emulator.address()
+    );
+}
+
+#[test]
+fn relocate_device_for_power_commands() {
+    let network = MemoryNetwork::new();
+    let bind = |address: &str| network.bind(address.parse().unwrap()).unwrap();
+    let emulator =
+        Emulator::start(bind("10.0.0.2:56700"), vec![Bulb::new(mac(1), "Kitchen")]).unwrap();
+    let client = client(&network);
+    let devices = client.discover().unwrap();
+    let kitchen = devices.iter().next().unwrap();
+
+    drop(emulator);
+    let emulator =
+        Emulator::start(bind("10.0.0.3:56700"), vec![Bulb::new(mac(1), "Kitchen")]).unwrap();
+
+    // Power commands wait for an acknowledgement, so they time out and relocate the device too.
+    let error = client.turn_on(kitchen).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+    client.turn_on(kitchen).unwrap();
+    assert_eq!(emulator.bulb(mac(1)).unwrap().power(), Power::On(0xffff));
+}
+
+#[test]
+fn refresh_devices() {
+    let network = MemoryNetwork::new();
+    let emulator = start(&network);
+    let client = client(&network);
+    client.discover().unwrap();
+    assert!(client
+        .refresh_all()
+        .values()
+        .all(|result| result.as_ref().unwrap().is_none()));
+
+    // The kitchen is renamed and moved to a different group.
+    drop(emulator);
+    let bulbs = vec![
+        Bulb::new(mac(1), "Pantry").with_group("Upstairs"),
+        Bulb::new(mac(2), "Bedroom").with_group("Upstairs"),
+    ];
+    let _emulator = Emulator::start(
+        network.bind("10.0.0.2:56700".parse().unwrap()).unwrap(),
+        bulbs,
+    )
+    .unwrap();
+
+    let kitchen = client.device_by_label("Kitchen").unwrap();
+    let update = client.refresh(&kitchen).unwrap().unwrap();
+    assert_eq!(update.old(), &kitchen);
+    assert_eq!(update.current().label(), "Pantry");
+    assert_eq!(update.current().group(), "Upstairs");
+    assert!(update.label_changed() && update.group_changed());
+    assert!(!update.address_changed() && !update.location_changed());
+    assert_eq!(client.device_by_mac(mac(1)).unwrap().label(), "Pantry");
+
+    let results = client.refresh_all();
+    assert_eq!(results.len(), 3);
+    for (device, result) in results {
+        match device.label().as_str() {
+            "Office" => assert!(result.is_err()),
+            _ => assert_eq!(result.unwrap(), Option::None),
+        }
+    }
+}
+
+#[test]
+fn start_from_registry() {
+    let network = MemoryNetwork::new();
+    let emulator = start(&network);
+    let client = client(&network);
+    let devices = client.discover().unwrap();
+
+    let mut registry = DeviceRegistry::new();
+    registry.merge(&devices);
+    for device in devices.iter() {
+        let product = client.get_product(device).unwrap();
+        registry.set_product(device.address().mac_address(), product);
+    }
+    let saved = registry.to_toml().unwrap();
+
+    // A new client controls the saved devices without discovering them.
+    let client = self::client(&network);
+    client.set_discovery_options(DiscoveryOptions::new().with_targets(vec![]));
+    client.load_registry(&DeviceRegistry::from_toml(&saved).unwrap());
+    let kitchen = client.device_by_label("Kitchen").unwrap();
+    client.set_color(&kitchen, Color::GREEN).unwrap();
+    assert_eq!(
+        emulator.bulb(mac(1)).unwrap().color(),
+        Hsbk::from(Color::GREEN)
+    );
+    let registry = DeviceRegistry::from_toml(&saved).unwrap();
+    assert_eq!(
+        registry.get(mac(1)).unwrap().product(),
+        Option::Some(emulator.bulb(mac(1)).unwrap().product())
+    );
+}
+
+#[test]
+fn watch_state() {
+    let network = MemoryNetwork::new();
+    let emulator = start(&network);
+    let client = client(&network);
+    client.set_relocate_after(Option::None);
+    let devices: Vec<_> = client.discover().unwrap().into_iter().collect();
+    let (_watcher, events) =
+        StateWatcher::start(client.clone(), devices, Duration::from_millis(20));
+    let next = || events.recv_timeout(Duration::from_secs(5)).unwrap();
+
+    // Let the watcher record the initial states, then change a light.
+    thread::sleep(Duration::from_millis(100));
+    let other = Client::new(network.bind("10.0.0.3:0".parse().unwrap()).unwrap());
+    let kitchen = client.device_by_label("Kitchen").unwrap();
+    other.set_color(&kitchen, Color::GREEN).unwrap();
+    match next() {
+        StateEvent::StateChanged { device, old, new } => {
+            assert_eq!(device.label(), "Kitchen");
+            assert_eq!(old.color(), Hsbk::new(0, 0, 0xffff, 3500));
+            assert_eq!(new.color(), Hsbk::from(Color::GREEN));
+        }
+        event => panic!("Unexpected event. {:?}", event),
+    }
+
+    drop(emulator);
+    let mut unreachable: Vec<String> = (0..3)
+        .map(|_| match next() {
+            StateEvent::Unreachable(device) => device.label().clone(),
+            event => panic!("Unexpected event. {:?}", event),
+        })
+        .collect();
+    unreachable.sort();
+    assert_eq!(unreachable, vec!["Bedroom", "Kitchen", "Office"]);
+}
+
+#[test]
+fn broadcast_commands() {
+    let network = MemoryNetwork::new();
+    let emulator = start(&network);
+    let client = client(&network);
+
+    client
+        .broadcast_power(true, Duration::from_secs(0), 2)
+        .unwrap();
+    client
+        .broadcast_color(Color::GREEN, Duration::from_secs(0), 2)
+        .unwrap();
+
+    // Broadcasts do not wait for a response, so wait until the emulator has handled them.
+    let changed =
+        |bulb: &Bulb| bulb.power() == Power::On(0xffff) && bulb.color() == Hsbk::from(Color::GREEN);
+    wait_until(|| emulator.bulbs().iter().all(changed));
+    assert!(emulator.bulbs().iter().all(changed));
+}
+
+#[test]
+fn concurrent_requests() {
+    let network = MemoryNetwork::new();
+    let bulbs = (1..=8)
+        .map(|n| Bulb::new(mac(n), &format!("Bulb {}", n)))
+        .collect();
+    let transport = network.bind("10.0.0.2:56700".parse().unwrap()).unwrap();
+    let emulator = Emulator::start(transport, bulbs).unwrap();
+    let client = client(&network);
+    let devices = client.discover().unwrap();
+    assert_eq!(devices.len(), 8);
+
+    let threads: Vec<_> = devices
+        .into_iter()
+        .map(|device| {
+            let client = client.clone();
+            // Use a different hue for each bulb, so misdirected replies are detected.
+            let hue = device.label()[5..].parse::<u16>().unwrap() * 1000;
+            thread::spawn(move || {
+                for n in 0..10u16 {
+                    let color = Hsbk::new(hue, n * 1000, 0xffff, 3500);
+                    client.set_color(&device, color.into()).unwrap();
+                    assert_eq!(Hsbk::from(client.get_color(&device).unwrap()), color);
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    for bulb in emulator.bulbs() {
+        assert_eq!(bulb.color().saturation(), 9000);
+    }
+}
@@ -7,6 +7,8 @@ use crate::forms::Preset;
 #[derive(Clone, Serialize, Deserialize)]
 pub(crate) struct AppConfig {
     devices: Option<Vec<String>>,
+    // Whether to listen on the LIFX port for changes made by other controllers.
+    listen: Option<bool>,
     presets: HashMap<String, Preset>,
 }
 
@@ -14,6 +16,7 @@ impl AppConfig {
     pub(crate) fn new() -> AppConfig {
         AppConfig {
             devices: Option::None,
+            listen: Option::None,
             presets: HashMap::new(),
         }
     }
@@ -26,6 +29,10 @@ impl AppConfig {
         }
     }
 
+    pub(crate) fn listen(&self) -> bool {
+        self.listen.unwrap_or(false)
+    }
+
     pub(crate) fn presets(&self) -> HashMap<String, Preset> {
         self.presets.clone()
     }
@@ -1,4 +1,7 @@
-use lifx_client::{client::Client, device::Device};
+use lifx_client::{
+    client::Client, device::Device, discovery::LIFX_PORT, listener::Listener, state::LightState,
+    transport::Transport,
+};
 use rocket::{http::Status, response::Responder, Response};
 use serde::{Deserialize, Serialize};
 use std::{
@@ -59,20 +62,59 @@ impl<'r> Responder<'r, 'static> for Presets {
     }
 }
 
-pub(crate) struct LifxController {
-    client: Mutex<Client>,
+pub(crate) struct LifxController<T: Transport = UdpSocket> {
+    client: Client<T>,
     config: Mutex<AppConfig>,
+    // Keeps cached light states up to date with changes made by other controllers.
+    listener: Option<Listener>,
 }
 
+// How long the state of each light is cached, so that relative changes do not query it first.
+const STATE_CACHE_TTL: Duration = Duration::from_secs(2);
+
 impl LifxController {
     pub(crate) fn new() -> Result<LifxController> {
+        LifxController::with_client(LifxController::client()?)
+    }
+
+    pub(crate) fn from_config(config: AppConfig) -> Result<LifxController> {
+        let listen = config.listen();
+        let controller = LifxController::with_client_and_config(LifxController::client()?, config)?;
+        if listen {
+            Result::Ok(controller.listen())
+        } else {
+            Result::Ok(controller)
+        }
+    }
+
+    fn client() -> Result<Client> {
         let socket = UdpSocket::bind("0.0.0.0:0")?;
-        socket.set_read_timeout(Option::Some(Duration::from_millis(500)))?;
         let client = Client::new(socket);
+        client.set_state_cache(Option::Some(STATE_CACHE_TTL));
+        Result::Ok(client)
+    }
 
+    /// Listen for changes made by other controllers. Another program may already be using the
+    /// LIFX port, in which case changes are only seen once cached states expire.
+    fn listen(mut self) -> LifxController {
+        match UdpSocket::bind(("0.0.0.0", LIFX_PORT)) {
+            Ok(socket) => {
+                self.listener =
+                    Option::Some(Listener::with_callback(self.client.clone(), socket, |_| {}));
+            }
+            Err(e) => eprintln!("Could not listen on port {}: {}", LIFX_PORT, e),
+        }
+        self
+    }
+}
+
+impl<T: Transport> LifxController<T> {
+    /// Create a controller using the specified client and discover devices.
+    pub(crate) fn with_client(client: Client<T>) -> Result<LifxController<T>> {
         let controller = LifxController {
-            client: Mutex::new(client),
+            client,
             config: Mutex::new(AppConfig::new()),
+            listener: Option::None,
         };
 
         controller.update()?;
@@ -80,11 +122,11 @@ impl LifxController {
         Result::Ok(controller)
     }
 
-    pub(crate) fn from_config(config: AppConfig) -> Result<LifxController> {
-        let socket = UdpSocket::bind("0.0.0.0:0")?;
-        socket.set_read_timeout(Option::Some(Duration::from_millis(500)))?;
-        let mut client = Client::new(socket);
-
+    /// Create a controller using the specified client and find the devices in `config`.
+    pub(crate) fn with_client_and_config(
+        client: Client<T>,
+        config: AppConfig,
+    ) -> Result<LifxController<T>> {
         for device in config.devices() {
             if let Ok(address) = device.parse() {
                 client.find_device(address)?;
@@ -94,60 +136,46 @@ impl LifxController {
         }
 
         Result::Ok(LifxController {
-            client: Mutex::new(client),
+            client,
             config: Mutex::new(config),
+            listener: Option::None,
         })
     }
 
     pub(crate) fn update(&self) -> Result<Devices> {
-        let devices = self
-            .client()?
-            .discover()?
-            .iter()
-            .map(|d| d.into())
-            .collect();
+        let devices = self.client.discover()?.iter().map(|d| d.into()).collect();
         Result::Ok(Devices { devices })
     }
 
     pub(crate) fn get_lights(&self) -> Result<Devices> {
-        let devices = self
-            .client()?
-            .get_devices()
-            .iter()
-            .map(|d| d.into())
-            .collect();
+        let devices = self.client.get_devices().iter().map(|d| d.into()).collect();
         Result::Ok(Devices { devices })
     }
 
     pub(crate) fn delete_lights(&self) -> Result<()> {
-        self.client()?.forget_devices();
+        self.client.forget_devices();
         Result::Ok(())
     }
 
     pub(crate) fn toggle(&self, selector: Selector, duration: u32) -> Result<()> {
-        let client = self.client()?;
-        for device in client.get_devices().iter().filter(|d| selector.filter(d)) {
-            client.transition_toggle(device, Duration::from_millis(duration as u64))?;
-        }
-
-        Result::Ok(())
+        let duration = Duration::from_millis(duration as u64);
+        self.apply(selector, |client, device| {
+            client.transition_toggle(device, duration)
+        })
     }
 
     pub(crate) fn on(&self, selector: Selector, duration: u32) -> Result<()> {
-        let client = self.client()?;
-        for device in client.get_devices().iter().filter(|d| selector.filter(d)) {
-            client.transition_on(device, Duration::from_millis(duration as u64))?;
-        }
-
-        Result::Ok(())
+        let duration = Duration::from_millis(duration as u64);
+        self.apply(selector, |client, device| {
+            client.transition_on(device, duration)
+        })
     }
 
     pub(crate) fn off(&self, selector: Selector, duration: u32) -> Result<()> {
-        let client = self.client()?;
-        for device in client.get_devices().iter().filter(|d| selector.filter(d)) {
-            client.transition_off(device, Duration::from_millis(duration as u64))?;
-        }
-        Result::Ok(())
+        let duration = Duration::from_millis(duration as u64);
+        self.apply(selector, |client, device| {
+            client.transition_off(device, duration)
+        })
     }
 
     pub(crate) fn set_brightness(
@@ -156,16 +184,10 @@ impl LifxController {
         brightness: f32,
         duration: u32,
     ) -> Result<()> {
-        let client = self.client()?;
-        for device in client.get_devices().iter().filter(|d| selector.filter(d)) {
-            client.transition_brightness(
-                device,
-                brightness,
-                Duration::from_millis(duration as u64),
-            )?;
-        }
-
-        Result::Ok(())
+        let duration = Duration::from_millis(duration as u64);
+        self.apply(selector, |client, device| {
+            client.transition_brightness(device, brightness, duration)
+        })
     }
 
     pub(crate) fn set_temperature(
@@ -174,16 +196,10 @@ impl LifxController {
         temperature: u16,
         duration: u32,
     ) -> Result<()> {
-        let client = self.client()?;
-        for device in client.get_devices().iter().filter(|d| selector.filter(d)) {
-            client.transition_temperature(
-                device,
-                temperature,
-                Duration::from_millis(duration as u64),
-            )?;
-        }
-
-        Result::Ok(())
+        let duration = Duration::from_millis(duration as u64);
+        self.apply(selector, |client, device| {
+            client.transition_temperature(device, temperature, duration)
+        })
     }
 
     pub(crate) fn update_lights(
@@ -195,48 +211,31 @@ impl LifxController {
         kelvin: Option<u16>,
         duration: u32,
     ) -> Result<()> {
-        let client = self.client()?;
-        for device in client.get_devices().iter().filter(|d| selector.filter(d)) {
-            let mut color = client.get_color(device)?;
-
-            let mut set_color = false;
-            if let Some(hue) = hue {
-                color = color.with_hue(hue);
-                set_color = true;
-            }
-
-            if let Some(saturation) = saturation {
-                color = color.with_saturation(saturation);
-                set_color = true;
-            }
-
-            if let Some(brightness) = brightness {
-                color = color.with_brightness(brightness);
-                set_color = true;
+        let mut state = LightState::new();
+        if let Some(hue) = hue {
+            state = state.with_hue(hue);
+        }
+        if let Some(saturation) = saturation {
+            state = state.with_saturation(saturation);
+        }
+        if let Some(brightness) = brightness {
+            state = state.with_brightness(brightness);
+            if brightness > 0.0 {
+                state = state.with_power(true);
             }
-
-            let duration = Duration::from_millis(duration as u64);
-
-            if set_color {
-                if let Some(brightness) = brightness {
-                    if brightness > 0.0 {
-                        client.transition_on(device, duration)?;
-                    }
-                }
-                client.transition_color(device, color, duration)?;
-            } else if let Some(kelvin) = kelvin {
-                if let Some(brightness) = brightness {
-                    client
-                        .transition_temperature_brightness(device, kelvin, brightness, duration)?;
-                    if brightness > 0.0 {
-                        client.transition_on(device, duration)?;
-                    }
-                }
-                client.transition_temperature(device, kelvin, duration)?;
+        }
+        if let Some(kelvin) = kelvin {
+            state = state.with_kelvin(kelvin);
+            // A temperature on its own sets the light to white.
+            if hue.is_none() && saturation.is_none() {
+                state = state.with_hue(0.0).with_saturation(0.0);
             }
         }
 
-        Result::Ok(())
+        let duration = Duration::from_millis(duration as u64);
+        self.apply(selector, |client, device| {
+            client.apply_state(device, &state, duration)
+        })
     }
 
     pub(crate) fn presets(&self) -> Result<Presets> {
@@ -269,13 +268,36 @@ impl LifxController {
         Result::Ok(())
     }
 
-    fn client(&self) -> Result<MutexGuard<Client>> {
-        self.client
-            .lock()
-            .map_err(|_| Error("Could not acquire client lock".to_string()))
+    /// Apply `operation` to every selected device concurrently. Every device is attempted, even if
+    /// some fail, and the error lists each device which failed.
+    fn apply<F>(&self, selector: Selector, operation: F) -> Result<()>
+    where
+        F: Fn(&Client<T>, &Device) -> io::Result<()> + Sync,
+    {
+        let devices: Vec<Device> = self
+            .client
+            .get_devices()
+            .into_iter()
+            .filter(|d| selector.filter(d))
+            .collect();
+
+        let mut errors: Vec<String> = self
+            .client
+            .apply(&devices, operation)
+            .into_iter()
+            .filter_map(|(device, result)| {
+                result.err().map(|e| format!("{}: {}", device.label(), e))
+            })
+            .collect();
+        if errors.is_empty() {
+            Result::Ok(())
+        } else {
+            errors.sort();
+            Result::Err(Error(errors.join(" ")))
+        }
     }
 
-    fn config(&self) -> Result<MutexGuard<AppConfig>> {
+    fn config(&self) -> Result<MutexGuard<'_, AppConfig>> {
         self.config
             .lock()
             .map_err(|_| Error("Could not acquire config lock".to_string()))
@@ -309,3 +331,119 @@ impl fmt::Display for Error {
         write!(f, "{}", self.0)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lifx_client::{
+        transport::{MemoryNetwork, MemoryTransport},
+        Hsbk, Power,
+    };
+    use lifx_emulator::{
+        bulb::Bulb,
+        emulator::Emulator,
+        fault::{Faults, FaultyTransport},
+        testing::{client, mac, EMULATOR_ADDRESS},
+    };
+    use std::sync::Arc;
+
+    fn controller(network: &MemoryNetwork) -> LifxController<MemoryTransport> {
+        LifxController::with_client(client(network)).unwrap()
+    }
+
+    fn bulbs() -> Vec<Bulb> {
+        vec![
+            Bulb::new(mac(1), "Kitchen").with_group("Downstairs"),
+            Bulb::new(mac(2), "Bedroom").with_group("Upstairs"),
+        ]
+    }
+
+    fn emulator(network: &MemoryNetwork) -> Emulator {
+        let transport = network.bind(EMULATOR_ADDRESS.parse().unwrap()).unwrap();
+        Emulator::start(transport, bulbs()).unwrap()
+    }
+
+    #[test]
+    fn no_devices() {
+        let network = MemoryNetwork::new();
+        let controller = controller(&network);
+
+        assert!(controller.update().unwrap().devices.is_empty());
+        assert!(controller.get_lights().unwrap().devices.is_empty());
+        assert!(controller.toggle(Selector::All, 0).is_ok());
+    }
+
+    #[test]
+    fn emulated_devices() {
+        let network = MemoryNetwork::new();
+        let emulator = emulator(&network);
+        let controller = controller(&network);
+
+        let devices = controller.get_lights().unwrap().devices;
+        assert_eq!(devices.len(), 2);
+        assert!(devices.contains(&JsonDevice {
+            label: "Kitchen".to_string(),
+            group: "Downstairs".to_string(),
+            location: "".to_string(),
+        }));
+
+        controller
+            .set_brightness(Selector::Group("Downstairs"), 0.5, 0)
+            .unwrap();
+
+        let kitchen = emulator.bulb(mac(1)).unwrap();
+        assert_eq!(kitchen.power(), Power::On(0xffff));
+        assert_eq!(kitchen.color().brightness(), 0x7fff);
+        assert_eq!(emulator.bulb(mac(2)).unwrap().power(), Power::Off);
+    }
+
+    #[test]
+    fn update_lights() {
+        let network = MemoryNetwork::new();
+        let emulator = emulator(&network);
+        let controller = controller(&network);
+        controller.update().unwrap();
+
+        controller
+            .update_lights(
+                Selector::Label("Kitchen"),
+                Option::None,
+                Option::None,
+                Option::Some(0.5),
+                Option::Some(2700),
+                0,
+            )
+            .unwrap();
+        let kitchen = emulator.bulb(mac(1)).unwrap();
+        assert_eq!(kitchen.power(), Power::On(0xffff));
+        assert_eq!(kitchen.color(), Hsbk::new(0, 0, 0x7fff, 2700));
+        assert_eq!(emulator.bulb(mac(2)).unwrap().power(), Power::Off);
+    }
+
+    #[test]
+    fn offline_device() {
+        let network = MemoryNetwork::new();
+        let transport = network.bind(EMULATOR_ADDRESS.parse().unwrap()).unwrap();
+        let faulty = Arc::new(FaultyTransport::new(transport, Faults::new()));
+        let emulator = Emulator::start(faulty.clone(), bulbs()).unwrap();
+        let controller = controller(&network);
+        assert_eq!(controller.get_lights().unwrap().devices.len(), 2);
+
+        faulty.set_offline(mac(2), true);
+        assert!(controller
+            .set_brightness(Selector::Label("Bedroom"), 0.5, 0)
+            .is_err());
+        controller
+            .set_brightness(Selector::Label("Kitchen"), 0.5, 0)
+            .unwrap();
+        assert_eq!(emulator.bulb(mac(1)).unwrap().power(), Power::On(0xffff));
+
+        // An offline device does not prevent the others from changing.
+        let error = controller
+            .set_temperature(Selector::All, 2700, 0)
+            .unwrap_err();
+        assert!(error.0.starts_with("Bedroom: "));
+        assert!(!error.0.contains("Kitchen"));
+        assert_eq!(emulator.bulb(mac(1)).unwrap().color().kelvin(), 2700);
+    }
+}
@@ -38,15 +38,12 @@ pub(crate) enum Selector<'a> {
 }
 
 impl<'a> Selector<'a> {
-    pub(crate) fn parse(s: &str) -> Selector {
-        if s.starts_with("label:") {
-            let label = &s["label:".len()..];
+    pub(crate) fn parse(s: &str) -> Selector<'_> {
+        if let Some(label) = s.strip_prefix("label:") {
             Selector::Label(label)
-        } else if s.starts_with("group:") {
-            let label = &s["group:".len()..];
+        } else if let Some(label) = s.strip_prefix("group:") {
             Selector::Group(label)
-        } else if s.starts_with("location:") {
-            let location = &s["location:".len()..];
+        } else if let Some(location) = s.strip_prefix("location:") {
             Selector::Location(location)
         } else if s.eq("all") {
             Selector::All
@@ -93,7 +90,7 @@ pub(crate) struct LightAction {
 }
 
 impl LightAction {
-    pub(crate) fn selector(&self) -> Selector {
+    pub(crate) fn selector(&self) -> Selector<'_> {
         Selector::parse(&self.selector)
     }
 